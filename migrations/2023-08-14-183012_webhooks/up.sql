@@ -0,0 +1,22 @@
+CREATE TABLE webhook_subscription (
+    id SERIAL PRIMARY KEY,
+    -- NULL subscribes to every door the owner manages
+    door_id INTEGER REFERENCES door(id) ON DELETE CASCADE,
+    owner_id INTEGER NOT NULL REFERENCES user_profile(id),
+    url VARCHAR NOT NULL,
+    secret VARCHAR NOT NULL,
+    event_types TEXT[] NOT NULL,
+    created_at timestamptz NOT NULL
+);
+
+CREATE TABLE webhook_delivery (
+    id SERIAL PRIMARY KEY,
+    subscription_id INTEGER NOT NULL REFERENCES webhook_subscription(id) ON DELETE CASCADE,
+    event_type VARCHAR NOT NULL,
+    payload TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    response_status INTEGER,
+    delivered BOOLEAN NOT NULL DEFAULT false,
+    created_at timestamptz NOT NULL,
+    last_attempt_at timestamptz
+);
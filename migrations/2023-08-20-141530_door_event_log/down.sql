@@ -0,0 +1 @@
+DROP TABLE door_event;
\ No newline at end of file
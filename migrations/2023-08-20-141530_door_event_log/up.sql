@@ -0,0 +1,9 @@
+CREATE TABLE door_event (
+    id BIGSERIAL PRIMARY KEY,
+    kind VARCHAR NOT NULL,
+    door_id INTEGER NOT NULL REFERENCES door(id) ON DELETE CASCADE,
+    user_profile_id INTEGER REFERENCES user_profile(id),
+    created_at timestamptz NOT NULL
+);
+
+CREATE INDEX door_event_door_id_idx ON door_event (door_id, id);
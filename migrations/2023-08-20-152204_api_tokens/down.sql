@@ -0,0 +1 @@
+DROP TABLE api_token;
\ No newline at end of file
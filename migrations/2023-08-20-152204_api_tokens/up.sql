@@ -0,0 +1,8 @@
+CREATE TABLE api_token (
+    id SERIAL PRIMARY KEY,
+    user_profile_id INTEGER NOT NULL REFERENCES user_profile(id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    created_at timestamptz NOT NULL,
+    last_used_at timestamptz
+);
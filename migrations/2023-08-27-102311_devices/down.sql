@@ -0,0 +1 @@
+DROP TABLE device;
\ No newline at end of file
@@ -0,0 +1,7 @@
+CREATE TABLE device (
+    id SERIAL PRIMARY KEY,
+    door_id INTEGER NOT NULL REFERENCES door(id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    created_at timestamptz NOT NULL
+);
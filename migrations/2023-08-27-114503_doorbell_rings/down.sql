@@ -0,0 +1 @@
+DROP TABLE doorbell_ring;
\ No newline at end of file
@@ -0,0 +1,9 @@
+CREATE TABLE doorbell_ring (
+    id SERIAL PRIMARY KEY,
+    door_id INTEGER NOT NULL REFERENCES door(id) ON DELETE CASCADE,
+    device_id INTEGER REFERENCES device(id) ON DELETE SET NULL,
+    rung_at timestamptz NOT NULL,
+    answered_by INTEGER REFERENCES user_profile(id),
+    answered_at timestamptz,
+    answer_latency_ms INTEGER
+);
@@ -0,0 +1 @@
+DROP TABLE snapshot;
\ No newline at end of file
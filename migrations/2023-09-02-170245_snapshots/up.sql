@@ -0,0 +1,10 @@
+CREATE TABLE snapshot (
+    id SERIAL PRIMARY KEY,
+    door_id INTEGER NOT NULL REFERENCES door(id) ON DELETE CASCADE,
+    device_id INTEGER REFERENCES device(id) ON DELETE SET NULL,
+    access_history_id INTEGER REFERENCES access_history(id) ON DELETE SET NULL,
+    doorbell_ring_id INTEGER REFERENCES doorbell_ring(id) ON DELETE SET NULL,
+    file_name VARCHAR NOT NULL,
+    size_bytes INTEGER NOT NULL,
+    created_at timestamptz NOT NULL
+);
@@ -0,0 +1,2 @@
+DROP TABLE door_state;
+ALTER TABLE door DROP COLUMN held_open_seconds;
\ No newline at end of file
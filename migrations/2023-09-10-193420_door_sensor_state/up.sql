@@ -0,0 +1,8 @@
+ALTER TABLE door ADD COLUMN held_open_seconds INTEGER NOT NULL DEFAULT 60;
+
+CREATE TABLE door_state (
+    door_id INTEGER PRIMARY KEY REFERENCES door(id) ON DELETE CASCADE,
+    state VARCHAR NOT NULL,
+    locked BOOLEAN,
+    changed_at timestamptz NOT NULL
+);
@@ -0,0 +1,4 @@
+DROP TABLE device_telemetry;
+ALTER TABLE device DROP COLUMN firmware_version;
+ALTER TABLE device DROP COLUMN online;
+ALTER TABLE device DROP COLUMN last_seen_at;
@@ -0,0 +1,15 @@
+ALTER TABLE device ADD COLUMN last_seen_at timestamptz;
+ALTER TABLE device ADD COLUMN online BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE device ADD COLUMN firmware_version VARCHAR;
+
+CREATE TABLE device_telemetry (
+    id SERIAL PRIMARY KEY,
+    device_id INTEGER NOT NULL REFERENCES device(id) ON DELETE CASCADE,
+    uptime_seconds BIGINT NOT NULL,
+    rssi INTEGER NOT NULL,
+    free_heap INTEGER NOT NULL,
+    firmware_version VARCHAR NOT NULL,
+    recorded_at timestamptz NOT NULL
+);
+
+CREATE INDEX device_telemetry_device_id_recorded_at ON device_telemetry (device_id, recorded_at);
@@ -0,0 +1,13 @@
+CREATE TABLE device_command (
+    id SERIAL PRIMARY KEY,
+    device_id INTEGER NOT NULL REFERENCES device(id) ON DELETE CASCADE,
+    door_id INTEGER NOT NULL REFERENCES door(id) ON DELETE CASCADE,
+    user_profile_id INTEGER REFERENCES user_profile(id) ON DELETE SET NULL,
+    command JSONB NOT NULL,
+    status VARCHAR NOT NULL DEFAULT 'pending',
+    created_at timestamptz NOT NULL,
+    expires_at timestamptz NOT NULL,
+    delivered_at timestamptz
+);
+
+CREATE INDEX device_command_pending ON device_command (device_id) WHERE status = 'pending';
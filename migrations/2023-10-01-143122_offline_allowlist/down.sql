@@ -0,0 +1,6 @@
+ALTER TABLE device DROP COLUMN allowlist_version;
+DROP TABLE allowlist_version;
+ALTER TABLE door_permission DROP COLUMN schedule_end;
+ALTER TABLE door_permission DROP COLUMN schedule_start;
+ALTER TABLE door_permission DROP COLUMN schedule_days;
+DROP TABLE credential;
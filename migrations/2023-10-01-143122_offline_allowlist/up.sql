@@ -0,0 +1,23 @@
+CREATE TABLE credential (
+    id SERIAL PRIMARY KEY,
+    user_profile_id INTEGER NOT NULL REFERENCES user_profile(id) ON DELETE CASCADE,
+    kind VARCHAR NOT NULL,
+    value VARCHAR NOT NULL,
+    label VARCHAR,
+    created_at timestamptz NOT NULL,
+    UNIQUE (kind, value)
+);
+
+ALTER TABLE door_permission ADD COLUMN schedule_days SMALLINT;
+ALTER TABLE door_permission ADD COLUMN schedule_start TIME;
+ALTER TABLE door_permission ADD COLUMN schedule_end TIME;
+
+CREATE TABLE allowlist_version (
+    door_id INTEGER NOT NULL REFERENCES door(id) ON DELETE CASCADE,
+    version INTEGER NOT NULL,
+    entries JSONB NOT NULL,
+    created_at timestamptz NOT NULL,
+    PRIMARY KEY (door_id, version)
+);
+
+ALTER TABLE device ADD COLUMN allowlist_version INTEGER NOT NULL DEFAULT 0;
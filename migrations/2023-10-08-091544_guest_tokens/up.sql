@@ -0,0 +1,11 @@
+CREATE TABLE guest_token (
+    id SERIAL PRIMARY KEY,
+    door_id INTEGER NOT NULL REFERENCES door(id) ON DELETE CASCADE,
+    creator_id INTEGER NOT NULL REFERENCES user_profile(id) ON DELETE CASCADE,
+    nonce VARCHAR(16) NOT NULL UNIQUE,
+    label VARCHAR,
+    valid_from timestamptz NOT NULL,
+    valid_until timestamptz NOT NULL,
+    revoked_at timestamptz,
+    created_at timestamptz NOT NULL
+);
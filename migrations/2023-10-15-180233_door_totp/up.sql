@@ -0,0 +1,5 @@
+CREATE TABLE door_totp (
+    door_id INTEGER PRIMARY KEY REFERENCES door(id) ON DELETE CASCADE,
+    secret VARCHAR(64) NOT NULL,
+    created_at timestamptz NOT NULL
+);
@@ -0,0 +1,6 @@
+DROP TABLE firmware_update;
+ALTER TABLE device DROP COLUMN target_firmware_id;
+ALTER TABLE device DROP COLUMN group_id;
+DROP TABLE device_group;
+DROP TABLE firmware_image;
+DROP TABLE admin;
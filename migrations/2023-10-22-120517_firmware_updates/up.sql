@@ -0,0 +1,36 @@
+-- Site administrators, granted by inserting rows by hand
+CREATE TABLE admin (
+    user_profile_id INTEGER PRIMARY KEY REFERENCES user_profile(id) ON DELETE CASCADE,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE TABLE firmware_image (
+    id SERIAL PRIMARY KEY,
+    version VARCHAR NOT NULL UNIQUE,
+    sha256 VARCHAR(64) NOT NULL,
+    signature VARCHAR(128) NOT NULL,
+    size_bytes INTEGER NOT NULL,
+    file_name VARCHAR NOT NULL,
+    uploaded_by INTEGER REFERENCES user_profile(id) ON DELETE SET NULL,
+    created_at timestamptz NOT NULL
+);
+
+CREATE TABLE device_group (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR NOT NULL UNIQUE,
+    target_firmware_id INTEGER REFERENCES firmware_image(id) ON DELETE SET NULL,
+    created_at timestamptz NOT NULL
+);
+
+ALTER TABLE device ADD COLUMN group_id INTEGER REFERENCES device_group(id) ON DELETE SET NULL;
+ALTER TABLE device ADD COLUMN target_firmware_id INTEGER REFERENCES firmware_image(id) ON DELETE SET NULL;
+
+CREATE TABLE firmware_update (
+    id SERIAL PRIMARY KEY,
+    device_id INTEGER NOT NULL REFERENCES device(id) ON DELETE CASCADE,
+    firmware_image_id INTEGER REFERENCES firmware_image(id) ON DELETE SET NULL,
+    version VARCHAR NOT NULL,
+    status VARCHAR NOT NULL,
+    detail VARCHAR,
+    created_at timestamptz NOT NULL
+);
@@ -0,0 +1,2 @@
+ALTER TABLE device DROP COLUMN config_version;
+DROP TABLE device_config;
@@ -0,0 +1,11 @@
+CREATE TABLE device_config (
+    device_id INTEGER NOT NULL REFERENCES device(id) ON DELETE CASCADE,
+    version INTEGER NOT NULL,
+    config JSONB NOT NULL,
+    created_by INTEGER REFERENCES user_profile(id) ON DELETE SET NULL,
+    created_at timestamptz NOT NULL,
+    applied_at timestamptz,
+    PRIMARY KEY (device_id, version)
+);
+
+ALTER TABLE device ADD COLUMN config_version INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+ALTER TABLE device_command DROP COLUMN acknowledged_at;
+
+ALTER TABLE device DROP COLUMN protocol_version;
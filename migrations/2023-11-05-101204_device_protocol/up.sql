@@ -0,0 +1,4 @@
+-- Controllers that never said hello speak version 1
+ALTER TABLE device ADD COLUMN protocol_version INTEGER NOT NULL DEFAULT 1;
+
+ALTER TABLE device_command ADD COLUMN acknowledged_at timestamptz;
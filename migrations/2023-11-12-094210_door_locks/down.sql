@@ -0,0 +1 @@
+DROP TABLE door_lock;
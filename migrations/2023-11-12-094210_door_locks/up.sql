@@ -0,0 +1,7 @@
+CREATE TABLE door_lock (
+    door_id INTEGER PRIMARY KEY REFERENCES door(id) ON DELETE CASCADE,
+    driver VARCHAR NOT NULL,
+    config JSONB NOT NULL DEFAULT '{}',
+    updated_by INTEGER REFERENCES user_profile(id) ON DELETE SET NULL,
+    updated_at timestamptz NOT NULL
+);
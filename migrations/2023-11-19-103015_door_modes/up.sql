@@ -0,0 +1,9 @@
+CREATE TABLE door_mode (
+    door_id INTEGER PRIMARY KEY REFERENCES door(id) ON DELETE CASCADE,
+    mode VARCHAR NOT NULL,
+    unlocked_until timestamptz,
+    changed_by INTEGER REFERENCES user_profile(id) ON DELETE SET NULL,
+    changed_at timestamptz NOT NULL
+);
+
+CREATE INDEX door_mode_unlocked_until_idx ON door_mode (unlocked_until) WHERE mode = 'unlocked';
@@ -0,0 +1,3 @@
+ALTER TABLE door_mode DROP COLUMN scheduled;
+DROP TABLE unlock_exception;
+DROP TABLE unlock_schedule;
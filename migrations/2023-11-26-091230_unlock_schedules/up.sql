@@ -0,0 +1,27 @@
+-- Weekdays as a bitmask like door_permission.schedule_days, times in server local time
+CREATE TABLE unlock_schedule (
+    id SERIAL PRIMARY KEY,
+    door_id INTEGER NOT NULL REFERENCES door(id) ON DELETE CASCADE,
+    days SMALLINT NOT NULL,
+    start_time TIME NOT NULL,
+    end_time TIME NOT NULL,
+    label VARCHAR,
+    -- Start of the last window the scheduler unlocked the door for, local time
+    applied_window_start TIMESTAMP,
+    created_by INTEGER REFERENCES user_profile(id) ON DELETE SET NULL,
+    created_at timestamptz NOT NULL
+);
+
+CREATE INDEX unlock_schedule_door_id_idx ON unlock_schedule (door_id);
+
+-- Days the door stays locked whatever its schedules say
+CREATE TABLE unlock_exception (
+    door_id INTEGER NOT NULL REFERENCES door(id) ON DELETE CASCADE,
+    date DATE NOT NULL,
+    label VARCHAR,
+    created_by INTEGER REFERENCES user_profile(id) ON DELETE SET NULL,
+    created_at timestamptz NOT NULL,
+    PRIMARY KEY (door_id, date)
+);
+
+ALTER TABLE door_mode ADD COLUMN scheduled BOOLEAN NOT NULL DEFAULT false;
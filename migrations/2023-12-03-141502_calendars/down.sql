@@ -0,0 +1,4 @@
+ALTER TABLE unlock_schedule DROP COLUMN calendar_id;
+ALTER TABLE door_permission DROP COLUMN calendar_id;
+DROP TABLE calendar_exception;
+DROP TABLE calendar;
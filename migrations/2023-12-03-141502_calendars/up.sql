@@ -0,0 +1,23 @@
+CREATE TABLE calendar (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    owner_id INTEGER REFERENCES user_profile(id) ON DELETE SET NULL,
+    created_at timestamptz NOT NULL
+);
+
+-- Closed all day without times, open during the given hours otherwise.
+-- Times are in server local time, like the schedules.
+CREATE TABLE calendar_exception (
+    calendar_id INTEGER NOT NULL REFERENCES calendar(id) ON DELETE CASCADE,
+    date DATE NOT NULL,
+    label VARCHAR,
+    start_time TIME,
+    end_time TIME,
+    -- The event an .ics import took it from
+    uid VARCHAR,
+    PRIMARY KEY (calendar_id, date),
+    CHECK ((start_time IS NULL) = (end_time IS NULL))
+);
+
+ALTER TABLE door_permission ADD COLUMN calendar_id INTEGER REFERENCES calendar(id) ON DELETE SET NULL;
+ALTER TABLE unlock_schedule ADD COLUMN calendar_id INTEGER REFERENCES calendar(id) ON DELETE SET NULL;
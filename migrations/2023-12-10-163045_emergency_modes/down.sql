@@ -0,0 +1,2 @@
+DROP TABLE responder;
+DROP TABLE emergency;
@@ -0,0 +1,21 @@
+-- Site-wide lockdowns and emergency egress. Rows stay after they are cleared
+-- as the record of who started and ended each one.
+CREATE TABLE emergency (
+    id SERIAL PRIMARY KEY,
+    mode VARCHAR NOT NULL CHECK (mode IN ('lockdown', 'egress')),
+    reason VARCHAR,
+    triggered_by INTEGER REFERENCES user_profile(id) ON DELETE SET NULL,
+    triggered_at timestamptz NOT NULL,
+    cleared_by INTEGER REFERENCES user_profile(id) ON DELETE SET NULL,
+    cleared_at timestamptz
+);
+
+-- One emergency at a time
+CREATE UNIQUE INDEX emergency_active_idx ON emergency ((true)) WHERE cleared_at IS NULL;
+
+-- People who may still open every door during a lockdown
+CREATE TABLE responder (
+    user_profile_id INTEGER PRIMARY KEY REFERENCES user_profile(id) ON DELETE CASCADE,
+    added_by INTEGER REFERENCES user_profile(id) ON DELETE SET NULL,
+    added_at timestamptz NOT NULL
+);
@@ -0,0 +1,3 @@
+DELETE FROM access_history WHERE user_profile_id IS NULL;
+ALTER TABLE access_history DROP COLUMN method;
+ALTER TABLE access_history ALTER COLUMN user_profile_id SET NOT NULL;
@@ -0,0 +1,4 @@
+-- Codes and guest tokens open doors without a user behind them, the method
+-- tells those opens apart
+ALTER TABLE access_history ALTER COLUMN user_profile_id DROP NOT NULL;
+ALTER TABLE access_history ADD COLUMN method VARCHAR NOT NULL DEFAULT 'permission';
@@ -0,0 +1,3 @@
+DELETE FROM access_history WHERE NOT opened;
+ALTER TABLE access_history DROP COLUMN opened;
+ALTER TABLE device_command DROP COLUMN method;
@@ -0,0 +1,5 @@
+-- Opens through the door's controllers only count once one of them confirms,
+-- the method is kept with the command until then
+ALTER TABLE device_command ADD COLUMN method VARCHAR;
+-- Opens that no controller confirmed in time are kept in the history too
+ALTER TABLE access_history ADD COLUMN opened BOOLEAN NOT NULL DEFAULT true;
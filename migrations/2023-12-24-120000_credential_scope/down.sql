@@ -0,0 +1,4 @@
+ALTER TABLE access_history DROP COLUMN flagged;
+ALTER TABLE door DROP COLUMN pin_salt;
+ALTER TABLE credential DROP CONSTRAINT credential_user_kind_value_key;
+ALTER TABLE credential ADD CONSTRAINT credential_kind_value_key UNIQUE (kind, value);
@@ -0,0 +1,9 @@
+-- A credential only has to be unique for its owner, a global constraint
+-- tells anyone trying PINs which ones are taken
+ALTER TABLE credential DROP CONSTRAINT credential_kind_value_key;
+ALTER TABLE credential ADD CONSTRAINT credential_user_kind_value_key UNIQUE (user_profile_id, kind, value);
+
+-- PINs go to controllers stretched with a salt of their door
+ALTER TABLE door ADD COLUMN pin_salt VARCHAR NOT NULL DEFAULT replace(gen_random_uuid()::text, '-', '');
+
+ALTER TABLE access_history ADD COLUMN flagged BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,3 @@
+ALTER TABLE door_totp DROP COLUMN locked_until;
+ALTER TABLE door_totp DROP COLUMN failed_attempts;
+ALTER TABLE door_totp DROP COLUMN last_step;
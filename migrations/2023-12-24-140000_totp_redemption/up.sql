@@ -0,0 +1,4 @@
+-- A code works once, and a keypad that keeps guessing is locked out for a while
+ALTER TABLE door_totp ADD COLUMN last_step BIGINT;
+ALTER TABLE door_totp ADD COLUMN failed_attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE door_totp ADD COLUMN locked_until timestamptz;
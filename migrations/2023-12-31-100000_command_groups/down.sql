@@ -0,0 +1,2 @@
+ALTER TABLE device_command DROP COLUMN recorded;
+ALTER TABLE device_command DROP COLUMN group_id;
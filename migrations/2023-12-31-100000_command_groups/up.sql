@@ -0,0 +1,7 @@
+-- Commands queued together for every controller of a door share a group, and
+-- the first controller to confirm claims the group's history entry
+ALTER TABLE device_command ADD COLUMN group_id VARCHAR(36);
+UPDATE device_command SET group_id = id::text;
+ALTER TABLE device_command ALTER COLUMN group_id SET NOT NULL;
+ALTER TABLE device_command ADD COLUMN recorded BOOLEAN NOT NULL DEFAULT false;
+UPDATE device_command SET recorded = true WHERE status IN ('acknowledged', 'expired');
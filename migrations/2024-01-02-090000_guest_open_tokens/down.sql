@@ -0,0 +1 @@
+ALTER TABLE access_history DROP COLUMN guest_token_id;
@@ -0,0 +1,3 @@
+-- Offline guest opens keep the token they were made with, so a retried upload
+-- can be told apart from another guest opening in the same second
+ALTER TABLE access_history ADD COLUMN guest_token_id INTEGER REFERENCES guest_token(id) ON DELETE SET NULL;
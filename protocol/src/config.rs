@@ -0,0 +1,54 @@
+use core::ops::RangeInclusive;
+use serde::{Deserialize, Serialize};
+
+// Shorter pulses do not move the strike, longer ones cook it
+pub const RELAY_PULSE_MS: RangeInclusive<u32> = 50..=10_000;
+
+// Settings a controller used to have compiled in. Missing fields fall back to
+// the `Default` values below, not to whatever the firmware was built with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub relay_pulse_ms: u32,
+    // Relay boards that switch on a low output
+    pub relay_active_low: bool,
+    pub buzzer: BuzzerMode,
+    pub keypad_timeout_seconds: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BuzzerMode {
+    Off,
+    OnOpen,
+    OnOpenAndDenied,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        DeviceConfig {
+            relay_pulse_ms: 500,
+            relay_active_low: false,
+            buzzer: BuzzerMode::OnOpen,
+            keypad_timeout_seconds: 10,
+        }
+    }
+}
+
+impl DeviceConfig {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if !RELAY_PULSE_MS.contains(&self.relay_pulse_ms) {
+            return Err("`relay_pulse_ms` must be between 50 and 10000.");
+        }
+        if !(1..=120).contains(&self.keypad_timeout_seconds) {
+            return Err("`keypad_timeout_seconds` must be between 1 and 120.");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConfigUpdate {
+    pub version: i32,
+    pub config: DeviceConfig,
+}
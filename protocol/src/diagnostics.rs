@@ -0,0 +1,63 @@
+use alloc::string::String;
+use serde::{Deserialize, Serialize};
+
+use crate::config::RELAY_PULSE_MS;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+// What a manager can ask a controller to do while troubleshooting
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Diagnostic {
+    Reboot,
+    // Pulses the relay with the default pulse when left out. The door opens,
+    // so the server logs it like any other open.
+    RelayTest {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pulse_ms: Option<u32>,
+    },
+    SensorRead,
+    LogLevel {
+        level: LogLevel,
+    },
+}
+
+impl Diagnostic {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        match self {
+            Diagnostic::RelayTest {
+                pulse_ms: Some(pulse_ms),
+            } if !RELAY_PULSE_MS.contains(pulse_ms) => {
+                Err("`pulse_ms` must be between 50 and 10000.")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Sensors the controller has wired up, the others stay empty
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SensorReadings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub door_open: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locked: Option<bool>,
+}
+
+// A controller's answer to a diagnostic, `readings` answers a sensor read
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DiagnosticResult {
+    pub action: String,
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub readings: Option<SensorReadings>,
+}
@@ -0,0 +1,114 @@
+use alloc::vec::Vec;
+use core::fmt;
+use serde::{de::DeserializeOwned, Serialize};
+
+// How messages are put on the wire. The controller offers the encodings it
+// speaks when it connects and the server picks one, JSON is there for
+// debugging and for controllers that offer nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Cbor,
+    MessagePack,
+}
+
+#[derive(Debug)]
+pub enum EncodingError {
+    // The encoding's feature is not enabled in this build
+    Unavailable(Encoding),
+    Encode(alloc::string::String),
+    Decode(alloc::string::String),
+}
+
+impl fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodingError::Unavailable(encoding) => {
+                write!(f, "{} support is not built in", encoding.name())
+            }
+            EncodingError::Encode(e) => write!(f, "could not encode message: {e}"),
+            EncodingError::Decode(e) => write!(f, "could not decode message: {e}"),
+        }
+    }
+}
+
+impl Encoding {
+    // Most compact first, the order the server picks in
+    pub const PREFERENCE: [Encoding; 3] = [Encoding::Cbor, Encoding::MessagePack, Encoding::Json];
+
+    // Also the WebSocket subprotocol names
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::Cbor => "cbor",
+            Encoding::MessagePack => "msgpack",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Encoding::PREFERENCE
+            .into_iter()
+            .find(|encoding| encoding.name() == name)
+    }
+
+    pub const fn is_binary(&self) -> bool {
+        !matches!(self, Encoding::Json)
+    }
+
+    pub fn available(&self) -> bool {
+        match self {
+            Encoding::Json => cfg!(feature = "json"),
+            Encoding::Cbor => cfg!(feature = "cbor"),
+            Encoding::MessagePack => cfg!(feature = "msgpack"),
+        }
+    }
+
+    #[cfg_attr(
+        not(any(feature = "json", feature = "cbor", feature = "msgpack")),
+        allow(unused_variables)
+    )]
+    pub fn encode<T: Serialize>(&self, message: &T) -> Result<Vec<u8>, EncodingError> {
+        match self {
+            #[cfg(feature = "json")]
+            Encoding::Json => serde_json::to_vec(message).map_err(|e| encode_error(&e)),
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(message, &mut bytes).map_err(|e| encode_error(&e))?;
+                Ok(bytes)
+            }
+            // Named fields, the enums are tagged by a `type` field like in JSON
+            #[cfg(feature = "msgpack")]
+            Encoding::MessagePack => rmp_serde::to_vec_named(message).map_err(|e| encode_error(&e)),
+            #[allow(unreachable_patterns)]
+            encoding => Err(EncodingError::Unavailable(*encoding)),
+        }
+    }
+
+    #[cfg_attr(
+        not(any(feature = "json", feature = "cbor", feature = "msgpack")),
+        allow(unused_variables)
+    )]
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, EncodingError> {
+        match self {
+            #[cfg(feature = "json")]
+            Encoding::Json => serde_json::from_slice(bytes).map_err(|e| decode_error(&e)),
+            #[cfg(feature = "cbor")]
+            Encoding::Cbor => ciborium::from_reader(bytes).map_err(|e| decode_error(&e)),
+            #[cfg(feature = "msgpack")]
+            Encoding::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| decode_error(&e)),
+            #[allow(unreachable_patterns)]
+            encoding => Err(EncodingError::Unavailable(*encoding)),
+        }
+    }
+}
+
+#[cfg(any(feature = "json", feature = "cbor", feature = "msgpack"))]
+fn encode_error(e: &dyn fmt::Debug) -> EncodingError {
+    EncodingError::Encode(alloc::format!("{e:?}"))
+}
+
+#[cfg(any(feature = "json", feature = "cbor", feature = "msgpack"))]
+fn decode_error(e: &dyn fmt::Debug) -> EncodingError {
+    EncodingError::Decode(alloc::format!("{e:?}"))
+}
@@ -0,0 +1,42 @@
+use alloc::string::String;
+use serde::{Deserialize, Serialize};
+
+// Tells a controller which image to fetch. It checks the hash and the signature
+// before flashing.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FirmwareOffer {
+    pub firmware_id: i32,
+    pub version: String,
+    pub sha256: String,
+    pub signature: String,
+    pub size_bytes: i32,
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateStatus {
+    Downloading,
+    Installed,
+    Failed,
+    RolledBack,
+}
+
+impl UpdateStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UpdateStatus::Downloading => "downloading",
+            UpdateStatus::Installed => "installed",
+            UpdateStatus::Failed => "failed",
+            UpdateStatus::RolledBack => "rolled_back",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FirmwareReport {
+    pub version: String,
+    pub status: UpdateStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
@@ -0,0 +1,26 @@
+#![no_std]
+
+// What the server and the door controllers say to each other. The firmware
+// builds this crate too, so only `core` and `alloc` are used here.
+extern crate alloc;
+
+pub mod config;
+pub mod diagnostics;
+pub mod encoding;
+pub mod firmware;
+pub mod offline;
+
+mod message;
+
+pub use encoding::{Encoding, EncodingError};
+pub use message::{CommandFrame, DeviceCommand, DeviceMessage, EmergencyMode, Heartbeat};
+
+// Bumped whenever a message changes shape. Version 1 is what controllers spoke
+// before they sent a hello.
+pub const PROTOCOL_VERSION: u16 = 4;
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+// Both sides talk the older of their two versions
+pub fn negotiate(device_version: u16) -> Option<u16> {
+    (device_version >= MIN_PROTOCOL_VERSION).then(|| device_version.min(PROTOCOL_VERSION))
+}
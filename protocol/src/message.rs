@@ -0,0 +1,116 @@
+use alloc::{string::String, vec::Vec};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::ConfigUpdate,
+    diagnostics::{Diagnostic, DiagnosticResult, LogLevel},
+    firmware::{FirmwareOffer, FirmwareReport},
+    offline::{OfflineOpen, SignedAllowlist, SignedRevocations},
+};
+
+// Sent by a door controller over its channel
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DeviceMessage {
+    // First message after connecting, from protocol version 2 on
+    Hello {
+        protocol_version: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        firmware_version: Option<String>,
+    },
+    // For controllers that can not send an `Authorization` header, has to
+    // come before anything else
+    Auth {
+        token: String,
+    },
+    // Confirms a command that carried an ID
+    Ack {
+        id: i32,
+    },
+    Doorbell,
+    DoorContact {
+        open: bool,
+    },
+    LockSensor {
+        locked: bool,
+    },
+    Heartbeat(Heartbeat),
+    AllowlistAck {
+        version: i32,
+    },
+    OfflineOpens {
+        opens: Vec<OfflineOpen>,
+    },
+    KeypadCode {
+        code: String,
+    },
+    FirmwareStatus(FirmwareReport),
+    ConfigApplied {
+        version: i32,
+    },
+    Log {
+        level: LogLevel,
+        message: String,
+    },
+    DiagnosticResult(DiagnosticResult),
+}
+
+// Sent to a door controller. The answers to a hello share the channel.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DeviceCommand {
+    Welcome {
+        protocol_version: u16,
+    },
+    Unsupported {
+        min_version: u16,
+        max_version: u16,
+    },
+    Open,
+    // Keeps the door unlocked until a `lock`, from protocol version 3 on. With
+    // `until` (UTC) the controller relocks by itself should the server be gone.
+    Unlock {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        until: Option<NaiveDateTime>,
+    },
+    Lock,
+    // The site-wide emergency, from protocol version 4 on. Sent again without
+    // a mode once it is cleared.
+    Emergency {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mode: Option<EmergencyMode>,
+    },
+    Allowlist(SignedAllowlist),
+    Revocations(SignedRevocations),
+    FirmwareUpdate(FirmwareOffer),
+    Config(ConfigUpdate),
+    Diagnostic(Diagnostic),
+}
+
+// In a lockdown only responders on the allowlist get in, guest tokens and
+// codes included. In an egress the door stays unlocked.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmergencyMode {
+    Lockdown,
+    Egress,
+}
+
+// What goes over the channel. Queued commands carry their ID so the
+// controller can acknowledge them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommandFrame {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<i32>,
+    #[serde(flatten)]
+    pub command: DeviceCommand,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Heartbeat {
+    pub uptime_seconds: i64,
+    pub rssi: i32,
+    pub free_heap: i32,
+    pub firmware_version: String,
+}
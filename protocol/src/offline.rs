@@ -0,0 +1,72 @@
+use alloc::{string::String, vec::Vec};
+use chrono::{NaiveDateTime, NaiveTime};
+use serde::{Deserialize, Serialize};
+
+// Who may open a door while its controller can not reach the server. Card
+// entries hold the UID, code entries the SHA-256 of the code, a TOTP entry the
+// door's rotating code secret. PIN entries hold PBKDF2-HMAC-SHA256 over the
+// hex SHA-256 of the PIN, with the payload's `pin_salt` and `pin_rounds`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AllowlistEntry {
+    pub kind: String,
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_profile_id: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub days: Option<i16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<NaiveTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<NaiveTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+// Either every entry or the changes since `base_version`, the version the
+// controller confirmed last
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AllowlistPayload {
+    pub door_id: i32,
+    pub version: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_version: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entries: Option<Vec<AllowlistEntry>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub added: Option<Vec<AllowlistEntry>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub removed: Option<Vec<AllowlistEntry>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pin_salt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pin_rounds: Option<u32>,
+}
+
+// Guest tokens revoked before they expired
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RevocationPayload {
+    pub door_id: i32,
+    pub nonces: Vec<String>,
+}
+
+// The payload stays a string so the controller checks the signature over
+// exactly the bytes the server signed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedAllowlist {
+    pub payload: String,
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedRevocations {
+    pub payload: String,
+    pub signature: String,
+}
+
+// An open granted from the allowlist while offline, uploaded on reconnect
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OfflineOpen {
+    pub kind: String,
+    pub value: String,
+    pub opened_at: NaiveDateTime,
+}
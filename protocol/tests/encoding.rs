@@ -0,0 +1,230 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use protocol::{
+    config::{BuzzerMode, ConfigUpdate, DeviceConfig},
+    diagnostics::{Diagnostic, DiagnosticResult, LogLevel, SensorReadings},
+    firmware::{FirmwareOffer, FirmwareReport, UpdateStatus},
+    offline::{OfflineOpen, SignedAllowlist, SignedRevocations},
+    CommandFrame, DeviceCommand, DeviceMessage, EmergencyMode, Encoding, Heartbeat,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+fn timestamp() -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(2023, 11, 12)
+        .unwrap()
+        .and_hms_opt(8, 30, 15)
+        .unwrap()
+}
+
+fn device_messages() -> Vec<DeviceMessage> {
+    vec![
+        DeviceMessage::Hello {
+            protocol_version: 2,
+            firmware_version: Some("1.4.0".to_string()),
+        },
+        DeviceMessage::Hello {
+            protocol_version: 1,
+            firmware_version: None,
+        },
+        DeviceMessage::Auth {
+            token: "0f".repeat(32),
+        },
+        DeviceMessage::Ack { id: 7 },
+        DeviceMessage::Doorbell,
+        DeviceMessage::DoorContact { open: true },
+        DeviceMessage::LockSensor { locked: false },
+        DeviceMessage::Heartbeat(Heartbeat {
+            uptime_seconds: 86_400,
+            rssi: -67,
+            free_heap: 81_920,
+            firmware_version: "1.4.0".to_string(),
+        }),
+        DeviceMessage::AllowlistAck { version: 12 },
+        DeviceMessage::OfflineOpens {
+            opens: vec![
+                OfflineOpen {
+                    kind: "card".to_string(),
+                    value: "04A1B2C3D4".to_string(),
+                    opened_at: timestamp(),
+                },
+                OfflineOpen {
+                    kind: "guest".to_string(),
+                    value: "a1b2c3d4e5f60718".to_string(),
+                    opened_at: timestamp(),
+                },
+            ],
+        },
+        DeviceMessage::KeypadCode {
+            code: "123456".to_string(),
+        },
+        DeviceMessage::FirmwareStatus(FirmwareReport {
+            version: "1.5.0".to_string(),
+            status: UpdateStatus::RolledBack,
+            detail: Some("watchdog reset after boot".to_string()),
+        }),
+        DeviceMessage::ConfigApplied { version: 3 },
+        DeviceMessage::Log {
+            level: LogLevel::Warn,
+            message: "relay driver: überhitzt".to_string(),
+        },
+        DeviceMessage::DiagnosticResult(DiagnosticResult {
+            action: "sensor_read".to_string(),
+            ok: true,
+            detail: None,
+            readings: Some(SensorReadings {
+                door_open: Some(false),
+                locked: None,
+            }),
+        }),
+    ]
+}
+
+fn command_frames() -> Vec<CommandFrame> {
+    let commands = vec![
+        DeviceCommand::Welcome {
+            protocol_version: 2,
+        },
+        DeviceCommand::Unsupported {
+            min_version: 1,
+            max_version: 2,
+        },
+        DeviceCommand::Open,
+        DeviceCommand::Unlock {
+            until: Some(timestamp()),
+        },
+        DeviceCommand::Unlock { until: None },
+        DeviceCommand::Lock,
+        DeviceCommand::Emergency {
+            mode: Some(EmergencyMode::Lockdown),
+        },
+        DeviceCommand::Emergency { mode: None },
+        DeviceCommand::Allowlist(SignedAllowlist {
+            payload: r#"{"door_id":1,"version":4,"entries":[]}"#.to_string(),
+            signature: "ab".repeat(64),
+        }),
+        DeviceCommand::Revocations(SignedRevocations {
+            payload: r#"{"door_id":1,"nonces":["a1b2c3d4e5f60718"]}"#.to_string(),
+            signature: "cd".repeat(64),
+        }),
+        DeviceCommand::FirmwareUpdate(FirmwareOffer {
+            firmware_id: 9,
+            version: "1.5.0".to_string(),
+            sha256: "ef".repeat(32),
+            signature: "01".repeat(64),
+            size_bytes: 1_048_576,
+            url: "/api/v1/firmware/9/image".to_string(),
+        }),
+        DeviceCommand::Config(ConfigUpdate {
+            version: 3,
+            config: DeviceConfig {
+                buzzer: BuzzerMode::OnOpenAndDenied,
+                ..DeviceConfig::default()
+            },
+        }),
+        DeviceCommand::Diagnostic(Diagnostic::Reboot),
+        DeviceCommand::Diagnostic(Diagnostic::RelayTest {
+            pulse_ms: Some(250),
+        }),
+        DeviceCommand::Diagnostic(Diagnostic::RelayTest { pulse_ms: None }),
+        DeviceCommand::Diagnostic(Diagnostic::SensorRead),
+        DeviceCommand::Diagnostic(Diagnostic::LogLevel {
+            level: LogLevel::Debug,
+        }),
+    ];
+
+    commands
+        .into_iter()
+        .enumerate()
+        .map(|(i, command)| CommandFrame {
+            // Queued and pushed commands both go over the channel
+            id: (i % 2 == 0).then_some(i as i32 + 1),
+            command,
+        })
+        .collect()
+}
+
+// Decodes what was encoded and compares both through their JSON form, which is
+// what the channel spoke before binary encodings
+fn assert_round_trip<T>(encoding: Encoding, message: &T)
+where
+    T: Serialize + DeserializeOwned,
+{
+    let bytes = encoding.encode(message).unwrap();
+    let decoded: T = encoding
+        .decode(&bytes)
+        .unwrap_or_else(|e| panic!("{} could not decode its own output: {e}", encoding.name()));
+
+    assert_eq!(
+        serde_json::to_value(&decoded).unwrap(),
+        serde_json::to_value(message).unwrap(),
+        "{} changed the message",
+        encoding.name()
+    );
+}
+
+#[test]
+fn device_messages_round_trip() {
+    for encoding in Encoding::PREFERENCE {
+        for message in device_messages() {
+            assert_round_trip(encoding, &message);
+        }
+    }
+}
+
+#[test]
+fn command_frames_round_trip() {
+    for encoding in Encoding::PREFERENCE {
+        for frame in command_frames() {
+            assert_round_trip(encoding, &frame);
+        }
+    }
+}
+
+#[test]
+fn encodings_agree_with_each_other() {
+    for message in device_messages() {
+        let json = Encoding::Json.encode(&message).unwrap();
+
+        for encoding in [Encoding::Cbor, Encoding::MessagePack] {
+            let bytes = encoding.encode(&message).unwrap();
+            let decoded: DeviceMessage = encoding.decode(&bytes).unwrap();
+
+            assert_eq!(Encoding::Json.encode(&decoded).unwrap(), json);
+        }
+    }
+}
+
+#[test]
+fn binary_encodings_are_smaller_than_json() {
+    for frame in command_frames() {
+        let json = Encoding::Json.encode(&frame).unwrap();
+
+        for encoding in [Encoding::Cbor, Encoding::MessagePack] {
+            let bytes = encoding.encode(&frame).unwrap();
+            assert!(
+                bytes.len() < json.len(),
+                "{} is not smaller than JSON for {:?}",
+                encoding.name(),
+                frame.command
+            );
+        }
+    }
+}
+
+#[test]
+fn unknown_message_types_are_rejected() {
+    let unknown = serde_json::json!({ "type": "self_destruct" });
+
+    for encoding in Encoding::PREFERENCE {
+        let bytes = encoding.encode(&unknown).unwrap();
+        assert!(encoding.decode::<DeviceMessage>(&bytes).is_err());
+    }
+}
+
+#[test]
+fn names_round_trip() {
+    for encoding in Encoding::PREFERENCE {
+        assert_eq!(Encoding::from_name(encoding.name()), Some(encoding));
+        assert!(encoding.available());
+    }
+    assert_eq!(Encoding::from_name("xml"), None);
+}
@@ -0,0 +1,338 @@
+use async_session::chrono::{Datelike, Duration, Local, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use diesel::{insert_into, prelude::*};
+use uuid::Uuid;
+
+use crate::{
+    calendars::{self, DayRule, Exceptions},
+    emergency,
+    events::{DoorEvent, EventBus, EventKind},
+    models::{DoorCode, DoorPermission},
+    schema::{access_history, admin, door, door_code, door_permission},
+};
+
+// Shared by the HTTP handlers and the Discord interactions endpoint so both
+// apply the same rules.
+
+pub fn can_open(conn: &mut PgConnection, door_id: i32, user_id: i32) -> bool {
+    // Responders get through every door in a lockdown, nobody else does
+    if emergency::in_lockdown(conn) {
+        return emergency::is_responder(conn, user_id);
+    }
+
+    permitted_at(conn, door_id, user_id, Local::now().naive_local())
+}
+
+// Whether the user's permission, its schedule and calendar let them in at the
+// given local time
+pub fn permitted_at(
+    conn: &mut PgConnection,
+    door_id: i32,
+    user_id: i32,
+    at: NaiveDateTime,
+) -> bool {
+    door_permission::table
+        .filter(
+            door_permission::door_id
+                .eq(door_id)
+                .and(door_permission::user_profile_id.eq(user_id))
+                .and(door_permission::open_permission),
+        )
+        .select(DoorPermission::as_select())
+        .get_result(conn)
+        .is_ok_and(|permission| {
+            // A window that started yesterday may still be open
+            let exceptions = match permission.calendar_id {
+                Some(calendar_id) => {
+                    let today = at.date();
+                    match calendars::exceptions_between(
+                        conn,
+                        &[calendar_id],
+                        today - Duration::days(1),
+                        today,
+                    ) {
+                        Ok(mut exceptions) => exceptions.remove(&calendar_id).unwrap_or_default(),
+                        Err(_) => return false,
+                    }
+                }
+                None => Exceptions::new(),
+            };
+            within_schedule(&permission, &exceptions, at)
+        })
+}
+
+// Schedules are in server local time, the clock controllers are set to as well.
+// A window whose end is before its start runs over midnight and, as in unlock
+// schedules, belongs to the day it starts on. On a day of the permission's
+// calendar, the calendar's rule replaces the schedule's hours, but never lets
+// anyone in on a day the schedule leaves out.
+pub fn within_schedule(
+    permission: &DoorPermission,
+    exceptions: &Exceptions,
+    now: NaiveDateTime,
+) -> bool {
+    let today = now.date();
+    [today, today - Duration::days(1)].into_iter().any(|day| {
+        let bit = 1 << day.weekday().num_days_from_monday();
+        if permission.schedule_days.is_some_and(|days| days & bit == 0) {
+            return false;
+        }
+
+        let (start, end) = match exceptions.get(&day) {
+            Some(DayRule::Closed) => return false,
+            Some(DayRule::Hours { start, end }) => window(day, Some(*start), Some(*end)),
+            None => window(day, permission.schedule_start, permission.schedule_end),
+        };
+        start <= now && now < end
+    })
+}
+
+// Open ends run from or to midnight
+fn window(
+    day: NaiveDate,
+    start: Option<NaiveTime>,
+    end: Option<NaiveTime>,
+) -> (NaiveDateTime, NaiveDateTime) {
+    let next = day + Duration::days(1);
+    let start = day.and_time(start.unwrap_or(NaiveTime::MIN));
+    let end = match end {
+        Some(end) if day.and_time(end) >= start => day.and_time(end),
+        Some(end) => next.and_time(end),
+        None => next.and_time(NaiveTime::MIN),
+    };
+    (start, end)
+}
+
+// Access history, and anything attached to it, is for people who can open or manage the door
+pub fn can_read_history(conn: &mut PgConnection, door_id: i32, user_id: i32) -> bool {
+    can_open(conn, door_id, user_id) || can_manage(conn, door_id, user_id)
+}
+
+// Door owners and users with edit permission manage a door
+pub fn can_manage(conn: &mut PgConnection, door_id: i32, user_id: i32) -> bool {
+    let owner = door::table
+        .find(door_id)
+        .select(door::owner_id)
+        .get_result::<Option<i32>>(conn);
+    if let Ok(Some(owner)) = owner {
+        if owner == user_id {
+            return true;
+        }
+    }
+
+    door_permission::table
+        .filter(
+            door_permission::door_id
+                .eq(door_id)
+                .and(door_permission::user_profile_id.eq(user_id))
+                .and(door_permission::edit_permission),
+        )
+        .select(DoorPermission::as_select())
+        .get_result(conn)
+        .is_ok()
+}
+
+// Site administrators look after the whole fleet rather than single doors
+pub fn is_admin(conn: &mut PgConnection, user_id: i32) -> bool {
+    admin::table
+        .find(user_id)
+        .select(admin::user_profile_id)
+        .get_result::<i32>(conn)
+        .is_ok()
+}
+
+// What got someone through the door, stored with each history entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMethod {
+    Permission,
+    Code,
+    Guest,
+    Totp,
+    // A manager pulsing the relay from the diagnostics
+    RelayTest,
+}
+
+impl OpenMethod {
+    pub const ALL: [OpenMethod; 5] = [
+        OpenMethod::Permission,
+        OpenMethod::Code,
+        OpenMethod::Guest,
+        OpenMethod::Totp,
+        OpenMethod::RelayTest,
+    ];
+
+    pub fn parse(method: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|m| m.as_str() == method)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OpenMethod::Permission => "permission",
+            OpenMethod::Code => "code",
+            OpenMethod::Guest => "guest",
+            OpenMethod::Totp => "totp",
+            OpenMethod::RelayTest => "relay_test",
+        }
+    }
+}
+
+// Every open goes through here so the history table and the live event
+// stream never disagree. Opens that happened earlier, like the ones a
+// controller made while offline, keep their own time.
+pub fn record_open_at(
+    conn: &mut PgConnection,
+    events: &EventBus,
+    door_id: i32,
+    user_id: i32,
+    opened_at: NaiveDateTime,
+) -> QueryResult<usize> {
+    record_open_by(
+        conn,
+        events,
+        door_id,
+        Some(user_id),
+        OpenMethod::Permission,
+        opened_at,
+    )
+}
+
+// Codes and guest tokens open without a user
+pub fn record_open_by(
+    conn: &mut PgConnection,
+    events: &EventBus,
+    door_id: i32,
+    user_id: Option<i32>,
+    method: OpenMethod,
+    opened_at: NaiveDateTime,
+) -> QueryResult<usize> {
+    let inserted = insert_history(conn, door_id, user_id, method, opened_at, true, false)?;
+
+    let mut event = DoorEvent::new(EventKind::Opened, door_id, user_id);
+    event.timestamp = opened_at;
+    events.publish(event);
+    Ok(inserted)
+}
+
+// For opens a controller granted offline that the user's permission did not
+// allow at the time. The door did open, so they are in the history too.
+pub fn record_flagged_open(
+    conn: &mut PgConnection,
+    events: &EventBus,
+    door_id: i32,
+    user_id: i32,
+    method: OpenMethod,
+    opened_at: NaiveDateTime,
+) -> QueryResult<usize> {
+    let inserted = insert_history(conn, door_id, Some(user_id), method, opened_at, true, true)?;
+
+    let mut event = DoorEvent::new(EventKind::FlaggedOpen, door_id, Some(user_id));
+    event.timestamp = opened_at;
+    events.publish(event);
+    Ok(inserted)
+}
+
+// For guest opens a controller granted offline. Flagged when the token was
+// revoked or outside its validity at the time.
+pub fn record_guest_open(
+    conn: &mut PgConnection,
+    events: &EventBus,
+    door_id: i32,
+    guest_token_id: i32,
+    opened_at: NaiveDateTime,
+    flagged: bool,
+) -> QueryResult<usize> {
+    let inserted = insert_into(access_history::table)
+        .values((
+            access_history::access_timestamp.eq(opened_at),
+            access_history::door_id.eq(door_id),
+            access_history::method.eq(OpenMethod::Guest.as_str()),
+            access_history::opened.eq(true),
+            access_history::flagged.eq(flagged),
+            access_history::guest_token_id.eq(guest_token_id),
+        ))
+        .execute(conn)?;
+
+    let kind = if flagged {
+        EventKind::FlaggedOpen
+    } else {
+        EventKind::Opened
+    };
+    let mut event = DoorEvent::new(kind, door_id, None);
+    event.timestamp = opened_at;
+    events.publish(event);
+    Ok(inserted)
+}
+
+// For opens no controller confirmed before they expired. The expiry itself
+// is on the event stream already.
+pub fn record_failed_open(
+    conn: &mut PgConnection,
+    door_id: i32,
+    user_id: Option<i32>,
+    method: OpenMethod,
+    requested_at: NaiveDateTime,
+) -> QueryResult<usize> {
+    insert_history(conn, door_id, user_id, method, requested_at, false, false)
+}
+
+fn insert_history(
+    conn: &mut PgConnection,
+    door_id: i32,
+    user_id: Option<i32>,
+    method: OpenMethod,
+    at: NaiveDateTime,
+    opened: bool,
+    flagged: bool,
+) -> QueryResult<usize> {
+    insert_into(access_history::table)
+        .values((
+            access_history::user_profile_id.eq(user_id),
+            access_history::access_timestamp.eq(at),
+            access_history::door_id.eq(door_id),
+            access_history::method.eq(method.as_str()),
+            access_history::opened.eq(opened),
+            access_history::flagged.eq(flagged),
+        ))
+        .execute(conn)
+}
+
+// Owners and anyone with a permission on the door, whatever its schedule
+pub fn can_see(conn: &mut PgConnection, door_id: i32, user_id: i32) -> bool {
+    visible_doors(conn, user_id).is_ok_and(|door_ids| door_ids.contains(&door_id))
+}
+
+// Doors a user owns or has any permission on
+pub fn visible_doors(conn: &mut PgConnection, user_id: i32) -> QueryResult<Vec<i32>> {
+    let mut door_ids = door::table
+        .filter(door::owner_id.eq(user_id))
+        .select(door::id)
+        .load::<i32>(conn)?;
+
+    door_ids.extend(
+        door_permission::table
+            .filter(door_permission::user_profile_id.eq(user_id))
+            .select(door_permission::door_id)
+            .load::<i32>(conn)?,
+    );
+    door_ids.sort_unstable();
+    door_ids.dedup();
+
+    Ok(door_ids)
+}
+
+pub fn new_door_code(door_id: i32, creator_id: i32, expires_at: Option<NaiveDateTime>) -> DoorCode {
+    DoorCode {
+        code: Uuid::new_v4().to_string(),
+        door_id,
+        created_at: Utc::now().naive_utc(),
+        expires_at,
+        creator_id,
+        used: false,
+    }
+}
+
+pub fn insert_door_code(conn: &mut PgConnection, code: &DoorCode) -> QueryResult<usize> {
+    insert_into(door_code::table).values(code).execute(conn)
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,157 @@
+use async_session::chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+
+use super::within_schedule;
+use crate::{
+    calendars::{DayRule, Exceptions},
+    models::DoorPermission,
+};
+
+const WEEKDAYS: i16 = 0b001_1111;
+
+fn permission(
+    days: Option<i16>,
+    start: Option<(u32, u32)>,
+    end: Option<(u32, u32)>,
+) -> DoorPermission {
+    let time = |(hour, minute)| NaiveTime::from_hms_opt(hour, minute, 0).unwrap();
+    DoorPermission {
+        door_id: 1,
+        user_profile_id: 1,
+        edit_permission: false,
+        open_permission: true,
+        schedule_days: days,
+        schedule_start: start.map(time),
+        schedule_end: end.map(time),
+        calendar_id: None,
+    }
+}
+
+fn hours(start: u32, end: u32) -> DayRule {
+    DayRule::Hours {
+        start: NaiveTime::from_hms_opt(start, 0, 0).unwrap(),
+        end: NaiveTime::from_hms_opt(end, 0, 0).unwrap(),
+    }
+}
+
+// A calendar with the rule on one day of December 2023
+fn on(day: u32, rule: DayRule) -> Exceptions {
+    Exceptions::from([(NaiveDate::from_ymd_opt(2023, 12, day).unwrap(), rule)])
+}
+
+// 2023-12-22 is a Friday
+fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(2023, 12, day)
+        .unwrap()
+        .and_hms_opt(hour, minute, 0)
+        .unwrap()
+}
+
+#[test]
+fn schedules_limit_days_and_hours() {
+    let office = permission(Some(WEEKDAYS), Some((8, 0)), Some((18, 0)));
+
+    assert!(within_schedule(&office, &Exceptions::new(), at(22, 8, 0)));
+    assert!(!within_schedule(&office, &Exceptions::new(), at(22, 18, 0)));
+    assert!(!within_schedule(&office, &Exceptions::new(), at(22, 7, 59)));
+    // Saturday
+    assert!(!within_schedule(&office, &Exceptions::new(), at(23, 12, 0)));
+
+    let anytime = permission(None, None, None);
+    assert!(within_schedule(&anytime, &Exceptions::new(), at(23, 3, 0)));
+}
+
+#[test]
+fn windows_run_over_midnight() {
+    let night = permission(None, Some((22, 0)), Some((6, 0)));
+
+    assert!(within_schedule(&night, &Exceptions::new(), at(22, 23, 0)));
+    assert!(within_schedule(&night, &Exceptions::new(), at(23, 5, 59)));
+    assert!(!within_schedule(&night, &Exceptions::new(), at(23, 6, 0)));
+}
+
+#[test]
+fn windows_over_midnight_belong_to_the_day_they_start() {
+    let friday_night = permission(Some(0b001_0000), Some((22, 0)), Some((2, 0)));
+
+    // Saturday is not one of its days
+    assert!(within_schedule(
+        &friday_night,
+        &Exceptions::new(),
+        at(23, 1, 0)
+    ));
+    assert!(!within_schedule(
+        &friday_night,
+        &Exceptions::new(),
+        at(23, 22, 30)
+    ));
+    // Thursday night does not start a window
+    assert!(!within_schedule(
+        &friday_night,
+        &Exceptions::new(),
+        at(22, 1, 0)
+    ));
+
+    // Friday's calendar rule decides, Saturday's does not
+    let closed_friday = on(22, DayRule::Closed);
+    assert!(!within_schedule(
+        &friday_night,
+        &closed_friday,
+        at(23, 1, 0)
+    ));
+    let closed_saturday = on(23, DayRule::Closed);
+    assert!(within_schedule(
+        &friday_night,
+        &closed_saturday,
+        at(23, 1, 0)
+    ));
+    assert!(within_schedule(
+        &friday_night,
+        &on(22, hours(23, 3)),
+        at(23, 2, 30)
+    ));
+}
+
+#[test]
+fn calendar_days_replace_the_hours() {
+    let office = permission(Some(WEEKDAYS), Some((8, 0)), Some((18, 0)));
+
+    assert!(!within_schedule(
+        &office,
+        &on(22, DayRule::Closed),
+        at(22, 12, 0)
+    ));
+    assert!(within_schedule(
+        &office,
+        &on(22, hours(10, 14)),
+        at(22, 13, 0)
+    ));
+    assert!(!within_schedule(
+        &office,
+        &on(22, hours(10, 14)),
+        at(22, 9, 0)
+    ));
+    assert!(!within_schedule(
+        &office,
+        &on(22, hours(10, 14)),
+        at(22, 15, 0)
+    ));
+}
+
+#[test]
+fn calendar_hours_do_not_open_days_the_schedule_leaves_out() {
+    let office = permission(Some(WEEKDAYS), Some((8, 0)), Some((18, 0)));
+
+    // Christmas Eve is a Sunday
+    assert!(!within_schedule(
+        &office,
+        &on(24, hours(9, 12)),
+        at(24, 10, 0)
+    ));
+
+    let any_day = permission(None, Some((8, 0)), Some((18, 0)));
+    assert!(within_schedule(
+        &any_day,
+        &on(24, hours(9, 12)),
+        at(24, 10, 0)
+    ));
+}
@@ -0,0 +1,514 @@
+use async_session::chrono::{Local, TimeZone, Utc};
+use diesel::{dsl::count_star, insert_into, prelude::*, update};
+use protocol::{
+    offline::{AllowlistEntry, AllowlistPayload, OfflineOpen, SignedAllowlist},
+    DeviceCommand,
+};
+use sha2::Sha256;
+
+use crate::{
+    access::{self, OpenMethod},
+    devices::DeviceHub,
+    emergency,
+    events::{DoorEvent, EventBus, EventKind},
+    models::{AllowlistVersion, Credential, Device, DoorCode, DoorPermission, GuestToken},
+    routes::auth::hash_token,
+    schema::{
+        access_history, allowlist_version, credential, device, door, door_code, door_permission,
+        guest_token, responder,
+    },
+    totp,
+};
+
+// Controllers stretch an entered PIN the same way before comparing it, few
+// enough rounds that the keypad still answers right away
+pub const PIN_ROUNDS: u32 = 10_000;
+
+fn pin_salt(conn: &mut PgConnection, door_id: i32) -> QueryResult<String> {
+    door::table
+        .find(door_id)
+        .select(door::pin_salt)
+        .get_result(conn)
+}
+
+// What the door's controllers compare against, PINs are only stored as a plain
+// SHA-256 and must not leave the server like that
+fn door_value(kind: &str, value: &str, salt: &str) -> String {
+    if kind != "pin" {
+        return value.to_string();
+    }
+
+    let mut stretched = [0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(
+        value.as_bytes(),
+        salt.as_bytes(),
+        PIN_ROUNDS,
+        &mut stretched,
+    );
+    hex::encode(stretched)
+}
+
+pub fn compile(conn: &mut PgConnection, door_id: i32) -> QueryResult<Vec<AllowlistEntry>> {
+    let salt = pin_salt(conn, door_id)?;
+    if emergency::in_lockdown(conn) {
+        return compile_lockdown(conn, &salt);
+    }
+
+    let permissions = door_permission::table
+        .filter(door_permission::door_id.eq(door_id))
+        .filter(door_permission::open_permission)
+        .select(DoorPermission::as_select())
+        .load(conn)?;
+
+    let credentials = credential::table
+        .filter(
+            credential::user_profile_id.eq_any(
+                permissions
+                    .iter()
+                    .map(|permission| permission.user_profile_id),
+            ),
+        )
+        .select(Credential::as_select())
+        .load(conn)?;
+
+    let mut entries = credentials
+        .into_iter()
+        .filter_map(|credential| {
+            let permission = permissions
+                .iter()
+                .find(|permission| permission.user_profile_id == credential.user_profile_id)?;
+            Some(AllowlistEntry {
+                value: door_value(&credential.kind, &credential.value, &salt),
+                kind: credential.kind,
+                user_profile_id: Some(credential.user_profile_id),
+                // Calendars are only checked online, offline the plain schedule applies
+                days: permission.schedule_days,
+                start: permission.schedule_start,
+                end: permission.schedule_end,
+                expires_at: None,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let now = Utc::now().naive_utc();
+    let codes = door_code::table
+        .filter(door_code::door_id.eq(door_id))
+        .filter(door_code::used.eq(false))
+        .filter(
+            door_code::expires_at
+                .is_null()
+                .or(door_code::expires_at.gt(now)),
+        )
+        .select(DoorCode::as_select())
+        .load(conn)?;
+
+    entries.extend(codes.into_iter().map(|code| AllowlistEntry {
+        kind: "code".to_string(),
+        value: hash_token(&code.code),
+        user_profile_id: None,
+        days: None,
+        start: None,
+        end: None,
+        expires_at: code.expires_at,
+    }));
+    // Keypads derive the rotating code from the door's secret themselves
+    if let Some(secret) = totp::door_secret(conn, door_id) {
+        entries.push(AllowlistEntry {
+            kind: "totp".to_string(),
+            value: secret,
+            user_profile_id: None,
+            days: None,
+            start: None,
+            end: None,
+            expires_at: None,
+        });
+    }
+    entries.sort();
+
+    Ok(entries)
+}
+
+// Responders at any time of day, no codes and no one else
+fn compile_lockdown(conn: &mut PgConnection, salt: &str) -> QueryResult<Vec<AllowlistEntry>> {
+    let mut entries = credential::table
+        .inner_join(responder::table.on(responder::user_profile_id.eq(credential::user_profile_id)))
+        .select(Credential::as_select())
+        .load(conn)?
+        .into_iter()
+        .map(|credential| AllowlistEntry {
+            value: door_value(&credential.kind, &credential.value, salt),
+            kind: credential.kind,
+            user_profile_id: Some(credential.user_profile_id),
+            days: None,
+            start: None,
+            end: None,
+            expires_at: None,
+        })
+        .collect::<Vec<_>>();
+    entries.sort();
+
+    Ok(entries)
+}
+
+fn entries_of(version: &AllowlistVersion) -> Vec<AllowlistEntry> {
+    serde_json::from_value(version.entries.clone()).unwrap_or_default()
+}
+
+fn find_version(
+    conn: &mut PgConnection,
+    door_id: i32,
+    version: i32,
+) -> QueryResult<AllowlistVersion> {
+    allowlist_version::table
+        .find((door_id, version))
+        .select(AllowlistVersion::as_select())
+        .get_result(conn)
+}
+
+// Compiles the allowlist and stores it as a new version if anything changed
+fn current_version(conn: &mut PgConnection, door_id: i32) -> QueryResult<AllowlistVersion> {
+    let entries = compile(conn, door_id)?;
+
+    let latest = allowlist_version::table
+        .filter(allowlist_version::door_id.eq(door_id))
+        .order(allowlist_version::version.desc())
+        .select(AllowlistVersion::as_select())
+        .first(conn)
+        .optional()?;
+
+    if let Some(latest) = &latest {
+        if entries_of(latest) == entries {
+            return Ok(latest.clone());
+        }
+    }
+
+    insert_into(allowlist_version::table)
+        .values((
+            allowlist_version::door_id.eq(door_id),
+            allowlist_version::version.eq(latest.map_or(1, |latest| latest.version + 1)),
+            allowlist_version::entries.eq(serde_json::to_value(&entries).unwrap()),
+            allowlist_version::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .returning(AllowlistVersion::as_returning())
+        .get_result(conn)
+}
+
+fn push(conn: &mut PgConnection, hub: &DeviceHub, device: &Device, current: &AllowlistVersion) {
+    let entries = entries_of(current);
+    let base = match device.allowlist_version {
+        0 => None,
+        version => find_version(conn, device.door_id, version).ok(),
+    };
+    let base_entries = base.as_ref().map(entries_of).unwrap_or_default();
+    let pin_salt = pin_salt(conn, device.door_id).ok();
+
+    let payload = match &base {
+        Some(base) => AllowlistPayload {
+            door_id: device.door_id,
+            version: current.version,
+            base_version: Some(base.version),
+            entries: None,
+            added: Some(
+                entries
+                    .iter()
+                    .filter(|entry| !base_entries.contains(entry))
+                    .cloned()
+                    .collect(),
+            ),
+            removed: Some(
+                base_entries
+                    .iter()
+                    .filter(|entry| !entries.contains(entry))
+                    .cloned()
+                    .collect(),
+            ),
+            pin_salt,
+            pin_rounds: Some(PIN_ROUNDS),
+        },
+        None => AllowlistPayload {
+            door_id: device.door_id,
+            version: current.version,
+            base_version: None,
+            entries: Some(entries.clone()),
+            added: None,
+            removed: None,
+            pin_salt,
+            pin_rounds: Some(PIN_ROUNDS),
+        },
+    };
+
+    let payload = serde_json::to_string(&payload).unwrap();
+    let signature = hub.signer().sign(payload.as_bytes());
+    hub.push(
+        device.id,
+        DeviceCommand::Allowlist(SignedAllowlist { payload, signature }),
+    );
+}
+
+// Call whenever permissions, credentials or codes of the door change
+pub fn publish(conn: &mut PgConnection, hub: &DeviceHub, door_id: i32) {
+    let current = match current_version(conn, door_id) {
+        Ok(current) => current,
+        Err(e) => {
+            tracing::error!("Could not compile allowlist of door {door_id}: {e}");
+            return;
+        }
+    };
+
+    let devices = device::table
+        .filter(device::door_id.eq(door_id))
+        .filter(device::allowlist_version.ne(current.version))
+        .select(Device::as_select())
+        .load(conn)
+        .unwrap_or_default();
+
+    for device in devices {
+        push(conn, hub, &device, &current);
+    }
+}
+
+// A user's credentials are on the allowlist of every door they may open
+pub fn publish_for_user(conn: &mut PgConnection, hub: &DeviceHub, user_id: i32) {
+    let door_ids = door_permission::table
+        .filter(door_permission::user_profile_id.eq(user_id))
+        .filter(door_permission::open_permission)
+        .select(door_permission::door_id)
+        .load::<i32>(conn)
+        .unwrap_or_default();
+
+    for door_id in door_ids {
+        publish(conn, hub, door_id);
+    }
+}
+
+// Brings a freshly connected device up to date
+pub fn sync_device(conn: &mut PgConnection, hub: &DeviceHub, device: &Device) {
+    match current_version(conn, device.door_id) {
+        Ok(current) if current.version != device.allowlist_version => {
+            push(conn, hub, device, &current)
+        }
+        Ok(_) => {}
+        Err(e) => tracing::error!(
+            "Could not compile allowlist of door {}: {e}",
+            device.door_id
+        ),
+    }
+}
+
+pub fn acknowledge(conn: &mut PgConnection, device: &Device, version: i32) {
+    if find_version(conn, device.door_id, version).is_err() {
+        tracing::warn!(
+            "Device {} confirmed unknown allowlist version {version}",
+            device.id
+        );
+        return;
+    }
+
+    let _ = update(device::table.find(device.id))
+        .set(device::allowlist_version.eq(version))
+        .execute(conn);
+}
+
+// Opens the device granted from its allowlist while it could not reach us
+pub fn merge_offline_opens(
+    conn: &mut PgConnection,
+    events: &EventBus,
+    device: &Device,
+    opens: Vec<OfflineOpen>,
+) {
+    let now = Utc::now().naive_utc();
+
+    for open in opens.into_iter().filter(|open| open.opened_at <= now) {
+        if open.kind == "code" {
+            redeem_offline_code(conn, events, device.door_id, &open);
+            continue;
+        }
+        if open.kind == "guest" {
+            record_guest_open(conn, events, device.door_id, &open);
+            continue;
+        }
+
+        let user_ids = match matching_users(conn, device.door_id, &open) {
+            Ok(user_ids) if !user_ids.is_empty() => user_ids,
+            Ok(_) => {
+                tracing::warn!(
+                    "Device {} reported an open with an unknown credential",
+                    device.id
+                );
+                continue;
+            }
+            Err(e) => {
+                tracing::error!(
+                    "Could not look up offline open of device {}: {e}",
+                    device.id
+                );
+                continue;
+            }
+        };
+
+        // The controller only had a copy of the rules, check them again at
+        // the time it reported. Schedules are in local time.
+        let local = Local.from_utc_datetime(&open.opened_at).naive_local();
+        let permitted = user_ids.iter().copied().find(|&user_id| {
+            access::permitted_at(conn, device.door_id, user_id, local)
+                || (emergency::in_lockdown(conn) && emergency::is_responder(conn, user_id))
+        });
+        let user_id = permitted.unwrap_or(user_ids[0]);
+
+        // Uploads are retried until acknowledged, so the same open may come twice
+        let known = access_history::table
+            .filter(access_history::door_id.eq(device.door_id))
+            .filter(access_history::user_profile_id.eq(user_id))
+            .filter(access_history::access_timestamp.eq(open.opened_at))
+            .select(count_star())
+            .get_result::<i64>(conn)
+            .is_ok_and(|count| count > 0);
+        if known {
+            continue;
+        }
+
+        if permitted.is_some() {
+            let _ = access::record_open_at(conn, events, device.door_id, user_id, open.opened_at);
+        } else {
+            tracing::warn!(
+                "Device {} let user {user_id} in while they were not allowed",
+                device.id
+            );
+            let _ = access::record_flagged_open(
+                conn,
+                events,
+                device.door_id,
+                user_id,
+                OpenMethod::Permission,
+                open.opened_at,
+            );
+        }
+    }
+}
+
+// Owners of the credential a controller reported. Several users may hold the
+// same card, and PINs are only known to the door in their stretched form.
+fn matching_users(
+    conn: &mut PgConnection,
+    door_id: i32,
+    open: &OfflineOpen,
+) -> QueryResult<Vec<i32>> {
+    if open.kind != "pin" {
+        return credential::table
+            .filter(credential::kind.eq(&open.kind))
+            .filter(credential::value.eq(&open.value))
+            .select(credential::user_profile_id)
+            .load(conn);
+    }
+
+    // Only PINs that could have been on the door's allowlist
+    let mut holders = door_permission::table
+        .filter(door_permission::door_id.eq(door_id))
+        .select(door_permission::user_profile_id)
+        .load::<i32>(conn)?;
+    holders.extend(
+        responder::table
+            .select(responder::user_profile_id)
+            .load::<i32>(conn)?,
+    );
+
+    let salt = pin_salt(conn, door_id)?;
+    let pins = credential::table
+        .filter(credential::kind.eq("pin"))
+        .filter(credential::user_profile_id.eq_any(holders))
+        .select((credential::user_profile_id, credential::value))
+        .load::<(i32, String)>(conn)?;
+
+    Ok(pins
+        .into_iter()
+        .filter(|(_, value)| door_value("pin", value, &salt) == open.value)
+        .map(|(user_id, _)| user_id)
+        .collect())
+}
+
+fn redeem_offline_code(
+    conn: &mut PgConnection,
+    events: &EventBus,
+    door_id: i32,
+    open: &OfflineOpen,
+) {
+    let codes = door_code::table
+        .filter(door_code::door_id.eq(door_id))
+        .filter(door_code::used.eq(false))
+        .select(door_code::code)
+        .load::<String>(conn)
+        .unwrap_or_default();
+
+    let Some(code) = codes
+        .into_iter()
+        .find(|code| hash_token(code) == open.value)
+    else {
+        return;
+    };
+
+    let redeemed = update(door_code::table.find(&code))
+        .set(door_code::used.eq(true))
+        .execute(conn);
+
+    if redeemed == Ok(1) {
+        let mut event = DoorEvent::new(EventKind::CodeRedeemed, door_id, None);
+        event.timestamp = open.opened_at;
+        events.publish(event);
+        let _ = access::record_open_by(
+            conn,
+            events,
+            door_id,
+            None,
+            OpenMethod::Code,
+            open.opened_at,
+        );
+    }
+}
+
+// Guest opens are reported with the token's nonce. The controller may not have
+// heard of a revocation yet, and its clock decides the validity window, so
+// both are checked again at the time of the open.
+fn record_guest_open(conn: &mut PgConnection, events: &EventBus, door_id: i32, open: &OfflineOpen) {
+    let token = guest_token::table
+        .filter(guest_token::door_id.eq(door_id))
+        .filter(guest_token::nonce.eq(&open.value))
+        .select(GuestToken::as_select())
+        .get_result(conn)
+        .optional();
+    let token = match token {
+        Ok(Some(token)) => token,
+        Ok(None) => return,
+        Err(e) => {
+            tracing::error!("Could not look up guest open of door {door_id}: {e}");
+            return;
+        }
+    };
+
+    // Uploads are retried until acknowledged, so the same open may come twice
+    let recorded = access_history::table
+        .filter(access_history::guest_token_id.eq(token.id))
+        .filter(access_history::access_timestamp.eq(open.opened_at))
+        .select(count_star())
+        .get_result::<i64>(conn)
+        .is_ok_and(|count| count > 0);
+    if recorded {
+        return;
+    }
+
+    let revoked = token
+        .revoked_at
+        .is_some_and(|revoked_at| revoked_at <= open.opened_at);
+    let valid = token.valid_from <= open.opened_at && open.opened_at <= token.valid_until;
+    let flagged = revoked || !valid;
+    if flagged {
+        tracing::warn!(
+            "A controller of door {door_id} let a guest in with token {} while it was not valid",
+            token.id
+        );
+    }
+
+    let _ = access::record_guest_open(conn, events, door_id, token.id, open.opened_at, flagged);
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,332 @@
+use async_session::chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{insert_into, prelude::*, update};
+use protocol::offline::OfflineOpen;
+
+use super::{compile, door_value, merge_offline_opens, pin_salt};
+use crate::{
+    events::EventKind,
+    models::AccessHistory,
+    routes::auth::hash_token,
+    schema::{access_history, credential, door_permission, guest_token},
+    testing,
+};
+
+fn credential(
+    conn: &mut PgConnection,
+    user_id: i32,
+    kind: &str,
+    value: &str,
+) -> QueryResult<usize> {
+    insert_into(credential::table)
+        .values((
+            credential::user_profile_id.eq(user_id),
+            credential::kind.eq(kind),
+            credential::value.eq(value),
+            credential::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)
+}
+
+fn permission(conn: &mut PgConnection, door_id: i32, user_id: i32, days: Option<i16>) {
+    insert_into(door_permission::table)
+        .values((
+            door_permission::door_id.eq(door_id),
+            door_permission::user_profile_id.eq(user_id),
+            door_permission::edit_permission.eq(false),
+            door_permission::open_permission.eq(true),
+            door_permission::schedule_days.eq(days),
+        ))
+        .execute(conn)
+        .unwrap();
+}
+
+// Valid from an hour ago
+fn guest(
+    conn: &mut PgConnection,
+    door_id: i32,
+    user_id: i32,
+    nonce: &str,
+    valid_until: NaiveDateTime,
+) -> i32 {
+    let now = Utc::now().naive_utc();
+    insert_into(guest_token::table)
+        .values((
+            guest_token::door_id.eq(door_id),
+            guest_token::creator_id.eq(user_id),
+            guest_token::nonce.eq(nonce),
+            guest_token::valid_from.eq(now - Duration::hours(1)),
+            guest_token::valid_until.eq(valid_until),
+            guest_token::created_at.eq(now),
+        ))
+        .returning(guest_token::id)
+        .get_result(conn)
+        .unwrap()
+}
+
+fn history(conn: &mut PgConnection, door_id: i32) -> Vec<AccessHistory> {
+    access_history::table
+        .filter(access_history::door_id.eq(door_id))
+        .select(AccessHistory::as_select())
+        .load(conn)
+        .unwrap()
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn pins_are_stretched_with_the_salt_of_each_door() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let front = testing::door(conn, user_id);
+    let back = testing::door(conn, user_id);
+    permission(conn, front, user_id, None);
+    permission(conn, back, user_id, None);
+    credential(conn, user_id, "pin", &hash_token("1234")).unwrap();
+    credential(conn, user_id, "card", "04A1B2C3").unwrap();
+
+    let pin_of = |conn: &mut PgConnection, door_id| {
+        compile(conn, door_id)
+            .unwrap()
+            .into_iter()
+            .find(|entry| entry.kind == "pin")
+            .unwrap()
+            .value
+    };
+    let front_pin = pin_of(conn, front);
+    let back_pin = pin_of(conn, back);
+
+    assert_ne!(front_pin, hash_token("1234"));
+    assert_ne!(front_pin, back_pin);
+    let salt = pin_salt(conn, front).unwrap();
+    assert_eq!(front_pin, door_value("pin", &hash_token("1234"), &salt));
+
+    let cards = compile(conn, front)
+        .unwrap()
+        .into_iter()
+        .filter(|entry| entry.kind == "card")
+        .map(|entry| entry.value)
+        .collect::<Vec<_>>();
+    assert_eq!(cards, ["04A1B2C3"]);
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn credentials_are_only_unique_per_user() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let alice = testing::user(conn, "alice");
+    let bob = testing::user(conn, "bob");
+
+    credential(conn, alice, "pin", &hash_token("1234")).unwrap();
+    credential(conn, bob, "pin", &hash_token("1234")).unwrap();
+
+    assert!(credential(conn, alice, "pin", &hash_token("1234")).is_err());
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn offline_opens_are_recorded_once() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let controller = testing::device(conn, door_id, "token");
+    permission(conn, door_id, user_id, None);
+    credential(conn, user_id, "pin", &hash_token("1234")).unwrap();
+    let events = testing::events();
+    let mut receiver = events.subscribe();
+
+    let salt = pin_salt(conn, door_id).unwrap();
+    let open = OfflineOpen {
+        kind: "pin".to_string(),
+        value: door_value("pin", &hash_token("1234"), &salt),
+        opened_at: Utc::now().naive_utc() - Duration::minutes(5),
+    };
+    merge_offline_opens(conn, &events, &controller, vec![open.clone()]);
+    merge_offline_opens(conn, &events, &controller, vec![open]);
+
+    let opens = history(conn, door_id);
+    assert_eq!(opens.len(), 1);
+    assert_eq!(opens[0].user_profile_id, Some(user_id));
+    assert!(!opens[0].flagged);
+    let kinds = testing::published(&events, &mut receiver)
+        .into_iter()
+        .map(|event| event.kind)
+        .collect::<Vec<_>>();
+    assert_eq!(kinds, [EventKind::Opened]);
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn offline_opens_outside_the_schedule_are_flagged() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let controller = testing::device(conn, door_id, "token");
+    // No day of the week at all
+    permission(conn, door_id, user_id, Some(0));
+    credential(conn, user_id, "card", "04A1B2C3").unwrap();
+    let events = testing::events();
+    let mut receiver = events.subscribe();
+
+    let open = OfflineOpen {
+        kind: "card".to_string(),
+        value: "04A1B2C3".to_string(),
+        opened_at: Utc::now().naive_utc() - Duration::minutes(5),
+    };
+    merge_offline_opens(conn, &events, &controller, vec![open]);
+
+    let opens = history(conn, door_id);
+    assert_eq!(opens.len(), 1);
+    assert!(opens[0].flagged);
+    let kinds = testing::published(&events, &mut receiver)
+        .into_iter()
+        .map(|event| event.kind)
+        .collect::<Vec<_>>();
+    assert_eq!(kinds, [EventKind::FlaggedOpen]);
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn offline_opens_with_unknown_credentials_are_dropped() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let controller = testing::device(conn, door_id, "token");
+    permission(conn, door_id, user_id, None);
+    credential(conn, user_id, "pin", &hash_token("1234")).unwrap();
+    let events = testing::events();
+
+    // The unsalted hash is not what this door's controllers know
+    let open = OfflineOpen {
+        kind: "pin".to_string(),
+        value: hash_token("1234"),
+        opened_at: Utc::now().naive_utc() - Duration::minutes(5),
+    };
+    merge_offline_opens(conn, &events, &controller, vec![open]);
+
+    assert!(history(conn, door_id).is_empty());
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn offline_guest_opens_are_recorded_once() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let controller = testing::device(conn, door_id, "token");
+    let now = Utc::now().naive_utc();
+    guest(
+        conn,
+        door_id,
+        user_id,
+        "00112233aabbccdd",
+        now + Duration::hours(1),
+    );
+    let events = testing::events();
+
+    let open = OfflineOpen {
+        kind: "guest".to_string(),
+        value: "00112233aabbccdd".to_string(),
+        opened_at: now - Duration::minutes(5),
+    };
+    merge_offline_opens(conn, &events, &controller, vec![open.clone()]);
+    merge_offline_opens(conn, &events, &controller, vec![open]);
+
+    let opens = history(conn, door_id);
+    assert_eq!(opens.len(), 1);
+    assert_eq!(opens[0].user_profile_id, None);
+    assert_eq!(opens[0].method, "guest");
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn guests_opening_in_the_same_second_are_recorded_apart() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let controller = testing::device(conn, door_id, "token");
+    let now = Utc::now().naive_utc();
+    let first = guest(
+        conn,
+        door_id,
+        user_id,
+        "00112233aabbccdd",
+        now + Duration::hours(1),
+    );
+    let second = guest(
+        conn,
+        door_id,
+        user_id,
+        "ddccbbaa33221100",
+        now + Duration::hours(1),
+    );
+    let events = testing::events();
+
+    let opened_at = now - Duration::minutes(5);
+    let opens = ["00112233aabbccdd", "ddccbbaa33221100"].map(|nonce| OfflineOpen {
+        kind: "guest".to_string(),
+        value: nonce.to_string(),
+        opened_at,
+    });
+    merge_offline_opens(conn, &events, &controller, opens.to_vec());
+    merge_offline_opens(conn, &events, &controller, opens.to_vec());
+
+    let mut tokens = history(conn, door_id)
+        .into_iter()
+        .map(|open| open.guest_token_id)
+        .collect::<Vec<_>>();
+    tokens.sort();
+    assert_eq!(tokens, [Some(first), Some(second)]);
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn offline_guest_opens_with_revoked_or_expired_tokens_are_flagged() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let controller = testing::device(conn, door_id, "token");
+    let now = Utc::now().naive_utc();
+    let revoked = guest(
+        conn,
+        door_id,
+        user_id,
+        "00112233aabbccdd",
+        now + Duration::hours(1),
+    );
+    update(guest_token::table.find(revoked))
+        .set(guest_token::revoked_at.eq(now - Duration::minutes(30)))
+        .execute(conn)
+        .unwrap();
+    guest(
+        conn,
+        door_id,
+        user_id,
+        "ddccbbaa33221100",
+        now - Duration::minutes(30),
+    );
+    let events = testing::events();
+    let mut receiver = events.subscribe();
+
+    let opens = ["00112233aabbccdd", "ddccbbaa33221100"].map(|nonce| OfflineOpen {
+        kind: "guest".to_string(),
+        value: nonce.to_string(),
+        opened_at: now - Duration::minutes(5),
+    });
+    merge_offline_opens(conn, &events, &controller, opens.to_vec());
+
+    let opens = history(conn, door_id);
+    assert_eq!(opens.len(), 2);
+    assert!(opens.iter().all(|open| open.flagged && open.opened));
+    let kinds = testing::published(&events, &mut receiver)
+        .into_iter()
+        .map(|event| event.kind)
+        .collect::<Vec<_>>();
+    assert_eq!(kinds, [EventKind::FlaggedOpen, EventKind::FlaggedOpen]);
+}
@@ -0,0 +1,302 @@
+use futures::{SinkExt, StreamExt};
+use protocol::{
+    diagnostics::{Diagnostic, DiagnosticResult, SensorReadings},
+    firmware::{FirmwareReport, UpdateStatus},
+    offline::AllowlistPayload,
+    CommandFrame, DeviceCommand, DeviceMessage, Encoding, Heartbeat, PROTOCOL_VERSION,
+};
+use std::time::{Duration, Instant};
+use tokio::{
+    sync::mpsc,
+    time::{interval_at, Interval},
+};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, http::HeaderValue, Message},
+};
+
+const FIRMWARE_VERSION: &str = "sim";
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// How long the simulated door stays open after an open
+const OPEN_DURATION: Duration = Duration::from_secs(1);
+
+// How the simulated controllers answer
+#[derive(Clone, Debug)]
+pub struct Behaviour {
+    pub latency: Duration,
+    pub failure_rate: f64,
+    pub heartbeat: Option<Duration>,
+    pub doorbell: Option<Duration>,
+}
+
+impl Default for Behaviour {
+    fn default() -> Self {
+        Behaviour {
+            latency: Duration::ZERO,
+            failure_rate: 0.0,
+            heartbeat: Some(Duration::from_secs(30)),
+            doorbell: None,
+        }
+    }
+}
+
+// A virtual controller that keeps its channel to the server open and
+// reconnects when it drops
+pub struct Controller {
+    outgoing: mpsc::UnboundedSender<DeviceMessage>,
+    // Every command the server sent, for scenarios to wait on
+    pub received: mpsc::UnboundedReceiver<DeviceCommand>,
+}
+
+impl Controller {
+    pub fn spawn(
+        id: i32,
+        token: String,
+        url: String,
+        encoding: Encoding,
+        behaviour: Behaviour,
+    ) -> Self {
+        let (outgoing, mut queued) = mpsc::unbounded_channel();
+        let (commands, received) = mpsc::unbounded_channel();
+
+        let replies = outgoing.clone();
+        tokio::spawn(async move {
+            let started = Instant::now();
+            loop {
+                let session = Session {
+                    id,
+                    encoding,
+                    behaviour: &behaviour,
+                    started,
+                    replies: &replies,
+                    commands: &commands,
+                };
+                match session.run(&token, &url, &mut queued).await {
+                    Ok(()) => println!("device {id}: disconnected"),
+                    Err(e) => eprintln!("device {id}: {e}"),
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        });
+
+        Controller { outgoing, received }
+    }
+
+    // Queued until the controller is connected
+    pub fn send(&self, message: DeviceMessage) {
+        let _ = self.outgoing.send(message);
+    }
+}
+
+struct Session<'a> {
+    id: i32,
+    encoding: Encoding,
+    behaviour: &'a Behaviour,
+    started: Instant,
+    replies: &'a mpsc::UnboundedSender<DeviceMessage>,
+    commands: &'a mpsc::UnboundedSender<DeviceCommand>,
+}
+
+fn every(period: Option<Duration>) -> Option<Interval> {
+    period.map(|period| interval_at((Instant::now() + period).into(), period))
+}
+
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+impl Session<'_> {
+    async fn run(
+        &self,
+        token: &str,
+        url: &str,
+        queued: &mut mpsc::UnboundedReceiver<DeviceMessage>,
+    ) -> Result<(), String> {
+        let mut request = url.into_client_request().map_err(|e| e.to_string())?;
+        request.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            HeaderValue::from_static(self.encoding.name()),
+        );
+        let (socket, _) = connect_async(request)
+            .await
+            .map_err(|e| format!("could not connect: {e}"))?;
+        let (mut sink, mut stream) = socket.split();
+
+        let hello = [
+            DeviceMessage::Auth {
+                token: token.to_string(),
+            },
+            DeviceMessage::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                firmware_version: Some(FIRMWARE_VERSION.to_string()),
+            },
+        ];
+        for message in hello {
+            sink.send(self.encode(&message))
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+
+        let mut heartbeat = every(self.behaviour.heartbeat);
+        let mut doorbell = every(self.behaviour.doorbell);
+
+        loop {
+            let message = tokio::select! {
+                frame = stream.next() => {
+                    let frame = match frame {
+                        Some(Ok(Message::Close(_))) | None => return Ok(()),
+                        Some(Ok(frame)) => frame,
+                        Some(Err(e)) => return Err(e.to_string()),
+                    };
+                    if let Some(frame) = self.decode(&frame)? {
+                        self.handle(frame)?;
+                    }
+                    continue;
+                }
+                Some(message) = queued.recv() => message,
+                _ = tick(&mut heartbeat) => DeviceMessage::Heartbeat(Heartbeat {
+                    uptime_seconds: self.started.elapsed().as_secs() as i64,
+                    rssi: -60 + rand::random::<i32>().rem_euclid(10),
+                    free_heap: 96_000,
+                    firmware_version: FIRMWARE_VERSION.to_string(),
+                }),
+                _ = tick(&mut doorbell) => {
+                    println!("device {}: ringing", self.id);
+                    DeviceMessage::Doorbell
+                }
+            };
+
+            sink.send(self.encode(&message))
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+    }
+
+    fn encode(&self, message: &DeviceMessage) -> Message {
+        let bytes = self.encoding.encode(message).unwrap();
+        if self.encoding.is_binary() {
+            Message::Binary(bytes)
+        } else {
+            Message::Text(String::from_utf8(bytes).unwrap())
+        }
+    }
+
+    // Text frames are always JSON, like on the server
+    fn decode(&self, frame: &Message) -> Result<Option<CommandFrame>, String> {
+        let decoded = match frame {
+            Message::Text(text) => Encoding::Json.decode(text.as_bytes()),
+            Message::Binary(bytes) => self.encoding.decode(bytes),
+            _ => return Ok(None),
+        };
+        decoded
+            .map(Some)
+            .map_err(|e| format!("server sent an invalid frame: {e}"))
+    }
+
+    fn handle(&self, frame: CommandFrame) -> Result<(), String> {
+        let id = self.id;
+        let name = serde_json::to_value(&frame.command).unwrap()["type"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        match frame.id {
+            Some(command_id) => println!("device {id}: received {name} #{command_id}"),
+            None => println!("device {id}: received {name}"),
+        }
+
+        let mut replies = Vec::new();
+        let mut after_open = Vec::new();
+        match &frame.command {
+            DeviceCommand::Welcome { protocol_version } => {
+                println!("device {id}: connected speaking version {protocol_version}")
+            }
+            DeviceCommand::Unsupported {
+                min_version,
+                max_version,
+            } => {
+                return Err(format!(
+                    "server only speaks versions {min_version} to {max_version}"
+                ))
+            }
+            DeviceCommand::Open if rand::random::<f64>() < self.behaviour.failure_rate => {
+                // Neither acknowledged nor opened, like a controller that hung
+                println!("device {id}: failed to open");
+                let _ = self.commands.send(frame.command.clone());
+                return Ok(());
+            }
+            DeviceCommand::Open => {
+                replies.push(DeviceMessage::LockSensor { locked: false });
+                replies.push(DeviceMessage::DoorContact { open: true });
+                after_open.push(DeviceMessage::DoorContact { open: false });
+                after_open.push(DeviceMessage::LockSensor { locked: true });
+            }
+            DeviceCommand::Unlock { .. } => {
+                replies.push(DeviceMessage::LockSensor { locked: false })
+            }
+            DeviceCommand::Lock => replies.push(DeviceMessage::LockSensor { locked: true }),
+            DeviceCommand::Emergency { .. } => {}
+            DeviceCommand::Allowlist(allowlist) => {
+                match serde_json::from_str::<AllowlistPayload>(&allowlist.payload) {
+                    Ok(payload) => replies.push(DeviceMessage::AllowlistAck {
+                        version: payload.version,
+                    }),
+                    Err(e) => eprintln!("device {id}: unreadable allowlist: {e}"),
+                }
+            }
+            DeviceCommand::Revocations(_) => {}
+            DeviceCommand::FirmwareUpdate(offer) => {
+                replies.push(DeviceMessage::FirmwareStatus(FirmwareReport {
+                    version: offer.version.clone(),
+                    status: UpdateStatus::Installed,
+                    detail: None,
+                }))
+            }
+            DeviceCommand::Config(update) => replies.push(DeviceMessage::ConfigApplied {
+                version: update.version,
+            }),
+            DeviceCommand::Diagnostic(diagnostic) => {
+                let readings =
+                    matches!(diagnostic, Diagnostic::SensorRead).then(|| SensorReadings {
+                        door_open: Some(false),
+                        locked: Some(true),
+                    });
+                replies.push(DeviceMessage::DiagnosticResult(DiagnosticResult {
+                    action: serde_json::to_value(diagnostic).unwrap()["action"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                    ok: true,
+                    detail: Some("simulated".to_string()),
+                    readings,
+                }))
+            }
+        }
+        if let Some(command_id) = frame.id {
+            replies.insert(0, DeviceMessage::Ack { id: command_id });
+        }
+
+        // Answers go through the queue so the channel keeps reading meanwhile
+        let latency = self.behaviour.latency;
+        let sender = self.replies.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(latency).await;
+            for reply in replies {
+                let _ = sender.send(reply);
+            }
+            if !after_open.is_empty() {
+                tokio::time::sleep(OPEN_DURATION).await;
+                for reply in after_open {
+                    let _ = sender.send(reply);
+                }
+            }
+        });
+
+        let _ = self.commands.send(frame.command);
+        Ok(())
+    }
+}
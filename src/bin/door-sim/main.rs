@@ -0,0 +1,152 @@
+use protocol::Encoding;
+use std::{collections::HashMap, env, process, time::Duration};
+
+mod controller;
+mod scenario;
+
+use controller::{Behaviour, Controller};
+use scenario::Scenario;
+
+const USAGE: &str = "\
+Runs virtual door controllers against a server.
+
+Usage: door-sim [OPTIONS] --device <ID:TOKEN>...
+
+Options:
+  --server <URL>           Server to connect to [default: http://127.0.0.1:3000]
+  --device <ID:TOKEN>      A controller and its device token, repeatable
+  --encoding <NAME>        json, cbor or msgpack [default: json]
+  --latency-ms <MS>        Delay before answering a command [default: 0]
+  --failure-rate <SHARE>   Share of opens that silently fail, 0 to 1 [default: 0]
+  --heartbeat-secs <SECS>  Heartbeat interval, 0 for none [default: 30]
+  --doorbell-secs <SECS>   Ring the doorbell this often [default: never]
+  --scenario <FILE>        Run a scripted scenario and exit with its result
+";
+
+struct Options {
+    server: String,
+    devices: Vec<(i32, String)>,
+    encoding: Encoding,
+    behaviour: Behaviour,
+    scenario: Option<String>,
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("door-sim: {message}\n\n{USAGE}");
+    process::exit(2);
+}
+
+fn number<T: std::str::FromStr>(flag: &str, value: &str) -> T {
+    value
+        .parse()
+        .unwrap_or_else(|_| fail(&format!("{flag} must be a number")))
+}
+
+fn parse_options() -> Options {
+    let mut options = Options {
+        server: "http://127.0.0.1:3000".to_string(),
+        devices: Vec::new(),
+        encoding: Encoding::Json,
+        behaviour: Behaviour::default(),
+        scenario: None,
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(flag) = args.next() {
+        if flag == "--help" || flag == "-h" {
+            print!("{USAGE}");
+            process::exit(0);
+        }
+        let value = args
+            .next()
+            .unwrap_or_else(|| fail(&format!("{flag} needs a value")));
+
+        match flag.as_str() {
+            "--server" => options.server = value.trim_end_matches('/').to_string(),
+            "--device" => {
+                let Some((id, token)) = value.split_once(':') else {
+                    fail("--device must look like ID:TOKEN");
+                };
+                options
+                    .devices
+                    .push((number("--device", id), token.to_string()));
+            }
+            "--encoding" => {
+                options.encoding = Encoding::from_name(&value)
+                    .unwrap_or_else(|| fail("--encoding must be json, cbor or msgpack"))
+            }
+            "--latency-ms" => {
+                options.behaviour.latency = Duration::from_millis(number(&flag, &value))
+            }
+            "--failure-rate" => {
+                let rate: f64 = number(&flag, &value);
+                if !(0.0..=1.0).contains(&rate) {
+                    fail("--failure-rate must be between 0 and 1");
+                }
+                options.behaviour.failure_rate = rate;
+            }
+            "--heartbeat-secs" => {
+                let seconds: u64 = number(&flag, &value);
+                options.behaviour.heartbeat = (seconds > 0).then(|| Duration::from_secs(seconds));
+            }
+            "--doorbell-secs" => {
+                let seconds: u64 = number(&flag, &value);
+                options.behaviour.doorbell = (seconds > 0).then(|| Duration::from_secs(seconds));
+            }
+            "--scenario" => options.scenario = Some(value),
+            flag => fail(&format!("unknown option {flag}")),
+        }
+    }
+
+    if options.devices.is_empty() {
+        fail("at least one --device is needed");
+    }
+    options
+}
+
+#[tokio::main]
+async fn main() {
+    let options = parse_options();
+
+    let scenario = options.scenario.as_ref().map(|path| {
+        Scenario::load(path).unwrap_or_else(|e| {
+            eprintln!("door-sim: could not load scenario {path}: {e}");
+            process::exit(2);
+        })
+    });
+
+    let ws_url = format!(
+        "{}/api/v1/devices/ws",
+        options
+            .server
+            .replacen("http://", "ws://", 1)
+            .replacen("https://", "wss://", 1)
+    );
+    let controllers: HashMap<i32, Controller> = options
+        .devices
+        .iter()
+        .map(|(id, token)| {
+            let controller = Controller::spawn(
+                *id,
+                token.clone(),
+                ws_url.clone(),
+                options.encoding,
+                options.behaviour.clone(),
+            );
+            (*id, controller)
+        })
+        .collect();
+
+    match scenario {
+        Some(scenario) => match scenario.run(&options.server, controllers).await {
+            Ok(()) => println!("scenario passed"),
+            Err(e) => {
+                eprintln!("scenario failed: {e}");
+                process::exit(1);
+            }
+        },
+        None => {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use protocol::{DeviceCommand, DeviceMessage};
+use serde::Deserialize;
+use std::{collections::HashMap, fs, time::Duration};
+
+use crate::controller::Controller;
+
+const DEFAULT_TIMEOUT_MS: u64 = 5000;
+
+// A scripted run, steps go one after the other:
+//
+//   { "steps": [
+//       { "expect": { "device": 1, "command": "welcome" } },
+//       { "http": { "method": "GET", "path": "/api/v1/doors/1/open?door_code=abc", "status": 200 } },
+//       { "expect": { "device": 1, "command": "open", "timeout_ms": 2000 } },
+//       { "send": { "device": 1, "message": { "type": "doorbell" } } },
+//       { "wait": { "ms": 500 } }
+//   ] }
+#[derive(Deserialize)]
+pub struct Scenario {
+    steps: Vec<Step>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Step {
+    // Waits for the server to send the device a command of this type
+    Expect {
+        device: i32,
+        command: String,
+        timeout_ms: Option<u64>,
+    },
+    Send {
+        device: i32,
+        message: DeviceMessage,
+    },
+    // A request to the server's API, checked against the expected status
+    Http {
+        #[serde(default = "default_method")]
+        method: String,
+        path: String,
+        // An API token
+        token: Option<String>,
+        status: Option<u16>,
+    },
+    Wait {
+        ms: u64,
+    },
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+fn command_type(command: &DeviceCommand) -> String {
+    serde_json::to_value(command).unwrap()["type"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn simulated(
+    controllers: &mut HashMap<i32, Controller>,
+    device: i32,
+    step: usize,
+) -> Result<&mut Controller, String> {
+    controllers
+        .get_mut(&device)
+        .ok_or_else(|| format!("step {step}: device {device} is not simulated"))
+}
+
+impl Scenario {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        serde_json::from_str(&contents).map_err(|e| e.to_string())
+    }
+
+    pub async fn run(
+        &self,
+        server: &str,
+        mut controllers: HashMap<i32, Controller>,
+    ) -> Result<(), String> {
+        let client = reqwest::Client::new();
+
+        for (number, step) in self.steps.iter().enumerate() {
+            let number = number + 1;
+            match step {
+                Step::Expect {
+                    device,
+                    command,
+                    timeout_ms,
+                } => {
+                    let simulated = simulated(&mut controllers, *device, number)?;
+                    let timeout = Duration::from_millis(timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS));
+
+                    // Anything else the server sends meanwhile is fine
+                    let found = tokio::time::timeout(timeout, async {
+                        while let Some(received) = simulated.received.recv().await {
+                            if &command_type(&received) == command {
+                                return true;
+                            }
+                        }
+                        false
+                    })
+                    .await;
+
+                    if found != Ok(true) {
+                        return Err(format!(
+                            "step {number}: device {device} did not get `{command}` within {}ms",
+                            timeout.as_millis()
+                        ));
+                    }
+                }
+                Step::Send { device, message } => {
+                    simulated(&mut controllers, *device, number)?.send(message.clone());
+                }
+                Step::Http {
+                    method,
+                    path,
+                    token,
+                    status,
+                } => {
+                    let method = method
+                        .parse::<reqwest::Method>()
+                        .map_err(|e| format!("step {number}: {e}"))?;
+                    let mut request = client.request(method, format!("{server}{path}"));
+                    if let Some(token) = token {
+                        request = request.bearer_auth(token);
+                    }
+
+                    let response = request
+                        .send()
+                        .await
+                        .map_err(|e| format!("step {number}: {e}"))?;
+                    let got = response.status().as_u16();
+                    if status.is_some_and(|status| status != got) {
+                        let body = response.text().await.unwrap_or_default();
+                        return Err(format!(
+                            "step {number}: {path} answered {got} instead of {}: {body}",
+                            status.unwrap()
+                        ));
+                    }
+                }
+                Step::Wait { ms } => tokio::time::sleep(Duration::from_millis(*ms)).await,
+            }
+            println!("step {number}: ok");
+        }
+
+        Ok(())
+    }
+}
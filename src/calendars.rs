@@ -0,0 +1,53 @@
+use async_session::chrono::{NaiveDate, NaiveTime};
+use diesel::prelude::*;
+use std::collections::HashMap;
+
+use crate::{models::CalendarException, schema::calendar_exception};
+
+pub mod ics;
+
+// Named lists of days that do not follow the usual schedules, like public
+// holidays. Access schedules and unlock schedules can each follow one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DayRule {
+    Closed,
+    // Replaces the schedule's own hours that day, in server local time
+    Hours { start: NaiveTime, end: NaiveTime },
+}
+
+impl DayRule {
+    pub fn of(exception: &CalendarException) -> Self {
+        match (exception.start_time, exception.end_time) {
+            (Some(start), Some(end)) => DayRule::Hours { start, end },
+            _ => DayRule::Closed,
+        }
+    }
+}
+
+pub type Exceptions = HashMap<NaiveDate, DayRule>;
+
+// The exceptions of each calendar between `from` and `to`, both included
+pub fn exceptions_between(
+    conn: &mut PgConnection,
+    calendar_ids: &[i32],
+    from: NaiveDate,
+    to: NaiveDate,
+) -> QueryResult<HashMap<i32, Exceptions>> {
+    let exceptions = calendar_exception::table
+        .filter(calendar_exception::calendar_id.eq_any(calendar_ids))
+        .filter(calendar_exception::date.between(from, to))
+        .select(CalendarException::as_select())
+        .load(conn)?;
+
+    let mut calendars = HashMap::<i32, Exceptions>::new();
+    for exception in exceptions {
+        calendars
+            .entry(exception.calendar_id)
+            .or_default()
+            .insert(exception.date, DayRule::of(&exception));
+    }
+    Ok(calendars)
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,218 @@
+use async_session::chrono::{Duration, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+
+// An all-day event longer than this is most likely not a closure
+const MAX_EVENT_DAYS: i64 = 366;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportedDay {
+    pub date: NaiveDate,
+    pub label: Option<String>,
+    // None for closed all day
+    pub hours: Option<(NaiveTime, NaiveTime)>,
+    pub uid: Option<String>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Imported {
+    pub days: Vec<ImportedDay>,
+    // Why events were left out, one line each
+    pub skipped: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct Event {
+    uid: Option<String>,
+    summary: Option<String>,
+    start: Option<Moment>,
+    end: Option<Moment>,
+    recurring: bool,
+    cancelled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Moment {
+    Date(NaiveDate),
+    // In server local time
+    DateTime(NaiveDateTime),
+    // Times in a named zone are not converted, their events are skipped
+    Zoned,
+}
+
+// Lines that start with a space or tab continue the one before
+fn unfold(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.lines() {
+        let line = line.trim_end_matches('\r');
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(continued), Some(last)) => last.push_str(continued),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+// `DTSTART;TZID="Europe/Berlin":20231224T100000` into the name, its
+// parameters and the value
+fn split_property(line: &str) -> Option<(String, Vec<String>, &str)> {
+    let mut quoted = false;
+    let colon = line.char_indices().find_map(|(i, c)| {
+        match c {
+            '"' => quoted = !quoted,
+            ':' if !quoted => return Some(i),
+            _ => {}
+        }
+        None
+    })?;
+
+    let mut parts = line[..colon].split(';');
+    let name = parts.next()?.to_ascii_uppercase();
+    let params = parts.map(|param| param.to_ascii_uppercase()).collect();
+    Some((name, params, &line[colon + 1..]))
+}
+
+// Times in UTC are converted to server local time, times without a zone are
+// taken as they are
+fn parse_moment(params: &[String], value: &str) -> Result<Moment, String> {
+    if params.iter().any(|param| param == "VALUE=DATE") || value.len() == 8 {
+        return NaiveDate::parse_from_str(value, "%Y%m%d")
+            .map(Moment::Date)
+            .map_err(|e| format!("invalid date {value}: {e}"));
+    }
+
+    let (value, utc) = match value.strip_suffix('Z') {
+        Some(value) => (value, true),
+        None => (value, false),
+    };
+    let time = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S")
+        .map_err(|e| format!("invalid time {value}: {e}"))?;
+    if params.iter().any(|param| param.starts_with("TZID=")) {
+        return Ok(Moment::Zoned);
+    }
+    Ok(Moment::DateTime(if utc {
+        Local.from_utc_datetime(&time).naive_local()
+    } else {
+        time
+    }))
+}
+
+impl Event {
+    fn name(&self) -> String {
+        self.summary
+            .clone()
+            .or_else(|| self.uid.clone())
+            .unwrap_or_else(|| "unnamed event".to_string())
+    }
+
+    fn days(self) -> Result<Vec<ImportedDay>, String> {
+        if self.cancelled {
+            return Err("it is cancelled".to_string());
+        }
+        if self.recurring {
+            return Err("recurring events are not supported".to_string());
+        }
+
+        let label = self.summary;
+        let uid = self.uid;
+        match (self.start, self.end) {
+            (Some(Moment::Zoned), _) | (_, Some(Moment::Zoned)) => {
+                Err("time zones are not supported".to_string())
+            }
+            // DTEND is the day after the last one
+            (Some(Moment::Date(start)), end) => {
+                let end = match end {
+                    Some(Moment::Date(end)) => end,
+                    None => start + Duration::days(1),
+                    Some(_) => return Err("it mixes dates and times".to_string()),
+                };
+                let days = (end - start).num_days();
+                if !(1..=MAX_EVENT_DAYS).contains(&days) {
+                    return Err(format!("it lasts {days} days"));
+                }
+
+                Ok((0..days)
+                    .map(|day| ImportedDay {
+                        date: start + Duration::days(day),
+                        label: label.clone(),
+                        hours: None,
+                        uid: uid.clone(),
+                    })
+                    .collect())
+            }
+            (Some(Moment::DateTime(start)), Some(Moment::DateTime(end))) => {
+                if end <= start || end.date() != start.date() {
+                    return Err("its hours do not fit in one day".to_string());
+                }
+                Ok(vec![ImportedDay {
+                    date: start.date(),
+                    label,
+                    hours: Some((start.time(), end.time())),
+                    uid,
+                }])
+            }
+            (Some(Moment::DateTime(_)), _) => Err("it has no end time".to_string()),
+            (None, _) => Err("it has no start".to_string()),
+        }
+    }
+}
+
+// All-day events close the calendar for their days, events with times open
+// it for those hours only. Later events replace earlier ones on the same day.
+pub fn parse(text: &str) -> Result<Imported, String> {
+    let lines = unfold(text);
+    if lines
+        .iter()
+        .find(|line| !line.trim().is_empty())
+        .is_none_or(|line| !line.eq_ignore_ascii_case("BEGIN:VCALENDAR"))
+    {
+        return Err("not an iCalendar file".to_string());
+    }
+
+    let mut imported = Imported::default();
+    let mut event: Option<Event> = None;
+
+    for line in &lines {
+        let Some((name, params, value)) = split_property(line) else {
+            continue;
+        };
+
+        match (name.as_str(), event.as_mut()) {
+            ("BEGIN", None) if value.eq_ignore_ascii_case("VEVENT") => {
+                event = Some(Event::default())
+            }
+            ("END", Some(_)) if value.eq_ignore_ascii_case("VEVENT") => {
+                let finished = event.take().unwrap();
+                let name = finished.name();
+                match finished.days() {
+                    Ok(days) => imported.days.extend(days),
+                    Err(reason) => imported.skipped.push(format!("{name}: {reason}")),
+                }
+            }
+            ("UID", Some(event)) => event.uid = Some(value.to_string()),
+            ("SUMMARY", Some(event)) => event.summary = Some(unescape(value)),
+            ("DTSTART", Some(event)) => event.start = Some(parse_moment(&params, value)?),
+            ("DTEND", Some(event)) => event.end = Some(parse_moment(&params, value)?),
+            ("RRULE" | "RDATE", Some(event)) => event.recurring = true,
+            ("STATUS", Some(event)) => event.cancelled = value.eq_ignore_ascii_case("CANCELLED"),
+            _ => {}
+        }
+    }
+
+    Ok(imported)
+}
@@ -0,0 +1,114 @@
+use async_session::chrono::{NaiveDate, NaiveTime};
+
+use super::ics::{parse, ImportedDay};
+
+fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(year, month, day).unwrap()
+}
+
+fn time(hour: u32, minute: u32) -> NaiveTime {
+    NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+}
+
+#[test]
+fn all_day_events_close_each_of_their_days() {
+    let imported = parse(
+        "BEGIN:VCALENDAR\r\n\
+         VERSION:2.0\r\n\
+         BEGIN:VEVENT\r\n\
+         UID:christmas@example.com\r\n\
+         DTSTART;VALUE=DATE:20231225\r\n\
+         DTEND;VALUE=DATE:20231227\r\n\
+         SUMMARY:Christmas\\, Boxing Day\r\n\
+         END:VEVENT\r\n\
+         END:VCALENDAR\r\n",
+    )
+    .unwrap();
+
+    assert!(imported.skipped.is_empty());
+    assert_eq!(
+        imported.days,
+        vec![
+            ImportedDay {
+                date: date(2023, 12, 25),
+                label: Some("Christmas, Boxing Day".to_string()),
+                hours: None,
+                uid: Some("christmas@example.com".to_string()),
+            },
+            ImportedDay {
+                date: date(2023, 12, 26),
+                label: Some("Christmas, Boxing Day".to_string()),
+                hours: None,
+                uid: Some("christmas@example.com".to_string()),
+            },
+        ]
+    );
+}
+
+#[test]
+fn timed_events_set_the_hours_of_their_day() {
+    let imported = parse(
+        "BEGIN:VCALENDAR\n\
+         BEGIN:VEVENT\n\
+         DTSTART:20231224T090000\n\
+         DTEND:20231224T130000\n\
+         SUMMARY:Christmas Eve\\, \n open until noon\n\
+         END:VEVENT\n\
+         END:VCALENDAR\n",
+    )
+    .unwrap();
+
+    assert_eq!(
+        imported.days,
+        vec![ImportedDay {
+            date: date(2023, 12, 24),
+            label: Some("Christmas Eve, open until noon".to_string()),
+            hours: Some((time(9, 0), time(13, 0))),
+            uid: None,
+        }]
+    );
+}
+
+#[test]
+fn events_that_can_not_be_imported_are_skipped() {
+    let imported = parse(
+        "BEGIN:VCALENDAR\n\
+         BEGIN:VEVENT\n\
+         SUMMARY:New Year\n\
+         DTSTART;VALUE=DATE:20240101\n\
+         RRULE:FREQ=YEARLY\n\
+         END:VEVENT\n\
+         BEGIN:VEVENT\n\
+         SUMMARY:Inventory\n\
+         DTSTART:20240102T200000\n\
+         DTEND:20240103T020000\n\
+         END:VEVENT\n\
+         BEGIN:VEVENT\n\
+         SUMMARY:Staff party\n\
+         DTSTART;TZID=\"Europe/Berlin\":20240104T180000\n\
+         DTEND;TZID=\"Europe/Berlin\":20240104T230000\n\
+         END:VEVENT\n\
+         BEGIN:VEVENT\n\
+         SUMMARY:Epiphany\n\
+         DTSTART;VALUE=DATE:20240106\n\
+         END:VEVENT\n\
+         END:VCALENDAR\n",
+    )
+    .unwrap();
+
+    assert_eq!(imported.days.len(), 1);
+    assert_eq!(imported.days[0].date, date(2024, 1, 6));
+    assert_eq!(imported.skipped.len(), 3);
+    assert!(imported.skipped[0].starts_with("New Year"));
+    assert!(imported.skipped[1].starts_with("Inventory"));
+    assert_eq!(
+        imported.skipped[2],
+        "Staff party: time zones are not supported"
+    );
+}
+
+#[test]
+fn other_files_are_refused() {
+    assert!(parse("name,date\nChristmas,2023-12-25\n").is_err());
+    assert!(parse("BEGIN:VCALENDAR\nBEGIN:VEVENT\nDTSTART:tomorrow\nEND:VEVENT\n").is_err());
+}
@@ -0,0 +1,97 @@
+use async_session::chrono::Utc;
+use diesel::{insert_into, prelude::*, update};
+use protocol::{
+    config::{ConfigUpdate, DeviceConfig},
+    DeviceCommand,
+};
+
+use crate::{
+    devices::DeviceHub,
+    models::{Device, DeviceConfigVersion},
+    schema::{device, device_config},
+};
+
+pub fn latest(conn: &mut PgConnection, device_id: i32) -> QueryResult<Option<DeviceConfigVersion>> {
+    device_config::table
+        .filter(device_config::device_id.eq(device_id))
+        .order(device_config::version.desc())
+        .select(DeviceConfigVersion::as_select())
+        .first(conn)
+        .optional()
+}
+
+pub fn save(
+    conn: &mut PgConnection,
+    device_id: i32,
+    config: &DeviceConfig,
+    user_id: i32,
+) -> QueryResult<DeviceConfigVersion> {
+    let version = latest(conn, device_id)?.map_or(1, |latest| latest.version + 1);
+
+    insert_into(device_config::table)
+        .values((
+            device_config::device_id.eq(device_id),
+            device_config::version.eq(version),
+            device_config::config.eq(serde_json::to_value(config).unwrap()),
+            device_config::created_by.eq(user_id),
+            device_config::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .returning(DeviceConfigVersion::as_returning())
+        .get_result(conn)
+}
+
+// Sends the newest configuration unless the device already applied it
+pub fn push(conn: &mut PgConnection, hub: &DeviceHub, device: &Device) {
+    let latest = match latest(conn, device.id) {
+        Ok(Some(latest)) if latest.version != device.config_version => latest,
+        Ok(_) => return,
+        Err(e) => {
+            tracing::error!("Could not load configuration of device {}: {e}", device.id);
+            return;
+        }
+    };
+
+    let Ok(config) = serde_json::from_value::<DeviceConfig>(latest.config) else {
+        tracing::error!(
+            "Stored configuration {} of device {} is invalid",
+            latest.version,
+            device.id
+        );
+        return;
+    };
+
+    hub.push(
+        device.id,
+        DeviceCommand::Config(ConfigUpdate {
+            version: latest.version,
+            config,
+        }),
+    );
+}
+
+// Late confirmations of an older version must not roll the device back
+pub fn applied(conn: &mut PgConnection, device: &Device, version: i32) {
+    let applied = conn.transaction(|conn| {
+        let updated = update(device_config::table.find((device.id, version)))
+            .set(device_config::applied_at.eq(Utc::now().naive_utc()))
+            .execute(conn)?;
+        if updated == 0 {
+            return Err(diesel::result::Error::NotFound);
+        }
+
+        update(device::table.find(device.id))
+            .filter(device::config_version.lt(version))
+            .set(device::config_version.eq(version))
+            .execute(conn)
+    });
+
+    if let Err(e) = applied {
+        tracing::warn!(
+            "Device {} confirmed configuration {version}: {e}",
+            device.id
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests;
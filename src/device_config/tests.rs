@@ -0,0 +1,46 @@
+use diesel::prelude::*;
+use protocol::config::DeviceConfig;
+
+use super::{applied, save};
+use crate::{schema::device, testing};
+
+fn config_version(conn: &mut PgConnection, device_id: i32) -> i32 {
+    device::table
+        .find(device_id)
+        .select(device::config_version)
+        .first(conn)
+        .unwrap()
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn late_confirmations_do_not_roll_back() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let device = testing::device(conn, door_id, "front");
+
+    let config = DeviceConfig::default();
+    save(conn, device.id, &config, user_id).unwrap();
+    save(conn, device.id, &config, user_id).unwrap();
+
+    applied(conn, &device, 2);
+    assert_eq!(config_version(conn, device.id), 2);
+
+    applied(conn, &device, 1);
+    assert_eq!(config_version(conn, device.id), 2);
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn unknown_versions_are_ignored() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let device = testing::device(conn, door_id, "front");
+
+    applied(conn, &device, 3);
+    assert_eq!(config_version(conn, device.id), 0);
+}
@@ -0,0 +1,526 @@
+use async_session::chrono::{Duration, Utc};
+use diesel::{insert_into, prelude::*, update};
+use dotenv::dotenv;
+use protocol::{CommandFrame, DeviceCommand, DeviceMessage};
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{
+    access::{self, OpenMethod},
+    allowlist,
+    db::establish_connection,
+    device_config,
+    diagnostics::{DeviceLogs, LogContent},
+    door_state, emergency,
+    events::{DoorEvent, EventBus, EventKind},
+    firmware, guest_tokens, lock_mode,
+    models::{Device, DeviceCommandEntry},
+    schema::{device, device_command, doorbell_ring},
+    signing::DeviceSigner,
+    telemetry, totp,
+};
+
+// Open requests should neither get lost in a Wi-Fi hiccup nor fire minutes later
+const DEFAULT_COMMAND_TTL_SECONDS: i64 = 30;
+const EXPIRY_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+const STATUS_PENDING: &str = "pending";
+const STATUS_DELIVERED: &str = "delivered";
+const STATUS_EXPIRED: &str = "expired";
+const STATUS_ACKNOWLEDGED: &str = "acknowledged";
+
+// Controllers that currently hold an open channel, keyed by device ID
+#[derive(Clone)]
+pub struct DeviceHub {
+    connections: Arc<Mutex<HashMap<i32, mpsc::UnboundedSender<CommandFrame>>>>,
+    command_ttl: Duration,
+    signer: DeviceSigner,
+    logs: DeviceLogs,
+}
+
+impl DeviceHub {
+    pub fn from_env() -> Self {
+        dotenv().ok();
+
+        let command_ttl = env::var("DEVICE_COMMAND_TTL_SECONDS")
+            .ok()
+            .map(|seconds| {
+                seconds
+                    .parse()
+                    .expect("DEVICE_COMMAND_TTL_SECONDS must be a number")
+            })
+            .unwrap_or(DEFAULT_COMMAND_TTL_SECONDS);
+
+        DeviceHub {
+            connections: Arc::default(),
+            command_ttl: Duration::seconds(command_ttl),
+            signer: DeviceSigner::from_env(),
+            logs: DeviceLogs::from_env(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        DeviceHub {
+            connections: Arc::default(),
+            command_ttl: Duration::seconds(DEFAULT_COMMAND_TTL_SECONDS),
+            signer: DeviceSigner::from_seed([7; 32]),
+            logs: DeviceLogs::from_env(),
+        }
+    }
+
+    pub fn signer(&self) -> &DeviceSigner {
+        &self.signer
+    }
+
+    pub fn logs(&self) -> &DeviceLogs {
+        &self.logs
+    }
+
+    pub fn connect(&self, device: &Device) -> mpsc::UnboundedReceiver<CommandFrame> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.connections.lock().unwrap().insert(device.id, sender);
+        receiver
+    }
+
+    // Call after dropping the receiver from `connect`. A reconnect that already
+    // replaced this connection is left alone.
+    pub fn disconnect(&self, device_id: i32) {
+        let mut connections = self.connections.lock().unwrap();
+        if connections
+            .get(&device_id)
+            .is_some_and(|sender| sender.is_closed())
+        {
+            connections.remove(&device_id);
+        }
+    }
+
+    // Ends the channel of a controller that was deleted. Its socket or MQTT
+    // session stops once it sees the channel closed.
+    pub fn close(&self, device_id: i32) {
+        self.connections.lock().unwrap().remove(&device_id);
+    }
+
+    // Queues the command for every controller of the door that last spoke
+    // `protocol_version` or newer and hands it to the connected ones right
+    // away. Returns how many controllers it was queued for, offline ones get
+    // it when they reconnect.
+    pub fn send_to_capable(
+        &self,
+        conn: &mut PgConnection,
+        door_id: i32,
+        command: DeviceCommand,
+        user_profile_id: Option<i32>,
+        protocol_version: i32,
+    ) -> usize {
+        self.queue(
+            conn,
+            door_id,
+            command,
+            user_profile_id,
+            None,
+            protocol_version,
+        )
+    }
+
+    // Every controller knows how to open. The open goes into the access
+    // history once the first controller confirms it, or as failed when none
+    // did in time.
+    pub fn open_door(
+        &self,
+        conn: &mut PgConnection,
+        door_id: i32,
+        user_profile_id: Option<i32>,
+        method: OpenMethod,
+    ) -> usize {
+        self.queue(
+            conn,
+            door_id,
+            DeviceCommand::Open,
+            user_profile_id,
+            Some(method),
+            0,
+        )
+    }
+
+    fn queue(
+        &self,
+        conn: &mut PgConnection,
+        door_id: i32,
+        command: DeviceCommand,
+        user_profile_id: Option<i32>,
+        method: Option<OpenMethod>,
+        protocol_version: i32,
+    ) -> usize {
+        let now = Utc::now().naive_utc();
+        let payload = serde_json::to_value(&command).unwrap();
+        let group_id = Uuid::new_v4().to_string();
+
+        let device_ids = device::table
+            .filter(device::door_id.eq(door_id))
+            .filter(device::protocol_version.ge(protocol_version))
+            .select(device::id)
+            .load::<i32>(conn)
+            .unwrap_or_default();
+
+        let queued = insert_into(device_command::table)
+            .values(
+                device_ids
+                    .iter()
+                    .map(|device_id| {
+                        (
+                            device_command::device_id.eq(device_id),
+                            device_command::door_id.eq(door_id),
+                            device_command::user_profile_id.eq(user_profile_id),
+                            device_command::command.eq(&payload),
+                            device_command::created_at.eq(now),
+                            device_command::expires_at.eq(now + self.command_ttl),
+                            device_command::method.eq(method.map(|method| method.as_str())),
+                            device_command::group_id.eq(&group_id),
+                        )
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .returning(DeviceCommandEntry::as_returning())
+            .get_results(conn);
+
+        match queued {
+            Ok(queued) => {
+                for entry in &queued {
+                    self.deliver(conn, entry);
+                }
+                queued.len()
+            }
+            Err(e) => {
+                tracing::error!("Could not queue command for door {door_id}: {e}");
+                0
+            }
+        }
+    }
+
+    // Sends whatever is still fresh in the device's queue, for when it reconnects
+    pub fn deliver_pending(&self, conn: &mut PgConnection, events: &EventBus, device_id: i32) {
+        expire_commands(conn, events);
+
+        let pending = device_command::table
+            .filter(device_command::device_id.eq(device_id))
+            .filter(device_command::status.eq(STATUS_PENDING))
+            .order(device_command::id.asc())
+            .select(DeviceCommandEntry::as_select())
+            .load(conn);
+
+        match pending {
+            Ok(pending) => {
+                for entry in pending {
+                    self.deliver(conn, &entry);
+                }
+            }
+            Err(e) => tracing::error!("Could not load queued commands of device {device_id}: {e}"),
+        }
+    }
+
+    // Sends straight to a connected device, for state it gets again on reconnect anyway
+    pub fn push(&self, device_id: i32, command: DeviceCommand) -> bool {
+        self.connections
+            .lock()
+            .unwrap()
+            .get(&device_id)
+            .is_some_and(|sender| sender.send(CommandFrame { id: None, command }).is_ok())
+    }
+
+    fn deliver(&self, conn: &mut PgConnection, entry: &DeviceCommandEntry) -> bool {
+        let command = match serde_json::from_value::<DeviceCommand>(entry.command.clone()) {
+            Ok(command) => command,
+            Err(e) => {
+                tracing::error!("Queued command {} is not a valid command: {e}", entry.id);
+                return false;
+            }
+        };
+
+        let sent = self
+            .connections
+            .lock()
+            .unwrap()
+            .get(&entry.device_id)
+            .is_some_and(|sender| {
+                sender
+                    .send(CommandFrame {
+                        id: Some(entry.id),
+                        command,
+                    })
+                    .is_ok()
+            });
+
+        if sent {
+            let _ = update(device_command::table.find(entry.id))
+                .set((
+                    device_command::status.eq(STATUS_DELIVERED),
+                    device_command::delivered_at.eq(Utc::now().naive_utc()),
+                ))
+                .execute(conn);
+        }
+        sent
+    }
+}
+
+// Brings a controller that just opened its channel up to date, whatever the transport
+pub fn on_connect(conn: &mut PgConnection, events: &EventBus, hub: &DeviceHub, device: &Device) {
+    if let Err(e) = telemetry::mark_seen(conn, events, device.id, Utc::now().naive_utc()) {
+        tracing::error!("Could not mark device {} as seen: {e}", device.id);
+    }
+    hub.deliver_pending(conn, events, device.id);
+    allowlist::sync_device(conn, hub, device);
+    guest_tokens::sync_device(conn, hub, device);
+    firmware::notify(conn, hub, device);
+    device_config::push(conn, hub, device);
+    lock_mode::sync_device(conn, hub, device);
+    emergency::sync_device(conn, hub, device);
+}
+
+// Answers a controller's hello with the version both sides will speak
+pub fn hello(conn: &mut PgConnection, device: &Device, protocol_version: u16) -> DeviceCommand {
+    let Some(negotiated) = protocol::negotiate(protocol_version) else {
+        tracing::warn!(
+            "Device {} speaks unsupported protocol version {protocol_version}",
+            device.id
+        );
+        return DeviceCommand::Unsupported {
+            min_version: protocol::MIN_PROTOCOL_VERSION,
+            max_version: protocol::PROTOCOL_VERSION,
+        };
+    };
+
+    let recorded = update(device::table.find(device.id))
+        .set(device::protocol_version.eq(i32::from(negotiated)))
+        .execute(conn);
+    if let Err(e) = recorded {
+        tracing::error!(
+            "Could not record protocol version of device {}: {e}",
+            device.id
+        );
+    }
+
+    DeviceCommand::Welcome {
+        protocol_version: negotiated,
+    }
+}
+
+// Only the device a command was queued for can confirm it
+fn acknowledge(conn: &mut PgConnection, events: &EventBus, device: &Device, command_id: i32) {
+    let now = Utc::now().naive_utc();
+    let acknowledged = update(
+        device_command::table
+            .find(command_id)
+            .filter(device_command::device_id.eq(device.id))
+            .filter(device_command::status.eq(STATUS_DELIVERED)),
+    )
+    .set((
+        device_command::status.eq(STATUS_ACKNOWLEDGED),
+        device_command::acknowledged_at.eq(now),
+    ))
+    .returning(DeviceCommandEntry::as_returning())
+    .get_result(conn);
+
+    let entry = match acknowledged {
+        Ok(entry) => entry,
+        Err(_) => {
+            tracing::warn!(
+                "Device {} acknowledged unknown command {command_id}",
+                device.id
+            );
+            return;
+        }
+    };
+
+    // Every controller of the door got the open, the first to confirm it counts
+    let Some(method) = entry.method.as_deref().and_then(OpenMethod::parse) else {
+        return;
+    };
+    if !claim_recording(conn, &entry) {
+        return;
+    }
+    if let Err(e) = access::record_open_by(
+        conn,
+        events,
+        entry.door_id,
+        entry.user_profile_id,
+        method,
+        now,
+    ) {
+        tracing::error!("Could not record open of door {}: {e}", entry.door_id);
+    }
+}
+
+// Only the first of the group's commands to get here records the open, the
+// update waits for a claim in flight and then finds nothing left to claim
+fn claim_recording(conn: &mut PgConnection, entry: &DeviceCommandEntry) -> bool {
+    let claimed = update(
+        device_command::table
+            .filter(device_command::group_id.eq(&entry.group_id))
+            .filter(device_command::recorded.eq(false)),
+    )
+    .set(device_command::recorded.eq(true))
+    .execute(conn);
+
+    match claimed {
+        Ok(claimed) => claimed > 0,
+        Err(e) => {
+            tracing::error!("Could not claim the open of command {}: {e}", entry.id);
+            false
+        }
+    }
+}
+
+// Fails commands that outlived their TTL unconfirmed and records them in the
+// door's history. Opens that no controller confirmed are recorded as failed.
+pub fn expire_commands(conn: &mut PgConnection, events: &EventBus) {
+    let expired = update(
+        device_command::table
+            .filter(device_command::status.eq_any([STATUS_PENDING, STATUS_DELIVERED]))
+            .filter(device_command::expires_at.le(Utc::now().naive_utc())),
+    )
+    .set(device_command::status.eq(STATUS_EXPIRED))
+    .returning(DeviceCommandEntry::as_returning())
+    .get_results(conn);
+
+    let expired = match expired {
+        Ok(expired) => expired,
+        Err(e) => {
+            tracing::error!("Could not expire queued commands: {e}");
+            return;
+        }
+    };
+
+    for entry in &expired {
+        tracing::warn!(
+            "Command {} for device {} expired unconfirmed",
+            entry.id,
+            entry.device_id
+        );
+        events.publish(DoorEvent::new(
+            EventKind::CommandExpired,
+            entry.door_id,
+            entry.user_profile_id,
+        ));
+    }
+
+    // One history entry per open, however many controllers it was queued for
+    let mut failed_opens = expired
+        .into_iter()
+        .filter_map(|entry| {
+            let method = entry.method.as_deref().and_then(OpenMethod::parse)?;
+            Some((entry, method))
+        })
+        .collect::<Vec<_>>();
+    failed_opens.sort_by(|(a, _), (b, _)| a.group_id.cmp(&b.group_id));
+    failed_opens.dedup_by(|(a, _), (b, _)| a.group_id == b.group_id);
+
+    for (entry, method) in failed_opens {
+        if !claim_recording(conn, &entry) {
+            continue;
+        }
+        if let Err(e) = access::record_failed_open(
+            conn,
+            entry.door_id,
+            entry.user_profile_id,
+            method,
+            entry.created_at,
+        ) {
+            tracing::error!(
+                "Could not record failed open of door {}: {e}",
+                entry.door_id
+            );
+        }
+    }
+}
+
+pub fn spawn_command_expiry(events: &EventBus) {
+    let events = events.clone();
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let conn = &mut establish_connection();
+            expire_commands(conn, &events);
+        }
+    });
+}
+
+pub fn handle_message(
+    conn: &mut PgConnection,
+    events: &EventBus,
+    hub: &DeviceHub,
+    device: &Device,
+    message: DeviceMessage,
+) {
+    match message {
+        // The channel answers these itself before anything is handed over here
+        DeviceMessage::Hello { .. } | DeviceMessage::Auth { .. } => {}
+        DeviceMessage::Ack { id } => acknowledge(conn, events, device, id),
+        DeviceMessage::Doorbell => {
+            let rung = insert_into(doorbell_ring::table)
+                .values((
+                    doorbell_ring::door_id.eq(device.door_id),
+                    doorbell_ring::device_id.eq(device.id),
+                    doorbell_ring::rung_at.eq(Utc::now().naive_utc()),
+                ))
+                .execute(conn);
+
+            match rung {
+                Ok(_) => events.publish(DoorEvent::new(EventKind::Doorbell, device.door_id, None)),
+                Err(e) => tracing::error!("Could not record doorbell ring: {e}"),
+            }
+        }
+        DeviceMessage::DoorContact { open } => {
+            door_state::door_contact(conn, events, device.door_id, open)
+        }
+        DeviceMessage::LockSensor { locked } => {
+            door_state::lock_sensor(conn, events, device.door_id, locked)
+        }
+        DeviceMessage::Heartbeat(heartbeat) => {
+            telemetry::record_heartbeat(conn, events, device, heartbeat)
+        }
+        DeviceMessage::AllowlistAck { version } => allowlist::acknowledge(conn, device, version),
+        DeviceMessage::FirmwareStatus(report) => firmware::record_report(conn, device, report),
+        DeviceMessage::ConfigApplied { version } => device_config::applied(conn, device, version),
+        DeviceMessage::Log { level, message } => hub
+            .logs()
+            .append(device.id, LogContent::Log { level, message }),
+        DeviceMessage::DiagnosticResult(result) => {
+            hub.logs().append(device.id, LogContent::Result(result))
+        }
+        DeviceMessage::KeypadCode { code } => {
+            // Codes wait out a lockdown, they are not used up either
+            let redeemed = !emergency::in_lockdown(conn)
+                && match totp::redeem(conn, device.door_id, &code, Utc::now().naive_utc()) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        tracing::info!("Keypad code for door {} refused: {e:?}", device.door_id);
+                        false
+                    }
+                };
+
+            // The keypad's controller is wired to the door's lock itself, the
+            // open is in the history once a controller confirmed it
+            if redeemed {
+                hub.open_door(conn, device.door_id, None, OpenMethod::Totp);
+            } else {
+                events.publish(DoorEvent::new(EventKind::Denied, device.door_id, None));
+            }
+        }
+        DeviceMessage::OfflineOpens { opens } => {
+            allowlist::merge_offline_opens(conn, events, device, opens);
+            // Codes redeemed offline are gone from the allowlist now
+            allowlist::publish(conn, hub, device.door_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,264 @@
+use async_session::chrono::{Duration, Utc};
+use diesel::{insert_into, prelude::*, update};
+use protocol::{DeviceCommand, DeviceMessage};
+
+use super::{expire_commands, handle_message, DeviceHub};
+use crate::{
+    access::OpenMethod,
+    events::EventKind,
+    models::AccessHistory,
+    schema::{access_history, device_command, door_totp},
+    testing, totp,
+};
+
+fn history(conn: &mut PgConnection, door_id: i32) -> Vec<AccessHistory> {
+    access_history::table
+        .filter(access_history::door_id.eq(door_id))
+        .select(AccessHistory::as_select())
+        .load(conn)
+        .unwrap()
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn opens_count_once_a_controller_confirms() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let front = testing::device(conn, door_id, "front");
+    let back = testing::device(conn, door_id, "back");
+    let events = testing::events();
+    let hub = DeviceHub::for_tests();
+    let mut receiver = hub.connect(&front);
+
+    assert_eq!(
+        hub.open_door(conn, door_id, Some(user_id), OpenMethod::Permission),
+        2
+    );
+    let frame = receiver.try_recv().unwrap();
+    assert!(matches!(frame.command, DeviceCommand::Open));
+    assert!(history(conn, door_id).is_empty());
+
+    let id = frame.id.unwrap();
+    handle_message(conn, &events, &hub, &front, DeviceMessage::Ack { id });
+
+    let opens = history(conn, door_id);
+    assert_eq!(opens.len(), 1);
+    assert_eq!(opens[0].user_profile_id, Some(user_id));
+    assert_eq!(opens[0].method, "permission");
+    assert!(opens[0].opened);
+
+    // The other controller catches up later, the door was opened only once
+    let mut receiver = hub.connect(&back);
+    hub.deliver_pending(conn, &events, back.id);
+    let id = receiver.try_recv().unwrap().id.unwrap();
+    handle_message(conn, &events, &hub, &back, DeviceMessage::Ack { id });
+
+    assert_eq!(history(conn, door_id).len(), 1);
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn only_the_addressed_controller_can_confirm() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let controller = testing::device(conn, door_id, "front");
+    let other_door = testing::door(conn, user_id);
+    let stranger = testing::device(conn, other_door, "stranger");
+    let events = testing::events();
+    let hub = DeviceHub::for_tests();
+    let mut receiver = hub.connect(&controller);
+
+    hub.open_door(conn, door_id, None, OpenMethod::Code);
+    let id = receiver.try_recv().unwrap().id.unwrap();
+    handle_message(conn, &events, &hub, &stranger, DeviceMessage::Ack { id });
+
+    assert!(history(conn, door_id).is_empty());
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn doors_without_controllers_are_not_opened() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let hub = DeviceHub::for_tests();
+
+    assert_eq!(
+        hub.open_door(conn, door_id, Some(user_id), OpenMethod::Permission),
+        0
+    );
+    assert!(history(conn, door_id).is_empty());
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn unconfirmed_opens_are_recorded_as_failed() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let front = testing::device(conn, door_id, "front");
+    testing::device(conn, door_id, "back");
+    let events = testing::events();
+    let mut published = events.subscribe();
+    let hub = DeviceHub::for_tests();
+    // Delivered but never confirmed, the other one never got it at all
+    let _receiver = hub.connect(&front);
+
+    hub.open_door(conn, door_id, None, OpenMethod::Guest);
+    update(device_command::table.filter(device_command::door_id.eq(door_id)))
+        .set(device_command::expires_at.eq(Utc::now().naive_utc() - Duration::seconds(1)))
+        .execute(conn)
+        .unwrap();
+    expire_commands(conn, &events);
+
+    let opens = history(conn, door_id);
+    assert_eq!(opens.len(), 1);
+    assert_eq!(opens[0].method, "guest");
+    assert!(!opens[0].opened);
+
+    let expired = testing::published(&events, &mut published)
+        .into_iter()
+        .filter(|event| event.door_id == door_id)
+        .map(|event| event.kind)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        expired,
+        [EventKind::CommandExpired, EventKind::CommandExpired]
+    );
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn confirmed_opens_do_not_fail_when_a_sibling_expires() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let front = testing::device(conn, door_id, "front");
+    testing::device(conn, door_id, "back");
+    let events = testing::events();
+    let hub = DeviceHub::for_tests();
+    let mut receiver = hub.connect(&front);
+
+    hub.open_door(conn, door_id, Some(user_id), OpenMethod::Permission);
+    let id = receiver.try_recv().unwrap().id.unwrap();
+    handle_message(conn, &events, &hub, &front, DeviceMessage::Ack { id });
+    update(device_command::table.filter(device_command::door_id.eq(door_id)))
+        .set(device_command::expires_at.eq(Utc::now().naive_utc() - Duration::seconds(1)))
+        .execute(conn)
+        .unwrap();
+    expire_commands(conn, &events);
+
+    let opens = history(conn, door_id);
+    assert_eq!(opens.len(), 1);
+    assert!(opens[0].opened);
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn keypad_codes_open_once_and_are_recorded() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let keypad = testing::device(conn, door_id, "keypad");
+    let secret = totp::generate_secret();
+    insert_into(door_totp::table)
+        .values((
+            door_totp::door_id.eq(door_id),
+            door_totp::secret.eq(&secret),
+            door_totp::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)
+        .unwrap();
+    let events = testing::events();
+    let mut published = events.subscribe();
+    let hub = DeviceHub::for_tests();
+    let mut receiver = hub.connect(&keypad);
+
+    let (code, _) = totp::current(&secret).unwrap();
+    let typed = || DeviceMessage::KeypadCode { code: code.clone() };
+    handle_message(conn, &events, &hub, &keypad, typed());
+    handle_message(conn, &events, &hub, &keypad, typed());
+
+    let id = receiver.try_recv().unwrap().id.unwrap();
+    assert!(receiver.try_recv().is_err());
+    handle_message(conn, &events, &hub, &keypad, DeviceMessage::Ack { id });
+
+    let opens = history(conn, door_id);
+    assert_eq!(opens.len(), 1);
+    assert_eq!(opens[0].method, "totp");
+    let kinds = testing::published(&events, &mut published)
+        .into_iter()
+        .filter(|event| event.door_id == door_id)
+        .map(|event| event.kind)
+        .collect::<Vec<_>>();
+    assert_eq!(kinds, [EventKind::Denied, EventKind::Opened]);
+}
+
+#[tokio::test]
+#[ignore = "needs TEST_DATABASE_URL"]
+async fn closing_a_device_ends_its_channel() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let device = testing::device(conn, door_id, "front");
+    let hub = DeviceHub::for_tests();
+
+    let mut commands = hub.connect(&device);
+    assert!(hub.push(device.id, DeviceCommand::Open));
+    assert!(commands.recv().await.is_some());
+
+    hub.close(device.id);
+    assert!(commands.recv().await.is_none());
+    assert!(!hub.push(device.id, DeviceCommand::Open));
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn opens_queued_at_the_same_moment_are_recorded_apart() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let front = testing::device(conn, door_id, "front");
+    let back = testing::device(conn, door_id, "back");
+    let events = testing::events();
+    let hub = DeviceHub::for_tests();
+    let mut front_receiver = hub.connect(&front);
+    let mut back_receiver = hub.connect(&back);
+
+    hub.open_door(conn, door_id, Some(user_id), OpenMethod::Permission);
+    hub.open_door(conn, door_id, None, OpenMethod::Code);
+    update(device_command::table.filter(device_command::door_id.eq(door_id)))
+        .set(device_command::created_at.eq(Utc::now().naive_utc()))
+        .execute(conn)
+        .unwrap();
+
+    // Each controller confirms a different one of the two opens
+    front_receiver.try_recv().unwrap();
+    let second = front_receiver.try_recv().unwrap().id.unwrap();
+    let first = back_receiver.try_recv().unwrap().id.unwrap();
+    handle_message(conn, &events, &hub, &back, DeviceMessage::Ack { id: first });
+    handle_message(
+        conn,
+        &events,
+        &hub,
+        &front,
+        DeviceMessage::Ack { id: second },
+    );
+
+    let mut methods = history(conn, door_id)
+        .into_iter()
+        .map(|open| open.method)
+        .collect::<Vec<_>>();
+    methods.sort();
+    assert_eq!(methods, ["code", "permission"]);
+}
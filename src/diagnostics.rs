@@ -0,0 +1,156 @@
+use async_session::chrono::{NaiveDateTime, Utc};
+use dotenv::dotenv;
+use protocol::diagnostics::{DiagnosticResult, LogLevel};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    env,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
+
+const DEFAULT_LOG_BUFFER_LINES: usize = 500;
+const MAX_LOG_LINE_LENGTH: usize = 1024;
+const CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LogContent {
+    Log { level: LogLevel, message: String },
+    Result(DiagnosticResult),
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct LogEntry {
+    // Counts up per device so a reconnecting browser can skip what it has seen
+    pub id: u64,
+    pub device_id: i32,
+    pub timestamp: NaiveDateTime,
+    #[serde(flatten)]
+    pub content: LogContent,
+}
+
+struct DeviceBuffer {
+    next_id: u64,
+    entries: VecDeque<LogEntry>,
+    // One channel per controller so a chatty device can't make watchers of
+    // another one lag
+    sender: broadcast::Sender<LogEntry>,
+}
+
+impl DeviceBuffer {
+    fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+
+        DeviceBuffer {
+            next_id: 0,
+            entries: VecDeque::new(),
+            sender,
+        }
+    }
+}
+
+// The last lines every controller sent, kept in memory only. Browsers get
+// the buffer first and live lines after.
+#[derive(Clone)]
+pub struct DeviceLogs {
+    buffers: Arc<Mutex<HashMap<i32, DeviceBuffer>>>,
+    // Entry ids restart with the server, so event ids carry the boot they
+    // belong to
+    boot: i64,
+    capacity: usize,
+}
+
+impl DeviceLogs {
+    pub fn from_env() -> Self {
+        dotenv().ok();
+
+        let capacity = env::var("DEVICE_LOG_BUFFER_LINES")
+            .ok()
+            .map(|lines| {
+                lines
+                    .parse()
+                    .expect("DEVICE_LOG_BUFFER_LINES must be a number")
+            })
+            .unwrap_or(DEFAULT_LOG_BUFFER_LINES);
+
+        DeviceLogs {
+            buffers: Arc::default(),
+            boot: Utc::now().timestamp_millis(),
+            capacity,
+        }
+    }
+
+    pub fn event_id(&self, entry: &LogEntry) -> String {
+        format!("{}-{}", self.boot, entry.id)
+    }
+
+    // The entry a browser last saw, as long as it was handed out by this boot.
+    // Anything else replays the whole buffer.
+    pub fn resume_after(&self, event_id: &str) -> Option<u64> {
+        let (boot, id) = event_id.split_once('-')?;
+        if boot.parse::<i64>().ok()? != self.boot {
+            return None;
+        }
+
+        id.parse().ok()
+    }
+
+    pub fn append(&self, device_id: i32, mut content: LogContent) {
+        if let LogContent::Log { message, .. } = &mut content {
+            if message.len() > MAX_LOG_LINE_LENGTH {
+                let mut end = MAX_LOG_LINE_LENGTH;
+                while !message.is_char_boundary(end) {
+                    end -= 1;
+                }
+                message.truncate(end);
+            }
+        }
+
+        let mut buffers = self.buffers.lock().unwrap();
+        let buffer = buffers.entry(device_id).or_insert_with(DeviceBuffer::new);
+
+        buffer.next_id += 1;
+        let entry = LogEntry {
+            id: buffer.next_id,
+            device_id,
+            timestamp: Utc::now().naive_utc(),
+            content,
+        };
+
+        if buffer.entries.len() >= self.capacity {
+            buffer.entries.pop_front();
+        }
+        buffer.entries.push_back(entry.clone());
+
+        // Having nobody watching is fine
+        let _ = buffer.sender.send(entry);
+    }
+
+    // Buffered entries after `after`, taken together with a subscription so
+    // nothing slips in between the two
+    pub fn subscribe(
+        &self,
+        device_id: i32,
+        after: Option<u64>,
+    ) -> (Vec<LogEntry>, broadcast::Receiver<LogEntry>) {
+        let mut buffers = self.buffers.lock().unwrap();
+        let buffer = buffers.entry(device_id).or_insert_with(DeviceBuffer::new);
+
+        let backlog = buffer
+            .entries
+            .iter()
+            .filter(|entry| after.is_none_or(|after| entry.id > after))
+            .cloned()
+            .collect();
+
+        (backlog, buffer.sender.subscribe())
+    }
+
+    pub fn forget(&self, device_id: i32) {
+        self.buffers.lock().unwrap().remove(&device_id);
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,60 @@
+use protocol::diagnostics::LogLevel;
+
+use super::{DeviceLogs, LogContent};
+
+fn line(message: &str) -> LogContent {
+    LogContent::Log {
+        level: LogLevel::Info,
+        message: message.to_owned(),
+    }
+}
+
+fn messages(entries: &[super::LogEntry]) -> Vec<String> {
+    entries
+        .iter()
+        .map(|entry| match &entry.content {
+            LogContent::Log { message, .. } => message.clone(),
+            LogContent::Result(_) => String::new(),
+        })
+        .collect()
+}
+
+#[test]
+fn watchers_only_hear_their_device() {
+    let logs = DeviceLogs::from_env();
+    let (_, mut front) = logs.subscribe(1, None);
+
+    logs.append(2, line("back"));
+    logs.append(1, line("front"));
+
+    let entry = front.try_recv().unwrap();
+    assert_eq!(entry.device_id, 1);
+    assert!(front.try_recv().is_err());
+}
+
+#[test]
+fn resumes_after_the_last_seen_line() {
+    let logs = DeviceLogs::from_env();
+    logs.append(1, line("first"));
+    logs.append(1, line("second"));
+
+    let (backlog, _) = logs.subscribe(1, None);
+    let last_seen = logs.event_id(&backlog[0]);
+
+    let after = logs.resume_after(&last_seen);
+    let (backlog, _) = logs.subscribe(1, after);
+    assert_eq!(messages(&backlog), ["second"]);
+}
+
+#[test]
+fn ids_from_another_boot_replay_everything() {
+    let logs = DeviceLogs::from_env();
+    logs.append(1, line("first"));
+
+    let previous_boot = format!("{}-5", logs.boot - 1);
+    assert_eq!(logs.resume_after(&previous_boot), None);
+    assert_eq!(logs.resume_after("5"), None);
+
+    let (backlog, _) = logs.subscribe(1, logs.resume_after(&previous_boot));
+    assert_eq!(messages(&backlog), ["first"]);
+}
@@ -0,0 +1,207 @@
+use async_session::chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{dsl::count_star, insert_into, prelude::*, update};
+
+use crate::{
+    db::establish_connection,
+    events::{DoorEvent, EventBus, EventKind},
+    lock_mode::{self, LockMode},
+    models::DoorState,
+    schema::{access_history, door, door_state},
+};
+
+// A contact opening this long after a granted open still counts as that open
+const GRANT_WINDOW_SECONDS: i64 = 30;
+const HELD_OPEN_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorState {
+    Closed,
+    Open,
+    HeldOpen,
+    ForcedOpen,
+}
+
+impl SensorState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SensorState::Closed => "closed",
+            SensorState::Open => "open",
+            SensorState::HeldOpen => "held_open",
+            SensorState::ForcedOpen => "forced_open",
+        }
+    }
+}
+
+fn current_state(conn: &mut PgConnection, door_id: i32) -> Option<DoorState> {
+    door_state::table
+        .find(door_id)
+        .select(DoorState::as_select())
+        .get_result(conn)
+        .ok()
+}
+
+fn set_state(
+    conn: &mut PgConnection,
+    door_id: i32,
+    state: SensorState,
+    now: NaiveDateTime,
+) -> QueryResult<usize> {
+    insert_into(door_state::table)
+        .values((
+            door_state::door_id.eq(door_id),
+            door_state::state.eq(state.as_str()),
+            door_state::changed_at.eq(now),
+        ))
+        .on_conflict(door_state::door_id)
+        .do_update()
+        .set((
+            door_state::state.eq(state.as_str()),
+            door_state::changed_at.eq(now),
+        ))
+        .execute(conn)
+}
+
+// Reed switch transition reported by the door's controller
+pub fn door_contact(conn: &mut PgConnection, events: &EventBus, door_id: i32, open: bool) {
+    let now = Utc::now().naive_utc();
+    let was_closed = current_state(conn, door_id)
+        .is_none_or(|current| current.state == SensorState::Closed.as_str());
+
+    // Controllers may repeat themselves after a reconnect
+    if open != was_closed {
+        return;
+    }
+
+    if !open {
+        if let Err(e) = set_state(conn, door_id, SensorState::Closed, now) {
+            tracing::error!("Could not update state of door {door_id}: {e}");
+        }
+        events.publish(DoorEvent::new(EventKind::SensorClosed, door_id, None));
+        return;
+    }
+
+    // The history row is written with the open, the event only later on the
+    // log thread, and the contact report may come right after the open
+    let granted = access_history::table
+        .filter(access_history::door_id.eq(door_id))
+        .filter(access_history::opened.eq(true))
+        .filter(access_history::access_timestamp.gt(now - Duration::seconds(GRANT_WINDOW_SECONDS)))
+        .select(count_star())
+        .get_result::<i64>(conn)
+        .is_ok_and(|count| count > 0)
+        || held_unlocked(conn, door_id);
+
+    let state = if granted {
+        SensorState::Open
+    } else {
+        SensorState::ForcedOpen
+    };
+    if let Err(e) = set_state(conn, door_id, state, now) {
+        tracing::error!("Could not update state of door {door_id}: {e}");
+    }
+
+    events.publish(DoorEvent::new(EventKind::SensorOpened, door_id, None));
+    if !granted {
+        events.publish(DoorEvent::new(EventKind::ForcedEntry, door_id, None));
+    }
+}
+
+// Anyone may walk through while the door is held unlocked, by hand, by its
+// schedule or for an egress
+fn held_unlocked(conn: &mut PgConnection, door_id: i32) -> bool {
+    match lock_mode::current(conn, door_id) {
+        Ok(mode) => mode.is_some_and(|mode| mode.mode == LockMode::Unlocked.as_str()),
+        Err(e) => {
+            tracing::error!("Could not load the mode of door {door_id}: {e}");
+            false
+        }
+    }
+}
+
+// Lock sensor (bolt or strike feedback) transition reported by the controller
+pub fn lock_sensor(conn: &mut PgConnection, events: &EventBus, door_id: i32, locked: bool) {
+    let current = current_state(conn, door_id);
+    if current.as_ref().and_then(|current| current.locked) == Some(locked) {
+        return;
+    }
+
+    let updated = insert_into(door_state::table)
+        .values((
+            door_state::door_id.eq(door_id),
+            door_state::state.eq(SensorState::Closed.as_str()),
+            door_state::locked.eq(locked),
+            door_state::changed_at.eq(Utc::now().naive_utc()),
+        ))
+        .on_conflict(door_state::door_id)
+        .do_update()
+        .set(door_state::locked.eq(locked))
+        .execute(conn);
+    if let Err(e) = updated {
+        tracing::error!("Could not update lock state of door {door_id}: {e}");
+    }
+
+    let kind = if locked {
+        EventKind::SensorLocked
+    } else {
+        EventKind::SensorUnlocked
+    };
+    events.publish(DoorEvent::new(kind, door_id, None));
+}
+
+// Raises the held-open alarm for doors open longer than they are allowed to be
+fn check_held_open(conn: &mut PgConnection, events: &EventBus) {
+    let now = Utc::now().naive_utc();
+
+    let open_doors = door_state::table
+        .inner_join(door::table)
+        .filter(door_state::state.eq(SensorState::Open.as_str()))
+        .select((
+            door_state::door_id,
+            door_state::changed_at,
+            door::held_open_seconds,
+        ))
+        .load::<(i32, NaiveDateTime, i32)>(conn);
+
+    let open_doors = match open_doors {
+        Ok(open_doors) => open_doors,
+        Err(e) => {
+            tracing::error!("Could not load open doors: {e}");
+            return;
+        }
+    };
+
+    for (door_id, opened_at, held_open_seconds) in open_doors {
+        if opened_at + Duration::seconds(held_open_seconds.into()) > now {
+            continue;
+        }
+
+        // changed_at keeps the time the door opened
+        let raised = update(
+            door_state::table
+                .find(door_id)
+                .filter(door_state::state.eq(SensorState::Open.as_str())),
+        )
+        .set(door_state::state.eq(SensorState::HeldOpen.as_str()))
+        .execute(conn);
+
+        if raised == Ok(1) {
+            events.publish(DoorEvent::new(EventKind::HeldOpen, door_id, None));
+        }
+    }
+}
+
+pub fn spawn_held_open_monitor(events: &EventBus) {
+    let events = events.clone();
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(HELD_OPEN_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let conn = &mut establish_connection();
+            check_held_open(conn, &events);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests;
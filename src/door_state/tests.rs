@@ -0,0 +1,161 @@
+use async_session::chrono::{Duration, Utc};
+use diesel::{insert_into, prelude::*, update, PgConnection};
+
+use super::{check_held_open, door_contact, lock_sensor, set_state, SensorState};
+use crate::{
+    access::{self, OpenMethod},
+    events::{DoorEvent, EventKind},
+    lock_mode::LockMode,
+    schema::{door, door_mode, door_state},
+    testing,
+};
+
+fn state(conn: &mut PgConnection, door_id: i32) -> String {
+    door_state::table
+        .find(door_id)
+        .select(door_state::state)
+        .get_result(conn)
+        .unwrap()
+}
+
+fn kinds(events: Vec<DoorEvent>) -> Vec<EventKind> {
+    events.into_iter().map(|event| event.kind).collect()
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn doors_opened_after_a_granted_open_are_open() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    // Only the history row, the event is not logged yet
+    let events = testing::events();
+    access::record_open_by(
+        conn,
+        &events,
+        door_id,
+        Some(user_id),
+        OpenMethod::Permission,
+        Utc::now().naive_utc() - Duration::seconds(3),
+    )
+    .unwrap();
+    let mut receiver = events.subscribe();
+
+    door_contact(conn, &events, door_id, true);
+
+    assert_eq!(state(conn, door_id), "open");
+    assert_eq!(
+        kinds(testing::published(&events, &mut receiver)),
+        [EventKind::SensorOpened]
+    );
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn doors_opened_without_a_granted_open_are_forced() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let events = testing::events();
+    let mut receiver = events.subscribe();
+
+    door_contact(conn, &events, door_id, true);
+
+    assert_eq!(state(conn, door_id), "forced_open");
+    assert_eq!(
+        kinds(testing::published(&events, &mut receiver)),
+        [EventKind::SensorOpened, EventKind::ForcedEntry]
+    );
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn doors_held_unlocked_are_not_forced() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    insert_into(door_mode::table)
+        .values((
+            door_mode::door_id.eq(door_id),
+            door_mode::mode.eq(LockMode::Unlocked.as_str()),
+            door_mode::changed_at.eq(Utc::now().naive_utc() - Duration::hours(2)),
+            door_mode::scheduled.eq(true),
+        ))
+        .execute(conn)
+        .unwrap();
+    let events = testing::events();
+    let mut receiver = events.subscribe();
+
+    door_contact(conn, &events, door_id, true);
+
+    assert_eq!(state(conn, door_id), "open");
+    assert_eq!(
+        kinds(testing::published(&events, &mut receiver)),
+        [EventKind::SensorOpened]
+    );
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn repeated_reports_are_ignored() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let events = testing::events();
+    let mut receiver = events.subscribe();
+
+    door_contact(conn, &events, door_id, false);
+    door_contact(conn, &events, door_id, true);
+    door_contact(conn, &events, door_id, true);
+    door_contact(conn, &events, door_id, false);
+    door_contact(conn, &events, door_id, false);
+    lock_sensor(conn, &events, door_id, true);
+    lock_sensor(conn, &events, door_id, true);
+
+    assert_eq!(state(conn, door_id), "closed");
+    assert_eq!(
+        kinds(testing::published(&events, &mut receiver)),
+        [
+            EventKind::SensorOpened,
+            EventKind::ForcedEntry,
+            EventKind::SensorClosed,
+            EventKind::SensorLocked,
+        ]
+    );
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn doors_open_too_long_raise_the_held_open_alarm_once() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let patient_door = testing::door(conn, user_id);
+    update(door::table.find(patient_door))
+        .set(door::held_open_seconds.eq(600))
+        .execute(conn)
+        .unwrap();
+    let opened_at = Utc::now().naive_utc() - Duration::seconds(120);
+    set_state(conn, door_id, SensorState::Open, opened_at).unwrap();
+    set_state(conn, patient_door, SensorState::Open, opened_at).unwrap();
+    let events = testing::events();
+    let mut receiver = events.subscribe();
+
+    check_held_open(conn, &events);
+    check_held_open(conn, &events);
+
+    assert_eq!(state(conn, door_id), "held_open");
+    assert_eq!(state(conn, patient_door), "open");
+    // Doors other tests left behind may be raised as well
+    let raised: Vec<_> = testing::published(&events, &mut receiver)
+        .into_iter()
+        .filter(|event| [door_id, patient_door].contains(&event.door_id))
+        .map(|event| (event.kind, event.door_id))
+        .collect();
+    assert_eq!(raised, [(EventKind::HeldOpen, door_id)]);
+}
@@ -0,0 +1,343 @@
+use async_session::chrono::{NaiveDateTime, Utc};
+use diesel::{insert_into, prelude::*, update};
+use protocol::{DeviceCommand, EmergencyMode};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+};
+
+use crate::{
+    allowlist,
+    devices::DeviceHub,
+    events::EventBus,
+    guest_tokens,
+    lock_mode::{self, LockMode},
+    locks::{self, LockDrivers},
+    models::{Device, Emergency},
+    schema::{device, door, door_mode, emergency, responder, unlock_schedule},
+};
+
+// One switch for the whole site. A lockdown keeps everyone but the responders
+// out and suspends guest tokens and codes, an egress unlocks every door. Both
+// last until an admin clears them.
+
+// Controllers before this only learn about an emergency through their
+// allowlist and their door's mode
+const EMERGENCY_PROTOCOL_VERSION: i32 = 4;
+
+pub fn as_str(mode: EmergencyMode) -> &'static str {
+    match mode {
+        EmergencyMode::Lockdown => "lockdown",
+        EmergencyMode::Egress => "egress",
+    }
+}
+
+fn mode_of(emergency: &Emergency) -> Option<EmergencyMode> {
+    match emergency.mode.as_str() {
+        "lockdown" => Some(EmergencyMode::Lockdown),
+        "egress" => Some(EmergencyMode::Egress),
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+pub enum EmergencyError {
+    AlreadyActive(Emergency),
+    NotActive,
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for EmergencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmergencyError::AlreadyActive(emergency) => {
+                write!(f, "the site is already in {}", emergency.mode)
+            }
+            EmergencyError::NotActive => write!(f, "there is no emergency to clear"),
+            EmergencyError::Database(e) => write!(f, "could not record the emergency: {e}"),
+        }
+    }
+}
+
+// Doors whose lock did not follow, they need someone on site
+#[derive(Serialize, Debug)]
+pub struct DoorFailure {
+    pub door_id: i32,
+    pub error: String,
+}
+
+pub fn active(conn: &mut PgConnection) -> QueryResult<Option<Emergency>> {
+    emergency::table
+        .filter(emergency::cleared_at.is_null())
+        .select(Emergency::as_select())
+        .get_result(conn)
+        .optional()
+}
+
+pub fn active_mode(conn: &mut PgConnection) -> Option<EmergencyMode> {
+    match active(conn) {
+        Ok(emergency) => emergency.as_ref().and_then(mode_of),
+        Err(e) => {
+            tracing::error!("Could not load the emergency state: {e}");
+            None
+        }
+    }
+}
+
+// Every door response carries this, null outside an emergency
+pub fn flag(conn: &mut PgConnection) -> Option<&'static str> {
+    active_mode(conn).map(as_str)
+}
+
+pub fn in_lockdown(conn: &mut PgConnection) -> bool {
+    active_mode(conn) == Some(EmergencyMode::Lockdown)
+}
+
+pub fn is_responder(conn: &mut PgConnection, user_id: i32) -> bool {
+    responder::table
+        .find(user_id)
+        .select(responder::user_profile_id)
+        .get_result::<i32>(conn)
+        .is_ok()
+}
+
+// Switching from one mode to the other clears the first in the same go
+pub async fn trigger(
+    conn: &mut PgConnection,
+    locks: &LockDrivers,
+    events: &EventBus,
+    hub: &DeviceHub,
+    mode: EmergencyMode,
+    reason: Option<String>,
+    user_id: i32,
+) -> Result<(Emergency, Vec<DoorFailure>), EmergencyError> {
+    let now = Utc::now().naive_utc();
+
+    let started = conn
+        .transaction(|conn| {
+            if let Some(current) = active(conn)? {
+                if mode_of(&current) == Some(mode) {
+                    return Ok(Err(current));
+                }
+                update(emergency::table.find(current.id))
+                    .set((
+                        emergency::cleared_by.eq(user_id),
+                        emergency::cleared_at.eq(now),
+                    ))
+                    .execute(conn)?;
+            }
+
+            insert_into(emergency::table)
+                .values((
+                    emergency::mode.eq(as_str(mode)),
+                    emergency::reason.eq(reason),
+                    emergency::triggered_by.eq(user_id),
+                    emergency::triggered_at.eq(now),
+                ))
+                .returning(Emergency::as_returning())
+                .get_result(conn)
+                .map(Ok)
+        })
+        .map_err(EmergencyError::Database)?
+        .map_err(EmergencyError::AlreadyActive)?;
+
+    tracing::warn!("User {user_id} started a site-wide {}", as_str(mode));
+    broadcast(conn, hub, Some(mode));
+
+    // Access checks already follow the new mode, the locks take longer
+    let failures = match mode {
+        EmergencyMode::Lockdown => lock_unlocked_doors(conn, locks, events, user_id).await,
+        EmergencyMode::Egress => unlock_every_door(conn, locks, events, user_id).await,
+    };
+    Ok((started, failures))
+}
+
+// Doors go back to locked and their unlock schedules pick up from there
+pub async fn clear(
+    conn: &mut PgConnection,
+    locks: &LockDrivers,
+    events: &EventBus,
+    hub: &DeviceHub,
+    user_id: i32,
+) -> Result<(Emergency, Vec<DoorFailure>), EmergencyError> {
+    let cleared = update(emergency::table.filter(emergency::cleared_at.is_null()))
+        .set((
+            emergency::cleared_by.eq(user_id),
+            emergency::cleared_at.eq(Utc::now().naive_utc()),
+        ))
+        .returning(Emergency::as_returning())
+        .get_result(conn)
+        .optional()
+        .map_err(EmergencyError::Database)?
+        .ok_or(EmergencyError::NotActive)?;
+
+    tracing::warn!("User {user_id} cleared the site-wide {}", cleared.mode);
+    broadcast(conn, hub, None);
+
+    let failures = match mode_of(&cleared) {
+        Some(EmergencyMode::Egress) => lock_unlocked_doors(conn, locks, events, user_id).await,
+        _ => Vec::new(),
+    };
+
+    // Windows that are open right now get unlocked again on the next check.
+    // Doors nobody touched during the emergency keep what they had.
+    let changed = door_mode::table
+        .filter(door_mode::changed_at.ge(cleared.triggered_at))
+        .select(door_mode::door_id);
+    let reset = update(unlock_schedule::table.filter(unlock_schedule::door_id.eq_any(changed)))
+        .set(unlock_schedule::applied_window_start.eq(None::<NaiveDateTime>))
+        .execute(conn);
+    if let Err(e) = reset {
+        tracing::error!("Could not reset unlock schedules: {e}");
+    }
+
+    Ok((cleared, failures))
+}
+
+async fn unlock_every_door(
+    conn: &mut PgConnection,
+    locks: &LockDrivers,
+    events: &EventBus,
+    user_id: i32,
+) -> Vec<DoorFailure> {
+    let door_ids = door::table
+        .select(door::id)
+        .order(door::id.asc())
+        .load::<i32>(conn)
+        .unwrap_or_else(|e| {
+            tracing::error!("Could not load doors: {e}");
+            Vec::new()
+        });
+
+    let mut failures = drive(conn, locks, events, door_ids, LockMode::Unlocked, user_id).await;
+    let failed = failures.iter().map(|failure| failure.door_id).collect();
+    failures.extend(partly_unlocked(conn, &failed));
+    failures
+}
+
+// Older controllers only open for a moment, their side of the door stays
+// locked even though the door counts as unlocked
+fn partly_unlocked(conn: &mut PgConnection, failed: &HashSet<i32>) -> Vec<DoorFailure> {
+    let devices = device::table
+        .filter(device::protocol_version.lt(lock_mode::MODE_PROTOCOL_VERSION))
+        .select((device::door_id, device::id))
+        .order((device::door_id.asc(), device::id.asc()))
+        .load::<(i32, i32)>(conn)
+        .unwrap_or_else(|e| {
+            tracing::error!("Could not load controllers: {e}");
+            Vec::new()
+        });
+
+    let mut by_door = BTreeMap::<i32, Vec<String>>::new();
+    for (door_id, device_id) in devices {
+        if !failed.contains(&door_id) {
+            by_door
+                .entry(door_id)
+                .or_default()
+                .push(device_id.to_string());
+        }
+    }
+
+    by_door
+        .into_iter()
+        .filter(|(door_id, _)| locks::driven_by_controllers(conn, *door_id))
+        .map(|(door_id, device_ids)| DoorFailure {
+            door_id,
+            error: format!(
+                "controllers {} can not hold the door unlocked",
+                device_ids.join(", ")
+            ),
+        })
+        .collect()
+}
+
+async fn lock_unlocked_doors(
+    conn: &mut PgConnection,
+    locks: &LockDrivers,
+    events: &EventBus,
+    user_id: i32,
+) -> Vec<DoorFailure> {
+    let door_ids = door_mode::table
+        .filter(door_mode::mode.eq(LockMode::Unlocked.as_str()))
+        .select(door_mode::door_id)
+        .order(door_mode::door_id.asc())
+        .load::<i32>(conn)
+        .unwrap_or_else(|e| {
+            tracing::error!("Could not load unlocked doors: {e}");
+            Vec::new()
+        });
+
+    drive(conn, locks, events, door_ids, LockMode::Locked, user_id).await
+}
+
+// Unlocks have no end, they last until the emergency is cleared
+async fn drive(
+    conn: &mut PgConnection,
+    locks: &LockDrivers,
+    events: &EventBus,
+    door_ids: Vec<i32>,
+    mode: LockMode,
+    user_id: i32,
+) -> Vec<DoorFailure> {
+    let mut failures = Vec::new();
+    for door_id in door_ids {
+        let changed = lock_mode::set(conn, locks, events, door_id, mode, None, Some(user_id)).await;
+        if let Err(e) = changed {
+            tracing::error!("Could not {} door {door_id}: {e}", mode.as_str());
+            failures.push(DoorFailure {
+                door_id,
+                error: e.to_string(),
+            });
+        }
+    }
+    failures
+}
+
+// Allowlists and revocations change with a lockdown, so older controllers
+// keep the guests out as well
+fn broadcast(conn: &mut PgConnection, hub: &DeviceHub, mode: Option<EmergencyMode>) {
+    let door_ids = door::table
+        .select(door::id)
+        .load::<i32>(conn)
+        .unwrap_or_default();
+    for door_id in door_ids {
+        allowlist::publish(conn, hub, door_id);
+        guest_tokens::publish_revocations(conn, hub, door_id);
+    }
+
+    let device_ids = device::table
+        .filter(device::protocol_version.ge(EMERGENCY_PROTOCOL_VERSION))
+        .select(device::id)
+        .load::<i32>(conn)
+        .unwrap_or_default();
+    for device_id in device_ids {
+        hub.push(device_id, DeviceCommand::Emergency { mode });
+    }
+}
+
+// Responders are the only ones on the allowlists during a lockdown
+pub fn responders_changed(conn: &mut PgConnection, hub: &DeviceHub) {
+    if !in_lockdown(conn) {
+        return;
+    }
+    let door_ids = door::table
+        .select(door::id)
+        .load::<i32>(conn)
+        .unwrap_or_default();
+    for door_id in door_ids {
+        allowlist::publish(conn, hub, door_id);
+    }
+}
+
+// Controllers that were offline may have missed the start or the end
+pub fn sync_device(conn: &mut PgConnection, hub: &DeviceHub, device: &Device) {
+    if device.protocol_version < EMERGENCY_PROTOCOL_VERSION {
+        return;
+    }
+    let mode = active_mode(conn);
+    hub.push(device.id, DeviceCommand::Emergency { mode });
+}
+
+#[cfg(test)]
+mod tests;
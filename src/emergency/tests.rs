@@ -0,0 +1,138 @@
+use async_session::chrono::{Duration, NaiveDateTime, NaiveTime, Utc};
+use diesel::{insert_into, prelude::*, update};
+use protocol::EmergencyMode;
+use serde_json::json;
+use std::collections::HashSet;
+
+use super::{clear, partly_unlocked};
+use crate::{
+    devices::DeviceHub,
+    lock_mode::{LockMode, MODE_PROTOCOL_VERSION},
+    locks::LockDrivers,
+    schema::{device, door_lock, door_mode, emergency, unlock_schedule},
+    testing,
+};
+
+fn mock_lock(conn: &mut PgConnection, door_id: i32) {
+    insert_into(door_lock::table)
+        .values((
+            door_lock::door_id.eq(door_id),
+            door_lock::driver.eq("mock"),
+            door_lock::config.eq(json!({})),
+            door_lock::updated_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)
+        .unwrap();
+}
+
+fn protocol_version(conn: &mut PgConnection, device_id: i32, version: i32) {
+    update(device::table.find(device_id))
+        .set(device::protocol_version.eq(version))
+        .execute(conn)
+        .unwrap();
+}
+
+fn locked_at(conn: &mut PgConnection, door_id: i32, changed_at: NaiveDateTime) {
+    insert_into(door_mode::table)
+        .values((
+            door_mode::door_id.eq(door_id),
+            door_mode::mode.eq(LockMode::Locked.as_str()),
+            door_mode::changed_at.eq(changed_at),
+            door_mode::scheduled.eq(false),
+        ))
+        .execute(conn)
+        .unwrap();
+}
+
+fn applied_schedule(conn: &mut PgConnection, door_id: i32, user_id: i32) -> i32 {
+    let now = Utc::now().naive_utc();
+    insert_into(unlock_schedule::table)
+        .values((
+            unlock_schedule::door_id.eq(door_id),
+            unlock_schedule::days.eq(127),
+            unlock_schedule::start_time.eq(NaiveTime::from_hms_opt(0, 0, 0).unwrap()),
+            unlock_schedule::end_time.eq(NaiveTime::from_hms_opt(23, 59, 0).unwrap()),
+            unlock_schedule::created_by.eq(user_id),
+            unlock_schedule::created_at.eq(now),
+            unlock_schedule::applied_window_start.eq(now - Duration::hours(3)),
+        ))
+        .returning(unlock_schedule::id)
+        .get_result(conn)
+        .unwrap()
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn doors_with_older_controllers_count_as_failed() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let mixed = testing::door(conn, user_id);
+    let capable = testing::door(conn, user_id);
+    let relay = testing::door(conn, user_id);
+    let failed = testing::door(conn, user_id);
+
+    let old = testing::device(conn, mixed, "old-token");
+    protocol_version(conn, old.id, MODE_PROTOCOL_VERSION - 1);
+    let new = testing::device(conn, mixed, "new-token");
+    protocol_version(conn, new.id, MODE_PROTOCOL_VERSION);
+    let new = testing::device(conn, capable, "capable-token");
+    protocol_version(conn, new.id, MODE_PROTOCOL_VERSION);
+    // The relay drives the lock, its controller only reads badges
+    mock_lock(conn, relay);
+    let reader = testing::device(conn, relay, "reader-token");
+    protocol_version(conn, reader.id, MODE_PROTOCOL_VERSION - 1);
+    // Already reported by the unlock itself
+    let gone = testing::device(conn, failed, "gone-token");
+    protocol_version(conn, gone.id, MODE_PROTOCOL_VERSION - 1);
+
+    let failures = partly_unlocked(conn, &HashSet::from([failed]));
+
+    let doors = failures
+        .iter()
+        .filter(|failure| [mixed, capable, relay, failed].contains(&failure.door_id))
+        .collect::<Vec<_>>();
+    assert_eq!(doors.len(), 1);
+    assert_eq!(doors[0].door_id, mixed);
+    assert!(doors[0].error.contains(&old.id.to_string()));
+}
+
+#[tokio::test]
+#[ignore = "needs TEST_DATABASE_URL"]
+async fn clearing_resets_only_the_schedules_of_changed_doors() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let changed = testing::door(conn, user_id);
+    let untouched = testing::door(conn, user_id);
+    let now = Utc::now().naive_utc();
+    locked_at(conn, changed, now - Duration::minutes(30));
+    locked_at(conn, untouched, now - Duration::hours(2));
+    let changed_schedule = applied_schedule(conn, changed, user_id);
+    let untouched_schedule = applied_schedule(conn, untouched, user_id);
+
+    insert_into(emergency::table)
+        .values((
+            emergency::mode.eq(super::as_str(EmergencyMode::Lockdown)),
+            emergency::triggered_by.eq(user_id),
+            emergency::triggered_at.eq(now - Duration::hours(1)),
+        ))
+        .execute(conn)
+        .unwrap();
+
+    let hub = DeviceHub::for_tests();
+    let locks = LockDrivers::new(&hub);
+    let events = testing::events();
+    let (_, failures) = clear(conn, &locks, &events, &hub, user_id).await.unwrap();
+    assert!(failures.is_empty());
+
+    let applied = |conn: &mut PgConnection, schedule_id: i32| {
+        unlock_schedule::table
+            .find(schedule_id)
+            .select(unlock_schedule::applied_window_start)
+            .get_result::<Option<NaiveDateTime>>(conn)
+            .unwrap()
+    };
+    assert_eq!(applied(conn, changed_schedule), None);
+    assert!(applied(conn, untouched_schedule).is_some());
+}
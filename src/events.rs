@@ -0,0 +1,295 @@
+use async_session::chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{delete, insert_into, prelude::*};
+use dotenv::dotenv;
+use serde::{Deserialize, Serialize};
+use std::{env, sync::mpsc, thread};
+use tokio::sync::broadcast;
+
+use crate::{db::establish_connection, schema::door_event};
+
+// How many events a slow subscriber may fall behind before it starts missing them
+const CHANNEL_CAPACITY: usize = 256;
+const DEFAULT_RETENTION_DAYS: i64 = 365;
+const RETENTION_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Opened,
+    Denied,
+    FlaggedOpen,
+    CodeRedeemed,
+    PermissionGranted,
+    PermissionRevoked,
+    DeviceOnline,
+    DeviceOffline,
+    CommandExpired,
+    Doorbell,
+    DoorbellAnswered,
+    SensorOpened,
+    SensorClosed,
+    SensorLocked,
+    SensorUnlocked,
+    HeldOpen,
+    ForcedEntry,
+    Unlocked,
+    Locked,
+    ScheduledUnlock,
+    ScheduledLock,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 21] = [
+        EventKind::Opened,
+        EventKind::Denied,
+        EventKind::FlaggedOpen,
+        EventKind::CodeRedeemed,
+        EventKind::PermissionGranted,
+        EventKind::PermissionRevoked,
+        EventKind::DeviceOnline,
+        EventKind::DeviceOffline,
+        EventKind::CommandExpired,
+        EventKind::Doorbell,
+        EventKind::DoorbellAnswered,
+        EventKind::SensorOpened,
+        EventKind::SensorClosed,
+        EventKind::SensorLocked,
+        EventKind::SensorUnlocked,
+        EventKind::HeldOpen,
+        EventKind::ForcedEntry,
+        EventKind::Unlocked,
+        EventKind::Locked,
+        EventKind::ScheduledUnlock,
+        EventKind::ScheduledLock,
+    ];
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|k| k.as_str() == kind)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Opened => "opened",
+            EventKind::Denied => "denied",
+            EventKind::FlaggedOpen => "flagged_open",
+            EventKind::CodeRedeemed => "code_redeemed",
+            EventKind::PermissionGranted => "permission_granted",
+            EventKind::PermissionRevoked => "permission_revoked",
+            EventKind::DeviceOnline => "device_online",
+            EventKind::DeviceOffline => "device_offline",
+            EventKind::CommandExpired => "command_expired",
+            EventKind::Doorbell => "doorbell",
+            EventKind::DoorbellAnswered => "doorbell_answered",
+            EventKind::SensorOpened => "sensor_opened",
+            EventKind::SensorClosed => "sensor_closed",
+            EventKind::SensorLocked => "sensor_locked",
+            EventKind::SensorUnlocked => "sensor_unlocked",
+            EventKind::HeldOpen => "held_open",
+            EventKind::ForcedEntry => "forced_entry",
+            EventKind::Unlocked => "unlocked",
+            EventKind::Locked => "locked",
+            EventKind::ScheduledUnlock => "scheduled_unlock",
+            EventKind::ScheduledLock => "scheduled_lock",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DoorEvent {
+    // Position in the persisted event log, assigned when the event is published
+    pub id: i64,
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    pub door_id: i32,
+    pub user_profile_id: Option<i32>,
+    pub timestamp: NaiveDateTime,
+}
+
+impl DoorEvent {
+    pub fn new(kind: EventKind, door_id: i32, user_profile_id: Option<i32>) -> Self {
+        DoorEvent {
+            id: 0,
+            kind,
+            door_id,
+            user_profile_id,
+            timestamp: Utc::now().naive_utc(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<DoorEvent>,
+    log: mpsc::Sender<DoorEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::with_log(log_to_database())
+    }
+
+    // Events are written to the log on a thread of their own, so publishing
+    // never waits on the database. They go out in the order they came in.
+    pub(crate) fn with_log(
+        mut append: impl FnMut(&DoorEvent) -> QueryResult<i64> + Send + 'static,
+    ) -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        let (log, queue) = mpsc::channel::<DoorEvent>();
+
+        let subscribers = sender.clone();
+        thread::Builder::new()
+            .name("event-log".to_string())
+            .spawn(move || {
+                for mut event in queue {
+                    match append(&event) {
+                        Ok(id) => event.id = id,
+                        Err(e) => {
+                            tracing::error!("Could not persist {} event: {e}", event.kind.as_str())
+                        }
+                    }
+
+                    // Having nobody listening is fine
+                    let _ = subscribers.send(event);
+                }
+            })
+            .expect("could not start the event log");
+
+        EventBus { sender, log }
+    }
+
+    // Subscribers get the event once it is in the log and has its ID
+    pub fn publish(&self, event: DoorEvent) {
+        if self.log.send(event).is_err() {
+            tracing::error!("The event log has stopped");
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<DoorEvent> {
+        self.sender.subscribe()
+    }
+}
+
+// Keeps one connection, and opens a new one after a failed insert
+fn log_to_database() -> impl FnMut(&DoorEvent) -> QueryResult<i64> {
+    let mut conn: Option<PgConnection> = None;
+
+    move |event| {
+        let id = insert_into(door_event::table)
+            .values((
+                door_event::kind.eq(event.kind.as_str()),
+                door_event::door_id.eq(event.door_id),
+                door_event::user_profile_id.eq(event.user_profile_id),
+                door_event::created_at.eq(event.timestamp),
+            ))
+            .returning(door_event::id)
+            .get_result(conn.get_or_insert_with(establish_connection));
+
+        if id.is_err() {
+            conn = None;
+        }
+        id
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+type EventRow = (i64, String, i32, Option<i32>, NaiveDateTime);
+
+fn from_rows(rows: Vec<EventRow>) -> Vec<DoorEvent> {
+    rows.into_iter()
+        .filter_map(|(id, kind, door_id, user_profile_id, timestamp)| {
+            Some(DoorEvent {
+                id,
+                kind: EventKind::parse(&kind)?,
+                door_id,
+                user_profile_id,
+                timestamp,
+            })
+        })
+        .collect()
+}
+
+// Logged events for the given doors that came after `last_id`, oldest first
+pub fn since(
+    conn: &mut PgConnection,
+    last_id: i64,
+    door_ids: &[i32],
+) -> QueryResult<Vec<DoorEvent>> {
+    let rows = door_event::table
+        .filter(door_event::id.gt(last_id))
+        .filter(door_event::door_id.eq_any(door_ids))
+        .order(door_event::id.asc())
+        .select((
+            door_event::id,
+            door_event::kind,
+            door_event::door_id,
+            door_event::user_profile_id,
+            door_event::created_at,
+        ))
+        .load::<EventRow>(conn)?;
+
+    Ok(from_rows(rows))
+}
+
+// Newest first, paging backwards from `before` when given
+pub fn history(
+    conn: &mut PgConnection,
+    door_id: i32,
+    before: Option<i64>,
+    limit: i64,
+) -> QueryResult<Vec<DoorEvent>> {
+    let rows = door_event::table
+        .filter(door_event::door_id.eq(door_id))
+        .filter(door_event::id.lt(before.unwrap_or(i64::MAX)))
+        .order(door_event::id.desc())
+        .limit(limit)
+        .select((
+            door_event::id,
+            door_event::kind,
+            door_event::door_id,
+            door_event::user_profile_id,
+            door_event::created_at,
+        ))
+        .load::<EventRow>(conn)?;
+
+    Ok(from_rows(rows))
+}
+
+// Old events are dropped from the log, streams can only resume from what is left
+pub fn spawn_retention() {
+    dotenv().ok();
+
+    let retention_days = env::var("EVENT_RETENTION_DAYS")
+        .ok()
+        .map(|days| days.parse().expect("EVENT_RETENTION_DAYS must be a number"))
+        .unwrap_or(DEFAULT_RETENTION_DAYS);
+    let retention = Duration::days(retention_days);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RETENTION_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let cutoff = Utc::now().naive_utc() - retention;
+            let deleted = tokio::task::spawn_blocking(move || {
+                let conn = &mut establish_connection();
+                delete(door_event::table.filter(door_event::created_at.lt(cutoff))).execute(conn)
+            })
+            .await;
+
+            match deleted {
+                Ok(Ok(0)) => {}
+                Ok(Ok(n)) => tracing::info!("Dropped {n} events past the retention limit"),
+                Ok(Err(e)) => tracing::error!("Could not drop old events: {e}"),
+                Err(e) => tracing::error!("Event retention stopped: {e}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests;
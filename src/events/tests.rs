@@ -0,0 +1,72 @@
+use async_session::chrono::NaiveDateTime;
+use diesel::result::Error;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use super::{from_rows, DoorEvent, EventBus, EventKind};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[tokio::test]
+async fn events_get_their_log_id_before_they_go_out() {
+    let logged = Arc::new(Mutex::new(Vec::new()));
+    let bus = EventBus::with_log({
+        let logged = logged.clone();
+        move |event| {
+            let mut logged = logged.lock().unwrap();
+            logged.push(event.kind);
+            Ok(logged.len() as i64)
+        }
+    });
+    let mut receiver = bus.subscribe();
+
+    bus.publish(DoorEvent::new(EventKind::Opened, 1, Some(1)));
+    bus.publish(DoorEvent::new(EventKind::Denied, 1, None));
+
+    let first = tokio::time::timeout(TIMEOUT, receiver.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    let second = tokio::time::timeout(TIMEOUT, receiver.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!((first.id, first.kind), (1, EventKind::Opened));
+    assert_eq!((second.id, second.kind), (2, EventKind::Denied));
+    assert_eq!(
+        *logged.lock().unwrap(),
+        [EventKind::Opened, EventKind::Denied]
+    );
+}
+
+#[tokio::test]
+async fn events_still_go_out_when_the_log_fails() {
+    let bus = EventBus::with_log(|_| Err(Error::NotFound));
+    let mut receiver = bus.subscribe();
+
+    bus.publish(DoorEvent::new(EventKind::HeldOpen, 2, None));
+
+    let event = tokio::time::timeout(TIMEOUT, receiver.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        (event.id, event.kind, event.door_id),
+        (0, EventKind::HeldOpen, 2)
+    );
+}
+
+#[test]
+fn unknown_kinds_in_the_log_are_skipped() {
+    let at = NaiveDateTime::default();
+    let events = from_rows(vec![
+        (1, "opened".to_string(), 1, Some(1), at),
+        (2, "teleported".to_string(), 1, None, at),
+        (3, "locked".to_string(), 2, None, at),
+    ]);
+
+    let ids: Vec<_> = events.iter().map(|event| (event.id, event.kind)).collect();
+    assert_eq!(ids, [(1, EventKind::Opened), (3, EventKind::Locked)]);
+}
@@ -0,0 +1,187 @@
+use async_session::chrono::Utc;
+use diesel::{insert_into, prelude::*, update};
+use dotenv::dotenv;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use protocol::{
+    firmware::{FirmwareOffer, FirmwareReport, UpdateStatus},
+    DeviceCommand,
+};
+use std::{env, path::PathBuf};
+
+use crate::{
+    devices::DeviceHub,
+    models::{Device, FirmwareImage},
+    schema::{device, device_group, firmware_image, firmware_update},
+};
+
+const DEFAULT_DIR: &str = "firmware";
+
+// Where uploaded firmware images live on disk. Images are signed offline with
+// a key of their own, the server only knows its public half, so taking over
+// the server is not enough to get firmware onto the controllers.
+#[derive(Clone)]
+pub struct FirmwareStore {
+    dir: PathBuf,
+    public_key: Option<VerifyingKey>,
+}
+
+impl FirmwareStore {
+    pub fn from_env() -> Self {
+        dotenv().ok();
+
+        let dir = env::var("FIRMWARE_DIR").unwrap_or_else(|_| DEFAULT_DIR.to_string());
+        let public_key = env::var("FIRMWARE_PUBLIC_KEY").ok().map(|key| {
+            let bytes: [u8; 32] = hex::decode(key)
+                .expect("FIRMWARE_PUBLIC_KEY is not valid hex")
+                .try_into()
+                .expect("FIRMWARE_PUBLIC_KEY must be 32 bytes");
+            VerifyingKey::from_bytes(&bytes).expect("FIRMWARE_PUBLIC_KEY is not an Ed25519 key")
+        });
+
+        FirmwareStore {
+            dir: PathBuf::from(dir),
+            public_key,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_public_key(public_key: VerifyingKey) -> Self {
+        FirmwareStore {
+            dir: PathBuf::from(DEFAULT_DIR),
+            public_key: Some(public_key),
+        }
+    }
+
+    // Without a configured key nothing can be uploaded
+    pub fn can_verify(&self) -> bool {
+        self.public_key.is_some()
+    }
+
+    // Checks the hex signature that came with an image against its SHA-256
+    pub fn verify(&self, digest: &[u8], signature: &str) -> bool {
+        let Some(public_key) = &self.public_key else {
+            return false;
+        };
+        hex::decode(signature)
+            .ok()
+            .and_then(|signature| Signature::from_slice(&signature).ok())
+            .is_some_and(|signature| public_key.verify(digest, &signature).is_ok())
+    }
+
+    pub fn path(&self, file_name: &str) -> PathBuf {
+        self.dir.join(file_name)
+    }
+
+    pub async fn write(&self, file_name: &str, bytes: &[u8]) -> std::io::Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        tokio::fs::write(self.path(file_name), bytes).await
+    }
+}
+
+// A device's own target wins over its group's
+pub fn target_for(conn: &mut PgConnection, device: &Device) -> Option<FirmwareImage> {
+    let target_id = match device.target_firmware_id {
+        Some(target_id) => target_id,
+        None => device_group::table
+            .find(device.group_id?)
+            .select(device_group::target_firmware_id)
+            .get_result::<Option<i32>>(conn)
+            .ok()??,
+    };
+
+    firmware_image::table
+        .find(target_id)
+        .select(FirmwareImage::as_select())
+        .get_result(conn)
+        .ok()
+}
+
+// Offers the target image to the device if it is not running it already
+pub fn notify(conn: &mut PgConnection, hub: &DeviceHub, device: &Device) {
+    let Some(target) = target_for(conn, device) else {
+        return;
+    };
+    if device.firmware_version.as_deref() == Some(target.version.as_str()) {
+        return;
+    }
+
+    hub.push(
+        device.id,
+        DeviceCommand::FirmwareUpdate(FirmwareOffer {
+            firmware_id: target.id,
+            url: format!("/api/v1/firmware/{}/image", target.id),
+            version: target.version,
+            sha256: target.sha256,
+            signature: target.signature,
+            size_bytes: target.size_bytes,
+        }),
+    );
+}
+
+pub fn notify_group(conn: &mut PgConnection, hub: &DeviceHub, group_id: i32) {
+    let devices = device::table
+        .filter(device::group_id.eq(group_id))
+        .select(Device::as_select())
+        .load(conn)
+        .unwrap_or_default();
+
+    for device in devices {
+        notify(conn, hub, &device);
+    }
+}
+
+pub fn record_report(conn: &mut PgConnection, device: &Device, report: FirmwareReport) {
+    let firmware_image_id = firmware_image::table
+        .filter(firmware_image::version.eq(&report.version))
+        .select(firmware_image::id)
+        .get_result::<i32>(conn)
+        .ok();
+
+    let recorded = insert_into(firmware_update::table)
+        .values((
+            firmware_update::device_id.eq(device.id),
+            firmware_update::firmware_image_id.eq(firmware_image_id),
+            firmware_update::version.eq(&report.version),
+            firmware_update::status.eq(report.status.as_str()),
+            firmware_update::detail.eq(&report.detail),
+            firmware_update::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn);
+
+    // Offers stop once the device runs its target
+    if report.status == UpdateStatus::Installed {
+        let installed = update(device::table.find(device.id))
+            .set(device::firmware_version.eq(&report.version))
+            .execute(conn);
+        if let Err(e) = installed {
+            tracing::error!(
+                "Could not record firmware version of device {}: {e}",
+                device.id
+            );
+        }
+    }
+
+    match recorded {
+        Ok(_)
+            if matches!(
+                report.status,
+                UpdateStatus::Failed | UpdateStatus::RolledBack
+            ) =>
+        {
+            tracing::warn!(
+                "Device {} could not update to {}: {}",
+                device.id,
+                report.version,
+                report.status.as_str()
+            )
+        }
+        Ok(_) => {}
+        Err(e) => tracing::error!(
+            "Could not record firmware report of device {}: {e}",
+            device.id
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests;
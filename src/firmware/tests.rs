@@ -0,0 +1,58 @@
+use diesel::prelude::*;
+use ed25519_dalek::{Signer, SigningKey};
+use protocol::firmware::{FirmwareReport, UpdateStatus};
+use sha2::{Digest, Sha256};
+
+use super::{record_report, FirmwareStore};
+use crate::{schema::device, testing};
+
+fn firmware_key() -> SigningKey {
+    SigningKey::from_bytes(&[3; 32])
+}
+
+#[test]
+fn only_the_firmware_key_signs_images() {
+    let store = FirmwareStore::with_public_key(firmware_key().verifying_key());
+    let digest = Sha256::digest(b"image");
+
+    let signed = hex::encode(firmware_key().sign(&digest).to_bytes());
+    assert!(store.verify(&digest, &signed));
+
+    let other_key = SigningKey::from_bytes(&[4; 32]);
+    assert!(!store.verify(&digest, &hex::encode(other_key.sign(&digest).to_bytes())));
+    assert!(!store.verify(&Sha256::digest(b"other image"), &signed));
+    assert!(!store.verify(&digest, "not hex"));
+}
+
+fn report(status: UpdateStatus) -> FirmwareReport {
+    FirmwareReport {
+        version: "2.0.0".to_string(),
+        status,
+        detail: None,
+    }
+}
+
+fn version_of(conn: &mut PgConnection, device_id: i32) -> Option<String> {
+    device::table
+        .find(device_id)
+        .select(device::firmware_version)
+        .get_result(conn)
+        .unwrap()
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn installed_images_become_the_device_version() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let controller = testing::device(conn, door_id, "token");
+
+    record_report(conn, &controller, report(UpdateStatus::Downloading));
+    record_report(conn, &controller, report(UpdateStatus::Failed));
+    assert_eq!(version_of(conn, controller.id), None);
+
+    record_report(conn, &controller, report(UpdateStatus::Installed));
+    assert_eq!(version_of(conn, controller.id).as_deref(), Some("2.0.0"));
+}
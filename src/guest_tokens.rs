@@ -0,0 +1,139 @@
+use async_session::chrono::{NaiveDateTime, Utc};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use diesel::prelude::*;
+use protocol::{
+    offline::{RevocationPayload, SignedRevocations},
+    DeviceCommand,
+};
+
+use crate::{
+    devices::DeviceHub,
+    emergency,
+    models::{Device, GuestToken},
+    schema::{device, guest_token},
+    signing::DeviceSigner,
+};
+
+// Door ID, valid from, valid until (both unix seconds) and nonce, all big endian,
+// followed by the Ed25519 signature over them
+const CLAIMS_LEN: usize = 4 + 4 + 4 + 8;
+const TOKEN_LEN: usize = CLAIMS_LEN + 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GuestClaims {
+    pub door_id: i32,
+    pub valid_from: NaiveDateTime,
+    pub valid_until: NaiveDateTime,
+    pub nonce: String,
+}
+
+fn unix_seconds(time: NaiveDateTime) -> u32 {
+    u32::try_from(time.timestamp()).unwrap_or(0)
+}
+
+fn from_unix_seconds(seconds: u32) -> Option<NaiveDateTime> {
+    NaiveDateTime::from_timestamp_opt(seconds.into(), 0)
+}
+
+// The short string a guest gets, also what their QR code encodes
+pub fn encode(signer: &DeviceSigner, token: &GuestToken) -> String {
+    let mut bytes = Vec::with_capacity(TOKEN_LEN);
+    bytes.extend_from_slice(&(token.door_id as u32).to_be_bytes());
+    bytes.extend_from_slice(&unix_seconds(token.valid_from).to_be_bytes());
+    bytes.extend_from_slice(&unix_seconds(token.valid_until).to_be_bytes());
+    bytes.extend_from_slice(&hex::decode(&token.nonce).unwrap_or_default());
+
+    let signature = signer.sign_bytes(&bytes);
+    bytes.extend_from_slice(&signature);
+
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// Only checks the signature, the validity window and revocation are up to the caller
+pub fn decode(signer: &DeviceSigner, token: &str) -> Option<GuestClaims> {
+    let bytes = URL_SAFE_NO_PAD.decode(token.trim()).ok()?;
+    if bytes.len() != TOKEN_LEN {
+        return None;
+    }
+
+    let (claims, signature) = bytes.split_at(CLAIMS_LEN);
+    if !signer.verify(claims, signature) {
+        return None;
+    }
+
+    let field = |at: usize| u32::from_be_bytes(claims[at..at + 4].try_into().unwrap());
+    Some(GuestClaims {
+        door_id: i32::try_from(field(0)).ok()?,
+        valid_from: from_unix_seconds(field(4))?,
+        valid_until: from_unix_seconds(field(8))?,
+        nonce: hex::encode(&claims[12..]),
+    })
+}
+
+// What `open_door` accepts: a genuine, current, unrevoked token for this door,
+// outside of a lockdown
+pub fn verify(conn: &mut PgConnection, signer: &DeviceSigner, token: &str, door_id: i32) -> bool {
+    if emergency::in_lockdown(conn) {
+        return false;
+    }
+
+    let Some(claims) = decode(signer, token) else {
+        return false;
+    };
+
+    let now = Utc::now().naive_utc();
+    if claims.door_id != door_id || now < claims.valid_from || now >= claims.valid_until {
+        return false;
+    }
+
+    guest_token::table
+        .filter(guest_token::nonce.eq(&claims.nonce))
+        .filter(guest_token::door_id.eq(door_id))
+        .filter(guest_token::revoked_at.is_null())
+        .select(guest_token::id)
+        .get_result::<i32>(conn)
+        .is_ok()
+}
+
+// A lockdown revokes every token for as long as it lasts
+fn revocations(conn: &mut PgConnection, hub: &DeviceHub, door_id: i32) -> DeviceCommand {
+    let mut query = guest_token::table
+        .filter(guest_token::door_id.eq(door_id))
+        .filter(guest_token::valid_until.gt(Utc::now().naive_utc()))
+        .into_boxed();
+    if !emergency::in_lockdown(conn) {
+        query = query.filter(guest_token::revoked_at.is_not_null());
+    }
+    let nonces = query
+        .order(guest_token::id.asc())
+        .select(guest_token::nonce)
+        .load::<String>(conn)
+        .unwrap_or_default();
+
+    let payload = serde_json::to_string(&RevocationPayload { door_id, nonces }).unwrap();
+    let signature = hub.signer().sign(payload.as_bytes());
+    DeviceCommand::Revocations(SignedRevocations { payload, signature })
+}
+
+// Sends the full list every time, it only holds tokens that have not expired yet
+pub fn publish_revocations(conn: &mut PgConnection, hub: &DeviceHub, door_id: i32) {
+    let command = revocations(conn, hub, door_id);
+
+    let device_ids = device::table
+        .filter(device::door_id.eq(door_id))
+        .select(device::id)
+        .load::<i32>(conn)
+        .unwrap_or_default();
+
+    for device_id in device_ids {
+        hub.push(device_id, command.clone());
+    }
+}
+
+pub fn sync_device(conn: &mut PgConnection, hub: &DeviceHub, device: &Device) {
+    let command = revocations(conn, hub, device.door_id);
+    hub.push(device.id, command);
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,117 @@
+use async_session::chrono::{Duration, NaiveDateTime, Utc};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use diesel::{insert_into, prelude::*, update};
+
+use super::{decode, encode, verify};
+use crate::{models::GuestToken, schema::guest_token, signing::DeviceSigner, testing};
+
+fn signer() -> DeviceSigner {
+    DeviceSigner::from_seed([7; 32])
+}
+
+// Whole seconds, that is all a token holds
+fn now() -> NaiveDateTime {
+    NaiveDateTime::from_timestamp_opt(Utc::now().timestamp(), 0).unwrap()
+}
+
+fn token(conn: &mut PgConnection, door_id: i32, creator_id: i32) -> GuestToken {
+    insert_into(guest_token::table)
+        .values((
+            guest_token::door_id.eq(door_id),
+            guest_token::creator_id.eq(creator_id),
+            guest_token::nonce.eq("00112233aabbccdd"),
+            guest_token::valid_from.eq(now() - Duration::hours(1)),
+            guest_token::valid_until.eq(now() + Duration::hours(1)),
+            guest_token::created_at.eq(now()),
+        ))
+        .returning(GuestToken::as_returning())
+        .get_result(conn)
+        .unwrap()
+}
+
+fn unsaved(door_id: i32) -> GuestToken {
+    GuestToken {
+        id: 0,
+        door_id,
+        creator_id: 1,
+        nonce: "00112233aabbccdd".to_string(),
+        label: None,
+        valid_from: now(),
+        valid_until: now() + Duration::hours(1),
+        revoked_at: None,
+        created_at: now(),
+    }
+}
+
+#[test]
+fn tokens_decode_to_what_was_encoded() {
+    let token = unsaved(4);
+
+    let claims = decode(&signer(), &encode(&signer(), &token)).unwrap();
+
+    assert_eq!(claims.door_id, 4);
+    assert_eq!(claims.valid_from, token.valid_from);
+    assert_eq!(claims.valid_until, token.valid_until);
+    assert_eq!(claims.nonce, token.nonce);
+}
+
+#[test]
+fn tampered_tokens_are_rejected() {
+    let encoded = encode(&signer(), &unsaved(4));
+
+    // Door 5 with the signature made for door 4
+    let mut bytes = URL_SAFE_NO_PAD.decode(&encoded).unwrap();
+    bytes[3] = 5;
+    assert!(decode(&signer(), &URL_SAFE_NO_PAD.encode(bytes)).is_none());
+
+    assert!(decode(&DeviceSigner::from_seed([8; 32]), &encoded).is_none());
+    assert!(decode(&signer(), &encoded[4..]).is_none());
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn current_tokens_open_their_door_only() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let other_door = testing::door(conn, user_id);
+    let token = token(conn, door_id, user_id);
+    let encoded = encode(&signer(), &token);
+
+    assert!(verify(conn, &signer(), &encoded, door_id));
+    assert!(!verify(conn, &signer(), &encoded, other_door));
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn revoked_and_expired_tokens_do_not_open() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let mut token = token(conn, door_id, user_id);
+
+    update(guest_token::table.find(token.id))
+        .set(guest_token::revoked_at.eq(now()))
+        .execute(conn)
+        .unwrap();
+    assert!(!verify(
+        conn,
+        &signer(),
+        &encode(&signer(), &token),
+        door_id
+    ));
+
+    update(guest_token::table.find(token.id))
+        .set(guest_token::revoked_at.eq(None::<NaiveDateTime>))
+        .execute(conn)
+        .unwrap();
+    token.valid_until = now() - Duration::minutes(1);
+    assert!(!verify(
+        conn,
+        &signer(),
+        &encode(&signer(), &token),
+        door_id
+    ));
+}
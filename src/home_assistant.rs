@@ -0,0 +1,420 @@
+use async_session::chrono::NaiveDateTime;
+use diesel::prelude::*;
+use dotenv::dotenv;
+use rumqttc::{AsyncClient, Event, LastWill, Packet, QoS};
+use serde::Serialize;
+use serde_json::json;
+use std::{collections::HashSet, env, time::Duration};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+
+use crate::{
+    access::{self, OpenMethod},
+    db::establish_connection,
+    door_state::SensorState,
+    events::{DoorEvent, EventBus, EventKind},
+    lock_mode,
+    locks::{self, LockDrivers},
+    models::{Door, DoorState},
+    mqtt::MqttConfig,
+    schema::{device, door, door_event, door_state, user_profile},
+};
+
+const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+const DEFAULT_NODE_ID: &str = "door_server";
+const DEFAULT_REFRESH_SECONDS: u64 = 60;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const CHANNEL_CAPACITY: usize = 64;
+const OPEN_PAYLOAD: &str = "OPEN";
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+// Every door shows up in Home Assistant as an `Open` button through MQTT
+// discovery. Under `<node_id>/` the server publishes, all retained:
+//
+//   status                   `online` or `offline`, the server's last will
+//   door/<id>/availability   `online` while any of the door's controllers is online
+//   door/<id>/attributes     online state, door sensor, lock mode and last opener
+//
+// and listens on `door/<id>/open`. Presses open the door as the configured
+// service account, so they only work where that user may open the door, and
+// only doors that account can see are published.
+//
+// The broker is the trust boundary: whoever may publish on `door/<id>/open`
+// opens doors as the service account, and whoever may subscribe sees who
+// opened them. The server only connects with broker credentials, and the
+// broker has to keep everyone but Home Assistant's own account off these
+// topics.
+#[derive(Clone, Debug)]
+pub struct HomeAssistantConfig {
+    pub user_id: i32,
+    pub discovery_prefix: String,
+    pub node_id: String,
+    // Controllers coming online and doors being added or removed are only
+    // picked up on refresh
+    pub refresh: Duration,
+}
+
+impl HomeAssistantConfig {
+    // Off unless a service account is configured
+    pub fn from_env() -> Option<Self> {
+        dotenv().ok();
+
+        let user_id = env::var("HOME_ASSISTANT_USER_ID")
+            .ok()?
+            .parse()
+            .expect("HOME_ASSISTANT_USER_ID must be a number");
+        let refresh = env::var("HOME_ASSISTANT_REFRESH_SECONDS")
+            .ok()
+            .map(|seconds| {
+                seconds
+                    .parse()
+                    .expect("HOME_ASSISTANT_REFRESH_SECONDS must be a number")
+            })
+            .unwrap_or(DEFAULT_REFRESH_SECONDS);
+
+        Some(HomeAssistantConfig {
+            user_id,
+            discovery_prefix: env::var("HOME_ASSISTANT_DISCOVERY_PREFIX")
+                .unwrap_or_else(|_| DEFAULT_DISCOVERY_PREFIX.to_string()),
+            node_id: env::var("HOME_ASSISTANT_NODE_ID")
+                .unwrap_or_else(|_| DEFAULT_NODE_ID.to_string()),
+            refresh: Duration::from_secs(refresh),
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct DoorAttributes {
+    online: bool,
+    last_seen_at: Option<NaiveDateTime>,
+    door_sensor: String,
+    locked: Option<bool>,
+    lock_mode: String,
+    unlocked_until: Option<NaiveDateTime>,
+    last_opener: Option<String>,
+    last_opened_at: Option<NaiveDateTime>,
+}
+
+struct Bridge {
+    client: AsyncClient,
+    config: HomeAssistantConfig,
+    // Doors with a discovery config out there, to retract deleted ones
+    announced: HashSet<i32>,
+}
+
+impl Bridge {
+    fn topic(&self, path: &str) -> String {
+        format!("{}/{path}", self.config.node_id)
+    }
+
+    fn discovery_topic(&self, door_id: i32) -> String {
+        format!(
+            "{}/button/{}/door_{door_id}/config",
+            self.config.discovery_prefix, self.config.node_id
+        )
+    }
+
+    async fn publish(&self, topic: String, payload: impl Into<Vec<u8>>) {
+        if let Err(e) = self
+            .client
+            .publish(topic, QoS::AtLeastOnce, true, payload)
+            .await
+        {
+            tracing::error!("Could not publish to Home Assistant: {e}");
+        }
+    }
+
+    async fn announce(&self, door: &Door) {
+        let node_id = &self.config.node_id;
+        let name = door
+            .about
+            .clone()
+            .unwrap_or_else(|| format!("Door {}", door.id));
+
+        let config = json!({
+            "name": "Open",
+            "unique_id": format!("{node_id}_door_{}_open", door.id),
+            "object_id": format!("door_{}_open", door.id),
+            "icon": "mdi:door-open",
+            "command_topic": self.topic(&format!("door/{}/open", door.id)),
+            "payload_press": OPEN_PAYLOAD,
+            "availability": [
+                { "topic": self.topic("status") },
+                { "topic": self.topic(&format!("door/{}/availability", door.id)) },
+            ],
+            "availability_mode": "all",
+            "json_attributes_topic": self.topic(&format!("door/{}/attributes", door.id)),
+            "device": {
+                "identifiers": [format!("{node_id}_door_{}", door.id)],
+                "name": name,
+                "manufacturer": "door-server",
+            },
+        });
+
+        self.publish(self.discovery_topic(door.id), config.to_string())
+            .await;
+    }
+
+    async fn publish_state(&self, conn: &mut PgConnection, door_id: i32) {
+        let attributes = match attributes(conn, door_id) {
+            Ok(attributes) => attributes,
+            Err(e) => {
+                tracing::error!("Could not load state of door {door_id} for Home Assistant: {e}");
+                return;
+            }
+        };
+
+        let availability = if attributes.online { ONLINE } else { OFFLINE };
+        self.publish(
+            self.topic(&format!("door/{door_id}/availability")),
+            availability,
+        )
+        .await;
+        self.publish(
+            self.topic(&format!("door/{door_id}/attributes")),
+            serde_json::to_vec(&attributes).unwrap(),
+        )
+        .await;
+    }
+
+    // Announces every door the service account can see and its state, and
+    // retracts doors that are gone or no longer visible
+    async fn refresh(&mut self, conn: &mut PgConnection) {
+        let doors = access::visible_doors(conn, self.config.user_id).and_then(|door_ids| {
+            door::table
+                .filter(door::id.eq_any(door_ids))
+                .select(Door::as_select())
+                .load(conn)
+        });
+        let doors = match doors {
+            Ok(doors) => doors,
+            Err(e) => {
+                tracing::error!("Could not load doors for Home Assistant: {e}");
+                return;
+            }
+        };
+
+        let current: HashSet<i32> = doors.iter().map(|door| door.id).collect();
+        for door_id in self.announced.difference(&current) {
+            // An empty retained config removes the entity
+            self.publish(self.discovery_topic(*door_id), Vec::new())
+                .await;
+        }
+
+        for door in &doors {
+            self.announce(door).await;
+            self.publish_state(conn, door.id).await;
+        }
+        self.announced = current;
+    }
+
+    async fn handle_command(
+        &self,
+        conn: &mut PgConnection,
+        events: &EventBus,
+        locks: &LockDrivers,
+        topic: &str,
+        payload: &[u8],
+    ) {
+        let door_id = topic
+            .strip_prefix(&self.topic("door/"))
+            .and_then(|rest| rest.strip_suffix("/open"))
+            .and_then(|door_id| door_id.parse::<i32>().ok());
+        let Some(door_id) = door_id else {
+            return;
+        };
+        if payload != OPEN_PAYLOAD.as_bytes() {
+            tracing::warn!("Ignoring Home Assistant command for door {door_id}");
+            return;
+        }
+
+        let user_id = self.config.user_id;
+        if !access::can_open(conn, door_id, user_id) {
+            tracing::warn!("Home Assistant is not allowed to open door {door_id}");
+            events.publish(DoorEvent::new(EventKind::Denied, door_id, Some(user_id)));
+            return;
+        }
+
+        let opened = locks
+            .open(conn, events, door_id, Some(user_id), OpenMethod::Permission)
+            .await;
+        if let Err(e) = opened {
+            tracing::error!("Could not open door {door_id} for Home Assistant: {e}");
+        }
+    }
+}
+
+fn attributes(conn: &mut PgConnection, door_id: i32) -> QueryResult<DoorAttributes> {
+    let devices = device::table
+        .filter(device::door_id.eq(door_id))
+        .select((device::online, device::last_seen_at))
+        .load::<(bool, Option<NaiveDateTime>)>(conn)?;
+
+    let state = door_state::table
+        .find(door_id)
+        .select(DoorState::as_select())
+        .get_result(conn)
+        .optional()?;
+    let mode = lock_mode::status(conn, door_id)?;
+
+    // Opens by door code or guest token have no user
+    let last_open = door_event::table
+        .left_join(user_profile::table)
+        .filter(door_event::door_id.eq(door_id))
+        .filter(door_event::kind.eq(EventKind::Opened.as_str()))
+        .order(door_event::id.desc())
+        .select((door_event::created_at, user_profile::username.nullable()))
+        .first::<(NaiveDateTime, Option<String>)>(conn)
+        .optional()?;
+
+    // Relays and mocks answer on their own, without a controller
+    let online =
+        !locks::driven_by_controllers(conn, door_id) || devices.iter().any(|(online, _)| *online);
+
+    Ok(DoorAttributes {
+        online,
+        last_seen_at: devices.iter().filter_map(|(_, seen)| *seen).max(),
+        door_sensor: state
+            .as_ref()
+            .map_or(SensorState::Closed.as_str().to_string(), |state| {
+                state.state.clone()
+            }),
+        locked: state.and_then(|state| state.locked),
+        lock_mode: mode.mode,
+        unlocked_until: mode.unlocked_until,
+        last_opener: last_open.as_ref().and_then(|(_, opener)| opener.clone()),
+        last_opened_at: last_open.map(|(opened_at, _)| opened_at),
+    })
+}
+
+// Events that change what a door looks like in Home Assistant
+fn changes_state(kind: EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Opened
+            | EventKind::DeviceOnline
+            | EventKind::DeviceOffline
+            | EventKind::SensorOpened
+            | EventKind::SensorClosed
+            | EventKind::SensorLocked
+            | EventKind::SensorUnlocked
+            | EventKind::HeldOpen
+            | EventKind::ForcedEntry
+            | EventKind::Unlocked
+            | EventKind::Locked
+            | EventKind::ScheduledUnlock
+            | EventKind::ScheduledLock
+    )
+}
+
+pub fn spawn(
+    mqtt: &MqttConfig,
+    config: HomeAssistantConfig,
+    locks: &LockDrivers,
+    events: &EventBus,
+) {
+    if mqtt.credentials.is_none() {
+        tracing::error!("Home Assistant needs broker credentials, the bridge stays off");
+        return;
+    }
+
+    let locks = locks.clone();
+    let events = events.clone();
+
+    let mut options = mqtt.options(&format!("{}-home-assistant", mqtt.client_id));
+    options.set_last_will(LastWill::new(
+        format!("{}/status", config.node_id),
+        OFFLINE,
+        QoS::AtLeastOnce,
+        true,
+    ));
+    let (client, mut event_loop) = AsyncClient::new(options, CHANNEL_CAPACITY);
+
+    {
+        let conn = &mut establish_connection();
+        let exists = user_profile::table
+            .find(config.user_id)
+            .select(user_profile::id)
+            .get_result::<i32>(conn)
+            .is_ok();
+        if !exists {
+            tracing::error!(
+                "Home Assistant service account {} does not exist, it will not open any door",
+                config.user_id
+            );
+        }
+    }
+
+    // The event loop has to keep running while the bridge publishes, so it
+    // only hands over what the bridge has to act on
+    let (packets, mut incoming) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            match event_loop.poll().await {
+                Ok(Event::Incoming(packet @ (Packet::ConnAck(_) | Packet::Publish(_)))) => {
+                    if packets.send(packet).is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::error!("Home Assistant MQTT connection failed: {e}");
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut bridge = Bridge {
+            client,
+            config,
+            announced: HashSet::new(),
+        };
+        let mut receiver = events.subscribe();
+        let mut interval = tokio::time::interval(bridge.config.refresh);
+        // Connecting refreshes too
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                packet = incoming.recv() => match packet {
+                    Some(Packet::ConnAck(_)) => {
+                        bridge.publish(bridge.topic("status"), ONLINE).await;
+                        let commands = bridge.topic("door/+/open");
+                        if let Err(e) = bridge.client.subscribe(commands, QoS::AtLeastOnce).await {
+                            tracing::error!("Could not subscribe to Home Assistant commands: {e}");
+                        }
+                        bridge.refresh(&mut establish_connection()).await;
+                    }
+                    Some(Packet::Publish(publish)) => {
+                        let conn = &mut establish_connection();
+                        bridge
+                            .handle_command(conn, &events, &locks, &publish.topic, &publish.payload)
+                            .await;
+                    }
+                    Some(_) => {}
+                    None => break,
+                },
+                event = receiver.recv() => match event {
+                    Ok(event)
+                        if changes_state(event.kind) && bridge.announced.contains(&event.door_id) =>
+                    {
+                        bridge
+                            .publish_state(&mut establish_connection(), event.door_id)
+                            .await;
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(n)) => {
+                        tracing::warn!("Home Assistant bridge skipped {n} events");
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = interval.tick() => bridge.refresh(&mut establish_connection()).await,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,116 @@
+use async_session::chrono::Utc;
+use diesel::{delete, insert_into, prelude::*};
+use rumqttc::{AsyncClient, MqttOptions};
+use serde_json::json;
+use std::{collections::HashSet, time::Duration};
+
+use super::{attributes, Bridge, HomeAssistantConfig};
+use crate::{
+    devices::DeviceHub,
+    events::EventKind,
+    locks::LockDrivers,
+    schema::{door_lock, door_permission},
+    testing,
+};
+
+fn bridge(user_id: i32) -> (Bridge, rumqttc::EventLoop) {
+    // Nothing is polled, publishes just wait in the client's queue
+    let (client, event_loop) = AsyncClient::new(MqttOptions::new("test", "127.0.0.1", 1883), 64);
+    let bridge = Bridge {
+        client,
+        config: HomeAssistantConfig {
+            user_id,
+            discovery_prefix: "homeassistant".to_string(),
+            node_id: "door_server".to_string(),
+            refresh: Duration::from_secs(60),
+        },
+        announced: HashSet::new(),
+    };
+    (bridge, event_loop)
+}
+
+fn permission(conn: &mut PgConnection, door_id: i32, user_id: i32) {
+    insert_into(door_permission::table)
+        .values((
+            door_permission::door_id.eq(door_id),
+            door_permission::user_profile_id.eq(user_id),
+            door_permission::edit_permission.eq(false),
+            door_permission::open_permission.eq(true),
+        ))
+        .execute(conn)
+        .unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs TEST_DATABASE_URL"]
+async fn only_doors_the_service_account_sees_are_announced() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let service = testing::user(conn, "home-assistant");
+    let bob = testing::user(conn, "bob");
+    let own = testing::door(conn, service);
+    let shared = testing::door(conn, bob);
+    let private = testing::door(conn, bob);
+    permission(conn, shared, service);
+    let (mut bridge, _event_loop) = bridge(service);
+
+    bridge.refresh(conn).await;
+    assert_eq!(bridge.announced, HashSet::from([own, shared]));
+    assert!(!bridge.announced.contains(&private));
+
+    // Losing the permission retracts the door
+    delete(door_permission::table.filter(door_permission::door_id.eq(shared)))
+        .execute(conn)
+        .unwrap();
+    bridge.refresh(conn).await;
+    assert_eq!(bridge.announced, HashSet::from([own]));
+}
+
+#[tokio::test]
+#[ignore = "needs TEST_DATABASE_URL"]
+async fn presses_for_other_doors_are_denied() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let service = testing::user(conn, "home-assistant");
+    let bob = testing::user(conn, "bob");
+    let private = testing::door(conn, bob);
+    let (bridge, _event_loop) = bridge(service);
+    let events = testing::events();
+    let locks = LockDrivers::new(&DeviceHub::for_tests());
+    let mut receiver = events.subscribe();
+
+    let topic = format!("door_server/door/{private}/open");
+    bridge
+        .handle_command(conn, &events, &locks, &topic, b"OPEN")
+        .await;
+
+    let event = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(event.kind, EventKind::Denied);
+    assert_eq!(event.door_id, private);
+    assert_eq!(event.user_profile_id, Some(service));
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn doors_without_controllers_are_online_when_a_relay_drives_them() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let alice = testing::user(conn, "alice");
+    let relay = testing::door(conn, alice);
+    let controlled = testing::door(conn, alice);
+    insert_into(door_lock::table)
+        .values((
+            door_lock::door_id.eq(relay),
+            door_lock::driver.eq("mock"),
+            door_lock::config.eq(json!({})),
+            door_lock::updated_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)
+        .unwrap();
+
+    assert!(attributes(conn, relay).unwrap().online);
+    assert!(!attributes(conn, controlled).unwrap().online);
+}
@@ -0,0 +1,281 @@
+use async_session::chrono::{NaiveDateTime, Utc};
+use diesel::{insert_into, prelude::*, update};
+use protocol::DeviceCommand;
+use serde::Serialize;
+use std::fmt;
+
+use crate::{
+    db::establish_connection,
+    devices::DeviceHub,
+    events::{DoorEvent, EventBus, EventKind},
+    locks::{self, LockDrivers, LockError},
+    models::{Device, DoorMode},
+    schema::door_mode,
+};
+
+const RELOCK_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+// Controllers before this can only be opened for a moment
+pub const MODE_PROTOCOL_VERSION: i32 = 3;
+
+// Doors are locked between opens unless someone holds them unlocked for a
+// while. The window lives in the database, so a restart does not lose the relock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    Locked,
+    Unlocked,
+}
+
+impl LockMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LockMode::Locked => "locked",
+            LockMode::Unlocked => "unlocked",
+        }
+    }
+}
+
+// What everyone who can see a door gets to know about its mode
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ModeStatus {
+    pub mode: String,
+    pub unlocked_until: Option<NaiveDateTime>,
+    pub changed_by: Option<i32>,
+    pub changed_at: Option<NaiveDateTime>,
+    pub scheduled: bool,
+}
+
+#[derive(Debug)]
+pub enum ModeError {
+    Lock(LockError),
+    Database(diesel::result::Error),
+}
+
+impl fmt::Display for ModeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModeError::Lock(e) => e.fmt(f),
+            ModeError::Database(e) => write!(f, "could not record the mode: {e}"),
+        }
+    }
+}
+
+pub fn current(conn: &mut PgConnection, door_id: i32) -> QueryResult<Option<DoorMode>> {
+    door_mode::table
+        .find(door_id)
+        .select(DoorMode::as_select())
+        .get_result(conn)
+        .optional()
+}
+
+pub fn status(conn: &mut PgConnection, door_id: i32) -> QueryResult<ModeStatus> {
+    Ok(match current(conn, door_id)? {
+        Some(mode) => ModeStatus {
+            mode: mode.mode,
+            unlocked_until: mode.unlocked_until,
+            changed_by: mode.changed_by,
+            changed_at: Some(mode.changed_at),
+            scheduled: mode.scheduled,
+        },
+        None => ModeStatus {
+            mode: LockMode::Locked.as_str().to_string(),
+            unlocked_until: None,
+            changed_by: None,
+            changed_at: None,
+            scheduled: false,
+        },
+    })
+}
+
+// A change someone made, it takes the door off its schedule until the next window
+pub async fn set(
+    conn: &mut PgConnection,
+    locks: &LockDrivers,
+    events: &EventBus,
+    door_id: i32,
+    mode: LockMode,
+    until: Option<NaiveDateTime>,
+    user_profile_id: Option<i32>,
+) -> Result<DoorMode, ModeError> {
+    let changer = Changer::User(user_profile_id);
+    apply(conn, locks, events, door_id, mode, until, changer).await
+}
+
+// A change made by the door's unlock schedule
+pub async fn set_scheduled(
+    conn: &mut PgConnection,
+    locks: &LockDrivers,
+    events: &EventBus,
+    door_id: i32,
+    mode: LockMode,
+    until: Option<NaiveDateTime>,
+) -> Result<DoorMode, ModeError> {
+    apply(conn, locks, events, door_id, mode, until, Changer::Schedule).await
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Changer {
+    // Nobody for relocks at the end of a window
+    User(Option<i32>),
+    Schedule,
+}
+
+// Drives the lock first and only records the mode once the lock took it
+async fn apply(
+    conn: &mut PgConnection,
+    locks: &LockDrivers,
+    events: &EventBus,
+    door_id: i32,
+    mode: LockMode,
+    until: Option<NaiveDateTime>,
+    changer: Changer,
+) -> Result<DoorMode, ModeError> {
+    let (user_profile_id, scheduled) = match changer {
+        Changer::User(user_profile_id) => (user_profile_id, false),
+        Changer::Schedule => (None, true),
+    };
+
+    let driven = match mode {
+        LockMode::Unlocked => locks.unlock(conn, door_id, until, user_profile_id).await,
+        LockMode::Locked => locks.lock(conn, door_id, user_profile_id).await,
+    };
+    driven.map_err(ModeError::Lock)?;
+
+    let until = until.filter(|_| mode == LockMode::Unlocked);
+    let now = Utc::now().naive_utc();
+    let recorded = insert_into(door_mode::table)
+        .values((
+            door_mode::door_id.eq(door_id),
+            door_mode::mode.eq(mode.as_str()),
+            door_mode::unlocked_until.eq(until),
+            door_mode::changed_by.eq(user_profile_id),
+            door_mode::changed_at.eq(now),
+            door_mode::scheduled.eq(scheduled),
+        ))
+        .on_conflict(door_mode::door_id)
+        .do_update()
+        .set((
+            door_mode::mode.eq(mode.as_str()),
+            door_mode::unlocked_until.eq(until),
+            door_mode::changed_by.eq(user_profile_id),
+            door_mode::changed_at.eq(now),
+            door_mode::scheduled.eq(scheduled),
+        ))
+        .returning(DoorMode::as_returning())
+        .get_result(conn)
+        .map_err(ModeError::Database)?;
+
+    let kind = event_kind(mode, scheduled);
+    events.publish(DoorEvent::new(kind, door_id, user_profile_id));
+    Ok(recorded)
+}
+
+fn event_kind(mode: LockMode, scheduled: bool) -> EventKind {
+    match (mode, scheduled) {
+        (LockMode::Unlocked, false) => EventKind::Unlocked,
+        (LockMode::Locked, false) => EventKind::Locked,
+        (LockMode::Unlocked, true) => EventKind::ScheduledUnlock,
+        (LockMode::Locked, true) => EventKind::ScheduledLock,
+    }
+}
+
+// Locks the doors whose unlock window is over. Relocks made by the server
+// carry no user, the end of a scheduled window counts as the schedule's.
+//
+// Each window is claimed with a conditional update first, so a window that
+// was extended in the meantime, or a second server checking at the same
+// time, is left alone. The claim only flips the mode, so a relock the lock
+// refused can be handed back and tried again on the next check.
+async fn relock_expired(conn: &mut PgConnection, locks: &LockDrivers, events: &EventBus) {
+    let now = Utc::now().naive_utc();
+    let claimed = update(door_mode::table)
+        .filter(door_mode::mode.eq(LockMode::Unlocked.as_str()))
+        .filter(door_mode::unlocked_until.le(now))
+        .set(door_mode::mode.eq(LockMode::Locked.as_str()))
+        .returning(DoorMode::as_returning())
+        .get_results(conn);
+
+    let claimed = match claimed {
+        Ok(claimed) => claimed,
+        Err(e) => {
+            tracing::error!("Could not claim expired unlock windows: {e}");
+            return;
+        }
+    };
+
+    for mode in claimed {
+        let door_id = mode.door_id;
+        // Any change made since replaced the window this relock is about
+        let ours = door_mode::table
+            .find(door_id)
+            .filter(door_mode::mode.eq(LockMode::Locked.as_str()))
+            .filter(door_mode::unlocked_until.eq(mode.unlocked_until));
+
+        if let Err(e) = locks.lock(conn, door_id, None).await {
+            tracing::error!("Could not relock door {door_id}: {e}");
+            let handed_back = update(ours)
+                .set(door_mode::mode.eq(LockMode::Unlocked.as_str()))
+                .execute(conn);
+            if let Err(e) = handed_back {
+                tracing::error!("Could not hand back the unlock window of door {door_id}: {e}");
+            }
+            continue;
+        }
+
+        let cleared = update(ours)
+            .set((
+                door_mode::unlocked_until.eq(None::<NaiveDateTime>),
+                door_mode::changed_by.eq(None::<i32>),
+                door_mode::changed_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(conn);
+        if let Err(e) = cleared {
+            tracing::error!("Could not record the relock of door {door_id}: {e}");
+        }
+
+        let kind = event_kind(LockMode::Locked, mode.scheduled);
+        events.publish(DoorEvent::new(kind, door_id, None));
+    }
+}
+
+// The first check runs right away and catches windows that ended while the
+// server was down
+pub fn spawn_relock_monitor(locks: &LockDrivers, events: &EventBus) {
+    let locks = locks.clone();
+    let events = events.clone();
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RELOCK_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let conn = &mut establish_connection();
+            relock_expired(conn, &locks, &events).await;
+        }
+    });
+}
+
+// Tells a controller that reconnects which mode its door is in, in case it
+// missed the change. Goes by the version it spoke last time.
+pub fn sync_device(conn: &mut PgConnection, hub: &DeviceHub, device: &Device) {
+    if device.protocol_version < MODE_PROTOCOL_VERSION {
+        return;
+    }
+    if locks::driver_of(conn, device.door_id).0 != locks::DEFAULT_DRIVER {
+        return;
+    }
+
+    let command = match current(conn, device.door_id) {
+        Ok(Some(mode)) if mode.mode == LockMode::Unlocked.as_str() => DeviceCommand::Unlock {
+            until: mode.unlocked_until,
+        },
+        Ok(Some(_)) => DeviceCommand::Lock,
+        Ok(None) => return,
+        Err(e) => {
+            tracing::error!("Could not load mode of door {}: {e}", device.door_id);
+            return;
+        }
+    };
+    hub.push(device.id, command);
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,163 @@
+use async_session::chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{insert_into, prelude::*, update};
+use protocol::DeviceCommand;
+use serde_json::json;
+use tokio::sync::broadcast::Receiver;
+
+use super::{relock_expired, LockMode, MODE_PROTOCOL_VERSION};
+use crate::{
+    devices::DeviceHub,
+    events::{DoorEvent, EventBus, EventKind},
+    locks::LockDrivers,
+    models::DoorMode,
+    schema::{device, device_command, door_lock, door_mode},
+    testing,
+};
+
+fn mock_lock(conn: &mut PgConnection, door_id: i32, config: serde_json::Value) {
+    insert_into(door_lock::table)
+        .values((
+            door_lock::door_id.eq(door_id),
+            door_lock::driver.eq("mock"),
+            door_lock::config.eq(config),
+            door_lock::updated_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)
+        .unwrap();
+}
+
+fn unlocked_until(conn: &mut PgConnection, door_id: i32, until: NaiveDateTime) {
+    insert_into(door_mode::table)
+        .values((
+            door_mode::door_id.eq(door_id),
+            door_mode::mode.eq(LockMode::Unlocked.as_str()),
+            door_mode::unlocked_until.eq(until),
+            door_mode::changed_at.eq(Utc::now().naive_utc()),
+            door_mode::scheduled.eq(false),
+        ))
+        .execute(conn)
+        .unwrap();
+}
+
+fn mode(conn: &mut PgConnection, door_id: i32) -> DoorMode {
+    door_mode::table
+        .find(door_id)
+        .select(DoorMode::as_select())
+        .first(conn)
+        .unwrap()
+}
+
+// Everything published so far, the log thread hands events over in order
+async fn published(events: &EventBus, receiver: &mut Receiver<DoorEvent>) -> Vec<EventKind> {
+    events.publish(DoorEvent::new(EventKind::Opened, i32::MIN, None));
+
+    let mut published = Vec::new();
+    loop {
+        let event = receiver.recv().await.unwrap();
+        if event.door_id == i32::MIN {
+            return published;
+        }
+        published.push(event.kind);
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs TEST_DATABASE_URL"]
+async fn expired_windows_are_relocked_once() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let expired = testing::door(conn, user_id);
+    let running = testing::door(conn, user_id);
+    let now = Utc::now().naive_utc();
+    for door_id in [expired, running] {
+        mock_lock(conn, door_id, serde_json::Value::Null);
+    }
+    unlocked_until(conn, expired, now - Duration::minutes(1));
+    unlocked_until(conn, running, now + Duration::minutes(30));
+
+    let events = testing::events();
+    let mut receiver = events.subscribe();
+    let locks = LockDrivers::new(&DeviceHub::for_tests());
+
+    relock_expired(conn, &locks, &events).await;
+    relock_expired(conn, &locks, &events).await;
+
+    let relocked = mode(conn, expired);
+    assert_eq!(relocked.mode, LockMode::Locked.as_str());
+    assert_eq!(relocked.unlocked_until, None);
+    assert_eq!(mode(conn, running).mode, LockMode::Unlocked.as_str());
+
+    assert_eq!(published(&events, &mut receiver).await, [EventKind::Locked]);
+}
+
+#[tokio::test]
+#[ignore = "needs TEST_DATABASE_URL"]
+async fn refused_relocks_are_tried_again() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let until = Utc::now().naive_utc() - Duration::minutes(1);
+    mock_lock(conn, door_id, json!({ "fail": true }));
+    unlocked_until(conn, door_id, until);
+    let until = mode(conn, door_id).unlocked_until;
+
+    let events = testing::events();
+    let locks = LockDrivers::new(&DeviceHub::for_tests());
+    relock_expired(conn, &locks, &events).await;
+
+    let kept = mode(conn, door_id);
+    assert_eq!(kept.mode, LockMode::Unlocked.as_str());
+    assert_eq!(kept.unlocked_until, until);
+
+    update(door_lock::table.find(door_id))
+        .set(door_lock::config.eq(serde_json::Value::Null))
+        .execute(conn)
+        .unwrap();
+    relock_expired(conn, &locks, &events).await;
+    assert_eq!(mode(conn, door_id).mode, LockMode::Locked.as_str());
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn modes_only_reach_controllers_that_know_them() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let old = testing::device(conn, door_id, "old");
+    let hub = DeviceHub::for_tests();
+
+    let sent = hub.send_to_capable(
+        conn,
+        door_id,
+        DeviceCommand::Lock,
+        None,
+        MODE_PROTOCOL_VERSION,
+    );
+    assert_eq!(sent, 0);
+
+    let new = testing::device(conn, door_id, "new");
+    update(device::table.find(new.id))
+        .set(device::protocol_version.eq(MODE_PROTOCOL_VERSION))
+        .execute(conn)
+        .unwrap();
+
+    let sent = hub.send_to_capable(
+        conn,
+        door_id,
+        DeviceCommand::Lock,
+        None,
+        MODE_PROTOCOL_VERSION,
+    );
+    assert_eq!(sent, 1);
+
+    let receivers: Vec<i32> = device_command::table
+        .filter(device_command::door_id.eq(door_id))
+        .select(device_command::device_id)
+        .load(conn)
+        .unwrap();
+    assert_eq!(receivers, [new.id]);
+    assert_ne!(old.id, new.id);
+}
@@ -0,0 +1,209 @@
+use async_session::{
+    async_trait,
+    chrono::{NaiveDateTime, Utc},
+};
+use diesel::prelude::*;
+use std::fmt;
+
+use crate::{
+    access::{self, OpenMethod},
+    devices::DeviceHub,
+    events::EventBus,
+    models::DoorLock,
+    schema::door_lock,
+};
+
+mod esp;
+mod http_relay;
+mod mock;
+
+pub use esp::EspDriver;
+pub use http_relay::HttpRelayDriver;
+pub use mock::MockDriver;
+
+// Doors without a configured lock are driven by their controllers
+pub const DEFAULT_DRIVER: &str = esp::NAME;
+
+#[derive(Debug)]
+pub enum LockError {
+    UnknownDriver(String),
+    Misconfigured(String),
+    Unreachable(String),
+    // The lock answered but did not do it
+    Refused(String),
+}
+
+// Locks that answer right away are open once `open` returns. Controllers
+// confirm later, the open only counts once one of them did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opening {
+    Done,
+    Queued,
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::UnknownDriver(driver) => write!(f, "unknown lock driver `{driver}`"),
+            LockError::Misconfigured(e) => write!(f, "lock is misconfigured: {e}"),
+            LockError::Unreachable(e) => write!(f, "lock did not respond: {e}"),
+            LockError::Refused(e) => write!(f, "lock refused: {e}"),
+        }
+    }
+}
+
+// Whatever actually moves the strike or bolt of a door. Each door picks one
+// and keeps its settings in `door_lock.config`.
+#[async_trait]
+pub trait LockDriver: Send + Sync {
+    // Checked before a door is switched to this driver
+    fn validate(&self, config: &serde_json::Value) -> Result<(), String>;
+
+    // Drivers that make the server call addresses from their config are left
+    // to administrators
+    fn admin_only(&self) -> bool {
+        false
+    }
+
+    // The config as shown to managers, without secrets
+    fn redact(&self, config: &serde_json::Value) -> serde_json::Value {
+        config.clone()
+    }
+
+    // Releases the door for a moment
+    async fn open(
+        &self,
+        door_id: i32,
+        config: &serde_json::Value,
+        user_profile_id: Option<i32>,
+        method: OpenMethod,
+    ) -> Result<Opening, LockError>;
+
+    // Keeps the door unlocked until `lock`. `until` is only a hint for locks
+    // that can relock by themselves, the server relocks the door either way.
+    async fn unlock(
+        &self,
+        door_id: i32,
+        config: &serde_json::Value,
+        until: Option<NaiveDateTime>,
+        user_profile_id: Option<i32>,
+    ) -> Result<(), LockError>;
+
+    async fn lock(
+        &self,
+        door_id: i32,
+        config: &serde_json::Value,
+        user_profile_id: Option<i32>,
+    ) -> Result<(), LockError>;
+}
+
+#[derive(Clone)]
+pub struct LockDrivers {
+    esp: EspDriver,
+    http_relay: HttpRelayDriver,
+    mock: MockDriver,
+}
+
+impl LockDrivers {
+    pub fn new(hub: &DeviceHub) -> Self {
+        LockDrivers {
+            esp: EspDriver::new(hub),
+            http_relay: HttpRelayDriver::from_env(),
+            mock: MockDriver::default(),
+        }
+    }
+
+    pub fn get(&self, driver: &str) -> Result<&dyn LockDriver, LockError> {
+        match driver {
+            esp::NAME => Ok(&self.esp),
+            http_relay::NAME => Ok(&self.http_relay),
+            mock::NAME => Ok(&self.mock),
+            driver => Err(LockError::UnknownDriver(driver.to_string())),
+        }
+    }
+
+    // Opens the door with whatever driver it is set up with and records the
+    // open in the door's history once it happened
+    pub async fn open(
+        &self,
+        conn: &mut PgConnection,
+        events: &EventBus,
+        door_id: i32,
+        user_profile_id: Option<i32>,
+        method: OpenMethod,
+    ) -> Result<(), LockError> {
+        let (driver, config) = driver_of(conn, door_id);
+        let opening = self
+            .get(&driver)?
+            .open(door_id, &config, user_profile_id, method)
+            .await?;
+
+        if opening == Opening::Done {
+            let recorded = access::record_open_by(
+                conn,
+                events,
+                door_id,
+                user_profile_id,
+                method,
+                Utc::now().naive_utc(),
+            );
+            if let Err(e) = recorded {
+                tracing::error!("Could not record open of door {door_id}: {e}");
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn unlock(
+        &self,
+        conn: &mut PgConnection,
+        door_id: i32,
+        until: Option<NaiveDateTime>,
+        user_profile_id: Option<i32>,
+    ) -> Result<(), LockError> {
+        let (driver, config) = driver_of(conn, door_id);
+        self.get(&driver)?
+            .unlock(door_id, &config, until, user_profile_id)
+            .await
+    }
+
+    pub async fn lock(
+        &self,
+        conn: &mut PgConnection,
+        door_id: i32,
+        user_profile_id: Option<i32>,
+    ) -> Result<(), LockError> {
+        let (driver, config) = driver_of(conn, door_id);
+        self.get(&driver)?
+            .lock(door_id, &config, user_profile_id)
+            .await
+    }
+}
+
+// The door's driver and its settings
+pub fn driver_of(conn: &mut PgConnection, door_id: i32) -> (String, serde_json::Value) {
+    match configured(conn, door_id) {
+        Some(lock) => (lock.driver, lock.config),
+        None => (DEFAULT_DRIVER.to_string(), serde_json::Value::Null),
+    }
+}
+
+// Only these doors depend on what their controllers can do
+pub fn driven_by_controllers(conn: &mut PgConnection, door_id: i32) -> bool {
+    driver_of(conn, door_id).0 == esp::NAME
+}
+
+pub fn configured(conn: &mut PgConnection, door_id: i32) -> Option<DoorLock> {
+    door_lock::table
+        .find(door_id)
+        .select(DoorLock::as_select())
+        .get_result(conn)
+        .optional()
+        .unwrap_or_else(|e| {
+            tracing::error!("Could not load lock of door {door_id}: {e}");
+            None
+        })
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,98 @@
+use async_session::{async_trait, chrono::NaiveDateTime};
+use diesel::PgConnection;
+use protocol::DeviceCommand;
+
+use super::{LockDriver, LockError, Opening};
+use crate::{access::OpenMethod, db::establish_connection, devices::DeviceHub, lock_mode};
+
+pub const NAME: &str = "esp";
+
+// The door's own controllers, over the device channel. Opens are queued for
+// controllers that are offline right now and count once one confirms them.
+#[derive(Clone)]
+pub struct EspDriver {
+    hub: DeviceHub,
+}
+
+impl EspDriver {
+    pub fn new(hub: &DeviceHub) -> Self {
+        EspDriver { hub: hub.clone() }
+    }
+}
+
+#[async_trait]
+impl LockDriver for EspDriver {
+    // Controllers are configured per device
+    fn validate(&self, _config: &serde_json::Value) -> Result<(), String> {
+        Ok(())
+    }
+
+    async fn open(
+        &self,
+        door_id: i32,
+        _config: &serde_json::Value,
+        user_profile_id: Option<i32>,
+        method: OpenMethod,
+    ) -> Result<Opening, LockError> {
+        let conn = &mut establish_connection();
+        match self.hub.open_door(conn, door_id, user_profile_id, method) {
+            0 => Err(LockError::Unreachable(
+                "the door has no controllers".to_string(),
+            )),
+            _ => Ok(Opening::Queued),
+        }
+    }
+
+    // Controllers that miss these get the door's mode again when they reconnect
+    async fn unlock(
+        &self,
+        door_id: i32,
+        _config: &serde_json::Value,
+        until: Option<NaiveDateTime>,
+        user_profile_id: Option<i32>,
+    ) -> Result<(), LockError> {
+        let conn = &mut establish_connection();
+        self.send_mode(
+            conn,
+            door_id,
+            DeviceCommand::Unlock { until },
+            user_profile_id,
+        )
+    }
+
+    async fn lock(
+        &self,
+        door_id: i32,
+        _config: &serde_json::Value,
+        user_profile_id: Option<i32>,
+    ) -> Result<(), LockError> {
+        let conn = &mut establish_connection();
+        self.send_mode(conn, door_id, DeviceCommand::Lock, user_profile_id)
+    }
+}
+
+impl EspDriver {
+    // Older controllers only know how to open for a moment. A door none of
+    // whose controllers can hold it keeps its mode.
+    fn send_mode(
+        &self,
+        conn: &mut PgConnection,
+        door_id: i32,
+        command: DeviceCommand,
+        user_profile_id: Option<i32>,
+    ) -> Result<(), LockError> {
+        let sent = self.hub.send_to_capable(
+            conn,
+            door_id,
+            command,
+            user_profile_id,
+            lock_mode::MODE_PROTOCOL_VERSION,
+        );
+        match sent {
+            0 => Err(LockError::Unreachable(
+                "none of the door's controllers can hold it unlocked".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use async_session::{async_trait, chrono::NaiveDateTime};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use super::{LockDriver, LockError, Opening};
+use crate::{access::OpenMethod, outbound::AllowedHosts};
+
+pub const NAME: &str = "http_relay";
+
+const DEFAULT_PULSE_MS: u32 = 1000;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const REDACTED: &str = "redacted";
+// Query parameters that look like they carry a secret
+const SECRET_PARAMS: [&str; 5] = ["pass", "token", "key", "secret", "auth"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Method {
+    #[default]
+    Get,
+    Post,
+}
+
+// A relay with an HTTP API, like a Shelly (`http://10.0.0.5/relay/0?turn=on&timer={pulse_seconds}`)
+// or Tasmota (`http://10.0.0.6/cm?cmnd=Backlog%20Power%20On%3BDelay%20{pulse_ds}%3BPower%20Off`).
+// `{door_id}`, `{user_id}`, `{pulse_ms}`, `{pulse_ds}` and `{pulse_seconds}` are
+// filled in for every request, in the URL and in the body. Relays that can be
+// held on (`turn=on` without a timer) also take `unlock_url` and `lock_url`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HttpRelayConfig {
+    #[serde(default)]
+    pub method: Method,
+    pub open_url: String,
+    #[serde(default)]
+    pub unlock_url: Option<String>,
+    #[serde(default)]
+    pub lock_url: Option<String>,
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default = "default_pulse_ms")]
+    pub pulse_ms: u32,
+}
+
+fn default_pulse_ms() -> u32 {
+    DEFAULT_PULSE_MS
+}
+
+impl HttpRelayConfig {
+    pub fn parse(config: &serde_json::Value) -> Result<Self, String> {
+        let config: HttpRelayConfig =
+            serde_json::from_value(config.clone()).map_err(|e| e.to_string())?;
+
+        let urls = [
+            ("open_url", Some(&config.open_url)),
+            ("unlock_url", config.unlock_url.as_ref()),
+            ("lock_url", config.lock_url.as_ref()),
+        ];
+        for (name, template) in urls {
+            let Some(template) = template else {
+                continue;
+            };
+            match Url::parse(&config.render(template, 0, None)) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+                Ok(url) => return Err(format!("`{}` URLs are not supported", url.scheme())),
+                Err(e) => return Err(format!("{name} is not a valid URL: {e}")),
+            }
+        }
+        if config.unlock_url.is_some() != config.lock_url.is_some() {
+            return Err("unlock_url and lock_url go together".to_string());
+        }
+        if config.pulse_ms == 0 {
+            return Err("pulse_ms must be positive".to_string());
+        }
+
+        Ok(config)
+    }
+
+    pub fn render(&self, template: &str, door_id: i32, user_profile_id: Option<i32>) -> String {
+        // Tasmota counts delays in tenths of a second, rounded up here without
+        // overflowing near u32::MAX
+        let tenths = self.pulse_ms / 100 + u32::from(self.pulse_ms % 100 != 0);
+        let seconds = if self.pulse_ms % 1000 == 0 {
+            (self.pulse_ms / 1000).to_string()
+        } else {
+            format!("{:.3}", f64::from(self.pulse_ms) / 1000.0)
+        };
+
+        template
+            .replace("{door_id}", &door_id.to_string())
+            .replace(
+                "{user_id}",
+                &user_profile_id.map(|id| id.to_string()).unwrap_or_default(),
+            )
+            .replace("{pulse_ms}", &self.pulse_ms.to_string())
+            .replace("{pulse_ds}", &tenths.to_string())
+            .replace("{pulse_seconds}", &seconds)
+    }
+}
+
+fn redact_url(template: &str) -> String {
+    let Ok(mut url) = Url::parse(template) else {
+        return template.to_string();
+    };
+
+    if !url.username().is_empty() {
+        let _ = url.set_username(REDACTED);
+    }
+    if url.password().is_some() {
+        let _ = url.set_password(Some(REDACTED));
+    }
+    let secret = |name: &str| {
+        let name = name.to_lowercase();
+        SECRET_PARAMS.iter().any(|secret| name.contains(secret))
+    };
+    // Rewriting the query re-encodes it, so it is left alone unless needed
+    if url.query_pairs().any(|(name, _)| secret(&name)) {
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(name, value)| {
+                let value = if secret(&name) {
+                    REDACTED.to_string()
+                } else {
+                    value.into_owned()
+                };
+                (name.into_owned(), value)
+            })
+            .collect();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+
+    // Placeholders come back escaped otherwise
+    url.to_string().replace("%7B", "{").replace("%7D", "}")
+}
+
+#[derive(Clone)]
+pub struct HttpRelayDriver {
+    client: reqwest::Client,
+    allowed: AllowedHosts,
+}
+
+impl HttpRelayDriver {
+    // Relays sit on the local network, their hosts go into
+    // HTTP_RELAY_ALLOWED_HOSTS (comma separated)
+    pub fn from_env() -> Self {
+        HttpRelayDriver::new(AllowedHosts::from_env("HTTP_RELAY_ALLOWED_HOSTS"))
+    }
+
+    #[cfg(test)]
+    pub fn allowing(hosts: &[&str]) -> Self {
+        HttpRelayDriver::new(AllowedHosts::new(hosts))
+    }
+
+    fn new(allowed: AllowedHosts) -> Self {
+        let client = allowed.client(REQUEST_TIMEOUT);
+        HttpRelayDriver { client, allowed }
+    }
+
+    fn parse(&self, config: &serde_json::Value) -> Result<HttpRelayConfig, String> {
+        let config = HttpRelayConfig::parse(config)?;
+
+        let templates = [
+            Some(&config.open_url),
+            config.unlock_url.as_ref(),
+            config.lock_url.as_ref(),
+        ];
+        for template in templates.into_iter().flatten() {
+            let url = Url::parse(&config.render(template, 0, None)).map_err(|e| e.to_string())?;
+            self.allowed.check(&url)?;
+        }
+
+        Ok(config)
+    }
+
+    async fn send(
+        &self,
+        config: &HttpRelayConfig,
+        url: &str,
+        door_id: i32,
+        user_profile_id: Option<i32>,
+    ) -> Result<(), LockError> {
+        let url = config.render(url, door_id, user_profile_id);
+
+        let request = match config.method {
+            Method::Get => self.client.get(&url),
+            Method::Post => self.client.post(&url),
+        };
+        let request = match &config.body {
+            Some(body) => request.body(config.render(body, door_id, user_profile_id)),
+            None => request,
+        };
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| LockError::Unreachable(e.without_url().to_string()))?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(LockError::Refused(format!(
+                "relay answered {}",
+                response.status()
+            )))
+        }
+    }
+}
+
+fn cannot_hold(door_id: i32) -> LockError {
+    LockError::Misconfigured(format!(
+        "the relay of door {door_id} has no unlock_url and lock_url"
+    ))
+}
+
+#[async_trait]
+impl LockDriver for HttpRelayDriver {
+    fn validate(&self, config: &serde_json::Value) -> Result<(), String> {
+        self.parse(config).map(|_| ())
+    }
+
+    fn admin_only(&self) -> bool {
+        true
+    }
+
+    // URL credentials, secret looking parameters and bodies stay hidden
+    fn redact(&self, config: &serde_json::Value) -> serde_json::Value {
+        let Ok(mut config) = serde_json::from_value::<HttpRelayConfig>(config.clone()) else {
+            return serde_json::Value::Null;
+        };
+
+        config.open_url = redact_url(&config.open_url);
+        config.unlock_url = config.unlock_url.as_deref().map(redact_url);
+        config.lock_url = config.lock_url.as_deref().map(redact_url);
+        if config.body.is_some() {
+            config.body = Some(REDACTED.to_string());
+        }
+
+        serde_json::to_value(config).unwrap()
+    }
+
+    async fn open(
+        &self,
+        door_id: i32,
+        config: &serde_json::Value,
+        user_profile_id: Option<i32>,
+        _method: OpenMethod,
+    ) -> Result<Opening, LockError> {
+        let config = self.parse(config).map_err(LockError::Misconfigured)?;
+        self.send(&config, &config.open_url, door_id, user_profile_id)
+            .await?;
+        Ok(Opening::Done)
+    }
+
+    async fn unlock(
+        &self,
+        door_id: i32,
+        config: &serde_json::Value,
+        _until: Option<NaiveDateTime>,
+        user_profile_id: Option<i32>,
+    ) -> Result<(), LockError> {
+        let config = self.parse(config).map_err(LockError::Misconfigured)?;
+        let url = config
+            .unlock_url
+            .as_ref()
+            .ok_or_else(|| cannot_hold(door_id))?;
+        self.send(&config, url, door_id, user_profile_id).await
+    }
+
+    async fn lock(
+        &self,
+        door_id: i32,
+        config: &serde_json::Value,
+        user_profile_id: Option<i32>,
+    ) -> Result<(), LockError> {
+        let config = self.parse(config).map_err(LockError::Misconfigured)?;
+        let url = config
+            .lock_url
+            .as_ref()
+            .ok_or_else(|| cannot_hold(door_id))?;
+        self.send(&config, url, door_id, user_profile_id).await
+    }
+}
@@ -0,0 +1,133 @@
+use async_session::{
+    async_trait,
+    chrono::{NaiveDateTime, Utc},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use super::{LockDriver, LockError, Opening};
+use crate::access::OpenMethod;
+
+pub const NAME: &str = "mock";
+
+const MAX_RECORDED_OPENS: usize = 100;
+
+// For doors without hardware, like in tests and on a development setup.
+// `fail` makes every open fail, `latency_ms` delays the answer.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct MockConfig {
+    #[serde(default)]
+    pub fail: bool,
+    #[serde(default)]
+    pub latency_ms: u64,
+}
+
+impl MockConfig {
+    pub fn parse(config: &serde_json::Value) -> Result<Self, String> {
+        if config.is_null() {
+            return Ok(MockConfig::default());
+        }
+        serde_json::from_value(config.clone()).map_err(|e| e.to_string())
+    }
+
+    // Behaves like a real lock would with these settings
+    async fn respond(config: &serde_json::Value) -> Result<(), LockError> {
+        let config = MockConfig::parse(config).map_err(LockError::Misconfigured)?;
+
+        if config.latency_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(config.latency_ms)).await;
+        }
+        if config.fail {
+            return Err(LockError::Unreachable(
+                "mock lock is set to fail".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockOpen {
+    pub door_id: i32,
+    pub user_profile_id: Option<i32>,
+    pub opened_at: NaiveDateTime,
+}
+
+// Remembers the opens and which doors it holds unlocked instead of
+// performing anything
+#[derive(Clone, Default)]
+pub struct MockDriver {
+    opens: Arc<Mutex<Vec<MockOpen>>>,
+    unlocked: Arc<Mutex<HashSet<i32>>>,
+}
+
+impl MockDriver {
+    #[cfg(test)]
+    pub fn opens(&self) -> Vec<MockOpen> {
+        self.opens.lock().unwrap().clone()
+    }
+
+    #[cfg(test)]
+    pub fn is_unlocked(&self, door_id: i32) -> bool {
+        self.unlocked.lock().unwrap().contains(&door_id)
+    }
+}
+
+#[async_trait]
+impl LockDriver for MockDriver {
+    fn validate(&self, config: &serde_json::Value) -> Result<(), String> {
+        MockConfig::parse(config).map(|_| ())
+    }
+
+    async fn open(
+        &self,
+        door_id: i32,
+        config: &serde_json::Value,
+        user_profile_id: Option<i32>,
+        _method: OpenMethod,
+    ) -> Result<Opening, LockError> {
+        MockConfig::respond(config).await?;
+
+        tracing::info!("Mock lock of door {door_id} opened");
+        let mut opens = self.opens.lock().unwrap();
+        if opens.len() == MAX_RECORDED_OPENS {
+            opens.remove(0);
+        }
+        opens.push(MockOpen {
+            door_id,
+            user_profile_id,
+            opened_at: Utc::now().naive_utc(),
+        });
+        Ok(Opening::Done)
+    }
+    async fn unlock(
+        &self,
+        door_id: i32,
+        config: &serde_json::Value,
+        _until: Option<NaiveDateTime>,
+        _user_profile_id: Option<i32>,
+    ) -> Result<(), LockError> {
+        MockConfig::respond(config).await?;
+
+        tracing::info!("Mock lock of door {door_id} unlocked");
+        self.unlocked.lock().unwrap().insert(door_id);
+        Ok(())
+    }
+
+    async fn lock(
+        &self,
+        door_id: i32,
+        config: &serde_json::Value,
+        _user_profile_id: Option<i32>,
+    ) -> Result<(), LockError> {
+        MockConfig::respond(config).await?;
+
+        tracing::info!("Mock lock of door {door_id} locked");
+        self.unlocked.lock().unwrap().remove(&door_id);
+        Ok(())
+    }
+}
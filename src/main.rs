@@ -16,6 +16,7 @@ use axum::{
 use diesel::{associations::HasTable, prelude::*};
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use dotenv::dotenv;
+use ed25519_dalek::VerifyingKey;
 use futures::{sink::SinkExt, stream::StreamExt};
 use http::{
     header::{self, CONTENT_TYPE},
@@ -36,17 +37,47 @@ use tracing_subscriber::{fmt::layer, layer::SubscriberExt, util::SubscriberInitE
 
 use crate::{
     db::establish_connection,
+    devices::DeviceHub,
+    events::EventBus,
+    firmware::FirmwareStore,
+    locks::LockDrivers,
+    snapshots::SnapshotStore,
     models::DoorPermission,
     schema::{door, door_code, door_permission, user_profile},
+    webhooks::Webhooks,
 };
 
 #[macro_use]
 extern crate diesel;
 
+mod access;
+mod allowlist;
+mod calendars;
 mod db;
+mod device_config;
+mod devices;
+mod diagnostics;
+mod door_state;
+mod emergency;
+mod events;
+mod firmware;
+mod guest_tokens;
+mod home_assistant;
+mod lock_mode;
+mod locks;
 mod models;
+mod mqtt;
+mod outbound;
 mod routes;
 mod schema;
+mod signing;
+mod snapshots;
+mod telemetry;
+#[cfg(test)]
+mod testing;
+mod totp;
+mod unlock_schedules;
+mod webhooks;
 
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/");
 static COOKIE_NAME: &str = "SESSION";
@@ -61,9 +92,40 @@ async fn main() {
     // `MemoryStore` is just used as an example. Don't use this in production.
     let store = MemoryStore::new();
     let oauth_client = oauth_client();
+    let discord_key = discord_public_key();
+    let events = EventBus::new();
+    events::spawn_retention();
+    let webhooks = Webhooks::new();
+    webhooks.spawn_dispatcher(&events);
+    door_state::spawn_held_open_monitor(&events);
+    telemetry::spawn_offline_monitor(&events);
+    telemetry::spawn_retention();
+    devices::spawn_command_expiry(&events);
+    let devices = DeviceHub::from_env();
+    let locks = LockDrivers::new(&devices);
+    lock_mode::spawn_relock_monitor(&locks, &events);
+    unlock_schedules::spawn_scheduler(&locks, &events);
+    let mqtt = mqtt::MqttConfig::from_env();
+    if let Some(config) = &mqtt {
+        if let Some(home_assistant) = home_assistant::HomeAssistantConfig::from_env() {
+            home_assistant::spawn(config, home_assistant, &locks, &events);
+        }
+        mqtt::spawn(config.clone(), &devices, &events);
+    }
+    let snapshots = SnapshotStore::from_env();
+    snapshots::spawn_retention(&snapshots);
+    let firmware = FirmwareStore::from_env();
     let app_state = AppState {
         store,
         oauth_client,
+        discord_key,
+        events,
+        devices,
+        locks,
+        snapshots,
+        firmware,
+        webhooks,
+        mqtt,
     };
 
     let cors = CorsLayer::new()
@@ -82,6 +144,16 @@ async fn main() {
                 .nest("/users", routes::user::create_router(app_state.clone()))
                 .nest("/auth", routes::auth::create_router(app_state.clone()))
                 .nest("/ws", routes::websocket::create_router(app_state.clone()))
+                .nest("/discord", routes::discord::create_router(app_state.clone()))
+                .nest("/webhooks", routes::webhook::create_router(app_state.clone()))
+                .nest("/events", routes::event_stream::create_router(app_state.clone()))
+                .nest("/tokens", routes::token::create_router(app_state.clone()))
+                .nest("/devices", routes::device::create_router(app_state.clone()))
+                .nest("/credentials", routes::credential::create_router(app_state.clone()))
+                .nest("/firmware", routes::firmware::create_router(app_state.clone()))
+                .nest("/calendars", routes::calendar::create_router(app_state.clone()))
+                .nest("/emergency", routes::emergency::create_router(app_state.clone()))
+                .nest("/mqtt", routes::mqtt::create_router(app_state.clone()))
                 .layer(cors),
         )
         .layer(
@@ -90,7 +162,7 @@ async fn main() {
                 .on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
         );
 
-    axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
+    axum::Server::bind(&bind_address())
         // axum::Server::bind(&"127.0.0.1:3000".parse().unwrap())
         .serve(app.into_make_service())
         .await
@@ -101,6 +173,14 @@ async fn main() {
 pub struct AppState {
     store: MemoryStore,
     oauth_client: BasicClient,
+    discord_key: Option<VerifyingKey>,
+    events: EventBus,
+    devices: DeviceHub,
+    locks: LockDrivers,
+    snapshots: SnapshotStore,
+    firmware: FirmwareStore,
+    webhooks: Webhooks,
+    mqtt: Option<mqtt::MqttConfig>,
 }
 
 impl FromRef<AppState> for MemoryStore {
@@ -115,6 +195,54 @@ impl FromRef<AppState> for BasicClient {
     }
 }
 
+impl FromRef<AppState> for Option<VerifyingKey> {
+    fn from_ref(state: &AppState) -> Self {
+        state.discord_key
+    }
+}
+
+impl FromRef<AppState> for EventBus {
+    fn from_ref(state: &AppState) -> Self {
+        state.events.clone()
+    }
+}
+
+impl FromRef<AppState> for DeviceHub {
+    fn from_ref(state: &AppState) -> Self {
+        state.devices.clone()
+    }
+}
+
+impl FromRef<AppState> for LockDrivers {
+    fn from_ref(state: &AppState) -> Self {
+        state.locks.clone()
+    }
+}
+
+impl FromRef<AppState> for SnapshotStore {
+    fn from_ref(state: &AppState) -> Self {
+        state.snapshots.clone()
+    }
+}
+
+impl FromRef<AppState> for FirmwareStore {
+    fn from_ref(state: &AppState) -> Self {
+        state.firmware.clone()
+    }
+}
+
+impl FromRef<AppState> for Webhooks {
+    fn from_ref(state: &AppState) -> Self {
+        state.webhooks.clone()
+    }
+}
+
+impl FromRef<AppState> for Option<mqtt::MqttConfig> {
+    fn from_ref(state: &AppState) -> Self {
+        state.mqtt.clone()
+    }
+}
+
 fn oauth_client() -> BasicClient {
     dotenv().ok();
 
@@ -131,3 +259,27 @@ fn oauth_client() -> BasicClient {
     )
     .set_redirect_uri(RedirectUrl::new(redirect_url).unwrap())
 }
+
+// Discord commands stay off unless the application's key is configured
+fn discord_public_key() -> Option<VerifyingKey> {
+    dotenv().ok();
+
+    let public_key = env::var("DISCORD_PUBLIC_KEY").ok()?;
+    let bytes: [u8; 32] = hex::decode(public_key)
+        .expect("DISCORD_PUBLIC_KEY is not valid hex")
+        .try_into()
+        .expect("DISCORD_PUBLIC_KEY must be 32 bytes");
+    let key =
+        VerifyingKey::from_bytes(&bytes).expect("DISCORD_PUBLIC_KEY is not a valid Ed25519 key");
+    Some(key)
+}
+
+// Lets tests and the simulator run a server next to the usual one
+fn bind_address() -> SocketAddr {
+    dotenv().ok();
+
+    env::var("BIND_ADDRESS")
+        .unwrap_or_else(|_| "0.0.0.0:3000".to_string())
+        .parse()
+        .expect("BIND_ADDRESS must be an address like 0.0.0.0:3000")
+}
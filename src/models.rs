@@ -1,5 +1,7 @@
 use async_session::async_trait;
+use async_session::chrono::NaiveDate;
 use async_session::chrono::NaiveDateTime;
+use async_session::chrono::NaiveTime;
 use async_session::MemoryStore;
 use async_session::SessionStore;
 use axum::extract::rejection::TypedHeaderRejectionReason;
@@ -15,10 +17,34 @@ use serde::Serialize;
 
 use crate::routes::auth::AuthRedirect;
 use crate::schema::access_history;
+use crate::schema::allowlist_version;
+use crate::schema::api_token;
+use crate::schema::calendar;
+use crate::schema::calendar_exception;
+use crate::schema::credential;
+use crate::schema::device;
+use crate::schema::device_command;
+use crate::schema::device_config;
+use crate::schema::device_group;
+use crate::schema::device_telemetry;
 use crate::schema::door;
 use crate::schema::door_code;
+use crate::schema::door_lock;
+use crate::schema::door_mode;
 use crate::schema::door_permission;
+use crate::schema::door_state;
+use crate::schema::doorbell_ring;
+use crate::schema::emergency;
+use crate::schema::firmware_image;
+use crate::schema::firmware_update;
+use crate::schema::guest_token;
+use crate::schema::responder;
+use crate::schema::snapshot;
+use crate::schema::unlock_exception;
+use crate::schema::unlock_schedule;
 use crate::schema::user_profile;
+use crate::schema::webhook_delivery;
+use crate::schema::webhook_subscription;
 use crate::COOKIE_NAME;
 
 #[derive(Queryable, Selectable, Identifiable, Insertable, Serialize, Deserialize, Debug, Clone)]
@@ -39,6 +65,7 @@ pub struct Door {
     pub id: i32,
     pub about: Option<String>,
     pub owner_id: Option<i32>,
+    pub held_open_seconds: i32,
 }
 
 #[derive(Serialize, Deserialize, Insertable, Clone)]
@@ -48,6 +75,9 @@ pub struct Door {
 pub struct InsertedDoor {
     pub about: Option<String>,
     pub owner_id: Option<i32>,
+    // Falls back to the column default when left out
+    #[serde(default)]
+    pub held_open_seconds: Option<i32>,
 }
 
 #[derive(
@@ -72,6 +102,17 @@ pub struct DoorPermission {
     pub user_profile_id: i32,
     pub edit_permission: bool,
     pub open_permission: bool,
+    // Weekdays as a bitmask, Monday = 1 through Sunday = 64, with an optional
+    // daily window in server local time. No days means any day.
+    #[serde(default)]
+    pub schedule_days: Option<i16>,
+    #[serde(default)]
+    pub schedule_start: Option<NaiveTime>,
+    #[serde(default)]
+    pub schedule_end: Option<NaiveTime>,
+    // Holidays and closures that override the schedule
+    #[serde(default)]
+    pub calendar_id: Option<i32>,
 }
 
 #[derive(
@@ -115,6 +156,430 @@ pub struct DoorCode {
 pub struct AccessHistory {
     pub id: i32,
     pub door_id: i32,
-    pub user_profile_id: i32,
+    // Empty for codes and guest tokens
+    pub user_profile_id: Option<i32>,
     pub access_timestamp: NaiveDateTime,
+    pub method: String,
+    // False when no controller confirmed the open in time
+    pub opened: bool,
+    // Offline opens the user was not allowed at the time
+    pub flagged: bool,
+    // The token behind offline guest opens
+    pub guest_token_id: Option<i32>,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[diesel(table_name = webhook_subscription)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct WebhookSubscription {
+    pub id: i32,
+    pub door_id: Option<i32>,
+    pub owner_id: i32,
+    pub url: String,
+    // Only handed out once, when the subscription is created
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub event_types: Vec<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(
+    Queryable,
+    Selectable,
+    Identifiable,
+    Associations,
+    Debug,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    Clone,
+)]
+#[diesel(table_name = webhook_delivery)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(belongs_to(WebhookSubscription, foreign_key = subscription_id))]
+pub struct WebhookDelivery {
+    pub id: i32,
+    pub subscription_id: i32,
+    pub event_type: String,
+    pub payload: String,
+    pub attempts: i32,
+    pub response_status: Option<i32>,
+    pub delivered: bool,
+    pub created_at: NaiveDateTime,
+    pub last_attempt_at: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[diesel(table_name = api_token)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ApiToken {
+    pub id: i32,
+    pub user_profile_id: i32,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[diesel(table_name = device)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Device {
+    pub id: i32,
+    pub door_id: i32,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: Option<NaiveDateTime>,
+    pub online: bool,
+    pub firmware_version: Option<String>,
+    // Last allowlist version the device confirmed
+    pub allowlist_version: i32,
+    pub group_id: Option<i32>,
+    // Overrides the group's target firmware
+    pub target_firmware_id: Option<i32>,
+    // Last configuration version the device applied
+    pub config_version: i32,
+    pub protocol_version: i32,
+}
+
+#[derive(
+    Queryable,
+    Selectable,
+    Identifiable,
+    Associations,
+    Debug,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    Clone,
+)]
+#[diesel(table_name = credential)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(belongs_to(UserProfile))]
+pub struct Credential {
+    pub id: i32,
+    pub user_profile_id: i32,
+    pub kind: String,
+    // Card UID, or the hash of a PIN
+    #[serde(skip_serializing)]
+    pub value: String,
+    pub label: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[diesel(table_name = allowlist_version)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(primary_key(door_id, version))]
+pub struct AllowlistVersion {
+    pub door_id: i32,
+    pub version: i32,
+    pub entries: serde_json::Value,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(
+    Queryable,
+    Selectable,
+    Identifiable,
+    Associations,
+    Debug,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    Clone,
+)]
+#[diesel(table_name = device_command)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(belongs_to(Device))]
+pub struct DeviceCommandEntry {
+    pub id: i32,
+    pub device_id: i32,
+    pub door_id: i32,
+    pub user_profile_id: Option<i32>,
+    pub command: serde_json::Value,
+    pub status: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub delivered_at: Option<NaiveDateTime>,
+    pub acknowledged_at: Option<NaiveDateTime>,
+    // How the user got in, set on opens only
+    pub method: Option<String>,
+    // Shared by the commands one request queued for the door's controllers
+    pub group_id: String,
+    // Whether the group's open is in the history already
+    pub recorded: bool,
+}
+
+#[derive(
+    Queryable,
+    Selectable,
+    Identifiable,
+    Associations,
+    Debug,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    Clone,
+)]
+#[diesel(table_name = device_telemetry)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(belongs_to(Device))]
+pub struct DeviceTelemetry {
+    pub id: i32,
+    pub device_id: i32,
+    pub uptime_seconds: i64,
+    pub rssi: i32,
+    pub free_heap: i32,
+    pub firmware_version: String,
+    pub recorded_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[diesel(table_name = doorbell_ring)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct DoorbellRing {
+    pub id: i32,
+    pub door_id: i32,
+    pub device_id: Option<i32>,
+    pub rung_at: NaiveDateTime,
+    pub answered_by: Option<i32>,
+    pub answered_at: Option<NaiveDateTime>,
+    pub answer_latency_ms: Option<i32>,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[diesel(table_name = snapshot)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Snapshot {
+    pub id: i32,
+    pub door_id: i32,
+    pub device_id: Option<i32>,
+    pub access_history_id: Option<i32>,
+    pub doorbell_ring_id: Option<i32>,
+    #[serde(skip_serializing)]
+    pub file_name: String,
+    pub size_bytes: i32,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[diesel(table_name = door_lock)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(primary_key(door_id))]
+pub struct DoorLock {
+    pub door_id: i32,
+    pub driver: String,
+    pub config: serde_json::Value,
+    pub updated_by: Option<i32>,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[diesel(table_name = door_mode)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(primary_key(door_id))]
+pub struct DoorMode {
+    pub door_id: i32,
+    pub mode: String,
+    pub unlocked_until: Option<NaiveDateTime>,
+    pub changed_by: Option<i32>,
+    pub changed_at: NaiveDateTime,
+    // Set by an unlock schedule rather than by someone
+    pub scheduled: bool,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[diesel(table_name = door_state)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(primary_key(door_id))]
+pub struct DoorState {
+    pub door_id: i32,
+    pub state: String,
+    pub locked: Option<bool>,
+    pub changed_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[diesel(table_name = guest_token)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct GuestToken {
+    pub id: i32,
+    pub door_id: i32,
+    pub creator_id: i32,
+    pub nonce: String,
+    pub label: Option<String>,
+    pub valid_from: NaiveDateTime,
+    pub valid_until: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[diesel(table_name = firmware_image)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct FirmwareImage {
+    pub id: i32,
+    pub version: String,
+    pub sha256: String,
+    // Ed25519 over the SHA-256 digest, checked against FIRMWARE_PUBLIC_KEY
+    // on upload
+    pub signature: String,
+    pub size_bytes: i32,
+    #[serde(skip_serializing)]
+    pub file_name: String,
+    pub uploaded_by: Option<i32>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[diesel(table_name = device_group)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct DeviceGroup {
+    pub id: i32,
+    pub name: String,
+    pub target_firmware_id: Option<i32>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(
+    Queryable,
+    Selectable,
+    Identifiable,
+    Associations,
+    Debug,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    Clone,
+)]
+#[diesel(table_name = firmware_update)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(belongs_to(Device))]
+pub struct FirmwareUpdate {
+    pub id: i32,
+    pub device_id: i32,
+    pub firmware_image_id: Option<i32>,
+    pub version: String,
+    pub status: String,
+    pub detail: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(
+    Queryable,
+    Selectable,
+    Identifiable,
+    Associations,
+    Debug,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    Clone,
+)]
+#[diesel(table_name = device_config)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(belongs_to(Device))]
+#[diesel(primary_key(device_id, version))]
+pub struct DeviceConfigVersion {
+    pub device_id: i32,
+    pub version: i32,
+    pub config: serde_json::Value,
+    pub created_by: Option<i32>,
+    pub created_at: NaiveDateTime,
+    pub applied_at: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[diesel(table_name = unlock_schedule)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct UnlockSchedule {
+    pub id: i32,
+    pub door_id: i32,
+    // Weekdays as a bitmask, Monday = 1 through Sunday = 64. Windows whose end
+    // is before their start run over midnight.
+    pub days: i16,
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+    pub label: Option<String>,
+    #[serde(skip_serializing)]
+    pub applied_window_start: Option<NaiveDateTime>,
+    pub created_by: Option<i32>,
+    pub created_at: NaiveDateTime,
+    pub calendar_id: Option<i32>,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[diesel(table_name = unlock_exception)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(primary_key(door_id, date))]
+pub struct UnlockException {
+    pub door_id: i32,
+    pub date: NaiveDate,
+    pub label: Option<String>,
+    pub created_by: Option<i32>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[diesel(table_name = calendar)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Calendar {
+    pub id: i32,
+    pub name: String,
+    pub owner_id: Option<i32>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(
+    Queryable,
+    Selectable,
+    Identifiable,
+    Insertable,
+    Associations,
+    Debug,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    Clone,
+)]
+#[diesel(table_name = calendar_exception)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(belongs_to(Calendar))]
+#[diesel(primary_key(calendar_id, date))]
+pub struct CalendarException {
+    pub calendar_id: i32,
+    pub date: NaiveDate,
+    pub label: Option<String>,
+    // Both set for custom hours, neither for closed all day
+    pub start_time: Option<NaiveTime>,
+    pub end_time: Option<NaiveTime>,
+    pub uid: Option<String>,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[diesel(table_name = emergency)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Emergency {
+    pub id: i32,
+    pub mode: String,
+    pub reason: Option<String>,
+    pub triggered_by: Option<i32>,
+    pub triggered_at: NaiveDateTime,
+    // Still in force while not cleared
+    pub cleared_by: Option<i32>,
+    pub cleared_at: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, Selectable, Identifiable, Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[diesel(table_name = responder)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(primary_key(user_profile_id))]
+pub struct Responder {
+    pub user_profile_id: i32,
+    pub added_by: Option<i32>,
+    pub added_at: NaiveDateTime,
 }
@@ -0,0 +1,521 @@
+use diesel::prelude::*;
+use dotenv::dotenv;
+use protocol::{CommandFrame, DeviceMessage, Encoding};
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, QoS};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    env, fmt,
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+
+use crate::{
+    db::establish_connection,
+    devices,
+    devices::DeviceHub,
+    events::EventBus,
+    models::Device,
+    routes::auth::{device_by_token, hash_token},
+    schema::device,
+};
+
+const DEFAULT_PORT: u16 = 1883;
+const DEFAULT_PREFIX: &str = "doors";
+const DEFAULT_CLIENT_ID: &str = "door-server";
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const CHANNEL_CAPACITY: usize = 64;
+
+// Every controller gets its own topics under `<prefix>/<door_id>/<device_id>/`:
+//
+//   session    hello, controller to server
+//   status     `online` or `offline`, the controller's retained last will
+//   heartbeat  heartbeats
+//   events     sensor events, keypad codes, offline opens, reports and logs
+//   acks       acknowledged commands, allowlists and configurations
+//   commands   command frames, server to controller
+//
+// The broker is the trust boundary. Controllers log in with their device ID
+// as username and their token as password, and the broker checks both and
+// every topic through the `/mqtt` routes (the HTTP backend of
+// mosquitto-go-auth and similar plugins). That keeps each controller on its
+// own topics, so the topic names the sender and no token is ever published.
+//
+// The server keeps `online` retained on `<prefix>/server` with `offline` as
+// its last will. Controllers say hello again when it comes back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Session,
+    Status,
+    Heartbeat,
+    Events,
+    Acks,
+    Commands,
+}
+
+impl Channel {
+    const ALL: [Channel; 6] = [
+        Channel::Session,
+        Channel::Status,
+        Channel::Heartbeat,
+        Channel::Events,
+        Channel::Acks,
+        Channel::Commands,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Channel::Session => "session",
+            Channel::Status => "status",
+            Channel::Heartbeat => "heartbeat",
+            Channel::Events => "events",
+            Channel::Acks => "acks",
+            Channel::Commands => "commands",
+        }
+    }
+
+    pub fn parse(channel: &str) -> Option<Self> {
+        Channel::ALL
+            .into_iter()
+            .find(|candidate| candidate.as_str() == channel)
+    }
+
+    // Keeps controllers from smuggling messages past broker ACLs on other topics
+    fn accepts(&self, message: &DeviceMessage) -> bool {
+        match self {
+            // The broker already authenticated the controller, a token here
+            // would only leak it to whoever can read the topic
+            Channel::Session => matches!(message, DeviceMessage::Hello { .. }),
+            Channel::Heartbeat => matches!(message, DeviceMessage::Heartbeat(_)),
+            Channel::Acks => matches!(
+                message,
+                DeviceMessage::Ack { .. }
+                    | DeviceMessage::AllowlistAck { .. }
+                    | DeviceMessage::ConfigApplied { .. }
+            ),
+            Channel::Events => matches!(
+                message,
+                DeviceMessage::Doorbell
+                    | DeviceMessage::DoorContact { .. }
+                    | DeviceMessage::LockSensor { .. }
+                    | DeviceMessage::KeypadCode { .. }
+                    | DeviceMessage::OfflineOpens { .. }
+                    | DeviceMessage::FirmwareStatus(_)
+                    | DeviceMessage::Log { .. }
+                    | DeviceMessage::DiagnosticResult(_)
+            ),
+            Channel::Status | Channel::Commands => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Incoming {
+    Status { online: bool },
+    Message(DeviceMessage),
+}
+
+#[derive(Debug)]
+pub struct IncomingPublish {
+    pub door_id: i32,
+    pub device_id: i32,
+    pub incoming: Incoming,
+}
+
+#[derive(Debug)]
+pub enum TopicError {
+    NotOurs,
+    UnknownChannel(String),
+    Undecodable(String),
+    WrongChannel(Channel),
+}
+
+impl fmt::Display for TopicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TopicError::NotOurs => write!(f, "topic is not a controller topic"),
+            TopicError::UnknownChannel(channel) => write!(f, "unknown channel `{channel}`"),
+            TopicError::Undecodable(e) => write!(f, "{e}"),
+            TopicError::WrongChannel(channel) => {
+                write!(f, "message does not belong on `{}`", channel.as_str())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub credentials: Option<(String, String)>,
+    pub prefix: String,
+    // MQTT has nothing to negotiate with, every controller on the broker
+    // speaks the same encoding
+    pub encoding: Encoding,
+}
+
+impl MqttConfig {
+    // The transport stays off unless a broker is configured
+    pub fn from_env() -> Option<Self> {
+        dotenv().ok();
+
+        let host = env::var("MQTT_HOST").ok()?;
+        let port = env::var("MQTT_PORT")
+            .ok()
+            .map(|port| port.parse().expect("MQTT_PORT must be a number"))
+            .unwrap_or(DEFAULT_PORT);
+        // The broker tells the server apart from controllers by these
+        let credentials = (
+            env::var("MQTT_USERNAME").expect("MQTT_USERNAME must be set"),
+            env::var("MQTT_PASSWORD").expect("MQTT_PASSWORD must be set"),
+        );
+        let encoding = env::var("MQTT_ENCODING")
+            .ok()
+            .map(|encoding| {
+                Encoding::from_name(&encoding).expect("MQTT_ENCODING must be json, cbor or msgpack")
+            })
+            .unwrap_or(Encoding::Json);
+
+        Some(MqttConfig {
+            host,
+            port,
+            client_id: env::var("MQTT_CLIENT_ID").unwrap_or_else(|_| DEFAULT_CLIENT_ID.to_string()),
+            credentials: Some(credentials),
+            prefix: env::var("MQTT_TOPIC_PREFIX").unwrap_or_else(|_| DEFAULT_PREFIX.to_string()),
+            encoding,
+        })
+    }
+
+    // Connection settings for a client of this broker
+    pub fn options(&self, client_id: &str) -> MqttOptions {
+        let mut options = MqttOptions::new(client_id, &self.host, self.port);
+        options.set_keep_alive(KEEP_ALIVE);
+        if let Some((username, password)) = &self.credentials {
+            options.set_credentials(username, password);
+        }
+        options
+    }
+
+    pub fn server_topic(&self) -> String {
+        format!("{}/server", self.prefix)
+    }
+
+    // Whether the broker should let a client with these credentials in. The
+    // username of a controller is its device ID.
+    pub fn authenticate(&self, conn: &mut PgConnection, username: &str, password: &str) -> bool {
+        if self.is_server(username) {
+            let (_, expected) = self.credentials.as_ref().unwrap();
+            return hash_token(password) == hash_token(expected);
+        }
+
+        let Ok(device_id) = username.parse::<i32>() else {
+            return false;
+        };
+        device_by_token(conn, password).is_ok_and(|device| device.id == device_id)
+    }
+
+    pub fn is_server(&self, username: &str) -> bool {
+        self.credentials
+            .as_ref()
+            .is_some_and(|(server, _)| server == username)
+    }
+
+    // Controllers may publish on their own topics and read their commands and
+    // the server status, nothing else. Wildcard subscriptions never match.
+    pub fn authorize(
+        &self,
+        door_id: i32,
+        device_id: i32,
+        topic: &str,
+        access: TopicAccess,
+    ) -> bool {
+        if topic == self.server_topic() {
+            return access == TopicAccess::Read;
+        }
+
+        let own = format!("{}/{door_id}/{device_id}/", self.prefix);
+        let Some(channel) = topic.strip_prefix(&own).and_then(Channel::parse) else {
+            return false;
+        };
+
+        match access {
+            TopicAccess::Read => channel == Channel::Commands,
+            TopicAccess::Write => channel != Channel::Commands,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopicAccess {
+    // Subscribing to or receiving from the topic
+    Read,
+    Write,
+}
+
+// The server's connection to the broker
+#[derive(Clone)]
+pub struct MqttTransport {
+    client: AsyncClient,
+    server_topic: String,
+    prefix: String,
+    encoding: Encoding,
+}
+
+impl MqttTransport {
+    pub fn new(config: &MqttConfig) -> (Self, EventLoop) {
+        let mut options = config.options(&config.client_id);
+        options.set_last_will(LastWill::new(
+            config.server_topic(),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        let (client, event_loop) = AsyncClient::new(options, CHANNEL_CAPACITY);
+        let transport = MqttTransport {
+            client,
+            server_topic: config.server_topic(),
+            prefix: config.prefix.clone(),
+            encoding: config.encoding,
+        };
+        (transport, event_loop)
+    }
+
+    pub fn topic(&self, door_id: i32, device_id: i32, channel: Channel) -> String {
+        format!("{}/{door_id}/{device_id}/{}", self.prefix, channel.as_str())
+    }
+
+    // Has to be repeated after every reconnect, the broker may have lost it
+    pub async fn subscribe(&self) -> Result<(), rumqttc::ClientError> {
+        self.client
+            .subscribe(format!("{}/+/+/+", self.prefix), QoS::AtLeastOnce)
+            .await
+    }
+
+    // Tells controllers to say hello again, whatever sessions they had died
+    // with the previous connection
+    pub async fn announce(&self) -> Result<(), rumqttc::ClientError> {
+        self.client
+            .publish(&self.server_topic, QoS::AtLeastOnce, true, "online")
+            .await
+    }
+
+    pub fn parse(&self, topic: &str, payload: &[u8]) -> Result<IncomingPublish, TopicError> {
+        let mut parts = topic
+            .strip_prefix(&self.prefix)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .ok_or(TopicError::NotOurs)?
+            .split('/');
+
+        let (Some(door_id), Some(device_id), Some(channel), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(TopicError::NotOurs);
+        };
+        let (Ok(door_id), Ok(device_id)) = (door_id.parse(), device_id.parse()) else {
+            return Err(TopicError::NotOurs);
+        };
+        let channel = Channel::parse(channel)
+            .ok_or_else(|| TopicError::UnknownChannel(channel.to_string()))?;
+
+        let incoming = match channel {
+            // Our own commands come back through the wildcard subscription
+            Channel::Commands => return Err(TopicError::NotOurs),
+            Channel::Status => Incoming::Status {
+                online: payload == b"online",
+            },
+            channel => {
+                let message: DeviceMessage = self
+                    .encoding
+                    .decode(payload)
+                    .map_err(|e| TopicError::Undecodable(e.to_string()))?;
+                if !channel.accepts(&message) {
+                    return Err(TopicError::WrongChannel(channel));
+                }
+                Incoming::Message(message)
+            }
+        };
+
+        Ok(IncomingPublish {
+            door_id,
+            device_id,
+            incoming,
+        })
+    }
+
+    pub async fn send(
+        &self,
+        door_id: i32,
+        device_id: i32,
+        frame: &CommandFrame,
+    ) -> Result<(), rumqttc::ClientError> {
+        let payload = self.encoding.encode(frame).unwrap();
+        self.client
+            .publish(
+                self.topic(door_id, device_id, Channel::Commands),
+                QoS::AtLeastOnce,
+                false,
+                payload,
+            )
+            .await
+    }
+}
+
+// A controller the server heard from. Its commands are forwarded to the
+// broker until it goes offline.
+struct Session {
+    device: Device,
+    forwarder: JoinHandle<()>,
+}
+
+impl Session {
+    async fn end(self, hub: &DeviceHub) {
+        self.forwarder.abort();
+        // The hub only lets go of the device once the receiver is gone
+        let _ = self.forwarder.await;
+        hub.disconnect(self.device.id);
+    }
+}
+
+pub fn spawn(config: MqttConfig, hub: &DeviceHub, events: &EventBus) {
+    let hub = hub.clone();
+    let events = events.clone();
+
+    tokio::spawn(async move {
+        let (transport, mut event_loop) = MqttTransport::new(&config);
+        let mut sessions: HashMap<i32, Session> = HashMap::new();
+
+        tracing::info!("Connecting to MQTT broker {}:{}", config.host, config.port);
+
+        loop {
+            match event_loop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    if let Err(e) = transport.subscribe().await {
+                        tracing::error!("Could not subscribe to controller topics: {e}");
+                    }
+                    if let Err(e) = transport.announce().await {
+                        tracing::error!("Could not announce the server on MQTT: {e}");
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    match transport.parse(&publish.topic, &publish.payload) {
+                        Ok(publish) => {
+                            let conn = &mut establish_connection();
+                            handle_publish(conn, &transport, &mut sessions, &hub, &events, publish)
+                                .await
+                        }
+                        Err(TopicError::NotOurs) => {}
+                        Err(e) => tracing::warn!("Ignoring MQTT message on {}: {e}", publish.topic),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::error!("MQTT connection failed: {e}");
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    });
+}
+
+async fn handle_publish(
+    conn: &mut PgConnection,
+    transport: &MqttTransport,
+    sessions: &mut HashMap<i32, Session>,
+    hub: &DeviceHub,
+    events: &EventBus,
+    publish: IncomingPublish,
+) {
+    let IncomingPublish {
+        door_id,
+        device_id,
+        incoming,
+    } = publish;
+
+    let message = match incoming {
+        Incoming::Status { online: false } => {
+            if let Some(session) = sessions.remove(&device_id) {
+                session.end(hub).await;
+                tracing::info!("Device {device_id} of door {door_id} went offline over MQTT");
+            }
+            return;
+        }
+        Incoming::Status { online: true } => None,
+        Incoming::Message(message) => Some(message),
+    };
+
+    // The hub closes the channel of a deleted device, which ends the
+    // forwarder. Starting over looks the device up again.
+    if sessions
+        .get(&device_id)
+        .is_some_and(|session| session.forwarder.is_finished())
+    {
+        if let Some(session) = sessions.remove(&device_id) {
+            session.end(hub).await;
+        }
+    }
+
+    // Sessions only live in memory. After a restart the retained status or
+    // the next message of each controller starts a new one.
+    let session = match sessions.entry(device_id) {
+        Entry::Occupied(session) => session.into_mut(),
+        Entry::Vacant(entry) => {
+            let Some(session) = start_session(conn, transport, hub, events, door_id, device_id)
+            else {
+                tracing::warn!(
+                    "Ignoring MQTT message of unknown device {device_id} of door {door_id}"
+                );
+                return;
+            };
+            entry.insert(session)
+        }
+    };
+
+    match message {
+        Some(DeviceMessage::Hello {
+            protocol_version, ..
+        }) => {
+            let reply = devices::hello(conn, &session.device, protocol_version);
+            hub.push(device_id, reply);
+        }
+        Some(message) => devices::handle_message(conn, events, hub, &session.device, message),
+        None => {}
+    }
+}
+
+// The broker only lets a controller publish on its own topics, so the topic
+// is enough to know who is talking. It still has to name an existing device.
+fn start_session(
+    conn: &mut PgConnection,
+    transport: &MqttTransport,
+    hub: &DeviceHub,
+    events: &EventBus,
+    door_id: i32,
+    device_id: i32,
+) -> Option<Session> {
+    let device = device::table
+        .find(device_id)
+        .filter(device::door_id.eq(door_id))
+        .select(Device::as_select())
+        .first(conn)
+        .ok()?;
+
+    let mut commands = hub.connect(&device);
+    let forwarder = {
+        let transport = transport.clone();
+        tokio::spawn(async move {
+            while let Some(frame) = commands.recv().await {
+                if let Err(e) = transport.send(door_id, device_id, &frame).await {
+                    tracing::error!("Could not publish command for device {device_id}: {e}");
+                }
+            }
+        })
+    };
+
+    tracing::info!("Device {device_id} of door {door_id} connected over MQTT");
+    devices::on_connect(conn, events, hub, &device);
+    Some(Session { device, forwarder })
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,620 @@
+use async_session::chrono::NaiveDateTime;
+use diesel::prelude::*;
+use protocol::{
+    diagnostics::Diagnostic, CommandFrame, DeviceCommand, DeviceMessage, Encoding, Heartbeat,
+};
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+use super::{
+    handle_publish, Channel, Incoming, IncomingPublish, MqttConfig, MqttTransport, TopicAccess,
+    TopicError,
+};
+use crate::{devices::DeviceHub, schema::device, testing};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// Just enough of an MQTT 3.1.1 broker to route publishes between clients.
+// Everything is delivered with QoS 0 and nothing is retained.
+struct Broker {
+    port: u16,
+}
+
+type Subscribers = Arc<Mutex<Vec<(String, mpsc::UnboundedSender<Vec<u8>>)>>>;
+
+impl Broker {
+    async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let subscribers = Subscribers::default();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve(stream, subscribers.clone()));
+            }
+        });
+
+        Broker { port }
+    }
+
+    fn config(&self, client_id: &str, encoding: Encoding) -> MqttConfig {
+        MqttConfig {
+            host: "127.0.0.1".to_string(),
+            port: self.port,
+            client_id: client_id.to_string(),
+            credentials: None,
+            prefix: "doors".to_string(),
+            encoding,
+        }
+    }
+
+    fn client(&self, client_id: &str) -> (AsyncClient, EventLoop) {
+        AsyncClient::new(MqttOptions::new(client_id, "127.0.0.1", self.port), 16)
+    }
+}
+
+async fn read_packet(stream: &mut (impl AsyncRead + Unpin)) -> Option<(u8, Vec<u8>)> {
+    let header = stream.read_u8().await.ok()?;
+
+    let mut length = 0usize;
+    for shift in (0..28).step_by(7) {
+        let byte = stream.read_u8().await.ok()?;
+        length |= usize::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await.ok()?;
+    Some((header, body))
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut length = body.len();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn read_string(body: &[u8], at: usize) -> (String, usize) {
+    let length = usize::from(u16::from_be_bytes([body[at], body[at + 1]]));
+    let string = String::from_utf8(body[at + 2..at + 2 + length].to_vec()).unwrap();
+    (string, at + 2 + length)
+}
+
+fn matches(filter: &str, topic: &str) -> bool {
+    let mut topic = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level, Some(part)) if level == part => {}
+            _ => return false,
+        }
+    }
+    topic.next().is_none()
+}
+
+async fn serve(stream: TcpStream, subscribers: Subscribers) {
+    let (sender, mut outgoing) = mpsc::unbounded_channel::<Vec<u8>>();
+    let (mut reader, mut writer) = stream.into_split();
+
+    tokio::spawn(async move {
+        while let Some(bytes) = outgoing.recv().await {
+            if writer.write_all(&bytes).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    while let Some((header, body)) = read_packet(&mut reader).await {
+        match header >> 4 {
+            // CONNECT
+            1 => {
+                let _ = sender.send(packet(0x20, &[0, 0]));
+            }
+            // PUBLISH
+            3 => {
+                let qos = (header >> 1) & 0x03;
+                let (topic, mut at) = read_string(&body, 0);
+                if qos > 0 {
+                    let _ = sender.send(packet(0x40, &body[at..at + 2]));
+                    at += 2;
+                }
+
+                let mut forwarded = (topic.len() as u16).to_be_bytes().to_vec();
+                forwarded.extend_from_slice(topic.as_bytes());
+                forwarded.extend_from_slice(&body[at..]);
+                let forwarded = packet(0x30, &forwarded);
+
+                for (filter, subscriber) in subscribers.lock().unwrap().iter() {
+                    if matches(filter, &topic) {
+                        let _ = subscriber.send(forwarded.clone());
+                    }
+                }
+            }
+            // SUBSCRIBE
+            8 => {
+                let mut at = 2;
+                let mut granted = body[..2].to_vec();
+                while at < body.len() {
+                    let (filter, next) = read_string(&body, at);
+                    at = next + 1;
+                    granted.push(0);
+                    subscribers.lock().unwrap().push((filter, sender.clone()));
+                }
+                let _ = sender.send(packet(0x90, &granted));
+            }
+            // PINGREQ
+            12 => {
+                let _ = sender.send(packet(0xD0, &[]));
+            }
+            // DISCONNECT
+            _ => break,
+        }
+    }
+}
+
+// Polls until a publish arrives, answering everything else on the way
+async fn next_publish(event_loop: &mut EventLoop) -> rumqttc::Publish {
+    tokio::time::timeout(TIMEOUT, async {
+        loop {
+            if let Event::Incoming(Packet::Publish(publish)) = event_loop.poll().await.unwrap() {
+                return publish;
+            }
+        }
+    })
+    .await
+    .expect("no publish arrived")
+}
+
+async fn wait_for_connack(event_loop: &mut EventLoop) {
+    tokio::time::timeout(TIMEOUT, async {
+        loop {
+            if let Event::Incoming(Packet::ConnAck(_)) = event_loop.poll().await.unwrap() {
+                return;
+            }
+        }
+    })
+    .await
+    .expect("broker did not accept the connection")
+}
+
+async fn wait_for_suback(event_loop: &mut EventLoop) {
+    tokio::time::timeout(TIMEOUT, async {
+        loop {
+            if let Event::Incoming(Packet::SubAck(_)) = event_loop.poll().await.unwrap() {
+                return;
+            }
+        }
+    })
+    .await
+    .expect("broker did not confirm the subscription")
+}
+
+fn config(encoding: Encoding) -> MqttConfig {
+    MqttConfig {
+        host: "127.0.0.1".to_string(),
+        port: 1883,
+        client_id: "test".to_string(),
+        credentials: Some(("door-server".to_string(), "server secret".to_string())),
+        prefix: "doors".to_string(),
+        encoding,
+    }
+}
+
+fn transport(encoding: Encoding) -> MqttTransport {
+    MqttTransport::new(&config(encoding)).0
+}
+
+#[test]
+fn topics_round_trip() {
+    let transport = transport(Encoding::Json);
+
+    assert_eq!(
+        transport.topic(3, 14, Channel::Commands),
+        "doors/3/14/commands"
+    );
+
+    let payload = Encoding::Json
+        .encode(&DeviceMessage::DoorContact { open: true })
+        .unwrap();
+    let parsed = transport
+        .parse(&transport.topic(3, 14, Channel::Events), &payload)
+        .unwrap();
+
+    assert_eq!((parsed.door_id, parsed.device_id), (3, 14));
+    assert!(matches!(
+        parsed.incoming,
+        Incoming::Message(DeviceMessage::DoorContact { open: true })
+    ));
+}
+
+#[test]
+fn status_comes_from_the_last_will() {
+    let transport = transport(Encoding::Json);
+
+    let parsed = transport.parse("doors/3/14/status", b"offline").unwrap();
+    assert!(matches!(
+        parsed.incoming,
+        Incoming::Status { online: false }
+    ));
+
+    let parsed = transport.parse("doors/3/14/status", b"online").unwrap();
+    assert!(matches!(parsed.incoming, Incoming::Status { online: true }));
+}
+
+#[test]
+fn messages_have_to_use_their_channel() {
+    let transport = transport(Encoding::Json);
+    let open = Encoding::Json
+        .encode(&DeviceMessage::DoorContact { open: true })
+        .unwrap();
+    let auth = Encoding::Json
+        .encode(&DeviceMessage::Auth {
+            token: "secret".to_string(),
+        })
+        .unwrap();
+
+    // Tokens are the broker's business, they never travel over a topic
+    assert!(matches!(
+        transport.parse("doors/3/14/session", &auth),
+        Err(TopicError::WrongChannel(Channel::Session))
+    ));
+    assert!(matches!(
+        transport.parse("doors/3/14/heartbeat", &open),
+        Err(TopicError::WrongChannel(Channel::Heartbeat))
+    ));
+    assert!(matches!(
+        transport.parse("doors/3/14/events", &auth),
+        Err(TopicError::WrongChannel(Channel::Events))
+    ));
+    assert!(matches!(
+        transport.parse("doors/3/14/commands", &open),
+        Err(TopicError::NotOurs)
+    ));
+    assert!(matches!(
+        transport.parse("doors/3/14/gossip", &open),
+        Err(TopicError::UnknownChannel(_))
+    ));
+    assert!(matches!(
+        transport.parse("doors/three/14/events", &open),
+        Err(TopicError::NotOurs)
+    ));
+    assert!(matches!(
+        transport.parse("lights/3/14/events", &open),
+        Err(TopicError::NotOurs)
+    ));
+    assert!(matches!(
+        transport.parse("doors/3/14/events", b"not json"),
+        Err(TopicError::Undecodable(_))
+    ));
+}
+
+#[tokio::test]
+async fn controller_messages_reach_the_server() {
+    for encoding in Encoding::PREFERENCE {
+        let broker = Broker::start().await;
+
+        let (server, mut server_loop) = MqttTransport::new(&broker.config("server", encoding));
+        wait_for_connack(&mut server_loop).await;
+        server.subscribe().await.unwrap();
+        wait_for_suback(&mut server_loop).await;
+
+        let (device, mut device_loop) = broker.client("device-14");
+        let heartbeat = DeviceMessage::Heartbeat(Heartbeat {
+            uptime_seconds: 120,
+            rssi: -58,
+            free_heap: 90_112,
+            firmware_version: "1.4.0".to_string(),
+        });
+        device
+            .publish(
+                server.topic(3, 14, Channel::Heartbeat),
+                QoS::AtLeastOnce,
+                false,
+                encoding.encode(&heartbeat).unwrap(),
+            )
+            .await
+            .unwrap();
+        tokio::spawn(async move { while device_loop.poll().await.is_ok() {} });
+
+        let publish = next_publish(&mut server_loop).await;
+        let parsed = server.parse(&publish.topic, &publish.payload).unwrap();
+
+        assert_eq!((parsed.door_id, parsed.device_id), (3, 14));
+        let Incoming::Message(DeviceMessage::Heartbeat(received)) = parsed.incoming else {
+            panic!(
+                "{} heartbeat arrived as {:?}",
+                encoding.name(),
+                parsed.incoming
+            );
+        };
+        assert_eq!(received.rssi, -58);
+        assert_eq!(received.firmware_version, "1.4.0");
+    }
+}
+
+#[tokio::test]
+async fn commands_reach_the_controller() {
+    for encoding in Encoding::PREFERENCE {
+        let broker = Broker::start().await;
+
+        let (device, mut device_loop) = broker.client("device-14");
+        wait_for_connack(&mut device_loop).await;
+        device
+            .subscribe("doors/3/14/commands", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        wait_for_suback(&mut device_loop).await;
+
+        let (server, mut server_loop) = MqttTransport::new(&broker.config("server", encoding));
+        tokio::spawn(async move { while server_loop.poll().await.is_ok() {} });
+
+        let frame = CommandFrame {
+            id: Some(42),
+            command: DeviceCommand::Open,
+        };
+        server.send(3, 14, &frame).await.unwrap();
+
+        let publish = next_publish(&mut device_loop).await;
+        assert_eq!(publish.topic, "doors/3/14/commands");
+
+        let received: CommandFrame = encoding.decode(&publish.payload).unwrap();
+        assert_eq!(received.id, Some(42));
+        assert!(matches!(received.command, DeviceCommand::Open));
+    }
+}
+
+#[tokio::test]
+async fn other_controllers_do_not_see_the_commands() {
+    let broker = Broker::start().await;
+
+    let (other, mut other_loop) = broker.client("device-15");
+    wait_for_connack(&mut other_loop).await;
+    other
+        .subscribe("doors/3/15/commands", QoS::AtLeastOnce)
+        .await
+        .unwrap();
+    wait_for_suback(&mut other_loop).await;
+
+    let (server, mut server_loop) = MqttTransport::new(&broker.config("server", Encoding::Json));
+    tokio::spawn(async move { while server_loop.poll().await.is_ok() {} });
+
+    let frame = CommandFrame {
+        id: None,
+        command: DeviceCommand::Open,
+    };
+    server.send(3, 14, &frame).await.unwrap();
+
+    let received = tokio::time::timeout(Duration::from_millis(300), async {
+        loop {
+            if let Ok(Event::Incoming(Packet::Publish(publish))) = other_loop.poll().await {
+                return publish;
+            }
+        }
+    })
+    .await;
+    assert!(received.is_err(), "device 15 got a command for device 14");
+}
+
+#[test]
+fn controllers_stay_on_their_own_topics() {
+    let config = config(Encoding::Json);
+    let allowed = |topic: &str, access| config.authorize(3, 14, topic, access);
+
+    assert!(allowed("doors/3/14/events", TopicAccess::Write));
+    assert!(allowed("doors/3/14/session", TopicAccess::Write));
+    assert!(allowed("doors/3/14/commands", TopicAccess::Read));
+    assert!(allowed("doors/server", TopicAccess::Read));
+
+    assert!(!allowed("doors/3/14/commands", TopicAccess::Write));
+    assert!(!allowed("doors/3/14/events", TopicAccess::Read));
+    assert!(!allowed("doors/3/15/events", TopicAccess::Write));
+    assert!(!allowed("doors/4/14/events", TopicAccess::Write));
+    assert!(!allowed("doors/3/15/commands", TopicAccess::Read));
+    assert!(!allowed("doors/3/14/#", TopicAccess::Read));
+    assert!(!allowed("doors/#", TopicAccess::Read));
+    assert!(!allowed("doors/server", TopicAccess::Write));
+    assert!(!allowed(
+        "homeassistant/lock/door_3/config",
+        TopicAccess::Write
+    ));
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn controllers_log_in_with_their_token() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let device = testing::device(conn, door_id, "front");
+    let other = testing::device(conn, door_id, "back");
+    let config = config(Encoding::Json);
+
+    assert!(config.authenticate(conn, &device.id.to_string(), "front"));
+    assert!(!config.authenticate(conn, &device.id.to_string(), "back"));
+    assert!(!config.authenticate(conn, &other.id.to_string(), "front"));
+    assert!(!config.authenticate(conn, "front", "front"));
+
+    assert!(config.authenticate(conn, "door-server", "server secret"));
+    assert!(!config.authenticate(conn, "door-server", "front"));
+    assert!(config.is_server("door-server"));
+    assert!(!config.is_server(&device.id.to_string()));
+}
+
+fn heartbeat() -> DeviceMessage {
+    DeviceMessage::Heartbeat(Heartbeat {
+        uptime_seconds: 120,
+        rssi: -58,
+        free_heap: 90_112,
+        firmware_version: "1.4.0".to_string(),
+    })
+}
+
+fn is_connected(hub: &DeviceHub, device_id: i32) -> bool {
+    hub.push(device_id, DeviceCommand::Diagnostic(Diagnostic::SensorRead))
+}
+
+#[tokio::test]
+#[ignore = "needs TEST_DATABASE_URL"]
+async fn sessions_come_back_after_a_restart() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let front = testing::device(conn, door_id, "front");
+    let back = testing::device(conn, door_id, "back");
+    let events = testing::events();
+    let hub = DeviceHub::for_tests();
+    let transport = transport(Encoding::Json);
+
+    // A fresh server only knows what the broker hands it
+    let mut sessions = HashMap::new();
+
+    // The retained status of a controller that stayed connected
+    let publish = IncomingPublish {
+        door_id,
+        device_id: front.id,
+        incoming: Incoming::Status { online: true },
+    };
+    handle_publish(conn, &transport, &mut sessions, &hub, &events, publish).await;
+    assert!(sessions.contains_key(&front.id));
+    assert!(is_connected(&hub, front.id));
+
+    // The next message of one that said nothing since
+    let publish = IncomingPublish {
+        door_id,
+        device_id: back.id,
+        incoming: Incoming::Message(heartbeat()),
+    };
+    handle_publish(conn, &transport, &mut sessions, &hub, &events, publish).await;
+    assert!(is_connected(&hub, back.id));
+
+    let last_seen: Option<NaiveDateTime> = device::table
+        .find(back.id)
+        .select(device::last_seen_at)
+        .first(conn)
+        .unwrap();
+    assert!(last_seen.is_some());
+
+    let publish = IncomingPublish {
+        door_id,
+        device_id: front.id,
+        incoming: Incoming::Status { online: false },
+    };
+    handle_publish(conn, &transport, &mut sessions, &hub, &events, publish).await;
+    assert!(!sessions.contains_key(&front.id));
+    assert!(!is_connected(&hub, front.id));
+}
+
+#[tokio::test]
+#[ignore = "needs TEST_DATABASE_URL"]
+async fn topics_have_to_name_an_existing_device_of_the_door() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let other_door_id = testing::door(conn, user_id);
+    let device = testing::device(conn, door_id, "front");
+    let events = testing::events();
+    let hub = DeviceHub::for_tests();
+    let transport = transport(Encoding::Json);
+    let mut sessions = HashMap::new();
+
+    for (door_id, device_id) in [(other_door_id, device.id), (door_id, device.id + 1000)] {
+        let publish = IncomingPublish {
+            door_id,
+            device_id,
+            incoming: Incoming::Message(heartbeat()),
+        };
+        handle_publish(conn, &transport, &mut sessions, &hub, &events, publish).await;
+    }
+
+    assert!(sessions.is_empty());
+    assert!(!is_connected(&hub, device.id));
+}
+
+#[tokio::test]
+async fn the_server_announces_itself() {
+    let broker = Broker::start().await;
+
+    let (device, mut device_loop) = broker.client("device-14");
+    wait_for_connack(&mut device_loop).await;
+    device
+        .subscribe("doors/server", QoS::AtLeastOnce)
+        .await
+        .unwrap();
+    wait_for_suback(&mut device_loop).await;
+
+    let (server, mut server_loop) = MqttTransport::new(&broker.config("server", Encoding::Json));
+    tokio::spawn(async move { while server_loop.poll().await.is_ok() {} });
+    server.announce().await.unwrap();
+
+    let publish = next_publish(&mut device_loop).await;
+    assert_eq!(publish.topic, "doors/server");
+    assert_eq!(&publish.payload[..], b"online");
+}
+
+#[tokio::test]
+#[ignore = "needs TEST_DATABASE_URL"]
+async fn deleted_devices_lose_their_session() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let device = testing::device(conn, door_id, "front");
+    let events = testing::events();
+    let hub = DeviceHub::for_tests();
+    let transport = transport(Encoding::Json);
+    let mut sessions = HashMap::new();
+
+    let publish = IncomingPublish {
+        door_id,
+        device_id: device.id,
+        incoming: Incoming::Message(heartbeat()),
+    };
+    handle_publish(conn, &transport, &mut sessions, &hub, &events, publish).await;
+    assert!(sessions.contains_key(&device.id));
+
+    // What deleting the device does
+    diesel::delete(device::table.find(device.id))
+        .execute(conn)
+        .unwrap();
+    hub.close(device.id);
+    while !sessions[&device.id].forwarder.is_finished() {
+        tokio::task::yield_now().await;
+    }
+
+    let publish = IncomingPublish {
+        door_id,
+        device_id: device.id,
+        incoming: Incoming::Message(heartbeat()),
+    };
+    handle_publish(conn, &transport, &mut sessions, &hub, &events, publish).await;
+    assert!(sessions.is_empty());
+    assert!(!is_connected(&hub, device.id));
+}
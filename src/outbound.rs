@@ -0,0 +1,128 @@
+use dotenv::dotenv;
+use hyper::client::connect::dns::Name;
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    redirect, Url,
+};
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+// Users hand the server URLs to call, for relays and webhooks. It must not be
+// talked into calling its own services or anything else private that happens
+// to be reachable. Allowed hosts may be anywhere, every other host has to
+// resolve to public addresses only.
+#[derive(Clone, Debug, Default)]
+pub struct AllowedHosts(Arc<Vec<String>>);
+
+impl AllowedHosts {
+    #[cfg(test)]
+    pub fn new(hosts: &[&str]) -> Self {
+        AllowedHosts(Arc::new(
+            hosts.iter().map(|host| host.to_string()).collect(),
+        ))
+    }
+
+    // A comma separated list in the given variable, nothing when unset
+    pub fn from_env(var: &str) -> Self {
+        dotenv().ok();
+
+        let hosts = env::var(var)
+            .map(|hosts| {
+                hosts
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|host| !host.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+        AllowedHosts(Arc::new(hosts))
+    }
+
+    // Resolves through this list and never follows a redirect, which could
+    // point anywhere
+    pub fn client(&self, timeout: Duration) -> reqwest::Client {
+        reqwest::Client::builder()
+            .timeout(timeout)
+            .dns_resolver(Arc::new(self.clone()))
+            .redirect(redirect::Policy::none())
+            .build()
+            .unwrap()
+    }
+
+    fn contains(&self, host: &str) -> bool {
+        self.0
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    }
+
+    // IP addresses in URLs never reach the resolver, so they are checked here
+    pub fn check(&self, url: &Url) -> Result<(), String> {
+        let host = url
+            .host_str()
+            .ok_or("URLs need a host")?
+            .trim_matches(['[', ']']);
+        if self.contains(host) {
+            return Ok(());
+        }
+
+        let Ok(ip) = host.parse::<IpAddr>() else {
+            return Ok(());
+        };
+        if is_public(ip) {
+            Ok(())
+        } else {
+            Err(format!("{host} is not an allowed address"))
+        }
+    }
+}
+
+impl Resolve for AllowedHosts {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allowed = self.contains(name.as_str());
+
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            if !allowed && addrs.iter().any(|addr| !is_public(addr.ip())) {
+                return Err(format!("{} resolves to a private address", name.as_str()).into());
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || is_shared(ip))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => !(ip.is_loopback() || ip.is_unspecified() || is_local_v6(ip)),
+        },
+    }
+}
+
+// 100.64.0.0/10, carrier-grade NAT
+fn is_shared(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    a == 100 && (b & 0xC0) == 64
+}
+
+// Unique local fc00::/7 and link-local fe80::/10
+fn is_local_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    (first & 0xFE00) == 0xFC00 || (first & 0xFFC0) == 0xFE80
+}
@@ -1,4 +1,4 @@
-use async_session::{async_trait, MemoryStore, Session, SessionStore};
+use async_session::{async_trait, chrono::Utc, MemoryStore, Session, SessionStore};
 use axum::{
     extract::{rejection::TypedHeaderRejectionReason, FromRef, FromRequestParts, Query, State},
     headers::Cookie,
@@ -6,24 +6,25 @@ use axum::{
     routing::get,
     RequestPartsExt, Router, TypedHeader,
 };
-use diesel::{insert_into, prelude::*};
+use diesel::{insert_into, prelude::*, update};
 use diesel::{ExpressionMethods, QueryDsl, SelectableHelper};
 use http::{
     header::{self, SET_COOKIE},
     request::Parts,
-    HeaderMap,
+    HeaderMap, StatusCode,
 };
 use oauth2::{
     basic::BasicClient, reqwest::async_http_client, AuthorizationCode, CsrfToken, Scope,
     TokenResponse,
 };
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 use crate::{
     db::establish_connection,
-    models::UserProfile,
+    models::{ApiToken, Device, UserProfile},
     schema::{
-        door, door_code, door_permission,
+        api_token, device, door, door_code, door_permission,
         user_profile::{self, discord_id},
     },
     AppState, COOKIE_NAME,
@@ -177,3 +178,78 @@ where
         Ok(user)
     }
 }
+
+// A user authenticated with an `Authorization: Bearer <token>` API token
+// instead of the session cookie, for clients that can not log in through Discord
+pub struct TokenUser(pub UserProfile);
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for TokenUser
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        let conn = &mut establish_connection();
+
+        let token = api_token::table
+            .filter(api_token::token_hash.eq(hash_token(token)))
+            .select(ApiToken::as_select())
+            .get_result(conn)
+            .map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+        let user = user_profile::table
+            .find(token.user_profile_id)
+            .select(UserProfile::as_select())
+            .get_result(conn)
+            .map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+        let _ = update(api_token::table.find(token.id))
+            .set(api_token::last_used_at.eq(Utc::now().naive_utc()))
+            .execute(conn);
+
+        Ok(TokenUser(user))
+    }
+}
+
+// Door controllers authenticate their channel with the token they got when
+// they were registered, also sent as `Authorization: Bearer <token>`
+#[async_trait]
+impl<S> FromRequestParts<S> for Device
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        let conn = &mut establish_connection();
+
+        device_by_token(conn, token).map_err(|_| StatusCode::UNAUTHORIZED)
+    }
+}
+
+pub fn device_by_token(conn: &mut PgConnection, token: &str) -> QueryResult<Device> {
+    device::table
+        .filter(device::token_hash.eq(hash_token(token)))
+        .select(Device::as_select())
+        .get_result(conn)
+}
@@ -0,0 +1,307 @@
+use async_session::chrono::{NaiveDate, NaiveTime, Utc};
+use axum::{
+    extract::Path,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use diesel::{delete, insert_into, prelude::*, upsert::excluded};
+use http::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    access,
+    calendars::ics,
+    db::establish_connection,
+    models::{Calendar, CalendarException, UserProfile},
+    schema::{calendar, calendar_exception},
+    AppState,
+};
+
+pub fn create_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/", get(get_calendars).post(create_calendar))
+        .route("/:id", get(get_calendar).delete(delete_calendar))
+        .route("/:id/exceptions", post(create_calendar_exception))
+        .route(
+            "/:id/exceptions/:date",
+            axum::routing::delete(delete_calendar_exception),
+        )
+        .route("/:id/import", post(import_calendar))
+        .with_state(app_state)
+}
+
+#[derive(Deserialize)]
+struct CreateCalendar {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct CalendarExceptionBody {
+    date: NaiveDate,
+    label: Option<String>,
+    // Leave both out to close the whole day
+    start_time: Option<NaiveTime>,
+    end_time: Option<NaiveTime>,
+}
+
+// Users see the calendars they made, admins see every calendar
+async fn get_calendars(user: UserProfile) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let mut query = calendar::table
+        .order(calendar::id.asc())
+        .select(Calendar::as_select())
+        .into_boxed();
+    if !access::is_admin(conn, user.id) {
+        query = query.filter(calendar::owner_id.eq(user.id));
+    }
+
+    match query.load(conn) {
+        Ok(calendars) => Ok((StatusCode::OK, Json(json!(calendars)))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+async fn get_calendar(user: UserProfile, Path(calendar_id): Path<i32>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let found = calendar::table
+        .find(calendar_id)
+        .select(Calendar::as_select())
+        .get_result(conn);
+    let found = match found {
+        Ok(found) if found.owner_id == Some(user.id) || access::is_admin(conn, user.id) => found,
+        _ => return Err(not_found(calendar_id)),
+    };
+
+    let exceptions = CalendarException::belonging_to(&found)
+        .order(calendar_exception::date.asc())
+        .select(CalendarException::as_select())
+        .load(conn);
+
+    match exceptions {
+        Ok(exceptions) => Ok((
+            StatusCode::OK,
+            Json(json!({ "calendar": found, "exceptions": exceptions })),
+        )),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+async fn create_calendar(user: UserProfile, Json(body): Json<CreateCalendar>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if body.name.trim().is_empty() {
+        let error_response = json!({ "message": "name can not be empty" });
+        return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+    }
+
+    let created = insert_into(calendar::table)
+        .values((
+            calendar::name.eq(body.name.trim()),
+            calendar::owner_id.eq(user.id),
+            calendar::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .returning(Calendar::as_returning())
+        .get_result(conn);
+
+    match created {
+        Ok(calendar) => Ok((StatusCode::CREATED, Json(json!(calendar)))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+// Schedules that followed the calendar go back to their usual hours
+async fn delete_calendar(user: UserProfile, Path(calendar_id): Path<i32>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    check_manage(conn, calendar_id, user.id)?;
+
+    match delete(calendar::table.find(calendar_id)).execute(conn) {
+        Ok(1) => Ok((
+            StatusCode::OK,
+            Json(json!(format!(
+                "Calendar with an ID {calendar_id} was deleted."
+            ))),
+        )),
+        _ => Err(not_found(calendar_id)),
+    }
+}
+
+async fn create_calendar_exception(
+    user: UserProfile,
+    Path(calendar_id): Path<i32>,
+    Json(body): Json<CalendarExceptionBody>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    check_manage(conn, calendar_id, user.id)?;
+    match (body.start_time, body.end_time) {
+        (Some(start), Some(end)) if start == end => {
+            let error_response =
+                json!({ "message": "start_time and end_time can not be the same" });
+            return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+        }
+        (Some(_), None) | (None, Some(_)) => {
+            let error_response = json!({ "message": "Set both start_time and end_time, or neither to close the day" });
+            return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+        }
+        _ => {}
+    }
+
+    let exception = CalendarException {
+        calendar_id,
+        date: body.date,
+        label: body.label,
+        start_time: body.start_time,
+        end_time: body.end_time,
+        uid: None,
+    };
+    match upsert_exceptions(conn, &[exception]) {
+        Ok(mut exceptions) => Ok((StatusCode::CREATED, Json(json!(exceptions.pop())))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+async fn delete_calendar_exception(
+    user: UserProfile,
+    Path((calendar_id, date)): Path<(i32, NaiveDate)>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    check_manage(conn, calendar_id, user.id)?;
+
+    let deleted = delete(calendar_exception::table.find((calendar_id, date))).execute(conn);
+
+    match deleted {
+        Ok(1) => Ok((
+            StatusCode::OK,
+            Json(json!(format!("Calendar exception on {date} was deleted."))),
+        )),
+        _ => {
+            let error_response =
+                json!({ "message": format!("Calendar exception on {date} could not be deleted.") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+// Takes the .ics file as the request body. Days already in the calendar are
+// replaced by the imported ones.
+async fn import_calendar(
+    user: UserProfile,
+    Path(calendar_id): Path<i32>,
+    body: String,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    check_manage(conn, calendar_id, user.id)?;
+
+    let imported = match ics::parse(&body) {
+        Ok(imported) => imported,
+        Err(e) => {
+            let error_response = json!({ "message": format!("Could not read calendar: {e}.") });
+            return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+        }
+    };
+
+    // Postgres refuses to update one row twice in an upsert, so the last
+    // event of each day wins here
+    let mut exceptions = Vec::<CalendarException>::new();
+    for day in imported.days {
+        exceptions.retain(|exception| exception.date != day.date);
+        exceptions.push(CalendarException {
+            calendar_id,
+            date: day.date,
+            label: day.label,
+            start_time: day.hours.map(|(start, _)| start),
+            end_time: day.hours.map(|(_, end)| end),
+            uid: day.uid,
+        });
+    }
+
+    match upsert_exceptions(conn, &exceptions) {
+        Ok(exceptions) => Ok((
+            StatusCode::OK,
+            Json(json!({ "imported": exceptions.len(), "skipped": imported.skipped })),
+        )),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+// Postgres takes at most 65535 bind parameters per statement, at six a row
+const EXCEPTIONS_PER_INSERT: usize = 5000;
+
+// Imports can span many days, so they are written in parts, all or nothing
+fn upsert_exceptions(
+    conn: &mut PgConnection,
+    exceptions: &[CalendarException],
+) -> QueryResult<Vec<CalendarException>> {
+    conn.transaction(|conn| {
+        let mut upserted = Vec::with_capacity(exceptions.len());
+        for chunk in exceptions.chunks(EXCEPTIONS_PER_INSERT) {
+            let inserted = insert_into(calendar_exception::table)
+                .values(chunk)
+                .on_conflict((calendar_exception::calendar_id, calendar_exception::date))
+                .do_update()
+                .set((
+                    calendar_exception::label.eq(excluded(calendar_exception::label)),
+                    calendar_exception::start_time.eq(excluded(calendar_exception::start_time)),
+                    calendar_exception::end_time.eq(excluded(calendar_exception::end_time)),
+                    calendar_exception::uid.eq(excluded(calendar_exception::uid)),
+                ))
+                .returning(CalendarException::as_returning())
+                .get_results(conn)?;
+            upserted.extend(inserted);
+        }
+        Ok(upserted)
+    })
+}
+
+// The owner and admins change a calendar, everyone following it sees the change
+fn check_manage(
+    conn: &mut PgConnection,
+    calendar_id: i32,
+    user_id: i32,
+) -> Result<(), (StatusCode, Json<serde_json::Value>)> {
+    let owner = calendar::table
+        .find(calendar_id)
+        .select(calendar::owner_id)
+        .get_result::<Option<i32>>(conn);
+
+    match owner {
+        Ok(owner) if owner == Some(user_id) || access::is_admin(conn, user_id) => Ok(()),
+        Ok(_) => {
+            let error_response =
+                json!({ "message": format!("You can not manage calendar with ID {calendar_id}.") });
+            Err((StatusCode::FORBIDDEN, Json(error_response)))
+        }
+        Err(_) => Err(not_found(calendar_id)),
+    }
+}
+
+fn not_found(calendar_id: i32) -> (StatusCode, Json<serde_json::Value>) {
+    let error_response =
+        json!({ "message": format!("Calendar with ID: {calendar_id} not found.") });
+    (StatusCode::NOT_FOUND, Json(error_response))
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,38 @@
+use async_session::chrono::{Duration, NaiveDate, Utc};
+use diesel::{insert_into, prelude::*};
+
+use super::upsert_exceptions;
+use crate::{models::CalendarException, schema::calendar, testing};
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn imports_beyond_one_statement_are_upserted_in_parts() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let calendar_id = insert_into(calendar::table)
+        .values((
+            calendar::name.eq("Holidays"),
+            calendar::owner_id.eq(user_id),
+            calendar::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .returning(calendar::id)
+        .get_result::<i32>(conn)
+        .unwrap();
+
+    // More rows than fit into the bind parameters of one statement
+    let first = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
+    let exceptions = (0..12_000)
+        .map(|day| CalendarException {
+            calendar_id,
+            date: first + Duration::days(day),
+            label: None,
+            start_time: None,
+            end_time: None,
+            uid: None,
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(upsert_exceptions(conn, &exceptions).unwrap().len(), 12_000);
+    assert_eq!(upsert_exceptions(conn, &exceptions).unwrap().len(), 12_000);
+}
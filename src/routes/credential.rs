@@ -0,0 +1,190 @@
+use async_session::chrono::Utc;
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use diesel::{delete, insert_into, prelude::*};
+use http::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    allowlist,
+    db::establish_connection,
+    devices::DeviceHub,
+    models::{Credential, UserProfile},
+    schema::credential,
+    AppState,
+};
+
+use super::auth::hash_token;
+
+pub fn create_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/", get(get_credentials).post(create_credential))
+        .route("/:id", get(get_credential).delete(delete_credential))
+        .with_state(app_state)
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum CredentialKind {
+    Card,
+    Pin,
+}
+
+impl CredentialKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CredentialKind::Card => "card",
+            CredentialKind::Pin => "pin",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateCredential {
+    kind: CredentialKind,
+    value: String,
+    label: Option<String>,
+}
+
+async fn get_credentials(user: UserProfile) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let credentials = credential::table
+        .filter(credential::user_profile_id.eq(user.id))
+        .select(Credential::as_select())
+        .load(conn);
+
+    match credentials {
+        Ok(credentials) => Ok((StatusCode::OK, Json(credentials))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+async fn get_credential(user: UserProfile, Path(credential_id): Path<i32>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    match find_credential(conn, credential_id, user.id) {
+        Some(credential) => Ok((StatusCode::OK, Json(credential))),
+        None => Err(not_found(credential_id)),
+    }
+}
+
+async fn create_credential(
+    State(devices): State<DeviceHub>,
+    user: UserProfile,
+    Json(body): Json<CreateCredential>,
+) -> impl IntoResponse {
+    // Readers report card UIDs in all sorts of notations
+    let value = match body.kind {
+        CredentialKind::Card => {
+            let uid = body
+                .value
+                .chars()
+                .filter(|c| !matches!(c, ':' | '-' | ' '))
+                .collect::<String>()
+                .to_uppercase();
+            if uid.len() < 8 || uid.len() > 20 || !uid.chars().all(|c| c.is_ascii_hexdigit()) {
+                let error_response = json!({ "message": "Card UIDs are 4 to 10 bytes of hex." });
+                return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+            }
+            uid
+        }
+        CredentialKind::Pin => {
+            let pin = &body.value;
+            if pin.len() < 4 || pin.len() > 8 || !pin.chars().all(|c| c.is_ascii_digit()) {
+                let error_response = json!({ "message": "PINs are 4 to 8 digits." });
+                return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+            }
+            hash_token(pin)
+        }
+    };
+
+    let conn = &mut establish_connection();
+
+    let created = insert_into(credential::table)
+        .values((
+            credential::user_profile_id.eq(user.id),
+            credential::kind.eq(body.kind.as_str()),
+            credential::value.eq(&value),
+            credential::label.eq(&body.label),
+            credential::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .returning(Credential::as_returning())
+        .get_result(conn);
+
+    match created {
+        Ok(created) => {
+            allowlist::publish_for_user(conn, &devices, user.id);
+            Ok((StatusCode::CREATED, Json(created)))
+        }
+        Err(diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            _,
+        )) => {
+            let error_response = json!({ "message": "You already have this credential." });
+            Err((StatusCode::CONFLICT, Json(error_response)))
+        }
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+async fn delete_credential(
+    State(devices): State<DeviceHub>,
+    user: UserProfile,
+    Path(credential_id): Path<i32>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if find_credential(conn, credential_id, user.id).is_none() {
+        return Err(not_found(credential_id));
+    }
+
+    match delete(credential::table.find(credential_id)).execute(conn) {
+        Ok(_) => {
+            allowlist::publish_for_user(conn, &devices, user.id);
+            Ok((
+                StatusCode::OK,
+                Json(json!(format!(
+                    "Credential with an ID {credential_id} was deleted."
+                ))),
+            ))
+        }
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+fn find_credential(
+    conn: &mut PgConnection,
+    credential_id: i32,
+    user_id: i32,
+) -> Option<Credential> {
+    credential::table
+        .filter(
+            credential::id
+                .eq(credential_id)
+                .and(credential::user_profile_id.eq(user_id)),
+        )
+        .select(Credential::as_select())
+        .get_result(conn)
+        .ok()
+}
+
+fn not_found(credential_id: i32) -> (StatusCode, Json<serde_json::Value>) {
+    let error_response =
+        json!({ "message": format!("Credential with ID: {credential_id} not found.") });
+    (StatusCode::NOT_FOUND, Json(error_response))
+}
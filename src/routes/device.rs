@@ -0,0 +1,495 @@
+use async_session::chrono::{Duration, NaiveDateTime, Utc};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        Path, Query, State, WebSocketUpgrade,
+    },
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use diesel::{delete, insert_into, prelude::*};
+use futures::{
+    sink::SinkExt,
+    stream::{Stream, StreamExt},
+};
+use http::StatusCode;
+use protocol::{
+    config::DeviceConfig, CommandFrame, DeviceCommand, DeviceMessage, Encoding, EncodingError,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    access,
+    db::establish_connection,
+    device_config,
+    devices::{self, DeviceHub},
+    events::EventBus,
+    models::{Device, DeviceCommandEntry, DeviceConfigVersion, DeviceTelemetry, UserProfile},
+    schema::{device, device_command, device_config as device_config_table, device_telemetry},
+    AppState,
+};
+
+use super::{
+    auth::{device_by_token, hash_token},
+    device_diagnostics, snapshot,
+};
+
+const AUTH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+pub fn create_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/", get(get_devices).post(create_device))
+        .route("/ws", get(device_ws))
+        .route("/signing_key", get(get_signing_key))
+        .route("/snapshots", post(snapshot::upload_snapshot))
+        .route("/:id", get(get_device).delete(delete_device))
+        .route("/:id/telemetry", get(get_device_telemetry))
+        .route("/:id/commands", get(get_device_commands))
+        .route(
+            "/:id/config",
+            get(get_device_config).post(update_device_config),
+        )
+        .route("/:id/config/history", get(get_device_config_history))
+        .route(
+            "/:id/diagnostics",
+            post(device_diagnostics::send_diagnostic),
+        )
+        .route("/:id/logs", get(device_diagnostics::stream_logs))
+        .with_state(app_state)
+}
+
+#[derive(Deserialize)]
+struct DevicesQuery {
+    door_id: i32,
+}
+
+#[derive(Deserialize)]
+struct TelemetryQuery {
+    since: Option<NaiveDateTime>,
+}
+
+#[derive(Deserialize)]
+struct CreateDevice {
+    door_id: i32,
+    name: String,
+}
+
+async fn get_devices(user: UserProfile, Query(query): Query<DevicesQuery>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_manage(conn, query.door_id, user.id) {
+        return Err(forbidden(query.door_id));
+    }
+
+    let devices = device::table
+        .filter(device::door_id.eq(query.door_id))
+        .select(Device::as_select())
+        .load(conn);
+
+    match devices {
+        Ok(devices) => Ok((StatusCode::OK, Json(devices))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+// Flashed into controllers so they can check allowlists while offline
+async fn get_signing_key(State(hub): State<DeviceHub>) -> impl IntoResponse {
+    Json(json!({ "public_key": hub.signer().public_key() }))
+}
+
+async fn get_device(user: UserProfile, Path(device_id): Path<i32>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    match find_managed_device(conn, device_id, user.id) {
+        Some(device) => Ok((StatusCode::OK, Json(device))),
+        None => Err(not_found(device_id)),
+    }
+}
+
+async fn create_device(user: UserProfile, Json(body): Json<CreateDevice>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_manage(conn, body.door_id, user.id) {
+        return Err(forbidden(body.door_id));
+    }
+
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = hex::encode(bytes);
+
+    let created = insert_into(device::table)
+        .values((
+            device::door_id.eq(body.door_id),
+            device::name.eq(&body.name),
+            device::token_hash.eq(hash_token(&token)),
+            device::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .returning(Device::as_returning())
+        .get_result(conn);
+
+    // The token goes into the controller's firmware config, we only keep its hash
+    #[derive(Serialize)]
+    struct CreatedDevice {
+        #[serde(flatten)]
+        device: Device,
+        token: String,
+    }
+
+    match created {
+        Ok(device) => Ok((StatusCode::CREATED, Json(CreatedDevice { device, token }))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+// Defaults to the last day of heartbeats
+async fn get_device_telemetry(
+    user: UserProfile,
+    Path(device_id): Path<i32>,
+    Query(query): Query<TelemetryQuery>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let Some(device) = find_managed_device(conn, device_id, user.id) else {
+        return Err(not_found(device_id));
+    };
+
+    let since = query
+        .since
+        .unwrap_or_else(|| Utc::now().naive_utc() - Duration::days(1));
+
+    let telemetry = DeviceTelemetry::belonging_to(&device)
+        .filter(device_telemetry::recorded_at.gt(since))
+        .order(device_telemetry::recorded_at.asc())
+        .select(DeviceTelemetry::as_select())
+        .load(conn);
+
+    match telemetry {
+        Ok(telemetry) => Ok((StatusCode::OK, Json(telemetry))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+async fn get_device_commands(user: UserProfile, Path(device_id): Path<i32>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let Some(device) = find_managed_device(conn, device_id, user.id) else {
+        return Err(not_found(device_id));
+    };
+
+    let commands = DeviceCommandEntry::belonging_to(&device)
+        .order(device_command::created_at.desc())
+        .limit(100)
+        .select(DeviceCommandEntry::as_select())
+        .load(conn);
+
+    match commands {
+        Ok(commands) => Ok((StatusCode::OK, Json(commands))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+// Devices without a stored configuration were never sent one, so nothing is
+// applied yet
+async fn get_device_config(user: UserProfile, Path(device_id): Path<i32>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let Some(device) = find_managed_device(conn, device_id, user.id) else {
+        return Err(not_found(device_id));
+    };
+
+    match device_config::latest(conn, device.id) {
+        Ok(Some(latest)) => Ok((
+            StatusCode::OK,
+            Json(json!({
+                "version": latest.version,
+                "applied": latest.version == device.config_version,
+                "config": latest.config,
+            })),
+        )),
+        Ok(None) => Ok((
+            StatusCode::OK,
+            Json(json!({
+                "version": 0,
+                "applied": false,
+                "config": DeviceConfig::default(),
+            })),
+        )),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+async fn update_device_config(
+    State(hub): State<DeviceHub>,
+    user: UserProfile,
+    Path(device_id): Path<i32>,
+    Json(config): Json<DeviceConfig>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let Some(device) = find_managed_device(conn, device_id, user.id) else {
+        return Err(not_found(device_id));
+    };
+
+    if let Err(message) = config.validate() {
+        let error_response = json!({ "message": message });
+        return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+    }
+
+    match device_config::save(conn, device.id, &config, user.id) {
+        Ok(saved) => {
+            device_config::push(conn, &hub, &device);
+            Ok((StatusCode::CREATED, Json(saved)))
+        }
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+async fn get_device_config_history(
+    user: UserProfile,
+    Path(device_id): Path<i32>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let Some(device) = find_managed_device(conn, device_id, user.id) else {
+        return Err(not_found(device_id));
+    };
+
+    let history = DeviceConfigVersion::belonging_to(&device)
+        .order(device_config_table::version.desc())
+        .select(DeviceConfigVersion::as_select())
+        .load(conn);
+
+    match history {
+        Ok(history) => Ok((StatusCode::OK, Json(history))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+async fn delete_device(
+    State(hub): State<DeviceHub>,
+    user: UserProfile,
+    Path(device_id): Path<i32>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if find_managed_device(conn, device_id, user.id).is_none() {
+        return Err(not_found(device_id));
+    }
+
+    match delete(device::table.find(device_id)).execute(conn) {
+        Ok(_) => {
+            hub.close(device_id);
+            hub.logs().forget(device_id);
+            Ok((
+                StatusCode::OK,
+                Json(json!(format!("Device with an ID {device_id} was deleted."))),
+            ))
+        }
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+// Controllers without an `Authorization` header send an auth frame first. The
+// encoding is picked from the subprotocols the controller offers.
+async fn device_ws(
+    ws: WebSocketUpgrade,
+    device: Option<Device>,
+    State(hub): State<DeviceHub>,
+    State(events): State<EventBus>,
+) -> impl IntoResponse {
+    ws.protocols(Encoding::PREFERENCE.map(|encoding| encoding.name()))
+        .on_upgrade(move |socket| {
+            let encoding = socket
+                .protocol()
+                .and_then(|protocol| protocol.to_str().ok())
+                .and_then(Encoding::from_name)
+                .unwrap_or(Encoding::Json);
+
+            handle_socket(socket, encoding, device, hub, events)
+        })
+}
+
+// Text frames are always JSON so the channel can be debugged by hand
+fn decode_frame(
+    encoding: Encoding,
+    message: &Message,
+) -> Option<Result<DeviceMessage, EncodingError>> {
+    match message {
+        Message::Text(text) => Some(Encoding::Json.decode(text.as_bytes())),
+        Message::Binary(bytes) => Some(encoding.decode(bytes)),
+        _ => None,
+    }
+}
+
+fn encode_frame(encoding: Encoding, frame: &CommandFrame) -> Message {
+    let bytes = encoding.encode(frame).unwrap();
+    if encoding.is_binary() {
+        Message::Binary(bytes)
+    } else {
+        Message::Text(String::from_utf8(bytes).unwrap())
+    }
+}
+
+// Nothing but the auth frame is accepted before the device is known
+async fn authenticate<S>(
+    receiver: &mut S,
+    encoding: Encoding,
+    timeout: std::time::Duration,
+    lookup: impl FnOnce(&str) -> Option<Device>,
+) -> Option<Device>
+where
+    S: Stream<Item = Result<Message, axum::Error>> + Unpin,
+{
+    let token = tokio::time::timeout(timeout, async {
+        let message = receiver.next().await?.ok()?;
+        match decode_frame(encoding, &message)? {
+            Ok(DeviceMessage::Auth { token }) => Some(token),
+            _ => None,
+        }
+    })
+    .await
+    .ok()??;
+
+    lookup(&token)
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    encoding: Encoding,
+    device: Option<Device>,
+    hub: DeviceHub,
+    events: EventBus,
+) {
+    let (mut sender, mut receiver) = socket.split();
+
+    let device = match device {
+        Some(device) => device,
+        None => match authenticate(&mut receiver, encoding, AUTH_TIMEOUT, |token| {
+            device_by_token(&mut establish_connection(), token).ok()
+        })
+        .await
+        {
+            Some(device) => device,
+            None => {
+                tracing::warn!("Device channel closed without authenticating");
+                return;
+            }
+        },
+    };
+
+    tracing::info!(
+        "Device {} of door {} connected speaking {}",
+        device.id,
+        device.door_id,
+        encoding.name()
+    );
+    let mut commands = hub.connect(&device);
+
+    let conn = &mut establish_connection();
+    devices::on_connect(conn, &events, &hub, &device);
+
+    loop {
+        tokio::select! {
+            message = receiver.next() => {
+                let message = match message {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(message)) => message,
+                };
+                // Pings are answered by axum
+                let Some(decoded) = decode_frame(encoding, &message) else {
+                    continue;
+                };
+
+                match decoded {
+                    Ok(DeviceMessage::Hello { protocol_version, .. }) => {
+                        let conn = &mut establish_connection();
+                        let reply = devices::hello(conn, &device, protocol_version);
+                        let unsupported = matches!(reply, DeviceCommand::Unsupported { .. });
+
+                        let reply = CommandFrame { id: None, command: reply };
+                        if sender.send(encode_frame(encoding, &reply)).await.is_err() || unsupported {
+                            break;
+                        }
+                    }
+                    Ok(message) => {
+                        let conn = &mut establish_connection();
+                        devices::handle_message(conn, &events, &hub, &device, message);
+                    }
+                    Err(e) => tracing::warn!("Device {} sent an invalid message: {e}", device.id),
+                }
+            },
+            command = commands.recv() => {
+                // The hub let go of the device, it was deleted
+                let Some(command) = command else {
+                    break;
+                };
+                if sender.send(encode_frame(encoding, &command)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    drop(commands);
+    hub.disconnect(device.id);
+
+    tracing::info!(
+        "Device {} of door {} disconnected",
+        device.id,
+        device.door_id
+    );
+}
+
+pub(super) fn find_managed_device(
+    conn: &mut PgConnection,
+    device_id: i32,
+    user_id: i32,
+) -> Option<Device> {
+    device::table
+        .find(device_id)
+        .select(Device::as_select())
+        .get_result(conn)
+        .ok()
+        .filter(|device| access::can_manage(conn, device.door_id, user_id))
+}
+
+fn forbidden(door_id: i32) -> (StatusCode, Json<serde_json::Value>) {
+    let error_response =
+        json!({ "message": format!("You can not manage door with ID {door_id}.") });
+    (StatusCode::FORBIDDEN, Json(error_response))
+}
+
+pub(super) fn not_found(device_id: i32) -> (StatusCode, Json<serde_json::Value>) {
+    let error_response = json!({ "message": format!("Device with ID: {device_id} not found.") });
+    (StatusCode::NOT_FOUND, Json(error_response))
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,90 @@
+use async_session::chrono::Utc;
+use axum::extract::ws::Message;
+use futures::stream::{self, Stream};
+use protocol::{DeviceMessage, Encoding};
+use std::time::Duration;
+
+use super::authenticate;
+use crate::models::Device;
+
+const TIMEOUT: Duration = Duration::from_millis(50);
+
+fn device() -> Device {
+    Device {
+        id: 1,
+        door_id: 1,
+        name: "front".to_string(),
+        token_hash: String::new(),
+        created_at: Utc::now().naive_utc(),
+        last_seen_at: None,
+        online: false,
+        firmware_version: None,
+        allowlist_version: 0,
+        group_id: None,
+        target_firmware_id: None,
+        config_version: 0,
+        protocol_version: 0,
+    }
+}
+
+fn frame(message: &DeviceMessage) -> Result<Message, axum::Error> {
+    let bytes = Encoding::Json.encode(message).unwrap();
+    Ok(Message::Text(String::from_utf8(bytes).unwrap()))
+}
+
+fn auth(token: &str) -> Result<Message, axum::Error> {
+    frame(&DeviceMessage::Auth {
+        token: token.to_string(),
+    })
+}
+
+fn lookup(token: &str) -> Option<Device> {
+    (token == "devtoken").then(device)
+}
+
+async fn run<S>(mut receiver: S) -> Option<Device>
+where
+    S: Stream<Item = Result<Message, axum::Error>> + Unpin,
+{
+    authenticate(&mut receiver, Encoding::Json, TIMEOUT, lookup).await
+}
+
+#[tokio::test]
+async fn the_right_token_lets_the_device_in() {
+    let device = run(stream::iter(vec![auth("devtoken")])).await;
+
+    assert_eq!(device.map(|device| device.id), Some(1));
+}
+
+#[tokio::test]
+async fn a_wrong_token_is_turned_away() {
+    assert!(run(stream::iter(vec![auth("not-the-token")]))
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn devices_that_stay_quiet_time_out() {
+    let started = tokio::time::Instant::now();
+
+    assert!(run(stream::pending()).await.is_none());
+    assert!(started.elapsed() >= TIMEOUT);
+}
+
+#[tokio::test]
+async fn messages_before_auth_close_the_channel() {
+    let hello = frame(&DeviceMessage::Hello {
+        protocol_version: protocol::PROTOCOL_VERSION,
+        firmware_version: None,
+    });
+
+    // The auth frame that follows does not count
+    assert!(run(stream::iter(vec![hello, auth("devtoken")]))
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn channels_closed_before_auth_are_turned_away() {
+    assert!(run(stream::empty()).await.is_none());
+}
@@ -0,0 +1,149 @@
+use async_session::chrono::Utc;
+use axum::{
+    extract::{Path, State},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    Json,
+};
+use futures::stream::Stream;
+use http::{HeaderMap, StatusCode};
+use protocol::{diagnostics::Diagnostic, DeviceCommand};
+use serde_json::json;
+use std::convert::Infallible;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    access::{self, OpenMethod},
+    db::establish_connection,
+    devices::DeviceHub,
+    diagnostics::{DeviceLogs, LogEntry},
+    emergency,
+    events::EventBus,
+    models::UserProfile,
+};
+
+use super::{
+    auth::TokenUser,
+    device::{find_managed_device, not_found},
+};
+
+// Diagnostics only make sense right now, so nothing is queued for offline devices.
+// A relay test opens the door, so it waits out a lockdown and goes into the
+// history like any other open.
+pub async fn send_diagnostic(
+    State(hub): State<DeviceHub>,
+    State(events): State<EventBus>,
+    user: UserProfile,
+    Path(device_id): Path<i32>,
+    Json(diagnostic): Json<Diagnostic>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let Some(device) = find_managed_device(conn, device_id, user.id) else {
+        return Err(not_found(device_id));
+    };
+
+    if let Err(message) = diagnostic.validate() {
+        let error_response = json!({ "message": message });
+        return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+    }
+    let relay_test = matches!(diagnostic, Diagnostic::RelayTest { .. });
+    if relay_test && emergency::in_lockdown(conn) {
+        let error_response =
+            json!({ "message": "Relay tests wait until the lockdown is cleared." });
+        return Err((StatusCode::CONFLICT, Json(error_response)));
+    }
+
+    if !hub.push(device.id, DeviceCommand::Diagnostic(diagnostic.clone())) {
+        let error_response =
+            json!({ "message": format!("Device with ID: {device_id} is offline.") });
+        return Err((StatusCode::CONFLICT, Json(error_response)));
+    }
+
+    tracing::info!(
+        "{} sent {diagnostic:?} to device {device_id}",
+        user.username
+    );
+
+    if relay_test {
+        let recorded = access::record_open_by(
+            conn,
+            &events,
+            device.door_id,
+            Some(user.id),
+            OpenMethod::RelayTest,
+            Utc::now().naive_utc(),
+        );
+        if let Err(e) = recorded {
+            tracing::error!("Could not record relay test of device {device_id}: {e}");
+        }
+    }
+
+    Ok((StatusCode::ACCEPTED, Json(diagnostic)))
+}
+
+// Replays the buffered lines, then follows the device's log live
+pub async fn stream_logs(
+    State(hub): State<DeviceHub>,
+    session_user: Option<UserProfile>,
+    token_user: Option<TokenUser>,
+    headers: HeaderMap,
+    Path(device_id): Path<i32>,
+) -> impl IntoResponse {
+    let Some(user) = session_user.or(token_user.map(|TokenUser(user)| user)) else {
+        let error_response = json!({ "message": "You are not logged in." });
+        return Err((StatusCode::UNAUTHORIZED, Json(error_response)));
+    };
+
+    let conn = &mut establish_connection();
+
+    if find_managed_device(conn, device_id, user.id).is_none() {
+        return Err(not_found(device_id));
+    }
+
+    let logs = hub.logs();
+    let last_entry_id = headers
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| logs.resume_after(value));
+
+    let (backlog, receiver) = logs.subscribe(device_id, last_entry_id);
+    let stream = log_stream(device_id, logs.clone(), backlog, receiver);
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+fn log_stream(
+    device_id: i32,
+    logs: DeviceLogs,
+    backlog: Vec<LogEntry>,
+    mut receiver: broadcast::Receiver<LogEntry>,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    async_stream::stream! {
+        for entry in backlog {
+            yield Ok(sse_event(&logs, &entry));
+        }
+
+        loop {
+            let entry = match receiver.recv().await {
+                Ok(entry) => entry,
+                Err(RecvError::Lagged(n)) => {
+                    tracing::warn!("Log stream of device {device_id} skipped {n} lines");
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            yield Ok(sse_event(&logs, &entry));
+        }
+    }
+}
+
+fn sse_event(logs: &DeviceLogs, entry: &LogEntry) -> Event {
+    Event::default()
+        .id(logs.event_id(entry))
+        .json_data(entry)
+        .unwrap()
+}
@@ -0,0 +1,229 @@
+use async_session::chrono::{Duration, Utc};
+use axum::{body::Bytes, extract::State, response::IntoResponse, routing::post, Json, Router};
+use diesel::prelude::*;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use http::{HeaderMap, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    access::{self, OpenMethod},
+    allowlist,
+    db::establish_connection,
+    devices::DeviceHub,
+    events::{DoorEvent, EventBus, EventKind},
+    locks::LockDrivers,
+    models::UserProfile,
+    schema::user_profile,
+    AppState,
+};
+
+// https://discord.com/developers/docs/interactions/receiving-and-responding
+const PING: u8 = 1;
+const APPLICATION_COMMAND: u8 = 2;
+const PONG: u8 = 1;
+const CHANNEL_MESSAGE_WITH_SOURCE: u8 = 4;
+const EPHEMERAL: u32 = 1 << 6;
+// Codes are for visitors, a month covers any stay
+const MAX_CODE_HOURS: i64 = 30 * 24;
+
+pub fn create_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/interactions", post(interactions))
+        .with_state(app_state)
+}
+
+#[derive(Debug, Deserialize)]
+struct Interaction {
+    #[serde(rename = "type")]
+    kind: u8,
+    data: Option<CommandData>,
+    // Set when the command is used in a guild
+    member: Option<Member>,
+    // Set when the command is used in a DM
+    user: Option<DiscordUser>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommandData {
+    name: String,
+    #[serde(default)]
+    options: Vec<CommandOption>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommandOption {
+    name: String,
+    value: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct Member {
+    user: DiscordUser,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscordUser {
+    id: String,
+}
+
+// Answers 404 unless DISCORD_PUBLIC_KEY is set
+async fn interactions(
+    State(public_key): State<Option<VerifyingKey>>,
+    State(events): State<EventBus>,
+    State(devices): State<DeviceHub>,
+    State(locks): State<LockDrivers>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    let Some(public_key) = public_key else {
+        let error_response = json!({ "message": "Discord commands are not set up." });
+        return Err((StatusCode::NOT_FOUND, Json(error_response)));
+    };
+    if !verify_signature(&public_key, &headers, &body) {
+        let error_response = json!({ "message": "Invalid request signature." });
+        return Err((StatusCode::UNAUTHORIZED, Json(error_response)));
+    }
+
+    let interaction: Interaction = match serde_json::from_slice(&body) {
+        Ok(interaction) => interaction,
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+        }
+    };
+
+    match interaction.kind {
+        PING => Ok((StatusCode::OK, Json(json!({ "type": PONG })))),
+        APPLICATION_COMMAND => {
+            let content = handle_command(&interaction, &events, &devices, &locks).await;
+            Ok((StatusCode::OK, Json(reply(content))))
+        }
+        kind => {
+            let error_response =
+                json!({ "message": format!("Unsupported interaction type {kind}.") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+fn verify_signature(public_key: &VerifyingKey, headers: &HeaderMap, body: &[u8]) -> bool {
+    let signature = headers
+        .get("X-Signature-Ed25519")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| hex::decode(value).ok())
+        .and_then(|bytes| Signature::from_slice(&bytes).ok());
+    let timestamp = headers
+        .get("X-Signature-Timestamp")
+        .and_then(|value| value.to_str().ok());
+
+    let (Some(signature), Some(timestamp)) = (signature, timestamp) else {
+        return false;
+    };
+
+    let mut message = timestamp.as_bytes().to_vec();
+    message.extend_from_slice(body);
+
+    public_key.verify(&message, &signature).is_ok()
+}
+
+fn reply(content: String) -> Value {
+    json!({
+        "type": CHANNEL_MESSAGE_WITH_SOURCE,
+        "data": { "content": content, "flags": EPHEMERAL },
+    })
+}
+
+async fn handle_command(
+    interaction: &Interaction,
+    events: &EventBus,
+    devices: &DeviceHub,
+    locks: &LockDrivers,
+) -> String {
+    let Some(data) = &interaction.data else {
+        return "Missing command data.".to_string();
+    };
+
+    let discord_user = interaction
+        .member
+        .as_ref()
+        .map(|member| &member.user)
+        .or(interaction.user.as_ref());
+    let Some(discord_user) = discord_user else {
+        return "Could not tell who sent this command.".to_string();
+    };
+
+    let conn = &mut establish_connection();
+
+    let user = user_profile::table
+        .filter(user_profile::discord_id.eq(&discord_user.id))
+        .select(UserProfile::as_select())
+        .first(conn);
+    let Ok(user) = user else {
+        return "Your Discord account is not linked yet, log in on the web first.".to_string();
+    };
+
+    let Some(door_id) = integer_option(data, "door").and_then(|id| i32::try_from(id).ok()) else {
+        return "Missing `door` option.".to_string();
+    };
+
+    match data.name.as_str() {
+        "open" => {
+            if !access::can_open(conn, door_id, user.id) {
+                events.publish(DoorEvent::new(EventKind::Denied, door_id, Some(user.id)));
+                return "You are not allowed to open doors".to_string();
+            }
+            let opened = locks
+                .open(conn, events, door_id, Some(user.id), OpenMethod::Permission)
+                .await;
+            if let Err(e) = opened {
+                tracing::error!("Could not open door {door_id}: {e}");
+                return format!("Door {door_id} could not be opened: {e}");
+            }
+
+            format!("Door {door_id} opened.")
+        }
+        "code" => {
+            let hours = match code_hours(data) {
+                Ok(hours) => hours,
+                Err(message) => return message,
+            };
+            if !access::can_open(conn, door_id, user.id) {
+                return "You are not allowed to create codes for this door".to_string();
+            }
+
+            let expires_at = Utc::now().naive_utc() + Duration::hours(hours);
+            let code = access::new_door_code(door_id, user.id, Some(expires_at));
+
+            match access::insert_door_code(conn, &code) {
+                Ok(_) => {
+                    allowlist::publish(conn, devices, door_id);
+                    format!(
+                        "Code for door {door_id}: `{}` (valid until {} UTC).",
+                        code.code,
+                        expires_at.format("%Y-%m-%d %H:%M")
+                    )
+                }
+                Err(e) => format!("Could not create code: {e}"),
+            }
+        }
+        name => format!("Unknown command `{name}`."),
+    }
+}
+
+fn integer_option(data: &CommandData, name: &str) -> Option<i64> {
+    data.options
+        .iter()
+        .find(|option| option.name == name)
+        .and_then(|option| option.value.as_i64())
+}
+
+fn code_hours(data: &CommandData) -> Result<i64, String> {
+    match integer_option(data, "hours") {
+        Some(hours) if (1..=MAX_CODE_HOURS).contains(&hours) => Ok(hours),
+        _ => Err(format!("`hours` must be between 1 and {MAX_CODE_HOURS}.")),
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,127 @@
+use ed25519_dalek::{Signer, SigningKey};
+use http::{HeaderMap, HeaderValue};
+use serde_json::json;
+
+use super::{code_hours, integer_option, reply, verify_signature, Interaction, EPHEMERAL};
+
+const TIMESTAMP: &str = "1700000000";
+const BODY: &[u8] = br#"{"type":1}"#;
+
+fn key() -> SigningKey {
+    SigningKey::from_bytes(&[7; 32])
+}
+
+fn signed_headers(key: &SigningKey, timestamp: &str, body: &[u8]) -> HeaderMap {
+    let mut message = timestamp.as_bytes().to_vec();
+    message.extend_from_slice(body);
+    let signature = hex::encode(key.sign(&message).to_bytes());
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "X-Signature-Ed25519",
+        HeaderValue::from_str(&signature).unwrap(),
+    );
+    headers.insert(
+        "X-Signature-Timestamp",
+        HeaderValue::from_str(timestamp).unwrap(),
+    );
+    headers
+}
+
+#[test]
+fn requests_signed_by_discord_are_accepted() {
+    let key = key();
+    let headers = signed_headers(&key, TIMESTAMP, BODY);
+
+    assert!(verify_signature(&key.verifying_key(), &headers, BODY));
+}
+
+#[test]
+fn changed_bodies_and_timestamps_are_refused() {
+    let key = key();
+    let mut headers = signed_headers(&key, TIMESTAMP, BODY);
+
+    assert!(!verify_signature(
+        &key.verifying_key(),
+        &headers,
+        br#"{"type":2}"#
+    ));
+
+    headers.insert(
+        "X-Signature-Timestamp",
+        HeaderValue::from_static("1700000001"),
+    );
+    assert!(!verify_signature(&key.verifying_key(), &headers, BODY));
+}
+
+#[test]
+fn requests_signed_by_another_key_are_refused() {
+    let other = SigningKey::from_bytes(&[8; 32]);
+    let headers = signed_headers(&other, TIMESTAMP, BODY);
+
+    assert!(!verify_signature(&key().verifying_key(), &headers, BODY));
+}
+
+#[test]
+fn unsigned_requests_are_refused() {
+    let key = key();
+    assert!(!verify_signature(
+        &key.verifying_key(),
+        &HeaderMap::new(),
+        BODY
+    ));
+
+    let mut headers = signed_headers(&key, TIMESTAMP, BODY);
+    headers.insert("X-Signature-Ed25519", HeaderValue::from_static("not-hex"));
+    assert!(!verify_signature(&key.verifying_key(), &headers, BODY));
+}
+
+#[test]
+fn command_options_are_read_as_integers() {
+    let interaction: Interaction = serde_json::from_value(json!({
+        "type": 2,
+        "data": {
+            "name": "code",
+            "options": [
+                { "name": "door", "value": 3 },
+                { "name": "hours", "value": "two" },
+            ],
+        },
+        "user": { "id": "42" },
+    }))
+    .unwrap();
+    let data = interaction.data.as_ref().unwrap();
+
+    assert_eq!(integer_option(data, "door"), Some(3));
+    assert_eq!(integer_option(data, "hours"), None);
+    assert_eq!(integer_option(data, "missing"), None);
+}
+
+#[test]
+fn codes_last_at_most_a_month() {
+    for (hours, valid) in [
+        (json!(1), true),
+        (json!(720), true),
+        (json!(0), false),
+        (json!(721), false),
+        (json!(i64::MAX), false),
+    ] {
+        let interaction: Interaction = serde_json::from_value(json!({
+            "type": 2,
+            "data": { "name": "code", "options": [{ "name": "hours", "value": hours }] },
+        }))
+        .unwrap();
+
+        let data = interaction.data.as_ref().unwrap();
+        assert_eq!(code_hours(data).is_ok(), valid, "{hours}");
+    }
+}
+
+#[test]
+fn replies_are_only_shown_to_the_sender() {
+    let reply = reply("Door 1 opened.".to_string());
+
+    assert_eq!(reply["type"], 4);
+    assert_eq!(reply["data"]["content"], "Door 1 opened.");
+    assert_eq!(reply["data"]["flags"], EPHEMERAL);
+}
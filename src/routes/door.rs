@@ -1,11 +1,18 @@
 use crate::{
+    access::{self, OpenMethod},
+    allowlist,
     db::establish_connection,
+    devices::DeviceHub,
+    emergency,
+    events::{DoorEvent, EventBus, EventKind},
+    guest_tokens, lock_mode,
+    locks::LockDrivers,
     models::DoorCode,
     models::{InsertedDoor},
     models::UserProfile,
-    models::{AccessHistory, Door, DoorPermission},
+    models::{AccessHistory, Device, Door, DoorPermission},
     schema::{
-        access_history::{self, access_timestamp, user_profile_id},
+        access_history, device,
         door, door_code, door_permission, user_profile,
     },
     AppState,
@@ -15,12 +22,12 @@ use async_session::{
     MemoryStore,
 };
 use axum::{
-    extract::{FromRef, Path, Query},
+    extract::{FromRef, Path, Query, State},
     response::{IntoResponse, Redirect},
     routing::{get, post},
     Json, Router,
 };
-use diesel::{delete, insert_into, prelude::*};
+use diesel::{delete, insert_into, prelude::*, update};
 use diesel::{ExpressionMethods, QueryDsl, SelectableHelper};
 use http::StatusCode;
 use serde::Deserialize;
@@ -28,6 +35,10 @@ use serde::Serialize;
 use serde_json::json;
 use tracing_subscriber::filter;
 
+use super::{
+    door_lock, door_mode, door_status, door_totp, doorbell, guest_token, snapshot, unlock_schedule,
+};
+
 pub fn create_router(app_state: AppState) -> Router {
     Router::new()
         .route("/", post(create_door))
@@ -43,6 +54,52 @@ pub fn create_router(app_state: AppState) -> Router {
         )
         .route("/:id/access_history", get(get_door_access_history))
         .route("/:id/access_history/:user_id", get(get_user_access_history))
+        .route("/:id/status", get(door_status::get_door_status))
+        .route("/:id/events", get(door_status::get_door_events))
+        .route("/:id/doorbell", get(doorbell::get_door_rings))
+        .route("/:id/doorbell/answer", post(doorbell::answer_ring))
+        .route(
+            "/:id/guest_tokens",
+            get(guest_token::get_guest_tokens).post(guest_token::create_guest_token),
+        )
+        .route(
+            "/:id/guest_tokens/:token_id/revoke",
+            post(guest_token::revoke_guest_token),
+        )
+        .route(
+            "/:id/totp",
+            get(door_totp::get_current_code)
+                .post(door_totp::rotate_secret)
+                .delete(door_totp::disable),
+        )
+        .route("/:id/snapshots", get(snapshot::get_door_snapshots))
+        .route("/:id/snapshots/:snapshot_id", get(snapshot::get_snapshot))
+        .route(
+            "/:id/lock",
+            get(door_lock::get_door_lock).post(door_lock::set_door_lock),
+        )
+        .route(
+            "/:id/mode",
+            get(door_mode::get_door_mode).post(door_mode::set_door_mode),
+        )
+        .route(
+            "/:id/unlock_schedules",
+            get(unlock_schedule::get_unlock_schedules)
+                .post(unlock_schedule::create_unlock_schedule),
+        )
+        .route(
+            "/:id/unlock_schedules/:schedule_id",
+            axum::routing::delete(unlock_schedule::delete_unlock_schedule),
+        )
+        .route(
+            "/:id/unlock_exceptions",
+            get(unlock_schedule::get_unlock_exceptions)
+                .post(unlock_schedule::create_unlock_exception),
+        )
+        .route(
+            "/:id/unlock_exceptions/:date",
+            axum::routing::delete(unlock_schedule::delete_unlock_exception),
+        )
         .with_state(app_state)
 }
 
@@ -54,8 +111,37 @@ async fn get_door(Path(door_id): Path<i32>) -> impl IntoResponse {
         .select(Door::as_select())
         .get_result(conn);
 
+    // Online state of the door's controllers, the door counts as online if any is
+    #[derive(Serialize)]
+    struct DoorWithStatus {
+        #[serde(flatten)]
+        door: Door,
+        online: bool,
+        last_seen_at: Option<NaiveDateTime>,
+        devices: Vec<Device>,
+        lock_mode: Option<lock_mode::ModeStatus>,
+        // Lockdown or egress, whatever the door's own mode says
+        emergency: Option<&'static str>,
+    }
+
     if let Ok(door) = door {
-        Ok((StatusCode::OK, Json(door)))
+        let devices = device::table
+            .filter(device::door_id.eq(door_id))
+            .select(Device::as_select())
+            .load(conn)
+            .unwrap_or_default();
+
+        Ok((
+            StatusCode::OK,
+            Json(DoorWithStatus {
+                door,
+                online: devices.iter().any(|device| device.online),
+                last_seen_at: devices.iter().filter_map(|device| device.last_seen_at).max(),
+                devices,
+                lock_mode: lock_mode::status(conn, door_id).ok(),
+                emergency: emergency::flag(conn),
+            }),
+        ))
     } else {
         let error_response = json!({ "message": format!("Doors with ID: {} not found.", door_id) });
         Err((StatusCode::NOT_FOUND, Json(error_response)))
@@ -164,7 +250,11 @@ async fn get_user_door_permission(Path((door_id, user_id)): Path<(i32, i32)>) ->
     }
 }
 
-async fn delete_user_access(Path((door_id, user_id)): Path<(i32, i32)>) -> impl IntoResponse {
+async fn delete_user_access(
+    State(events): State<EventBus>,
+    State(devices): State<DeviceHub>,
+    Path((door_id, user_id)): Path<(i32, i32)>,
+) -> impl IntoResponse {
     let conn = &mut establish_connection();
 
     let deleted = delete(
@@ -177,6 +267,14 @@ async fn delete_user_access(Path((door_id, user_id)): Path<(i32, i32)>) -> impl
     .execute(conn);
 
     if let Ok(n) = deleted {
+        if n > 0 {
+            events.publish(DoorEvent::new(
+                EventKind::PermissionRevoked,
+                door_id,
+                Some(user_id),
+            ));
+            allowlist::publish(conn, &devices, door_id);
+        }
         Ok((
             StatusCode::OK,
             Json(json!(format!(
@@ -200,72 +298,128 @@ async fn create_door(Json(body): Json<InsertedDoor>) -> impl IntoResponse {
     }
 }
 
-async fn create_door_permission(Json(body): Json<DoorPermission>) -> impl IntoResponse {
+async fn create_door_permission(
+    State(events): State<EventBus>,
+    State(devices): State<DeviceHub>,
+    Json(body): Json<DoorPermission>,
+) -> impl IntoResponse {
     let conn = &mut establish_connection();
 
     match insert_into(door_permission::table).values(body.clone()).execute(conn) {
-        Ok(_) => Ok((StatusCode::CREATED, Json(body))),
+        Ok(_) => {
+            events.publish(DoorEvent::new(
+                EventKind::PermissionGranted,
+                body.door_id,
+                Some(body.user_profile_id),
+            ));
+            allowlist::publish(conn, &devices, body.door_id);
+            Ok((StatusCode::CREATED, Json(body)))
+        }
         Err(e) => Err((StatusCode::BAD_REQUEST, Json(json!(e.to_string())))),
     }
 }
 
 #[derive(Deserialize)]
 struct OpenDoorQuery {
-    door_code: String,
+    door_code: Option<String>,
+    guest_token: Option<String>,
 }
 
 async fn open_door(
+    State(events): State<EventBus>,
+    State(devices): State<DeviceHub>,
+    State(locks): State<LockDrivers>,
     user: Option<UserProfile>,
     Path(door_id): Path<i32>,
     query: Option<Query<OpenDoorQuery>>,
 ) -> impl IntoResponse {
     let conn = &mut establish_connection();
 
-    if let Some(Query(query)) = query {
-        let results = door_code::table
-            .filter(door_code::code.eq(query.door_code.clone()))
-            .select(DoorCode::as_select())
-            .get_result(conn);
-
-        if let Ok(mut result) = results {
-            if !result.used {
-                result.used = true;
-                result.save_changes::<DoorCode>(conn);
+    let (door_code, guest_token) = match query {
+        Some(Query(query)) => (query.door_code, query.guest_token),
+        None => (None, None),
+    };
+
+    // Codes wait out a lockdown, they stay unused
+    let door_code = door_code.filter(|_| !emergency::in_lockdown(conn));
+    if let Some(door_code) = door_code {
+        // Claimed before the open so two requests can not both use it
+        let now = Utc::now().naive_utc();
+        let claimed = update(
+            door_code::table
+                .filter(door_code::code.eq(&door_code))
+                .filter(door_code::door_id.eq(door_id))
+                .filter(door_code::used.eq(false))
+                .filter(
+                    door_code::expires_at
+                        .is_null()
+                        .or(door_code::expires_at.gt(now)),
+                ),
+        )
+        .set(door_code::used.eq(true))
+        .returning(DoorCode::as_returning())
+        .get_result(conn)
+        .optional();
+
+        match claimed {
+            Ok(Some(_)) => {
+                // A code is only used up once the door actually opened
+                let opened = locks
+                    .open(conn, &events, door_id, None, OpenMethod::Code)
+                    .await;
+                if let Err(e) = opened {
+                    let released = update(door_code::table.find(&door_code))
+                        .set(door_code::used.eq(false))
+                        .execute(conn);
+                    if let Err(e) = released {
+                        tracing::error!("Could not release code for door {door_id}: {e}");
+                    }
+                    return Err(door_lock::open_failed(door_id, e));
+                }
+                allowlist::publish(conn, &devices, door_id);
+                events.publish(DoorEvent::new(EventKind::CodeRedeemed, door_id, None));
                 return Ok((StatusCode::OK, Json("door opened")));
             }
+            Ok(None) => {}
+            Err(e) => tracing::error!("Could not redeem code for door {door_id}: {e}"),
+        }
+    }
+
+    if let Some(guest_token) = guest_token {
+        if guest_tokens::verify(conn, devices.signer(), &guest_token, door_id) {
+            let opened = locks
+                .open(conn, &events, door_id, None, OpenMethod::Guest)
+                .await;
+            if let Err(e) = opened {
+                return Err(door_lock::open_failed(door_id, e));
+            }
+            return Ok((StatusCode::OK, Json("door opened")));
         }
     }
 
     if let Some(user) = user {
-        let results = door_permission::table
-            .filter(
-                door_permission::door_id
-                    .eq(door_id)
-                    .and(door_permission::user_profile_id.eq(user.id))
-                    .and(door_permission::open_permission),
-            )
-            .select(DoorPermission::as_select())
-            .get_result(conn);
-
-        if let Ok(_) = results {
-            let _ = insert_into(access_history::table).values((
-                user_profile_id.eq(user.id),
-                access_timestamp.eq(Utc::now().naive_utc()),
-                access_history::door_id.eq(door_id),
-            ));
+        if access::can_open(conn, door_id, user.id) {
+            let opened = locks
+                .open(conn, &events, door_id, Some(user.id), OpenMethod::Permission)
+                .await;
+            if let Err(e) = opened {
+                return Err(door_lock::open_failed(door_id, e));
+            }
 
             return Ok((StatusCode::OK, Json("door opened")));
         } else {
+            events.publish(DoorEvent::new(EventKind::Denied, door_id, Some(user.id)));
             return Err((
                 StatusCode::UNAUTHORIZED,
-                Json("You are not allowed to open doors"),
+                Json(json!("You are not allowed to open doors")),
             ));
         }
     }
 
+    events.publish(DoorEvent::new(EventKind::Denied, door_id, None));
     return Err((
         StatusCode::UNAUTHORIZED,
-        Json("You are not allowed to open doors"),
+        Json(json!("You are not allowed to open doors")),
     ));
 }
 
@@ -1,5 +1,7 @@
 use crate::{
+    access, allowlist,
     db::establish_connection,
+    devices::DeviceHub,
     models::DoorCode,
     models::UserProfile,
     models::{Door, DoorPermission},
@@ -8,12 +10,12 @@ use crate::{
 };
 use async_session::MemoryStore;
 use axum::{
-    extract::{FromRef, Path, Query},
+    extract::{FromRef, Path, Query, State},
     response::{IntoResponse, Redirect},
     routing::{get, post},
     Json, Router,
 };
-use diesel::{insert_into, prelude::*};
+use diesel::prelude::*;
 use diesel::{ExpressionMethods, QueryDsl, SelectableHelper};
 use http::StatusCode;
 use serde::Deserialize;
@@ -42,14 +44,17 @@ async fn get_door_code(Path(code): Path<String>) -> impl IntoResponse {
     }
 }
 
-async fn create_door_code(Json(body): Json<DoorCode>) -> impl IntoResponse {
+async fn create_door_code(
+    State(devices): State<DeviceHub>,
+    Json(body): Json<DoorCode>,
+) -> impl IntoResponse {
     let conn = &mut establish_connection();
 
-    match insert_into(door_code::table)
-        .values(body.clone())
-        .execute(conn)
-    {
-        Ok(_) => Ok((StatusCode::CREATED, Json(body))),
+    match access::insert_door_code(conn, &body) {
+        Ok(_) => {
+            allowlist::publish(conn, &devices, body.door_id);
+            Ok((StatusCode::CREATED, Json(body)))
+        }
         Err(e) => Err((StatusCode::BAD_REQUEST, Json(e.to_string()))),
     }
 }
@@ -0,0 +1,134 @@
+use async_session::chrono::Utc;
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    Json,
+};
+use diesel::{insert_into, prelude::*};
+use http::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    access,
+    db::establish_connection,
+    locks::{self, LockDrivers, LockError},
+    models::{DoorLock, UserProfile},
+    schema::door_lock,
+};
+
+#[derive(Deserialize)]
+pub struct DoorLockBody {
+    driver: String,
+    #[serde(default)]
+    config: serde_json::Value,
+}
+
+// Only managers see the lock, and even they get relay credentials redacted
+pub async fn get_door_lock(
+    State(locks): State<LockDrivers>,
+    user: UserProfile,
+    Path(door_id): Path<i32>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_manage(conn, door_id, user.id) {
+        return Err(forbidden(door_id));
+    }
+
+    match locks::configured(conn, door_id) {
+        Some(mut lock) => {
+            lock.config = match locks.get(&lock.driver) {
+                Ok(driver) => driver.redact(&lock.config),
+                Err(_) => serde_json::Value::Null,
+            };
+            Ok((StatusCode::OK, Json(json!(lock))))
+        }
+        None => Ok((
+            StatusCode::OK,
+            Json(json!({
+                "door_id": door_id,
+                "driver": locks::DEFAULT_DRIVER,
+                "config": null,
+                "updated_by": null,
+                "updated_at": null,
+            })),
+        )),
+    }
+}
+
+pub async fn set_door_lock(
+    State(locks): State<LockDrivers>,
+    user: UserProfile,
+    Path(door_id): Path<i32>,
+    Json(body): Json<DoorLockBody>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_manage(conn, door_id, user.id) {
+        return Err(forbidden(door_id));
+    }
+
+    let driver = match locks.get(&body.driver) {
+        Ok(driver) => driver,
+        Err(e) => {
+            let error_response = json!({ "message": e.to_string() });
+            return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+        }
+    };
+    if driver.admin_only() && !access::is_admin(conn, user.id) {
+        let error_response = json!({
+            "message": format!("Only administrators can set up `{}` locks.", body.driver)
+        });
+        return Err((StatusCode::FORBIDDEN, Json(error_response)));
+    }
+    if let Err(message) = driver.validate(&body.config) {
+        let error_response = json!({ "message": message });
+        return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+    }
+
+    let now = Utc::now().naive_utc();
+    let saved = insert_into(door_lock::table)
+        .values((
+            door_lock::door_id.eq(door_id),
+            door_lock::driver.eq(&body.driver),
+            door_lock::config.eq(&body.config),
+            door_lock::updated_by.eq(user.id),
+            door_lock::updated_at.eq(now),
+        ))
+        .on_conflict(door_lock::door_id)
+        .do_update()
+        .set((
+            door_lock::driver.eq(&body.driver),
+            door_lock::config.eq(&body.config),
+            door_lock::updated_by.eq(user.id),
+            door_lock::updated_at.eq(now),
+        ))
+        .returning(DoorLock::as_returning())
+        .get_result(conn);
+
+    match saved {
+        Ok(mut lock) => {
+            lock.config = driver.redact(&lock.config);
+            Ok((StatusCode::OK, Json(json!(lock))))
+        }
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+pub(super) fn open_failed(door_id: i32, e: LockError) -> (StatusCode, Json<serde_json::Value>) {
+    tracing::error!("Could not open door {door_id}: {e}");
+    (
+        StatusCode::BAD_GATEWAY,
+        Json(json!(format!("Door could not be opened: {e}"))),
+    )
+}
+
+fn forbidden(door_id: i32) -> (StatusCode, Json<serde_json::Value>) {
+    let error_response =
+        json!({ "message": format!("You can not manage door with ID {door_id}.") });
+    (StatusCode::FORBIDDEN, Json(error_response))
+}
@@ -0,0 +1,121 @@
+use async_session::chrono::{Duration, NaiveDateTime, Utc};
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    Json,
+};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    access,
+    db::establish_connection,
+    emergency,
+    events::EventBus,
+    lock_mode::{self, LockMode, ModeError},
+    locks::LockDrivers,
+    models::UserProfile,
+};
+
+// A year. Longer windows take an `until`, far larger values would overflow the
+// window end.
+const MAX_UNLOCK_MINUTES: i64 = 366 * 24 * 60;
+
+// `{"mode": "unlocked", "minutes": 30}`, `{"mode": "unlocked", "until": "2023-11-20T17:00:00"}`
+// or `{"mode": "locked"}`. Times are UTC like everywhere else in the API.
+#[derive(Deserialize)]
+pub struct DoorModeBody {
+    mode: String,
+    minutes: Option<i64>,
+    until: Option<NaiveDateTime>,
+}
+
+pub async fn get_door_mode(user: UserProfile, Path(door_id): Path<i32>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_see(conn, door_id, user.id) {
+        let error_response = json!({ "message": format!("Doors with ID: {door_id} not found.") });
+        return Err((StatusCode::NOT_FOUND, Json(error_response)));
+    }
+
+    #[derive(Serialize)]
+    struct ModeWithEmergency {
+        #[serde(flatten)]
+        status: lock_mode::ModeStatus,
+        emergency: Option<&'static str>,
+    }
+
+    match lock_mode::status(conn, door_id) {
+        Ok(status) => Ok((
+            StatusCode::OK,
+            Json(json!(ModeWithEmergency {
+                status,
+                emergency: emergency::flag(conn),
+            })),
+        )),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+pub async fn set_door_mode(
+    State(events): State<EventBus>,
+    State(locks): State<LockDrivers>,
+    user: UserProfile,
+    Path(door_id): Path<i32>,
+    Json(body): Json<DoorModeBody>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_manage(conn, door_id, user.id) {
+        let error_response =
+            json!({ "message": format!("You can not manage door with ID {door_id}.") });
+        return Err((StatusCode::FORBIDDEN, Json(error_response)));
+    }
+    // Only clearing the emergency hands the doors back
+    if let Some(mode) = emergency::flag(conn) {
+        let error_response =
+            json!({ "message": format!("Doors can not be changed during a site-wide {mode}.") });
+        return Err((StatusCode::CONFLICT, Json(error_response)));
+    }
+
+    let now = Utc::now().naive_utc();
+    let (mode, until) = match (body.mode.as_str(), body.minutes, body.until) {
+        ("locked", None, None) => (LockMode::Locked, None),
+        ("unlocked", Some(minutes), None) if (1..=MAX_UNLOCK_MINUTES).contains(&minutes) => {
+            (LockMode::Unlocked, Some(now + Duration::minutes(minutes)))
+        }
+        ("unlocked", None, Some(until)) if until > now => (LockMode::Unlocked, Some(until)),
+        ("unlocked", Some(_), None) => {
+            let message = format!("minutes must be between 1 and {MAX_UNLOCK_MINUTES}");
+            return Err(bad_request(&message));
+        }
+        ("unlocked", None, Some(_)) => return Err(bad_request("until has to be in the future")),
+        // Doors are never left unlocked without a way back
+        ("unlocked", ..) => return Err(bad_request("Unlocking needs either minutes or until")),
+        ("locked", ..) => return Err(bad_request("Locking takes neither minutes nor until")),
+        _ => return Err(bad_request("mode must be locked or unlocked")),
+    };
+
+    match lock_mode::set(conn, &locks, &events, door_id, mode, until, Some(user.id)).await {
+        Ok(mode) => Ok((StatusCode::OK, Json(json!(mode)))),
+        Err(ModeError::Lock(e)) => {
+            tracing::error!("Could not switch door {door_id} to {}: {e}", mode.as_str());
+            Err((
+                StatusCode::BAD_GATEWAY,
+                Json(json!({ "message": format!("Door could not be {}: {e}", mode.as_str()) })),
+            ))
+        }
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)))
+        }
+    }
+}
+
+fn bad_request(message: &str) -> (StatusCode, Json<serde_json::Value>) {
+    (StatusCode::BAD_REQUEST, Json(json!({ "message": message })))
+}
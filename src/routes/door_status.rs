@@ -0,0 +1,89 @@
+use axum::{
+    extract::{Path, Query},
+    response::IntoResponse,
+    Json,
+};
+use diesel::prelude::*;
+use http::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    access,
+    db::establish_connection,
+    door_state::SensorState,
+    events,
+    models::{DoorState, UserProfile},
+    schema::door_state,
+};
+
+const DEFAULT_EVENT_LIMIT: i64 = 50;
+const MAX_EVENT_LIMIT: i64 = 500;
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    before: Option<i64>,
+    limit: Option<i64>,
+}
+
+pub async fn get_door_status(user: UserProfile, Path(door_id): Path<i32>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_read_history(conn, door_id, user.id) {
+        return Err(forbidden());
+    }
+
+    let state = door_state::table
+        .find(door_id)
+        .select(DoorState::as_select())
+        .get_result(conn)
+        .optional();
+
+    match state {
+        Ok(Some(state)) => Ok((StatusCode::OK, Json(json!(state)))),
+        // Doors without a reporting sensor have never left the initial state
+        Ok(None) => Ok((
+            StatusCode::OK,
+            Json(json!({
+                "door_id": door_id,
+                "state": SensorState::Closed.as_str(),
+                "locked": null,
+                "changed_at": null,
+            })),
+        )),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+pub async fn get_door_events(
+    user: UserProfile,
+    Path(door_id): Path<i32>,
+    Query(query): Query<EventsQuery>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_read_history(conn, door_id, user.id) {
+        return Err(forbidden());
+    }
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_EVENT_LIMIT)
+        .clamp(1, MAX_EVENT_LIMIT);
+
+    match events::history(conn, door_id, query.before, limit) {
+        Ok(events) => Ok((StatusCode::OK, Json(events))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+fn forbidden() -> (StatusCode, Json<serde_json::Value>) {
+    let error_response = json!({ "message": "You are not allowed to see this door's history." });
+    (StatusCode::FORBIDDEN, Json(error_response))
+}
@@ -0,0 +1,119 @@
+use async_session::chrono::Utc;
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    Json,
+};
+use diesel::{delete, insert_into, prelude::*};
+use http::StatusCode;
+use serde_json::json;
+
+use crate::{
+    access, allowlist, db::establish_connection, devices::DeviceHub, models::UserProfile,
+    schema::door_totp, totp,
+};
+
+// What permitted users type into the keypad right now
+pub async fn get_current_code(user: UserProfile, Path(door_id): Path<i32>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_open(conn, door_id, user.id) {
+        let error_response = json!({ "message": "You are not allowed to open this door." });
+        return Err((StatusCode::FORBIDDEN, Json(error_response)));
+    }
+
+    match totp::door_secret(conn, door_id).and_then(|secret| totp::current(&secret)) {
+        Some((code, expires_in)) => Ok((
+            StatusCode::OK,
+            Json(json!({
+                "code": code,
+                "period": totp::PERIOD_SECONDS,
+                "expires_in": expires_in,
+            })),
+        )),
+        None => Err(not_enabled(door_id)),
+    }
+}
+
+// Enables rotating codes for the door, or rotates the secret if they already are
+pub async fn rotate_secret(
+    State(devices): State<DeviceHub>,
+    user: UserProfile,
+    Path(door_id): Path<i32>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_manage(conn, door_id, user.id) {
+        return Err(forbidden(door_id));
+    }
+
+    let secret = totp::generate_secret();
+    let now = Utc::now().naive_utc();
+
+    let rotated = insert_into(door_totp::table)
+        .values((
+            door_totp::door_id.eq(door_id),
+            door_totp::secret.eq(&secret),
+            door_totp::created_at.eq(now),
+        ))
+        .on_conflict(door_totp::door_id)
+        .do_update()
+        .set((door_totp::secret.eq(&secret), door_totp::created_at.eq(now)))
+        .execute(conn);
+
+    match rotated {
+        Ok(_) => {
+            // Keypads check codes themselves while offline
+            allowlist::publish(conn, &devices, door_id);
+            Ok((
+                StatusCode::OK,
+                Json(json!({ "door_id": door_id, "created_at": now })),
+            ))
+        }
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+pub async fn disable(
+    State(devices): State<DeviceHub>,
+    user: UserProfile,
+    Path(door_id): Path<i32>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_manage(conn, door_id, user.id) {
+        return Err(forbidden(door_id));
+    }
+
+    match delete(door_totp::table.find(door_id)).execute(conn) {
+        Ok(0) => Err(not_enabled(door_id)),
+        Ok(_) => {
+            allowlist::publish(conn, &devices, door_id);
+            Ok((
+                StatusCode::OK,
+                Json(json!(format!(
+                    "Rotating codes for door {door_id} were disabled."
+                ))),
+            ))
+        }
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+fn forbidden(door_id: i32) -> (StatusCode, Json<serde_json::Value>) {
+    let error_response =
+        json!({ "message": format!("You can not manage door with ID {door_id}.") });
+    (StatusCode::FORBIDDEN, Json(error_response))
+}
+
+fn not_enabled(door_id: i32) -> (StatusCode, Json<serde_json::Value>) {
+    let error_response =
+        json!({ "message": format!("Door with ID {door_id} has no rotating code.") });
+    (StatusCode::NOT_FOUND, Json(error_response))
+}
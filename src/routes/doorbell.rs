@@ -0,0 +1,167 @@
+use async_session::chrono::{Duration, NaiveDateTime, Utc};
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    Json,
+};
+use diesel::{prelude::*, update};
+use http::StatusCode;
+use serde_json::json;
+
+use crate::{
+    access::{self, OpenMethod},
+    db::establish_connection,
+    events::{DoorEvent, EventBus, EventKind},
+    locks::LockDrivers,
+    models::{DoorbellRing, UserProfile},
+    schema::doorbell_ring,
+};
+
+use super::door_lock;
+
+// Rings older than this can no longer be answered with a remote open
+const RING_TIMEOUT_MINUTES: i64 = 5;
+
+pub async fn get_door_rings(user: UserProfile, Path(door_id): Path<i32>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_open(conn, door_id, user.id) {
+        let error_response = json!({ "message": "You are not allowed to see this doorbell." });
+        return Err((StatusCode::FORBIDDEN, Json(error_response)));
+    }
+
+    let rings = doorbell_ring::table
+        .filter(doorbell_ring::door_id.eq(door_id))
+        .order(doorbell_ring::rung_at.desc())
+        .select(DoorbellRing::as_select())
+        .load(conn);
+
+    match rings {
+        Ok(rings) => Ok((StatusCode::OK, Json(rings))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+// Opens the door for whoever is ringing and marks the latest ring as answered
+pub async fn answer_ring(
+    State(events): State<EventBus>,
+    State(locks): State<LockDrivers>,
+    user: UserProfile,
+    Path(door_id): Path<i32>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_open(conn, door_id, user.id) {
+        events.publish(DoorEvent::new(EventKind::Denied, door_id, Some(user.id)));
+        let error_response = json!({ "message": "You are not allowed to open doors" });
+        return Err((StatusCode::UNAUTHORIZED, Json(error_response)));
+    }
+
+    let answered = claim_ring(conn, door_id, user.id, Utc::now().naive_utc());
+
+    match answered {
+        Ok(ring) => {
+            let opened = locks
+                .open(
+                    conn,
+                    &events,
+                    door_id,
+                    Some(user.id),
+                    OpenMethod::Permission,
+                )
+                .await;
+            if let Err(e) = opened {
+                // Somebody else may still get the door open
+                if let Err(e) = release_ring(conn, &ring) {
+                    tracing::error!("Could not release doorbell ring {}: {e}", ring.id);
+                }
+                return Err(door_lock::open_failed(door_id, e));
+            }
+            events.publish(DoorEvent::new(
+                EventKind::DoorbellAnswered,
+                door_id,
+                Some(user.id),
+            ));
+            Ok((StatusCode::OK, Json(ring)))
+        }
+        Err(AnswerError::NobodyRinging) => {
+            let error_response = json!({ "message": "Nobody is ringing at this door." });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+        Err(AnswerError::AlreadyAnswered) => {
+            let error_response = json!({ "message": "Somebody else already answered." });
+            Err((StatusCode::CONFLICT, Json(error_response)))
+        }
+        Err(AnswerError::Database(e)) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+#[derive(Debug)]
+enum AnswerError {
+    NobodyRinging,
+    AlreadyAnswered,
+    Database(diesel::result::Error),
+}
+
+// Marks the latest unanswered ring as answered by the user
+fn claim_ring(
+    conn: &mut PgConnection,
+    door_id: i32,
+    user_id: i32,
+    now: NaiveDateTime,
+) -> Result<DoorbellRing, AnswerError> {
+    let ring = doorbell_ring::table
+        .filter(doorbell_ring::door_id.eq(door_id))
+        .filter(doorbell_ring::answered_at.is_null())
+        .filter(doorbell_ring::rung_at.gt(now - Duration::minutes(RING_TIMEOUT_MINUTES)))
+        .order(doorbell_ring::rung_at.desc())
+        .select(DoorbellRing::as_select())
+        .first(conn)
+        .optional()
+        .map_err(AnswerError::Database)?
+        .ok_or(AnswerError::NobodyRinging)?;
+
+    let latency = (now - ring.rung_at).num_milliseconds();
+    // Only the first of several people answering at once gets to open the door
+    update(
+        doorbell_ring::table
+            .find(ring.id)
+            .filter(doorbell_ring::answered_at.is_null()),
+    )
+    .set((
+        doorbell_ring::answered_by.eq(user_id),
+        doorbell_ring::answered_at.eq(now),
+        doorbell_ring::answer_latency_ms.eq(i32::try_from(latency).unwrap_or(i32::MAX)),
+    ))
+    .returning(DoorbellRing::as_returning())
+    .get_result(conn)
+    .map_err(|e| match e {
+        diesel::result::Error::NotFound => AnswerError::AlreadyAnswered,
+        e => AnswerError::Database(e),
+    })
+}
+
+// Undoes the claim when the door did not open
+fn release_ring(conn: &mut PgConnection, ring: &DoorbellRing) -> QueryResult<usize> {
+    update(
+        doorbell_ring::table
+            .find(ring.id)
+            .filter(doorbell_ring::answered_by.eq(ring.answered_by))
+            .filter(doorbell_ring::answered_at.eq(ring.answered_at)),
+    )
+    .set((
+        doorbell_ring::answered_by.eq(None::<i32>),
+        doorbell_ring::answered_at.eq(None::<NaiveDateTime>),
+        doorbell_ring::answer_latency_ms.eq(None::<i32>),
+    ))
+    .execute(conn)
+}
+
+#[cfg(test)]
+mod tests;
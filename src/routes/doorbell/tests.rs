@@ -0,0 +1,86 @@
+use async_session::chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{insert_into, prelude::*, PgConnection};
+
+use super::{claim_ring, release_ring, AnswerError};
+use crate::{schema::doorbell_ring, testing};
+
+fn ring(conn: &mut PgConnection, door_id: i32, rung_at: NaiveDateTime) -> i32 {
+    insert_into(doorbell_ring::table)
+        .values((
+            doorbell_ring::door_id.eq(door_id),
+            doorbell_ring::rung_at.eq(rung_at),
+        ))
+        .returning(doorbell_ring::id)
+        .get_result(conn)
+        .unwrap()
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn the_latest_ring_is_answered_with_its_latency() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let now = Utc::now().naive_utc();
+    ring(conn, door_id, now - Duration::seconds(90));
+    let latest = ring(conn, door_id, now - Duration::seconds(12));
+
+    let answered = claim_ring(conn, door_id, user_id, now).unwrap();
+
+    assert_eq!(answered.id, latest);
+    assert_eq!(answered.answered_by, Some(user_id));
+    assert_eq!(answered.answer_latency_ms, Some(12_000));
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn rings_are_answered_once() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let now = Utc::now().naive_utc();
+    ring(conn, door_id, now - Duration::seconds(5));
+
+    assert!(claim_ring(conn, door_id, user_id, now).is_ok());
+    assert!(matches!(
+        claim_ring(conn, door_id, user_id, now),
+        Err(AnswerError::NobodyRinging)
+    ));
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn stale_rings_can_not_be_answered() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let now = Utc::now().naive_utc();
+    ring(conn, door_id, now - Duration::minutes(6));
+
+    assert!(matches!(
+        claim_ring(conn, door_id, user_id, now),
+        Err(AnswerError::NobodyRinging)
+    ));
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn released_rings_can_be_answered_again() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let alice = testing::user(conn, "alice");
+    let bob = testing::user(conn, "bob");
+    let door_id = testing::door(conn, alice);
+    let now = Utc::now().naive_utc();
+    let rung = ring(conn, door_id, now - Duration::seconds(5));
+
+    let claimed = claim_ring(conn, door_id, alice, now).unwrap();
+    assert_eq!(release_ring(conn, &claimed).unwrap(), 1);
+
+    let answered = claim_ring(conn, door_id, bob, now).unwrap();
+    assert_eq!(answered.id, rung);
+    assert_eq!(answered.answered_by, Some(bob));
+}
@@ -0,0 +1,247 @@
+use async_session::chrono::Utc;
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use diesel::{delete, insert_into, prelude::*};
+use http::StatusCode;
+use protocol::EmergencyMode;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    access,
+    db::establish_connection,
+    devices::DeviceHub,
+    emergency::{self, EmergencyError},
+    events::EventBus,
+    locks::LockDrivers,
+    models::{Emergency, Responder, UserProfile},
+    schema::{emergency as emergency_table, responder},
+    AppState,
+};
+
+const HISTORY_LIMIT: i64 = 50;
+
+pub fn create_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/", get(get_emergency))
+        .route("/lockdown", post(start_lockdown))
+        .route("/egress", post(start_egress))
+        .route("/clear", post(clear_emergency))
+        .route("/responders", get(get_responders).post(add_responder))
+        .route(
+            "/responders/:user_id",
+            axum::routing::delete(remove_responder),
+        )
+        .with_state(app_state)
+}
+
+#[derive(Deserialize, Default)]
+struct TriggerBody {
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ResponderBody {
+    user_profile_id: i32,
+}
+
+// The one in force, if any, and the latest ones with who started and cleared them
+async fn get_emergency(user: UserProfile) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::is_admin(conn, user.id) {
+        return Err(forbidden());
+    }
+
+    let history = emergency_table::table
+        .order(emergency_table::id.desc())
+        .limit(HISTORY_LIMIT)
+        .select(Emergency::as_select())
+        .load(conn);
+
+    match history {
+        Ok(history) => Ok((
+            StatusCode::OK,
+            Json(json!({
+                "active": history.iter().find(|emergency| emergency.cleared_at.is_none()),
+                "history": history,
+            })),
+        )),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+async fn start_lockdown(
+    State(events): State<EventBus>,
+    State(devices): State<DeviceHub>,
+    State(locks): State<LockDrivers>,
+    user: UserProfile,
+    body: Option<Json<TriggerBody>>,
+) -> impl IntoResponse {
+    let body = body.map(|Json(body)| body).unwrap_or_default();
+    trigger(
+        &events,
+        &devices,
+        &locks,
+        user,
+        EmergencyMode::Lockdown,
+        body,
+    )
+    .await
+}
+
+async fn start_egress(
+    State(events): State<EventBus>,
+    State(devices): State<DeviceHub>,
+    State(locks): State<LockDrivers>,
+    user: UserProfile,
+    body: Option<Json<TriggerBody>>,
+) -> impl IntoResponse {
+    let body = body.map(|Json(body)| body).unwrap_or_default();
+    trigger(&events, &devices, &locks, user, EmergencyMode::Egress, body).await
+}
+
+async fn trigger(
+    events: &EventBus,
+    devices: &DeviceHub,
+    locks: &LockDrivers,
+    user: UserProfile,
+    mode: EmergencyMode,
+    body: TriggerBody,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, Json<serde_json::Value>)> {
+    let conn = &mut establish_connection();
+
+    if !access::is_admin(conn, user.id) {
+        return Err(forbidden());
+    }
+
+    match emergency::trigger(conn, locks, events, devices, mode, body.reason, user.id).await {
+        // Doors that did not follow are listed, the emergency stands regardless
+        Ok((emergency, failed_doors)) => Ok((
+            StatusCode::CREATED,
+            Json(json!({ "emergency": emergency, "failed_doors": failed_doors })),
+        )),
+        Err(e) => Err(emergency_error(e)),
+    }
+}
+
+async fn clear_emergency(
+    State(events): State<EventBus>,
+    State(devices): State<DeviceHub>,
+    State(locks): State<LockDrivers>,
+    user: UserProfile,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::is_admin(conn, user.id) {
+        return Err(forbidden());
+    }
+
+    match emergency::clear(conn, &locks, &events, &devices, user.id).await {
+        Ok((emergency, failed_doors)) => Ok((
+            StatusCode::OK,
+            Json(json!({ "emergency": emergency, "failed_doors": failed_doors })),
+        )),
+        Err(e) => Err(emergency_error(e)),
+    }
+}
+
+async fn get_responders(user: UserProfile) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::is_admin(conn, user.id) {
+        return Err(forbidden());
+    }
+
+    let responders = responder::table
+        .order(responder::user_profile_id.asc())
+        .select(Responder::as_select())
+        .load(conn);
+
+    match responders {
+        Ok(responders) => Ok((StatusCode::OK, Json(json!(responders)))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+async fn add_responder(
+    State(devices): State<DeviceHub>,
+    user: UserProfile,
+    Json(body): Json<ResponderBody>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::is_admin(conn, user.id) {
+        return Err(forbidden());
+    }
+
+    let added = insert_into(responder::table)
+        .values((
+            responder::user_profile_id.eq(body.user_profile_id),
+            responder::added_by.eq(user.id),
+            responder::added_at.eq(Utc::now().naive_utc()),
+        ))
+        .returning(Responder::as_returning())
+        .get_result(conn);
+
+    match added {
+        Ok(responder) => {
+            emergency::responders_changed(conn, &devices);
+            Ok((StatusCode::CREATED, Json(json!(responder))))
+        }
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+async fn remove_responder(
+    State(devices): State<DeviceHub>,
+    user: UserProfile,
+    Path(user_id): Path<i32>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::is_admin(conn, user.id) {
+        return Err(forbidden());
+    }
+
+    match delete(responder::table.find(user_id)).execute(conn) {
+        Ok(1) => {
+            emergency::responders_changed(conn, &devices);
+            Ok((
+                StatusCode::OK,
+                Json(json!(format!("User {user_id} is no longer a responder."))),
+            ))
+        }
+        _ => {
+            let error_response =
+                json!({ "message": format!("User {user_id} is not a responder.") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+fn emergency_error(e: EmergencyError) -> (StatusCode, Json<serde_json::Value>) {
+    let status = match e {
+        EmergencyError::AlreadyActive(_) | EmergencyError::NotActive => StatusCode::CONFLICT,
+        EmergencyError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, Json(json!({ "message": e.to_string() })))
+}
+
+fn forbidden() -> (StatusCode, Json<serde_json::Value>) {
+    let error_response = json!({ "message": "Only administrators can manage emergencies." });
+    (StatusCode::FORBIDDEN, Json(error_response))
+}
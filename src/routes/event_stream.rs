@@ -0,0 +1,142 @@
+use axum::{
+    extract::{Query, State},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    routing::get,
+    Json, Router,
+};
+use futures::stream::Stream;
+use http::{HeaderMap, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use std::convert::Infallible;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    access,
+    db::establish_connection,
+    events::{self, DoorEvent, EventBus, EventKind},
+    models::UserProfile,
+    AppState,
+};
+
+use super::auth::TokenUser;
+
+pub fn create_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/", get(event_stream))
+        .with_state(app_state)
+}
+
+#[derive(Deserialize)]
+struct EventStreamQuery {
+    // Comma separated door IDs, every visible door when left out
+    doors: Option<String>,
+}
+
+async fn event_stream(
+    State(events): State<EventBus>,
+    session_user: Option<UserProfile>,
+    token_user: Option<TokenUser>,
+    headers: HeaderMap,
+    Query(query): Query<EventStreamQuery>,
+) -> impl IntoResponse {
+    let Some(user) = session_user.or(token_user.map(|TokenUser(user)| user)) else {
+        let error_response = json!({ "message": "You are not logged in." });
+        return Err((StatusCode::UNAUTHORIZED, Json(error_response)));
+    };
+
+    let conn = &mut establish_connection();
+
+    let visible = match access::visible_doors(conn, user.id) {
+        Ok(visible) => visible,
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            return Err((StatusCode::NOT_FOUND, Json(error_response)));
+        }
+    };
+
+    let door_ids: Vec<i32> = match query.doors {
+        Some(doors) => doors
+            .split(',')
+            .filter_map(|id| id.trim().parse().ok())
+            .filter(|id| visible.contains(id))
+            .collect(),
+        None => visible,
+    };
+
+    let last_event_id = headers
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<i64>().ok());
+
+    // Subscribe before reading the backlog so nothing slips in between the two
+    let receiver = events.subscribe();
+
+    let backlog = match last_event_id {
+        Some(last_event_id) => match events::since(conn, last_event_id, &door_ids) {
+            Ok(backlog) => backlog,
+            Err(e) => {
+                let error_response = json!({ "error": format!("{e}") });
+                return Err((StatusCode::NOT_FOUND, Json(error_response)));
+            }
+        },
+        None => Vec::new(),
+    };
+
+    let stream = door_event_stream(user, door_ids, backlog, receiver);
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+fn door_event_stream(
+    user: UserProfile,
+    mut door_ids: Vec<i32>,
+    backlog: Vec<DoorEvent>,
+    mut receiver: tokio::sync::broadcast::Receiver<DoorEvent>,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    async_stream::stream! {
+        let replayed_up_to = backlog.last().map_or(0, |event| event.id);
+
+        for event in backlog {
+            yield Ok(sse_event(&event));
+        }
+
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(n)) => {
+                    tracing::warn!("Event stream for {} skipped {n} events", user.username);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            if !door_ids.contains(&event.door_id) || (event.id != 0 && event.id <= replayed_up_to) {
+                continue;
+            }
+
+            yield Ok(sse_event(&event));
+
+            // Same as the WebSocket stream, losing access ends the door's feed
+            if event.kind == EventKind::PermissionRevoked && event.user_profile_id == Some(user.id) {
+                let conn = &mut establish_connection();
+                let still_visible = access::visible_doors(conn, user.id)
+                    .is_ok_and(|visible| visible.contains(&event.door_id));
+                if !still_visible {
+                    door_ids.retain(|id| *id != event.door_id);
+                }
+            }
+        }
+    }
+}
+
+fn sse_event(event: &DoorEvent) -> Event {
+    Event::default()
+        .id(event.id.to_string())
+        .event(event.kind.as_str())
+        .json_data(event)
+        .unwrap()
+}
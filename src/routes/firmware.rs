@@ -0,0 +1,357 @@
+use async_session::chrono::Utc;
+use axum::{
+    body::{boxed, Body, Bytes},
+    extract::{DefaultBodyLimit, Path, Query, State},
+    handler::Handler,
+    http::Request,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use diesel::{insert_into, prelude::*, update};
+use http::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
+use uuid::Uuid;
+
+use crate::{
+    access,
+    db::establish_connection,
+    devices::DeviceHub,
+    firmware::{self, FirmwareStore},
+    models::{Device, DeviceGroup, FirmwareImage, FirmwareUpdate, UserProfile},
+    schema::{device, device_group, firmware_image, firmware_update},
+    AppState,
+};
+
+// Leaves room for the largest OTA partition controllers have
+const MAX_IMAGE_BYTES: usize = 16 * 1024 * 1024;
+
+pub fn create_router(app_state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/",
+            get(get_images).post(upload_image.layer(DefaultBodyLimit::max(MAX_IMAGE_BYTES))),
+        )
+        .route("/:id/image", get(download_image))
+        .route("/groups", get(get_groups).post(create_group))
+        .route("/groups/:id/target", post(set_group_target))
+        .route("/groups/:id/devices", post(add_group_device))
+        .route("/devices/:device_id/target", post(set_device_target))
+        .route("/devices/:device_id/updates", get(get_device_updates))
+        .with_state(app_state)
+}
+
+// The signature is made offline with the firmware key, over the image's SHA-256
+#[derive(Deserialize)]
+struct UploadQuery {
+    version: String,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct CreateGroup {
+    name: String,
+}
+
+// No firmware clears the target
+#[derive(Deserialize)]
+struct SetTarget {
+    firmware_id: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct AddGroupDevice {
+    device_id: i32,
+}
+
+async fn get_images(user: UserProfile) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::is_admin(conn, user.id) {
+        return Err(forbidden());
+    }
+
+    let images = firmware_image::table
+        .order(firmware_image::created_at.desc())
+        .select(FirmwareImage::as_select())
+        .load(conn);
+
+    match images {
+        Ok(images) => Ok((StatusCode::OK, Json(images))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+// The raw image is the request body. It is only stored if its signature
+// checks out, controllers check it again before flashing.
+async fn upload_image(
+    State(store): State<FirmwareStore>,
+    user: UserProfile,
+    Query(query): Query<UploadQuery>,
+    body: Bytes,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::is_admin(conn, user.id) {
+        return Err(forbidden());
+    }
+    if body.is_empty() {
+        let error_response = json!({ "message": "Firmware images can not be empty." });
+        return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+    }
+
+    if !store.can_verify() {
+        let error_response =
+            json!({ "message": "Firmware uploads need FIRMWARE_PUBLIC_KEY to be set." });
+        return Err((StatusCode::SERVICE_UNAVAILABLE, Json(error_response)));
+    }
+
+    let digest = Sha256::digest(&body);
+    if !store.verify(&digest, &query.signature) {
+        let error_response =
+            json!({ "message": "The signature does not match the image or the firmware key." });
+        return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+    }
+
+    let file_name = format!("{}.bin", Uuid::new_v4());
+
+    if let Err(e) = store.write(&file_name, &body).await {
+        let error_response = json!({ "error": format!("{e}") });
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)));
+    }
+
+    let created = insert_into(firmware_image::table)
+        .values((
+            firmware_image::version.eq(&query.version),
+            firmware_image::sha256.eq(hex::encode(digest)),
+            firmware_image::signature.eq(query.signature.to_lowercase()),
+            firmware_image::size_bytes.eq(body.len() as i32),
+            firmware_image::file_name.eq(&file_name),
+            firmware_image::uploaded_by.eq(user.id),
+            firmware_image::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .returning(FirmwareImage::as_returning())
+        .get_result(conn);
+
+    match created {
+        Ok(created) => Ok((StatusCode::CREATED, Json(created))),
+        Err(e) => {
+            let _ = tokio::fs::remove_file(store.path(&file_name)).await;
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+// Controllers fetch images in chunks and resume with Range requests
+async fn download_image(
+    _device: Device,
+    State(store): State<FirmwareStore>,
+    Path(firmware_id): Path<i32>,
+    request: Request<Body>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let file_name = firmware_image::table
+        .find(firmware_id)
+        .select(firmware_image::file_name)
+        .get_result::<String>(conn);
+
+    let Ok(file_name) = file_name else {
+        return Err(not_found(firmware_id));
+    };
+
+    match ServeFile::new(store.path(&file_name))
+        .oneshot(request)
+        .await
+    {
+        Ok(response) => Ok(response.map(boxed)),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)))
+        }
+    }
+}
+
+async fn get_groups(user: UserProfile) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::is_admin(conn, user.id) {
+        return Err(forbidden());
+    }
+
+    let groups = device_group::table
+        .order(device_group::name.asc())
+        .select(DeviceGroup::as_select())
+        .load(conn);
+
+    match groups {
+        Ok(groups) => Ok((StatusCode::OK, Json(groups))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+async fn create_group(user: UserProfile, Json(body): Json<CreateGroup>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::is_admin(conn, user.id) {
+        return Err(forbidden());
+    }
+
+    let created = insert_into(device_group::table)
+        .values((
+            device_group::name.eq(&body.name),
+            device_group::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .returning(DeviceGroup::as_returning())
+        .get_result(conn);
+
+    match created {
+        Ok(created) => Ok((StatusCode::CREATED, Json(created))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+async fn set_group_target(
+    State(devices): State<DeviceHub>,
+    user: UserProfile,
+    Path(group_id): Path<i32>,
+    Json(body): Json<SetTarget>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::is_admin(conn, user.id) {
+        return Err(forbidden());
+    }
+
+    let updated = update(device_group::table.find(group_id))
+        .set(device_group::target_firmware_id.eq(body.firmware_id))
+        .returning(DeviceGroup::as_returning())
+        .get_result(conn);
+
+    match updated {
+        Ok(group) => {
+            firmware::notify_group(conn, &devices, group.id);
+            Ok((StatusCode::OK, Json(group)))
+        }
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+async fn add_group_device(
+    State(devices): State<DeviceHub>,
+    user: UserProfile,
+    Path(group_id): Path<i32>,
+    Json(body): Json<AddGroupDevice>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::is_admin(conn, user.id) {
+        return Err(forbidden());
+    }
+
+    let updated = update(device::table.find(body.device_id))
+        .set(device::group_id.eq(group_id))
+        .returning(Device::as_returning())
+        .get_result(conn);
+
+    match updated {
+        Ok(device) => {
+            firmware::notify(conn, &devices, &device);
+            Ok((StatusCode::OK, Json(device)))
+        }
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+async fn set_device_target(
+    State(devices): State<DeviceHub>,
+    user: UserProfile,
+    Path(device_id): Path<i32>,
+    Json(body): Json<SetTarget>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::is_admin(conn, user.id) {
+        return Err(forbidden());
+    }
+
+    let updated = update(device::table.find(device_id))
+        .set(device::target_firmware_id.eq(body.firmware_id))
+        .returning(Device::as_returning())
+        .get_result(conn);
+
+    match updated {
+        Ok(device) => {
+            firmware::notify(conn, &devices, &device);
+            Ok((StatusCode::OK, Json(device)))
+        }
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+// Admins and whoever manages the device's door can follow its updates
+async fn get_device_updates(user: UserProfile, Path(device_id): Path<i32>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let device = device::table
+        .find(device_id)
+        .select(Device::as_select())
+        .get_result(conn)
+        .ok()
+        .filter(|device| {
+            access::is_admin(conn, user.id) || access::can_manage(conn, device.door_id, user.id)
+        });
+
+    let Some(device) = device else {
+        let error_response =
+            json!({ "message": format!("Device with ID: {device_id} not found.") });
+        return Err((StatusCode::NOT_FOUND, Json(error_response)));
+    };
+
+    let updates = FirmwareUpdate::belonging_to(&device)
+        .order(firmware_update::created_at.desc())
+        .select(FirmwareUpdate::as_select())
+        .load(conn);
+
+    match updates {
+        Ok(updates) => Ok((StatusCode::OK, Json(updates))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+fn forbidden() -> (StatusCode, Json<serde_json::Value>) {
+    let error_response = json!({ "message": "Only administrators can manage firmware." });
+    (StatusCode::FORBIDDEN, Json(error_response))
+}
+
+fn not_found(firmware_id: i32) -> (StatusCode, Json<serde_json::Value>) {
+    let error_response =
+        json!({ "message": format!("Firmware image with ID: {firmware_id} not found.") });
+    (StatusCode::NOT_FOUND, Json(error_response))
+}
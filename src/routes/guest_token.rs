@@ -0,0 +1,155 @@
+use async_session::chrono::{NaiveDateTime, Utc};
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    Json,
+};
+use diesel::{insert_into, prelude::*, update};
+use http::StatusCode;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    access,
+    db::establish_connection,
+    devices::DeviceHub,
+    guest_tokens,
+    models::{GuestToken, UserProfile},
+    schema::guest_token,
+};
+
+#[derive(Deserialize)]
+pub struct CreateGuestToken {
+    valid_from: Option<NaiveDateTime>,
+    valid_until: NaiveDateTime,
+    label: Option<String>,
+}
+
+// Everyone who may open the door may let a guest in, like with door codes
+pub async fn create_guest_token(
+    State(devices): State<DeviceHub>,
+    user: UserProfile,
+    Path(door_id): Path<i32>,
+    Json(body): Json<CreateGuestToken>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_open(conn, door_id, user.id) {
+        let error_response =
+            json!({ "message": "You are not allowed to invite guests to this door." });
+        return Err((StatusCode::FORBIDDEN, Json(error_response)));
+    }
+
+    let now = Utc::now().naive_utc();
+    let valid_from = body.valid_from.unwrap_or(now);
+    if body.valid_until <= valid_from || body.valid_until <= now {
+        let error_response =
+            json!({ "message": "`valid_until` must be in the future and after `valid_from`." });
+        return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+    }
+
+    let mut nonce = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let created = insert_into(guest_token::table)
+        .values((
+            guest_token::door_id.eq(door_id),
+            guest_token::creator_id.eq(user.id),
+            guest_token::nonce.eq(hex::encode(nonce)),
+            guest_token::label.eq(&body.label),
+            guest_token::valid_from.eq(valid_from),
+            guest_token::valid_until.eq(body.valid_until),
+            guest_token::created_at.eq(now),
+        ))
+        .returning(GuestToken::as_returning())
+        .get_result(conn);
+
+    // The token string is what the guest's QR code encodes
+    #[derive(Serialize)]
+    struct CreatedGuestToken {
+        #[serde(flatten)]
+        guest_token: GuestToken,
+        token: String,
+    }
+
+    match created {
+        Ok(guest_token) => {
+            let token = guest_tokens::encode(devices.signer(), &guest_token);
+            Ok((
+                StatusCode::CREATED,
+                Json(CreatedGuestToken { guest_token, token }),
+            ))
+        }
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+// Managers see every token of the door, everyone else only their own
+pub async fn get_guest_tokens(user: UserProfile, Path(door_id): Path<i32>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let mut query = guest_token::table
+        .filter(guest_token::door_id.eq(door_id))
+        .into_boxed();
+    if !access::can_manage(conn, door_id, user.id) {
+        query = query.filter(guest_token::creator_id.eq(user.id));
+    }
+
+    let tokens = query
+        .order(guest_token::created_at.desc())
+        .select(GuestToken::as_select())
+        .load(conn);
+
+    match tokens {
+        Ok(tokens) => Ok((StatusCode::OK, Json(tokens))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+pub async fn revoke_guest_token(
+    State(devices): State<DeviceHub>,
+    user: UserProfile,
+    Path((door_id, token_id)): Path<(i32, i32)>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let token = guest_token::table
+        .filter(
+            guest_token::id
+                .eq(token_id)
+                .and(guest_token::door_id.eq(door_id)),
+        )
+        .select(GuestToken::as_select())
+        .get_result(conn)
+        .ok()
+        .filter(|token| token.creator_id == user.id || access::can_manage(conn, door_id, user.id));
+
+    let Some(token) = token else {
+        let error_response =
+            json!({ "message": format!("Guest token with ID: {token_id} not found.") });
+        return Err((StatusCode::NOT_FOUND, Json(error_response)));
+    };
+
+    let revoked = update(guest_token::table.find(token.id))
+        .set(guest_token::revoked_at.eq(Utc::now().naive_utc()))
+        .returning(GuestToken::as_returning())
+        .get_result(conn);
+
+    match revoked {
+        Ok(revoked) => {
+            guest_tokens::publish_revocations(conn, &devices, door_id);
+            Ok((StatusCode::OK, Json(revoked)))
+        }
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
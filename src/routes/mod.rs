@@ -1,6 +1,25 @@
 pub mod auth;
+pub mod calendar;
+pub mod credential;
+pub mod device;
+pub mod device_diagnostics;
+pub mod discord;
 pub mod door;
 pub mod door_code;
+pub mod door_lock;
+pub mod door_mode;
+pub mod door_status;
+pub mod door_totp;
+pub mod doorbell;
+pub mod emergency;
+pub mod event_stream;
+pub mod firmware;
 pub mod general;
+pub mod guest_token;
+pub mod mqtt;
+pub mod snapshot;
+pub mod token;
+pub mod unlock_schedule;
 pub mod user;
+pub mod webhook;
 pub mod websocket;
@@ -0,0 +1,110 @@
+use axum::{extract::State, routing::post, Json, Router};
+use diesel::prelude::*;
+use http::StatusCode;
+use serde::Deserialize;
+
+use crate::{
+    db::establish_connection,
+    models::Device,
+    mqtt::{MqttConfig, TopicAccess},
+    schema::device,
+    AppState,
+};
+
+// The HTTP backend of broker auth plugins like mosquitto-go-auth, with JSON
+// parameters and status responses. Anything but 200 keeps the client out.
+pub fn create_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/user", post(check_user))
+        .route("/superuser", post(check_superuser))
+        .route("/acl", post(check_acl))
+        .with_state(app_state)
+}
+
+#[derive(Deserialize)]
+struct UserCheck {
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct SuperuserCheck {
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct AclCheck {
+    username: String,
+    topic: String,
+    // 1 read, 2 write, 3 both, 4 subscribe
+    acc: u8,
+}
+
+fn verdict(allowed: bool) -> StatusCode {
+    if allowed {
+        StatusCode::OK
+    } else {
+        StatusCode::FORBIDDEN
+    }
+}
+
+async fn check_user(
+    State(config): State<Option<MqttConfig>>,
+    Json(check): Json<UserCheck>,
+) -> StatusCode {
+    let Some(config) = config else {
+        return StatusCode::NOT_FOUND;
+    };
+    let conn = &mut establish_connection();
+
+    verdict(config.authenticate(conn, &check.username, &check.password))
+}
+
+// Only the server itself may use every topic
+async fn check_superuser(
+    State(config): State<Option<MqttConfig>>,
+    Json(check): Json<SuperuserCheck>,
+) -> StatusCode {
+    let Some(config) = config else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    verdict(config.is_server(&check.username))
+}
+
+async fn check_acl(
+    State(config): State<Option<MqttConfig>>,
+    Json(check): Json<AclCheck>,
+) -> StatusCode {
+    let Some(config) = config else {
+        return StatusCode::NOT_FOUND;
+    };
+    if config.is_server(&check.username) {
+        return StatusCode::OK;
+    }
+
+    let access = match check.acc {
+        1 | 4 => vec![TopicAccess::Read],
+        2 => vec![TopicAccess::Write],
+        3 => vec![TopicAccess::Read, TopicAccess::Write],
+        _ => return StatusCode::BAD_REQUEST,
+    };
+    let Ok(device_id) = check.username.parse::<i32>() else {
+        return StatusCode::FORBIDDEN;
+    };
+
+    let conn = &mut establish_connection();
+    let Ok(device) = device::table
+        .find(device_id)
+        .select(Device::as_select())
+        .first(conn)
+    else {
+        return StatusCode::FORBIDDEN;
+    };
+
+    verdict(
+        access
+            .into_iter()
+            .all(|access| config.authorize(device.door_id, device.id, &check.topic, access)),
+    )
+}
@@ -0,0 +1,182 @@
+use async_session::chrono::{Duration, NaiveDateTime, Utc};
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    response::IntoResponse,
+    Json,
+};
+use diesel::{delete, insert_into, prelude::*};
+use http::{header::CONTENT_TYPE, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    access,
+    db::establish_connection,
+    models::{Device, Snapshot, UserProfile},
+    schema::{access_history, doorbell_ring, snapshot},
+    snapshots::SnapshotStore,
+};
+
+// How far back an upload looks for the open or ring it belongs to
+const MATCH_WINDOW_SECONDS: i64 = 120;
+const JPEG_MAGIC: [u8; 3] = [0xFF, 0xD8, 0xFF];
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotTrigger {
+    Open,
+    Doorbell,
+}
+
+#[derive(Deserialize)]
+pub struct UploadQuery {
+    trigger: SnapshotTrigger,
+}
+
+// Called by ESP32-CAM controllers with the raw JPEG as the request body
+pub async fn upload_snapshot(
+    device: Device,
+    State(store): State<SnapshotStore>,
+    Query(query): Query<UploadQuery>,
+    body: Bytes,
+) -> impl IntoResponse {
+    if !body.starts_with(&JPEG_MAGIC) {
+        let error_response = json!({ "message": "Snapshots must be JPEG images." });
+        return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, Json(error_response)));
+    }
+
+    let conn = &mut establish_connection();
+    let (access_history_id, doorbell_ring_id) =
+        matching_entry(conn, device.door_id, query.trigger, Utc::now().naive_utc());
+
+    let file_name = format!("{}/{}.jpg", device.door_id, Uuid::new_v4());
+
+    let created = insert_into(snapshot::table)
+        .values((
+            snapshot::door_id.eq(device.door_id),
+            snapshot::device_id.eq(device.id),
+            snapshot::access_history_id.eq(access_history_id),
+            snapshot::doorbell_ring_id.eq(doorbell_ring_id),
+            snapshot::file_name.eq(&file_name),
+            snapshot::size_bytes.eq(body.len() as i32),
+            snapshot::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .returning(Snapshot::as_returning())
+        .get_result(conn);
+
+    let created = match created {
+        Ok(created) => created,
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+        }
+    };
+
+    if let Err(e) = store.write(&file_name, &body).await {
+        let _ = delete(snapshot::table.find(created.id)).execute(conn);
+        let error_response = json!({ "error": format!("{e}") });
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)));
+    }
+
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+// The open or ring the snapshot was taken for, the latest one in the window
+fn matching_entry(
+    conn: &mut PgConnection,
+    door_id: i32,
+    trigger: SnapshotTrigger,
+    now: NaiveDateTime,
+) -> (Option<i32>, Option<i32>) {
+    let since = now - Duration::seconds(MATCH_WINDOW_SECONDS);
+
+    match trigger {
+        SnapshotTrigger::Open => {
+            let entry = access_history::table
+                .filter(access_history::door_id.eq(door_id))
+                .filter(access_history::access_timestamp.gt(since))
+                .order(access_history::access_timestamp.desc())
+                .select(access_history::id)
+                .first::<i32>(conn)
+                .ok();
+            (entry, None)
+        }
+        SnapshotTrigger::Doorbell => {
+            let ring = doorbell_ring::table
+                .filter(doorbell_ring::door_id.eq(door_id))
+                .filter(doorbell_ring::rung_at.gt(since))
+                .order(doorbell_ring::rung_at.desc())
+                .select(doorbell_ring::id)
+                .first::<i32>(conn)
+                .ok();
+            (None, ring)
+        }
+    }
+}
+
+pub async fn get_door_snapshots(user: UserProfile, Path(door_id): Path<i32>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_read_history(conn, door_id, user.id) {
+        return Err(forbidden());
+    }
+
+    let snapshots = snapshot::table
+        .filter(snapshot::door_id.eq(door_id))
+        .order(snapshot::created_at.desc())
+        .select(Snapshot::as_select())
+        .load(conn);
+
+    match snapshots {
+        Ok(snapshots) => Ok((StatusCode::OK, Json(snapshots))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+pub async fn get_snapshot(
+    user: UserProfile,
+    State(store): State<SnapshotStore>,
+    Path((door_id, snapshot_id)): Path<(i32, i32)>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_read_history(conn, door_id, user.id) {
+        return Err(forbidden());
+    }
+
+    let snapshot = snapshot::table
+        .filter(
+            snapshot::id
+                .eq(snapshot_id)
+                .and(snapshot::door_id.eq(door_id)),
+        )
+        .select(Snapshot::as_select())
+        .get_result(conn);
+
+    let bytes = match snapshot {
+        Ok(snapshot) => tokio::fs::read(store.path(&snapshot.file_name)).await.ok(),
+        Err(_) => None,
+    };
+
+    match bytes {
+        Some(bytes) => Ok((StatusCode::OK, [(CONTENT_TYPE, "image/jpeg")], bytes)),
+        None => {
+            let error_response =
+                json!({ "message": format!("Snapshot with ID: {snapshot_id} not found.") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+fn forbidden() -> (StatusCode, Json<serde_json::Value>) {
+    let error_response = json!({ "message": "You are not allowed to see this door's history." });
+    (StatusCode::FORBIDDEN, Json(error_response))
+}
+
+#[cfg(test)]
+mod tests;
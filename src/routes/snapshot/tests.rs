@@ -0,0 +1,83 @@
+use async_session::chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{insert_into, prelude::*, PgConnection};
+
+use super::{matching_entry, SnapshotTrigger};
+use crate::{
+    schema::{access_history, doorbell_ring},
+    testing,
+};
+
+fn open(conn: &mut PgConnection, door_id: i32, user_id: i32, at: NaiveDateTime) -> i32 {
+    insert_into(access_history::table)
+        .values((
+            access_history::door_id.eq(door_id),
+            access_history::user_profile_id.eq(user_id),
+            access_history::access_timestamp.eq(at),
+        ))
+        .returning(access_history::id)
+        .get_result(conn)
+        .unwrap()
+}
+
+fn ring(conn: &mut PgConnection, door_id: i32, at: NaiveDateTime) -> i32 {
+    insert_into(doorbell_ring::table)
+        .values((
+            doorbell_ring::door_id.eq(door_id),
+            doorbell_ring::rung_at.eq(at),
+        ))
+        .returning(doorbell_ring::id)
+        .get_result(conn)
+        .unwrap()
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn open_snapshots_link_to_the_latest_open_of_their_door() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let other_door = testing::door(conn, user_id);
+    let now = Utc::now().naive_utc();
+    open(conn, door_id, user_id, now - Duration::seconds(60));
+    let latest = open(conn, door_id, user_id, now - Duration::seconds(10));
+    open(conn, other_door, user_id, now - Duration::seconds(5));
+
+    assert_eq!(
+        matching_entry(conn, door_id, SnapshotTrigger::Open, now),
+        (Some(latest), None)
+    );
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn doorbell_snapshots_link_to_the_ring() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let now = Utc::now().naive_utc();
+    open(conn, door_id, user_id, now - Duration::seconds(5));
+    let rung = ring(conn, door_id, now - Duration::seconds(20));
+
+    assert_eq!(
+        matching_entry(conn, door_id, SnapshotTrigger::Doorbell, now),
+        (None, Some(rung))
+    );
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn snapshots_outside_the_window_stay_unlinked() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let now = Utc::now().naive_utc();
+    open(conn, door_id, user_id, now - Duration::minutes(3));
+
+    assert_eq!(
+        matching_entry(conn, door_id, SnapshotTrigger::Open, now),
+        (None, None)
+    );
+}
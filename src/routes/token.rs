@@ -0,0 +1,127 @@
+use async_session::chrono::Utc;
+use axum::{extract::Path, response::IntoResponse, routing::get, Json, Router};
+use diesel::{delete, insert_into, prelude::*};
+use http::StatusCode;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    db::establish_connection,
+    models::{ApiToken, UserProfile},
+    schema::api_token,
+    AppState,
+};
+
+use super::auth::hash_token;
+
+pub fn create_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/", get(get_tokens).post(create_token))
+        .route("/:id", get(get_token).delete(delete_token))
+        .with_state(app_state)
+}
+
+#[derive(Deserialize)]
+struct CreateToken {
+    name: String,
+}
+
+async fn get_tokens(user: UserProfile) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let tokens = api_token::table
+        .filter(api_token::user_profile_id.eq(user.id))
+        .select(ApiToken::as_select())
+        .load(conn);
+
+    match tokens {
+        Ok(tokens) => Ok((StatusCode::OK, Json(tokens))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+async fn get_token(user: UserProfile, Path(token_id): Path<i32>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let token = api_token::table
+        .filter(
+            api_token::id
+                .eq(token_id)
+                .and(api_token::user_profile_id.eq(user.id)),
+        )
+        .select(ApiToken::as_select())
+        .get_result(conn);
+
+    if let Ok(token) = token {
+        Ok((StatusCode::OK, Json(token)))
+    } else {
+        let error_response =
+            json!({ "message": format!("API token with ID: {token_id} not found.") });
+        Err((StatusCode::NOT_FOUND, Json(error_response)))
+    }
+}
+
+async fn create_token(user: UserProfile, Json(body): Json<CreateToken>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = hex::encode(bytes);
+
+    let created = insert_into(api_token::table)
+        .values((
+            api_token::user_profile_id.eq(user.id),
+            api_token::name.eq(&body.name),
+            api_token::token_hash.eq(hash_token(&token)),
+            api_token::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .returning(ApiToken::as_returning())
+        .get_result(conn);
+
+    // Only the hash is stored, so this is the one chance to see the token
+    #[derive(Serialize)]
+    struct CreatedToken {
+        #[serde(flatten)]
+        api_token: ApiToken,
+        token: String,
+    }
+
+    match created {
+        Ok(api_token) => Ok((StatusCode::CREATED, Json(CreatedToken { api_token, token }))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+async fn delete_token(user: UserProfile, Path(token_id): Path<i32>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let deleted = delete(
+        api_token::table.filter(
+            api_token::id
+                .eq(token_id)
+                .and(api_token::user_profile_id.eq(user.id)),
+        ),
+    )
+    .execute(conn);
+
+    match deleted {
+        Ok(1) => Ok((
+            StatusCode::OK,
+            Json(json!(format!(
+                "API token with an ID {token_id} was deleted."
+            ))),
+        )),
+        _ => {
+            let error_response =
+                json!({ "message": format!("API token with ID: {token_id} not found.") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use async_session::chrono::{NaiveDate, NaiveTime, Utc};
+use axum::{extract::Path, response::IntoResponse, Json};
+use diesel::{delete, insert_into, prelude::*};
+use http::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    access,
+    db::establish_connection,
+    models::{UnlockException, UnlockSchedule, UserProfile},
+    schema::{calendar, unlock_exception, unlock_schedule},
+};
+
+#[derive(Deserialize)]
+pub struct UnlockScheduleBody {
+    days: i16,
+    start_time: NaiveTime,
+    end_time: NaiveTime,
+    label: Option<String>,
+    // Holidays of the calendar close or move the schedule's windows
+    calendar_id: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct UnlockExceptionBody {
+    date: NaiveDate,
+    label: Option<String>,
+}
+
+// Everyone who can see the door can see when it is unlocked
+pub async fn get_unlock_schedules(
+    user: UserProfile,
+    Path(door_id): Path<i32>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_see(conn, door_id, user.id) {
+        return Err(not_found(door_id));
+    }
+
+    let schedules = unlock_schedule::table
+        .filter(unlock_schedule::door_id.eq(door_id))
+        .order(unlock_schedule::id.asc())
+        .select(UnlockSchedule::as_select())
+        .load(conn);
+
+    match schedules {
+        Ok(schedules) => Ok((StatusCode::OK, Json(json!(schedules)))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+pub async fn create_unlock_schedule(
+    user: UserProfile,
+    Path(door_id): Path<i32>,
+    Json(body): Json<UnlockScheduleBody>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_manage(conn, door_id, user.id) {
+        return Err(forbidden(door_id));
+    }
+    if !(1..=127).contains(&body.days) {
+        let error_response = json!({ "message": "days must pick at least one weekday, Monday = 1 through Sunday = 64" });
+        return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+    }
+    if body.start_time == body.end_time {
+        let error_response = json!({ "message": "start_time and end_time can not be the same" });
+        return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+    }
+    if let Some(calendar_id) = body.calendar_id {
+        let owner = calendar::table
+            .find(calendar_id)
+            .select(calendar::owner_id)
+            .get_result::<Option<i32>>(conn);
+        let usable = match owner {
+            Ok(owner) => owner == Some(user.id) || access::is_admin(conn, user.id),
+            Err(_) => false,
+        };
+        if !usable {
+            let error_response = json!({ "message": format!("calendar {calendar_id} not found") });
+            return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+        }
+    }
+
+    let created = insert_into(unlock_schedule::table)
+        .values((
+            unlock_schedule::door_id.eq(door_id),
+            unlock_schedule::days.eq(body.days),
+            unlock_schedule::start_time.eq(body.start_time),
+            unlock_schedule::end_time.eq(body.end_time),
+            unlock_schedule::label.eq(body.label),
+            unlock_schedule::calendar_id.eq(body.calendar_id),
+            unlock_schedule::created_by.eq(user.id),
+            unlock_schedule::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .returning(UnlockSchedule::as_returning())
+        .get_result(conn);
+
+    match created {
+        Ok(schedule) => Ok((StatusCode::CREATED, Json(json!(schedule)))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+// A door unlocked by the removed schedule is locked on the scheduler's next check
+pub async fn delete_unlock_schedule(
+    user: UserProfile,
+    Path((door_id, schedule_id)): Path<(i32, i32)>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_manage(conn, door_id, user.id) {
+        return Err(forbidden(door_id));
+    }
+
+    let deleted = delete(
+        unlock_schedule::table
+            .find(schedule_id)
+            .filter(unlock_schedule::door_id.eq(door_id)),
+    )
+    .execute(conn);
+
+    match deleted {
+        Ok(1) => Ok((
+            StatusCode::OK,
+            Json(json!(format!(
+                "Unlock schedule with an ID {schedule_id} was deleted."
+            ))),
+        )),
+        _ => {
+            let error_response = json!({
+                "message": format!("Unlock schedule with ID {schedule_id} could not be deleted.")
+            });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+pub async fn get_unlock_exceptions(
+    user: UserProfile,
+    Path(door_id): Path<i32>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_see(conn, door_id, user.id) {
+        return Err(not_found(door_id));
+    }
+
+    let exceptions = unlock_exception::table
+        .filter(unlock_exception::door_id.eq(door_id))
+        .order(unlock_exception::date.asc())
+        .select(UnlockException::as_select())
+        .load(conn);
+
+    match exceptions {
+        Ok(exceptions) => Ok((StatusCode::OK, Json(json!(exceptions)))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+// Holidays and other days the door's schedules are off
+pub async fn create_unlock_exception(
+    user: UserProfile,
+    Path(door_id): Path<i32>,
+    Json(body): Json<UnlockExceptionBody>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_manage(conn, door_id, user.id) {
+        return Err(forbidden(door_id));
+    }
+
+    let created = insert_into(unlock_exception::table)
+        .values((
+            unlock_exception::door_id.eq(door_id),
+            unlock_exception::date.eq(body.date),
+            unlock_exception::label.eq(body.label),
+            unlock_exception::created_by.eq(user.id),
+            unlock_exception::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .returning(UnlockException::as_returning())
+        .get_result(conn);
+
+    match created {
+        Ok(exception) => Ok((StatusCode::CREATED, Json(json!(exception)))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+pub async fn delete_unlock_exception(
+    user: UserProfile,
+    Path((door_id, date)): Path<(i32, NaiveDate)>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if !access::can_manage(conn, door_id, user.id) {
+        return Err(forbidden(door_id));
+    }
+
+    let deleted = delete(unlock_exception::table.find((door_id, date))).execute(conn);
+
+    match deleted {
+        Ok(1) => Ok((
+            StatusCode::OK,
+            Json(json!(format!("Unlock exception on {date} was deleted."))),
+        )),
+        _ => {
+            let error_response =
+                json!({ "message": format!("Unlock exception on {date} could not be deleted.") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+fn not_found(door_id: i32) -> (StatusCode, Json<serde_json::Value>) {
+    let error_response = json!({ "message": format!("Doors with ID: {door_id} not found.") });
+    (StatusCode::NOT_FOUND, Json(error_response))
+}
+
+fn forbidden(door_id: i32) -> (StatusCode, Json<serde_json::Value>) {
+    let error_response =
+        json!({ "message": format!("You can not manage door with ID {door_id}.") });
+    (StatusCode::FORBIDDEN, Json(error_response))
+}
@@ -1,5 +1,6 @@
 use crate::{
     db::establish_connection,
+    emergency,
     models::DoorCode,
     models::UserProfile,
     models::{Door, DoorPermission},
@@ -89,6 +90,7 @@ async fn get_user_doors(Path(user_id): Path<i32>) -> impl IntoResponse {
         #[serde(flatten)]
         door: Door,
         owner: UserProfile,
+        emergency: Option<&'static str>,
     }
 
     let doors = door::table
@@ -99,9 +101,14 @@ async fn get_user_doors(Path(user_id): Path<i32>) -> impl IntoResponse {
 
     match doors {
         Ok(doors) => {
+            let emergency = emergency::flag(conn);
             let data = doors
                 .into_iter()
-                .map(|(door, owner)| DoorWithOwner { owner, door })
+                .map(|(door, owner)| DoorWithOwner {
+                    owner,
+                    door,
+                    emergency,
+                })
                 .collect::<Vec<DoorWithOwner>>();
             Ok((StatusCode::OK, Json(data)))
         }
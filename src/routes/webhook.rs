@@ -0,0 +1,230 @@
+use async_session::chrono::Utc;
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use diesel::{delete, insert_into, prelude::*};
+use http::StatusCode;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    access,
+    db::establish_connection,
+    events::EventKind,
+    models::{UserProfile, WebhookDelivery, WebhookSubscription},
+    schema::{webhook_delivery, webhook_subscription},
+    webhooks::{self, Webhooks},
+    AppState,
+};
+
+pub fn create_router(app_state: AppState) -> Router {
+    Router::new()
+        .route("/", get(get_webhooks).post(create_webhook))
+        .route("/:id", get(get_webhook).delete(delete_webhook))
+        .route("/:id/deliveries", get(get_webhook_deliveries))
+        .route(
+            "/:id/deliveries/:delivery_id/replay",
+            post(replay_webhook_delivery),
+        )
+        .with_state(app_state)
+}
+
+#[derive(Deserialize)]
+struct CreateWebhook {
+    // Leave empty to receive events from every door you manage
+    door_id: Option<i32>,
+    url: String,
+    event_types: Vec<String>,
+}
+
+async fn get_webhooks(user: UserProfile) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let subscriptions = webhook_subscription::table
+        .filter(webhook_subscription::owner_id.eq(user.id))
+        .select(WebhookSubscription::as_select())
+        .load(conn);
+
+    match subscriptions {
+        Ok(subscriptions) => Ok((StatusCode::OK, Json(subscriptions))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+async fn get_webhook(user: UserProfile, Path(webhook_id): Path<i32>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    match find_subscription(conn, webhook_id, user.id) {
+        Some(subscription) => Ok((StatusCode::OK, Json(subscription))),
+        None => Err(not_found(webhook_id)),
+    }
+}
+
+async fn create_webhook(
+    State(webhooks): State<Webhooks>,
+    user: UserProfile,
+    Json(body): Json<CreateWebhook>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    if let Some(door_id) = body.door_id {
+        if !access::can_manage(conn, door_id, user.id) {
+            let error_response =
+                json!({ "message": format!("You can not manage door with ID {door_id}.") });
+            return Err((StatusCode::FORBIDDEN, Json(error_response)));
+        }
+    }
+
+    if let Some(unknown) = body
+        .event_types
+        .iter()
+        .find(|event_type| EventKind::parse(event_type).is_none())
+    {
+        let error_response = json!({ "message": format!("Unknown event type: {unknown}.") });
+        return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+    }
+
+    if let Err(e) = webhooks.check_url(&body.url) {
+        let error_response = json!({ "message": format!("Invalid URL {}: {e}.", body.url) });
+        return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+    }
+
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+
+    let subscription = insert_into(webhook_subscription::table)
+        .values((
+            webhook_subscription::door_id.eq(body.door_id),
+            webhook_subscription::owner_id.eq(user.id),
+            webhook_subscription::url.eq(&body.url),
+            webhook_subscription::secret.eq(hex::encode(secret)),
+            webhook_subscription::event_types.eq(&body.event_types),
+            webhook_subscription::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .returning(WebhookSubscription::as_returning())
+        .get_result(conn);
+
+    #[derive(Serialize)]
+    struct SubscriptionWithSecret {
+        #[serde(flatten)]
+        subscription: WebhookSubscription,
+        secret: String,
+    }
+
+    match subscription {
+        Ok(subscription) => Ok((
+            StatusCode::CREATED,
+            Json(SubscriptionWithSecret {
+                secret: subscription.secret.clone(),
+                subscription,
+            }),
+        )),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+async fn delete_webhook(user: UserProfile, Path(webhook_id): Path<i32>) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let deleted = delete(
+        webhook_subscription::table.filter(
+            webhook_subscription::id
+                .eq(webhook_id)
+                .and(webhook_subscription::owner_id.eq(user.id)),
+        ),
+    )
+    .execute(conn);
+
+    match deleted {
+        Ok(1) => Ok((
+            StatusCode::OK,
+            Json(json!(format!(
+                "Webhook with an ID {webhook_id} was deleted."
+            ))),
+        )),
+        _ => Err(not_found(webhook_id)),
+    }
+}
+
+async fn get_webhook_deliveries(
+    user: UserProfile,
+    Path(webhook_id): Path<i32>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let Some(subscription) = find_subscription(conn, webhook_id, user.id) else {
+        return Err(not_found(webhook_id));
+    };
+
+    let deliveries = WebhookDelivery::belonging_to(&subscription)
+        .order(webhook_delivery::created_at.desc())
+        .select(WebhookDelivery::as_select())
+        .load(conn);
+
+    match deliveries {
+        Ok(deliveries) => Ok((StatusCode::OK, Json(deliveries))),
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+    }
+}
+
+async fn replay_webhook_delivery(
+    State(webhooks): State<Webhooks>,
+    user: UserProfile,
+    Path((webhook_id, delivery_id)): Path<(i32, i32)>,
+) -> impl IntoResponse {
+    let conn = &mut establish_connection();
+
+    let Some(subscription) = find_subscription(conn, webhook_id, user.id) else {
+        return Err(not_found(webhook_id));
+    };
+
+    match webhooks::replay(conn, &subscription, delivery_id) {
+        Ok(Some(delivery)) => {
+            webhooks.deliver(subscription, delivery.clone());
+            Ok((StatusCode::ACCEPTED, Json(delivery)))
+        }
+        Ok(None) => {
+            let error_response =
+                json!({ "message": format!("Delivery with ID: {delivery_id} not found.") });
+            Err((StatusCode::NOT_FOUND, Json(error_response)))
+        }
+        Err(e) => {
+            let error_response = json!({ "error": format!("{e}") });
+            Err((StatusCode::BAD_REQUEST, Json(error_response)))
+        }
+    }
+}
+
+fn find_subscription(
+    conn: &mut PgConnection,
+    webhook_id: i32,
+    owner_id: i32,
+) -> Option<WebhookSubscription> {
+    webhook_subscription::table
+        .filter(
+            webhook_subscription::id
+                .eq(webhook_id)
+                .and(webhook_subscription::owner_id.eq(owner_id)),
+        )
+        .select(WebhookSubscription::as_select())
+        .get_result(conn)
+        .ok()
+}
+
+fn not_found(webhook_id: i32) -> (StatusCode, Json<serde_json::Value>) {
+    let error_response = json!({ "message": format!("Webhook with ID: {webhook_id} not found.") });
+    (StatusCode::NOT_FOUND, Json(error_response))
+}
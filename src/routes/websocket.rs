@@ -1,18 +1,24 @@
-use axum::{Router, extract::WebSocketUpgrade};
 use axum::{
     extract::{
-        ws::{CloseFrame, Message, WebSocket},
-        ConnectInfo
+        ws::{Message, WebSocket},
+        State, WebSocketUpgrade,
     },
+    response::IntoResponse,
     routing::get,
-    headers::{UserAgent},
-    response::{IntoResponse},
-    TypedHeader,
+    Router,
 };
 use futures::{sink::SinkExt, stream::StreamExt};
-use std::{borrow::Cow, net::SocketAddr};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tokio::sync::broadcast::error::RecvError;
 
-use crate::AppState;
+use crate::{
+    access,
+    db::establish_connection,
+    events::{DoorEvent, EventBus, EventKind},
+    models::UserProfile,
+    AppState,
+};
 
 pub fn create_router(app_state: AppState) -> Router {
     Router::new()
@@ -20,52 +26,123 @@ pub fn create_router(app_state: AppState) -> Router {
         .with_state(app_state)
 }
 
+// What the browser sends us
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe { door_ids: Vec<i32> },
+    Unsubscribe { door_ids: Vec<i32> },
+}
+
+// What we send back besides the door events themselves
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    Subscribed { door_ids: Vec<i32> },
+    Error { message: String },
+}
+
 async fn ws_handler(
     ws: WebSocketUpgrade,
-    user_agent: Option<TypedHeader<UserAgent>>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    user: UserProfile,
+    State(events): State<EventBus>,
 ) -> impl IntoResponse {
-    let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
-        user_agent.to_string()
-    } else {
-        String::from("Unknown browser")
-    };
-    println!("`{user_agent}` at {addr} connected.");
+    tracing::info!("{} opened the event stream", user.username);
 
-    ws.on_upgrade(move |socket| handle_socket(socket, addr))
+    ws.on_upgrade(move |socket| handle_socket(socket, user, events))
 }
 
-async fn handle_socket(mut socket: WebSocket, who: SocketAddr) {
+async fn handle_socket(socket: WebSocket, user: UserProfile, events: EventBus) {
     let (mut sender, mut receiver) = socket.split();
+    let mut event_receiver = events.subscribe();
+    let mut subscribed: HashSet<i32> = HashSet::new();
 
-    // Spawn a task that will push several messages to the client (does not matter what client does)
-    let mut send_task = tokio::spawn(async move {
-        let n_msg = 20;
-        for i in 0..n_msg {
-            // In case of any websocket error, we exit.
-            if sender
-                .send(Message::Text(format!("Server message {i} ...")))
-                .await
-                .is_err()
-            {
-                return i;
-            }
+    loop {
+        let outgoing = tokio::select! {
+            message = receiver.next() => match message {
+                Some(Ok(Message::Text(text))) => handle_message(&text, &user, &mut subscribed),
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // Pings are answered by axum, binary frames are not part of the protocol
+                Some(Ok(_)) => continue,
+            },
+            event = event_receiver.recv() => match event {
+                Ok(event) => match forward_event(event, &user, &mut subscribed) {
+                    Some(event) => serde_json::to_string(&event).unwrap(),
+                    None => continue,
+                },
+                Err(RecvError::Lagged(n)) => {
+                    tracing::warn!("Event stream for {} skipped {n} events", user.username);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            },
+        };
 
-            tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        if sender.send(Message::Text(outgoing)).await.is_err() {
+            break;
         }
+    }
+
+    tracing::info!("{} closed the event stream", user.username);
+}
 
-        println!("Sending close to {who}...");
-        if let Err(e) = sender
-            .send(Message::Close(Some(CloseFrame {
-                code: axum::extract::ws::close_code::NORMAL,
-                reason: Cow::from("Goodbye"),
-            })))
-            .await
-        {
-            println!("Could not send Close due to {}, probably it is ok?", e);
+fn handle_message(text: &str, user: &UserProfile, subscribed: &mut HashSet<i32>) -> String {
+    let reply = match serde_json::from_str::<ClientMessage>(text) {
+        Ok(ClientMessage::Subscribe { door_ids }) => {
+            let conn = &mut establish_connection();
+
+            match access::visible_doors(conn, user.id) {
+                Ok(visible) => {
+                    // Doors the user can not see are silently left out
+                    subscribed.extend(door_ids.into_iter().filter(|id| visible.contains(id)));
+                    subscribed_message(subscribed)
+                }
+                Err(e) => ServerMessage::Error {
+                    message: format!("{e}"),
+                },
+            }
         }
-        n_msg
-    });
+        Ok(ClientMessage::Unsubscribe { door_ids }) => {
+            for door_id in door_ids {
+                subscribed.remove(&door_id);
+            }
+            subscribed_message(subscribed)
+        }
+        Err(e) => ServerMessage::Error {
+            message: format!("{e}"),
+        },
+    };
+
+    serde_json::to_string(&reply).unwrap()
+}
+
+fn forward_event(
+    event: DoorEvent,
+    user: &UserProfile,
+    subscribed: &mut HashSet<i32>,
+) -> Option<DoorEvent> {
+    if !subscribed.contains(&event.door_id) {
+        return None;
+    }
+
+    // Losing access to a door also ends its stream, after telling the user about it
+    if event.kind == EventKind::PermissionRevoked && event.user_profile_id == Some(user.id) {
+        let conn = &mut establish_connection();
+        let still_visible = access::visible_doors(conn, user.id)
+            .is_ok_and(|visible| visible.contains(&event.door_id));
+        if !still_visible {
+            subscribed.remove(&event.door_id);
+        }
+    }
+
+    Some(event)
+}
+
+fn subscribed_message(subscribed: &HashSet<i32>) -> ServerMessage {
+    let mut door_ids: Vec<i32> = subscribed.iter().copied().collect();
+    door_ids.sort_unstable();
+    ServerMessage::Subscribed { door_ids }
+}
 
-    println!("Websocket context {} destroyed", who);
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,68 @@
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+use super::{forward_event, handle_message};
+use crate::{
+    events::{DoorEvent, EventKind},
+    models::UserProfile,
+};
+
+fn user() -> UserProfile {
+    UserProfile {
+        id: 1,
+        discord_id: "1".to_string(),
+        username: "alice".to_string(),
+        avatar: None,
+    }
+}
+
+fn reply(text: &str, subscribed: &mut HashSet<i32>) -> Value {
+    serde_json::from_str(&handle_message(text, &user(), subscribed)).unwrap()
+}
+
+#[test]
+fn unsubscribing_lists_the_doors_left() {
+    let mut subscribed = HashSet::from([3, 1, 2]);
+
+    let reply = reply(
+        r#"{"type":"unsubscribe","door_ids":[2,9]}"#,
+        &mut subscribed,
+    );
+
+    assert_eq!(reply, json!({ "type": "subscribed", "door_ids": [1, 3] }));
+    assert_eq!(subscribed, HashSet::from([1, 3]));
+}
+
+#[test]
+fn unknown_messages_get_an_error() {
+    let mut subscribed = HashSet::from([1]);
+
+    let reply = reply(r#"{"type":"open","door_id":1}"#, &mut subscribed);
+
+    assert_eq!(reply["type"], "error");
+    assert_eq!(subscribed, HashSet::from([1]));
+}
+
+#[test]
+fn only_subscribed_doors_are_forwarded() {
+    let mut subscribed = HashSet::from([1]);
+
+    let opened = DoorEvent::new(EventKind::Opened, 1, Some(2));
+    let elsewhere = DoorEvent::new(EventKind::Opened, 2, Some(2));
+
+    assert!(forward_event(opened, &user(), &mut subscribed).is_some());
+    assert!(forward_event(elsewhere, &user(), &mut subscribed).is_none());
+}
+
+#[test]
+fn events_are_sent_as_typed_json() {
+    let mut event = DoorEvent::new(EventKind::CodeRedeemed, 1, None);
+    event.id = 7;
+
+    let sent = serde_json::to_value(&event).unwrap();
+
+    assert_eq!(sent["id"], 7);
+    assert_eq!(sent["type"], "code_redeemed");
+    assert_eq!(sent["door_id"], 1);
+    assert_eq!(sent["user_profile_id"], Value::Null);
+}
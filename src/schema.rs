@@ -4,8 +4,141 @@ diesel::table! {
     access_history (id) {
         id -> Int4,
         door_id -> Int4,
-        user_profile_id -> Int4,
+        user_profile_id -> Nullable<Int4>,
         access_timestamp -> Timestamptz,
+        method -> Varchar,
+        opened -> Bool,
+        flagged -> Bool,
+        guest_token_id -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    admin (user_profile_id) {
+        user_profile_id -> Int4,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    allowlist_version (door_id, version) {
+        door_id -> Int4,
+        version -> Int4,
+        entries -> Jsonb,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    api_token (id) {
+        id -> Int4,
+        user_profile_id -> Int4,
+        name -> Varchar,
+        #[max_length = 64]
+        token_hash -> Varchar,
+        created_at -> Timestamptz,
+        last_used_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    calendar (id) {
+        id -> Int4,
+        name -> Varchar,
+        owner_id -> Nullable<Int4>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    calendar_exception (calendar_id, date) {
+        calendar_id -> Int4,
+        date -> Date,
+        label -> Nullable<Varchar>,
+        start_time -> Nullable<Time>,
+        end_time -> Nullable<Time>,
+        uid -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    credential (id) {
+        id -> Int4,
+        user_profile_id -> Int4,
+        kind -> Varchar,
+        value -> Varchar,
+        label -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    device (id) {
+        id -> Int4,
+        door_id -> Int4,
+        name -> Varchar,
+        #[max_length = 64]
+        token_hash -> Varchar,
+        created_at -> Timestamptz,
+        last_seen_at -> Nullable<Timestamptz>,
+        online -> Bool,
+        firmware_version -> Nullable<Varchar>,
+        allowlist_version -> Int4,
+        group_id -> Nullable<Int4>,
+        target_firmware_id -> Nullable<Int4>,
+        config_version -> Int4,
+        protocol_version -> Int4,
+    }
+}
+
+diesel::table! {
+    device_command (id) {
+        id -> Int4,
+        device_id -> Int4,
+        door_id -> Int4,
+        user_profile_id -> Nullable<Int4>,
+        command -> Jsonb,
+        status -> Varchar,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        delivered_at -> Nullable<Timestamptz>,
+        acknowledged_at -> Nullable<Timestamptz>,
+        method -> Nullable<Varchar>,
+        #[max_length = 36]
+        group_id -> Varchar,
+        recorded -> Bool,
+    }
+}
+
+diesel::table! {
+    device_config (device_id, version) {
+        device_id -> Int4,
+        version -> Int4,
+        config -> Jsonb,
+        created_by -> Nullable<Int4>,
+        created_at -> Timestamptz,
+        applied_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    device_group (id) {
+        id -> Int4,
+        name -> Varchar,
+        target_firmware_id -> Nullable<Int4>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    device_telemetry (id) {
+        id -> Int4,
+        device_id -> Int4,
+        uptime_seconds -> Int8,
+        rssi -> Int4,
+        free_heap -> Int4,
+        firmware_version -> Varchar,
+        recorded_at -> Timestamptz,
     }
 }
 
@@ -14,6 +147,8 @@ diesel::table! {
         id -> Int4,
         about -> Nullable<Varchar>,
         owner_id -> Nullable<Int4>,
+        held_open_seconds -> Int4,
+        pin_salt -> Varchar,
     }
 }
 
@@ -29,12 +164,180 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    door_event (id) {
+        id -> Int8,
+        kind -> Varchar,
+        door_id -> Int4,
+        user_profile_id -> Nullable<Int4>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    door_lock (door_id) {
+        door_id -> Int4,
+        driver -> Varchar,
+        config -> Jsonb,
+        updated_by -> Nullable<Int4>,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    door_mode (door_id) {
+        door_id -> Int4,
+        mode -> Varchar,
+        unlocked_until -> Nullable<Timestamptz>,
+        changed_by -> Nullable<Int4>,
+        changed_at -> Timestamptz,
+        scheduled -> Bool,
+    }
+}
+
 diesel::table! {
     door_permission (door_id, user_profile_id) {
         door_id -> Int4,
         user_profile_id -> Int4,
         edit_permission -> Bool,
         open_permission -> Bool,
+        schedule_days -> Nullable<Int2>,
+        schedule_start -> Nullable<Time>,
+        schedule_end -> Nullable<Time>,
+        calendar_id -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    door_state (door_id) {
+        door_id -> Int4,
+        state -> Varchar,
+        locked -> Nullable<Bool>,
+        changed_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    door_totp (door_id) {
+        door_id -> Int4,
+        #[max_length = 64]
+        secret -> Varchar,
+        created_at -> Timestamptz,
+        last_step -> Nullable<Int8>,
+        failed_attempts -> Int4,
+        locked_until -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    doorbell_ring (id) {
+        id -> Int4,
+        door_id -> Int4,
+        device_id -> Nullable<Int4>,
+        rung_at -> Timestamptz,
+        answered_by -> Nullable<Int4>,
+        answered_at -> Nullable<Timestamptz>,
+        answer_latency_ms -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    emergency (id) {
+        id -> Int4,
+        mode -> Varchar,
+        reason -> Nullable<Varchar>,
+        triggered_by -> Nullable<Int4>,
+        triggered_at -> Timestamptz,
+        cleared_by -> Nullable<Int4>,
+        cleared_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    firmware_image (id) {
+        id -> Int4,
+        version -> Varchar,
+        #[max_length = 64]
+        sha256 -> Varchar,
+        #[max_length = 128]
+        signature -> Varchar,
+        size_bytes -> Int4,
+        file_name -> Varchar,
+        uploaded_by -> Nullable<Int4>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    firmware_update (id) {
+        id -> Int4,
+        device_id -> Int4,
+        firmware_image_id -> Nullable<Int4>,
+        version -> Varchar,
+        status -> Varchar,
+        detail -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    guest_token (id) {
+        id -> Int4,
+        door_id -> Int4,
+        creator_id -> Int4,
+        #[max_length = 16]
+        nonce -> Varchar,
+        label -> Nullable<Varchar>,
+        valid_from -> Timestamptz,
+        valid_until -> Timestamptz,
+        revoked_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    responder (user_profile_id) {
+        user_profile_id -> Int4,
+        added_by -> Nullable<Int4>,
+        added_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    snapshot (id) {
+        id -> Int4,
+        door_id -> Int4,
+        device_id -> Nullable<Int4>,
+        access_history_id -> Nullable<Int4>,
+        doorbell_ring_id -> Nullable<Int4>,
+        file_name -> Varchar,
+        size_bytes -> Int4,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    unlock_exception (door_id, date) {
+        door_id -> Int4,
+        date -> Date,
+        label -> Nullable<Varchar>,
+        created_by -> Nullable<Int4>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    unlock_schedule (id) {
+        id -> Int4,
+        door_id -> Int4,
+        days -> Int2,
+        start_time -> Time,
+        end_time -> Time,
+        label -> Nullable<Varchar>,
+        applied_window_start -> Nullable<Timestamp>,
+        created_by -> Nullable<Int4>,
+        created_at -> Timestamptz,
+        calendar_id -> Nullable<Int4>,
     }
 }
 
@@ -47,18 +350,117 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    webhook_delivery (id) {
+        id -> Int4,
+        subscription_id -> Int4,
+        event_type -> Varchar,
+        payload -> Text,
+        attempts -> Int4,
+        response_status -> Nullable<Int4>,
+        delivered -> Bool,
+        created_at -> Timestamptz,
+        last_attempt_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    webhook_subscription (id) {
+        id -> Int4,
+        door_id -> Nullable<Int4>,
+        owner_id -> Int4,
+        url -> Varchar,
+        secret -> Varchar,
+        event_types -> Array<Text>,
+        created_at -> Timestamptz,
+    }
+}
+
 diesel::joinable!(access_history -> door (door_id));
+diesel::joinable!(access_history -> guest_token (guest_token_id));
 diesel::joinable!(access_history -> user_profile (user_profile_id));
+diesel::joinable!(admin -> user_profile (user_profile_id));
+diesel::joinable!(allowlist_version -> door (door_id));
+diesel::joinable!(api_token -> user_profile (user_profile_id));
+diesel::joinable!(calendar -> user_profile (owner_id));
+diesel::joinable!(calendar_exception -> calendar (calendar_id));
+diesel::joinable!(credential -> user_profile (user_profile_id));
+diesel::joinable!(device -> device_group (group_id));
+diesel::joinable!(device -> door (door_id));
+diesel::joinable!(device -> firmware_image (target_firmware_id));
+diesel::joinable!(device_command -> device (device_id));
+diesel::joinable!(device_command -> door (door_id));
+diesel::joinable!(device_command -> user_profile (user_profile_id));
+diesel::joinable!(device_config -> device (device_id));
+diesel::joinable!(device_config -> user_profile (created_by));
+diesel::joinable!(device_group -> firmware_image (target_firmware_id));
+diesel::joinable!(device_telemetry -> device (device_id));
 diesel::joinable!(door -> user_profile (owner_id));
 diesel::joinable!(door_code -> door (door_id));
 diesel::joinable!(door_code -> user_profile (creator_id));
+diesel::joinable!(door_event -> door (door_id));
+diesel::joinable!(door_event -> user_profile (user_profile_id));
+diesel::joinable!(door_lock -> door (door_id));
+diesel::joinable!(door_lock -> user_profile (updated_by));
+diesel::joinable!(door_mode -> door (door_id));
+diesel::joinable!(door_mode -> user_profile (changed_by));
+diesel::joinable!(door_permission -> calendar (calendar_id));
 diesel::joinable!(door_permission -> door (door_id));
 diesel::joinable!(door_permission -> user_profile (user_profile_id));
+diesel::joinable!(door_state -> door (door_id));
+diesel::joinable!(door_totp -> door (door_id));
+diesel::joinable!(doorbell_ring -> device (device_id));
+diesel::joinable!(doorbell_ring -> door (door_id));
+diesel::joinable!(doorbell_ring -> user_profile (answered_by));
+diesel::joinable!(firmware_image -> user_profile (uploaded_by));
+diesel::joinable!(firmware_update -> device (device_id));
+diesel::joinable!(firmware_update -> firmware_image (firmware_image_id));
+diesel::joinable!(guest_token -> door (door_id));
+diesel::joinable!(guest_token -> user_profile (creator_id));
+diesel::joinable!(snapshot -> access_history (access_history_id));
+diesel::joinable!(snapshot -> device (device_id));
+diesel::joinable!(snapshot -> door (door_id));
+diesel::joinable!(snapshot -> doorbell_ring (doorbell_ring_id));
+diesel::joinable!(unlock_exception -> door (door_id));
+diesel::joinable!(unlock_exception -> user_profile (created_by));
+diesel::joinable!(unlock_schedule -> calendar (calendar_id));
+diesel::joinable!(unlock_schedule -> door (door_id));
+diesel::joinable!(unlock_schedule -> user_profile (created_by));
+diesel::joinable!(webhook_delivery -> webhook_subscription (subscription_id));
+diesel::joinable!(webhook_subscription -> door (door_id));
+diesel::joinable!(webhook_subscription -> user_profile (owner_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
     access_history,
+    admin,
+    allowlist_version,
+    api_token,
+    calendar,
+    calendar_exception,
+    credential,
+    device,
+    device_command,
+    device_config,
+    device_group,
+    device_telemetry,
     door,
     door_code,
+    door_event,
+    door_lock,
+    door_mode,
     door_permission,
+    door_state,
+    door_totp,
+    doorbell_ring,
+    emergency,
+    firmware_image,
+    firmware_update,
+    guest_token,
+    responder,
+    snapshot,
+    unlock_exception,
+    unlock_schedule,
     user_profile,
+    webhook_delivery,
+    webhook_subscription,
 );
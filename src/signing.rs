@@ -0,0 +1,50 @@
+use dotenv::dotenv;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier};
+use std::{env, sync::Arc};
+
+// Signs what controllers have to trust while they can not reach us. Devices
+// are flashed with the public half.
+#[derive(Clone)]
+pub struct DeviceSigner {
+    key: Arc<SigningKey>,
+}
+
+impl DeviceSigner {
+    pub fn from_env() -> Self {
+        dotenv().ok();
+
+        let seed = env::var("DEVICE_SIGNING_KEY").expect("DEVICE_SIGNING_KEY not found");
+        let bytes: [u8; 32] = hex::decode(seed)
+            .expect("DEVICE_SIGNING_KEY is not valid hex")
+            .try_into()
+            .expect("DEVICE_SIGNING_KEY must be 32 bytes");
+
+        DeviceSigner {
+            key: Arc::new(SigningKey::from_bytes(&bytes)),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        DeviceSigner {
+            key: Arc::new(SigningKey::from_bytes(&seed)),
+        }
+    }
+
+    pub fn sign(&self, message: &[u8]) -> String {
+        hex::encode(self.sign_bytes(message))
+    }
+
+    pub fn sign_bytes(&self, message: &[u8]) -> [u8; 64] {
+        self.key.sign(message).to_bytes()
+    }
+
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        Signature::from_slice(signature)
+            .is_ok_and(|signature| self.key.verifying_key().verify(message, &signature).is_ok())
+    }
+
+    pub fn public_key(&self) -> String {
+        hex::encode(self.key.verifying_key().to_bytes())
+    }
+}
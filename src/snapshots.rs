@@ -0,0 +1,95 @@
+use async_session::chrono::{Duration, Utc};
+use diesel::{delete, prelude::*};
+use dotenv::dotenv;
+use std::{env, path::PathBuf};
+
+use crate::{db::establish_connection, models::Snapshot, schema::snapshot};
+
+const DEFAULT_DIR: &str = "snapshots";
+const DEFAULT_RETENTION_DAYS: i64 = 30;
+const RETENTION_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+// Where uploaded JPEGs live on disk and how long they are kept
+#[derive(Clone)]
+pub struct SnapshotStore {
+    dir: PathBuf,
+    retention: Duration,
+}
+
+impl SnapshotStore {
+    pub fn from_env() -> Self {
+        dotenv().ok();
+
+        let dir = env::var("SNAPSHOT_DIR").unwrap_or_else(|_| DEFAULT_DIR.to_string());
+        let retention_days = env::var("SNAPSHOT_RETENTION_DAYS")
+            .ok()
+            .map(|days| {
+                days.parse()
+                    .expect("SNAPSHOT_RETENTION_DAYS must be a number")
+            })
+            .unwrap_or(DEFAULT_RETENTION_DAYS);
+
+        SnapshotStore {
+            dir: PathBuf::from(dir),
+            retention: Duration::days(retention_days),
+        }
+    }
+
+    pub fn path(&self, file_name: &str) -> PathBuf {
+        self.dir.join(file_name)
+    }
+
+    pub async fn write(&self, file_name: &str, bytes: &[u8]) -> std::io::Result<()> {
+        let path = self.path(file_name);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(path, bytes).await
+    }
+
+    // Drops snapshots past the retention limit, both the files and their rows
+    pub async fn enforce_retention(&self, conn: &mut PgConnection) {
+        let cutoff = Utc::now().naive_utc() - self.retention;
+
+        let expired = snapshot::table
+            .filter(snapshot::created_at.lt(cutoff))
+            .select(Snapshot::as_select())
+            .load(conn);
+
+        let expired = match expired {
+            Ok(expired) => expired,
+            Err(e) => {
+                tracing::error!("Could not load expired snapshots: {e}");
+                return;
+            }
+        };
+
+        for expired in expired {
+            match tokio::fs::remove_file(self.path(&expired.file_name)).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    tracing::warn!("Could not remove snapshot {}: {e}", expired.file_name);
+                    continue;
+                }
+            }
+            let _ = delete(snapshot::table.find(expired.id)).execute(conn);
+        }
+    }
+}
+
+pub fn spawn_retention(store: &SnapshotStore) {
+    let store = store.clone();
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RETENTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            let conn = &mut establish_connection();
+            store.enforce_retention(conn).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests;
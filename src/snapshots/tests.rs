@@ -0,0 +1,88 @@
+use async_session::chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{insert_into, prelude::*, PgConnection};
+use std::env;
+use uuid::Uuid;
+
+use super::SnapshotStore;
+use crate::{schema::snapshot, testing};
+
+fn store() -> SnapshotStore {
+    SnapshotStore {
+        dir: env::temp_dir().join(format!("snapshots-{}", Uuid::new_v4())),
+        retention: Duration::days(30),
+    }
+}
+
+async fn upload(
+    conn: &mut PgConnection,
+    store: &SnapshotStore,
+    door_id: i32,
+    created_at: NaiveDateTime,
+) -> (i32, String) {
+    let file_name = format!("{door_id}/{}.jpg", Uuid::new_v4());
+    store.write(&file_name, &[0xFF, 0xD8, 0xFF]).await.unwrap();
+
+    let id = insert_into(snapshot::table)
+        .values((
+            snapshot::door_id.eq(door_id),
+            snapshot::file_name.eq(&file_name),
+            snapshot::size_bytes.eq(3),
+            snapshot::created_at.eq(created_at),
+        ))
+        .returning(snapshot::id)
+        .get_result(conn)
+        .unwrap();
+    (id, file_name)
+}
+
+#[tokio::test]
+#[ignore = "needs TEST_DATABASE_URL"]
+async fn snapshots_past_the_retention_limit_are_removed() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let store = store();
+    let now = Utc::now().naive_utc();
+    let (old_id, old_file) = upload(conn, &store, door_id, now - Duration::days(31)).await;
+    let (new_id, new_file) = upload(conn, &store, door_id, now - Duration::days(29)).await;
+
+    store.enforce_retention(conn).await;
+
+    let left: Vec<i32> = snapshot::table
+        .filter(snapshot::id.eq_any([old_id, new_id]))
+        .select(snapshot::id)
+        .load(conn)
+        .unwrap();
+    assert_eq!(left, [new_id]);
+    assert!(!store.path(&old_file).exists());
+    assert!(store.path(&new_file).exists());
+}
+
+#[tokio::test]
+#[ignore = "needs TEST_DATABASE_URL"]
+async fn rows_whose_file_is_already_gone_are_removed() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let store = store();
+    let (id, file_name) = upload(
+        conn,
+        &store,
+        door_id,
+        Utc::now().naive_utc() - Duration::days(40),
+    )
+    .await;
+    std::fs::remove_file(store.path(&file_name)).unwrap();
+
+    store.enforce_retention(conn).await;
+
+    let left = snapshot::table
+        .find(id)
+        .select(snapshot::id)
+        .get_result::<i32>(conn)
+        .optional()
+        .unwrap();
+    assert_eq!(left, None);
+}
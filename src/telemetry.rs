@@ -0,0 +1,172 @@
+use async_session::chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{delete, insert_into, prelude::*, update};
+use dotenv::dotenv;
+use protocol::Heartbeat;
+use std::env;
+
+use crate::{
+    db::establish_connection,
+    events::{DoorEvent, EventBus, EventKind},
+    models::Device,
+    schema::{device, device_telemetry},
+};
+
+const DEFAULT_OFFLINE_SECONDS: i64 = 90;
+const OFFLINE_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+const DEFAULT_RETENTION_DAYS: i64 = 30;
+const RETENTION_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+// Any sign of life from a controller, a heartbeat or a fresh connection. The
+// first one after an outage announces the device is back, a device that was
+// never seen before just comes online.
+pub fn mark_seen(
+    conn: &mut PgConnection,
+    events: &EventBus,
+    device_id: i32,
+    now: NaiveDateTime,
+) -> QueryResult<usize> {
+    let came_back = update(
+        device::table
+            .find(device_id)
+            .filter(device::online.eq(false))
+            .filter(device::last_seen_at.is_not_null()),
+    )
+    .set((device::last_seen_at.eq(now), device::online.eq(true)))
+    .returning(device::door_id)
+    .get_result::<i32>(conn)
+    .optional()?;
+
+    if let Some(door_id) = came_back {
+        tracing::info!("Device {device_id} of door {door_id} is online");
+        events.publish(DoorEvent::new(EventKind::DeviceOnline, door_id, None));
+        return Ok(1);
+    }
+
+    update(device::table.find(device_id))
+        .set((device::last_seen_at.eq(now), device::online.eq(true)))
+        .execute(conn)
+}
+
+pub fn record_heartbeat(
+    conn: &mut PgConnection,
+    events: &EventBus,
+    device: &Device,
+    heartbeat: Heartbeat,
+) {
+    let now = Utc::now().naive_utc();
+
+    if let Err(e) = mark_seen(conn, events, device.id, now) {
+        tracing::error!("Could not mark device {} as seen: {e}", device.id);
+    }
+
+    let recorded = conn.transaction(|conn| {
+        update(device::table.find(device.id))
+            .set(device::firmware_version.eq(&heartbeat.firmware_version))
+            .execute(conn)?;
+
+        insert_into(device_telemetry::table)
+            .values((
+                device_telemetry::device_id.eq(device.id),
+                device_telemetry::uptime_seconds.eq(heartbeat.uptime_seconds),
+                device_telemetry::rssi.eq(heartbeat.rssi),
+                device_telemetry::free_heap.eq(heartbeat.free_heap),
+                device_telemetry::firmware_version.eq(&heartbeat.firmware_version),
+                device_telemetry::recorded_at.eq(now),
+            ))
+            .execute(conn)
+    });
+
+    if let Err(e) = recorded {
+        tracing::error!("Could not record heartbeat of device {}: {e}", device.id);
+    }
+}
+
+// Flags devices silent past the threshold and alerts once per outage
+fn check_offline(conn: &mut PgConnection, events: &EventBus, threshold: Duration) {
+    let cutoff = Utc::now().naive_utc() - threshold;
+
+    let silent = update(
+        device::table
+            .filter(device::online.eq(true))
+            .filter(device::last_seen_at.lt(cutoff)),
+    )
+    .set(device::online.eq(false))
+    .returning(Device::as_returning())
+    .get_results(conn);
+
+    match silent {
+        Ok(silent) => {
+            for device in silent {
+                tracing::warn!(
+                    "Device {} of door {} went offline",
+                    device.id,
+                    device.door_id
+                );
+                events.publish(DoorEvent::new(
+                    EventKind::DeviceOffline,
+                    device.door_id,
+                    None,
+                ));
+            }
+        }
+        Err(e) => tracing::error!("Could not check for offline devices: {e}"),
+    }
+}
+
+pub fn spawn_offline_monitor(events: &EventBus) {
+    dotenv().ok();
+
+    let threshold = env::var("DEVICE_OFFLINE_SECONDS")
+        .ok()
+        .map(|seconds| {
+            seconds
+                .parse()
+                .expect("DEVICE_OFFLINE_SECONDS must be a number")
+        })
+        .unwrap_or(DEFAULT_OFFLINE_SECONDS);
+    let threshold = Duration::seconds(threshold);
+    let events = events.clone();
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(OFFLINE_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let conn = &mut establish_connection();
+            check_offline(conn, &events, threshold);
+        }
+    });
+}
+
+fn drop_before(conn: &mut PgConnection, cutoff: NaiveDateTime) -> QueryResult<usize> {
+    delete(device_telemetry::table.filter(device_telemetry::recorded_at.lt(cutoff))).execute(conn)
+}
+
+// Heartbeats come every few seconds from every controller, only recent ones are kept
+pub fn spawn_retention() {
+    dotenv().ok();
+
+    let retention_days = env::var("TELEMETRY_RETENTION_DAYS")
+        .ok()
+        .map(|days| {
+            days.parse()
+                .expect("TELEMETRY_RETENTION_DAYS must be a number")
+        })
+        .unwrap_or(DEFAULT_RETENTION_DAYS);
+    let retention = Duration::days(retention_days);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RETENTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            let conn = &mut establish_connection();
+            match drop_before(conn, Utc::now().naive_utc() - retention) {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Dropped {n} heartbeats past the retention limit"),
+                Err(e) => tracing::error!("Could not drop old heartbeats: {e}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests;
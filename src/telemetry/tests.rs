@@ -0,0 +1,143 @@
+use async_session::chrono::{Duration, Utc};
+use diesel::{insert_into, prelude::*, update};
+use protocol::Heartbeat;
+
+use super::{check_offline, drop_before, mark_seen, record_heartbeat};
+use crate::{
+    events::EventKind,
+    models::Device,
+    schema::{device, device_telemetry},
+    testing,
+};
+
+fn heartbeat() -> Heartbeat {
+    Heartbeat {
+        uptime_seconds: 120,
+        rssi: -61,
+        free_heap: 81_920,
+        firmware_version: "1.4.0".to_string(),
+    }
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn heartbeats_are_recorded() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let controller = testing::device(conn, door_id, "token");
+    let events = testing::events();
+
+    record_heartbeat(conn, &events, &controller, heartbeat());
+
+    let controller: Device = device::table
+        .find(controller.id)
+        .select(Device::as_select())
+        .get_result(conn)
+        .unwrap();
+    assert!(controller.online);
+    assert!(controller.last_seen_at.is_some());
+    assert_eq!(controller.firmware_version.as_deref(), Some("1.4.0"));
+    let rssi: Vec<i32> = device_telemetry::table
+        .filter(device_telemetry::device_id.eq(controller.id))
+        .select(device_telemetry::rssi)
+        .load(conn)
+        .unwrap();
+    assert_eq!(rssi, [-61]);
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn devices_coming_back_are_announced_once() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let controller = testing::device(conn, door_id, "token");
+    let events = testing::events();
+    let mut receiver = events.subscribe();
+    let now = Utc::now().naive_utc();
+
+    // The very first connection is no comeback
+    mark_seen(conn, &events, controller.id, now).unwrap();
+    update(device::table.find(controller.id))
+        .set(device::online.eq(false))
+        .execute(conn)
+        .unwrap();
+
+    mark_seen(conn, &events, controller.id, now).unwrap();
+    mark_seen(conn, &events, controller.id, now).unwrap();
+
+    let published: Vec<_> = testing::published(&events, &mut receiver)
+        .into_iter()
+        .map(|event| (event.kind, event.door_id))
+        .collect();
+    assert_eq!(published, [(EventKind::DeviceOnline, door_id)]);
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn silent_devices_go_offline_once() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let silent = testing::device(conn, door_id, "silent");
+    let chatty = testing::device(conn, door_id, "chatty");
+    let events = testing::events();
+    let now = Utc::now().naive_utc();
+    mark_seen(conn, &events, silent.id, now - Duration::minutes(5)).unwrap();
+    mark_seen(conn, &events, chatty.id, now).unwrap();
+    let mut receiver = events.subscribe();
+
+    check_offline(conn, &events, Duration::seconds(90));
+    check_offline(conn, &events, Duration::seconds(90));
+
+    let online: Vec<(i32, bool)> = device::table
+        .filter(device::id.eq_any([silent.id, chatty.id]))
+        .order(device::id.asc())
+        .select((device::id, device::online))
+        .load(conn)
+        .unwrap();
+    assert_eq!(online, [(silent.id, false), (chatty.id, true)]);
+    let published: Vec<_> = testing::published(&events, &mut receiver)
+        .into_iter()
+        .filter(|event| event.door_id == door_id)
+        .map(|event| event.kind)
+        .collect();
+    assert_eq!(published, [EventKind::DeviceOffline]);
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn old_heartbeats_are_dropped() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    let controller = testing::device(conn, door_id, "token");
+    let now = Utc::now().naive_utc();
+    for days in [40, 20] {
+        insert_into(device_telemetry::table)
+            .values((
+                device_telemetry::device_id.eq(controller.id),
+                device_telemetry::uptime_seconds.eq(days),
+                device_telemetry::rssi.eq(-50),
+                device_telemetry::free_heap.eq(1024),
+                device_telemetry::firmware_version.eq("1.0.0"),
+                device_telemetry::recorded_at.eq(now - Duration::days(days)),
+            ))
+            .execute(conn)
+            .unwrap();
+    }
+
+    drop_before(conn, now - Duration::days(30)).unwrap();
+
+    let left: Vec<i64> = device_telemetry::table
+        .filter(device_telemetry::device_id.eq(controller.id))
+        .select(device_telemetry::uptime_seconds)
+        .load(conn)
+        .unwrap();
+    assert_eq!(left, [20]);
+}
@@ -0,0 +1,98 @@
+use async_session::chrono::Utc;
+use diesel::{insert_into, prelude::*};
+use diesel_migrations::MigrationHarness;
+use std::{
+    env,
+    sync::{Mutex, MutexGuard},
+};
+
+use tokio::sync::broadcast::Receiver;
+
+use crate::{
+    events::{DoorEvent, EventBus, EventKind},
+    models::Device,
+    routes::auth::hash_token,
+    schema::{device, door, user_profile},
+    MIGRATIONS,
+};
+
+// Tests that need Postgres run against TEST_DATABASE_URL, inside a transaction
+// that is never committed. They are marked `#[ignore]`, run them with
+// `cargo test -- --ignored`.
+
+// Migrations run once, and the tests take turns so they never wait on each other's rows
+static DATABASE: Mutex<bool> = Mutex::new(false);
+
+pub struct TestDatabase {
+    pub conn: PgConnection,
+    _lock: MutexGuard<'static, bool>,
+}
+
+pub fn database() -> TestDatabase {
+    let database_url = env::var("TEST_DATABASE_URL")
+        .expect("TEST_DATABASE_URL must point at a Postgres database for this test");
+
+    let mut migrated = DATABASE.lock().unwrap_or_else(|e| e.into_inner());
+    let mut conn = PgConnection::establish(&database_url).unwrap();
+    if !*migrated {
+        conn.run_pending_migrations(MIGRATIONS).unwrap();
+        *migrated = true;
+    }
+    conn.begin_test_transaction().unwrap();
+
+    TestDatabase {
+        conn,
+        _lock: migrated,
+    }
+}
+
+// Nothing is written to the event log, subscribers still get every event
+pub fn events() -> EventBus {
+    EventBus::with_log(|_| Ok(0))
+}
+
+// Everything published so far, the log thread is caught up by the time this returns
+pub fn published(events: &EventBus, receiver: &mut Receiver<DoorEvent>) -> Vec<DoorEvent> {
+    events.publish(DoorEvent::new(EventKind::Opened, i32::MIN, None));
+
+    let mut published = Vec::new();
+    loop {
+        let event = receiver.blocking_recv().unwrap();
+        if event.door_id == i32::MIN {
+            return published;
+        }
+        published.push(event);
+    }
+}
+
+pub fn user(conn: &mut PgConnection, username: &str) -> i32 {
+    insert_into(user_profile::table)
+        .values((
+            user_profile::discord_id.eq(format!("discord-{username}")),
+            user_profile::username.eq(username),
+        ))
+        .returning(user_profile::id)
+        .get_result(conn)
+        .unwrap()
+}
+
+pub fn door(conn: &mut PgConnection, owner_id: i32) -> i32 {
+    insert_into(door::table)
+        .values((door::about.eq("test door"), door::owner_id.eq(owner_id)))
+        .returning(door::id)
+        .get_result(conn)
+        .unwrap()
+}
+
+pub fn device(conn: &mut PgConnection, door_id: i32, token: &str) -> Device {
+    insert_into(device::table)
+        .values((
+            device::door_id.eq(door_id),
+            device::name.eq("test controller"),
+            device::token_hash.eq(hash_token(token)),
+            device::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .returning(Device::as_returning())
+        .get_result(conn)
+        .unwrap()
+}
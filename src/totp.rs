@@ -0,0 +1,151 @@
+use async_session::chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{prelude::*, update};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::schema::door_totp;
+
+// RFC 6238 with HMAC-SHA256, 30 second steps and 6 digits
+pub const PERIOD_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+// Steps either side of now that still count, for keypads with a drifting clock
+const SKEW_STEPS: i64 = 1;
+// Wrong codes in a row before the keypad has to wait, with a million codes
+// and three of them valid at a time guessing stays hopeless
+const MAX_FAILED_ATTEMPTS: i32 = 5;
+const LOCKOUT_SECONDS: i64 = 5 * 60;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RedeemError {
+    Disabled,
+    LockedOut,
+    Invalid,
+    // The code, or a later one, opened the door already
+    Replayed,
+    Database,
+}
+
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    hex::encode(secret)
+}
+
+fn step_of(time: NaiveDateTime) -> i64 {
+    time.timestamp().div_euclid(PERIOD_SECONDS)
+}
+
+fn code_at(secret: &[u8], step: i64) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation from RFC 4226
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+// The code valid right now and how many seconds it has left
+pub fn current(secret: &str) -> Option<(String, i64)> {
+    let secret = hex::decode(secret).ok()?;
+    let now = Utc::now().naive_utc();
+
+    let step = step_of(now);
+    let remaining = (step + 1) * PERIOD_SECONDS - now.timestamp();
+    Some((code_at(&secret, step), remaining))
+}
+
+// The step the code belongs to, if it is one of those valid around `now`
+pub fn verify(secret: &str, code: &str, now: NaiveDateTime) -> Option<i64> {
+    let secret = hex::decode(secret).ok()?;
+
+    let step = step_of(now);
+    (step - SKEW_STEPS..=step + SKEW_STEPS).find(|&step| code_at(&secret, step) == code)
+}
+
+// Accepts a code typed on the door's keypad. Each code opens once, and so
+// does every code before it, as RFC 6238 recommends.
+pub fn redeem(
+    conn: &mut PgConnection,
+    door_id: i32,
+    code: &str,
+    now: NaiveDateTime,
+) -> Result<(), RedeemError> {
+    let (secret, locked_until) = door_totp::table
+        .find(door_id)
+        .select((door_totp::secret, door_totp::locked_until))
+        .get_result::<(String, Option<NaiveDateTime>)>(conn)
+        .optional()
+        .map_err(|_| RedeemError::Database)?
+        .ok_or(RedeemError::Disabled)?;
+
+    if locked_until.is_some_and(|locked_until| now < locked_until) {
+        return Err(RedeemError::LockedOut);
+    }
+
+    let Some(step) = verify(&secret, code, now) else {
+        record_failure(conn, door_id, now);
+        return Err(RedeemError::Invalid);
+    };
+
+    // Two keypads sending the same code at once only get one open
+    let redeemed = update(
+        door_totp::table.find(door_id).filter(
+            door_totp::last_step
+                .is_null()
+                .or(door_totp::last_step.lt(step)),
+        ),
+    )
+    .set((
+        door_totp::last_step.eq(step),
+        door_totp::failed_attempts.eq(0),
+    ))
+    .execute(conn)
+    .map_err(|_| RedeemError::Database)?;
+
+    match redeemed {
+        0 => Err(RedeemError::Replayed),
+        _ => Ok(()),
+    }
+}
+
+fn record_failure(conn: &mut PgConnection, door_id: i32, now: NaiveDateTime) {
+    let failed_attempts = update(door_totp::table.find(door_id))
+        .set(door_totp::failed_attempts.eq(door_totp::failed_attempts + 1))
+        .returning(door_totp::failed_attempts)
+        .get_result::<i32>(conn);
+
+    match failed_attempts {
+        Ok(failed_attempts) if failed_attempts >= MAX_FAILED_ATTEMPTS => {
+            tracing::warn!(
+                "Keypad of door {door_id} is locked out after {failed_attempts} wrong codes"
+            );
+            let _ = update(door_totp::table.find(door_id))
+                .set((
+                    door_totp::failed_attempts.eq(0),
+                    door_totp::locked_until.eq(now + Duration::seconds(LOCKOUT_SECONDS)),
+                ))
+                .execute(conn);
+        }
+        Ok(_) => {}
+        Err(e) => tracing::error!("Could not count wrong code for door {door_id}: {e}"),
+    }
+}
+
+pub fn door_secret(conn: &mut PgConnection, door_id: i32) -> Option<String> {
+    door_totp::table
+        .find(door_id)
+        .select(door_totp::secret)
+        .get_result(conn)
+        .ok()
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,130 @@
+use async_session::chrono::{Duration, NaiveDateTime};
+use diesel::{insert_into, prelude::*};
+
+use super::{code_at, redeem, step_of, verify, RedeemError, MAX_FAILED_ATTEMPTS, PERIOD_SECONDS};
+use crate::{schema::door_totp, testing};
+
+// The SHA-256 seed of RFC 6238, appendix B
+const RFC_SEED: &[u8] = b"12345678901234567890123456789012";
+
+fn at(seconds: i64) -> NaiveDateTime {
+    NaiveDateTime::from_timestamp_opt(seconds, 0).unwrap()
+}
+
+#[test]
+fn codes_match_the_rfc_6238_vectors() {
+    // The RFC lists 8 digits, the last 6 are the same code cut short
+    let vectors = [
+        (59, "46119246"),
+        (1_111_111_109, "68084774"),
+        (1_111_111_111, "67062674"),
+        (1_234_567_890, "91819424"),
+        (2_000_000_000, "90698825"),
+        (20_000_000_000, "77737706"),
+    ];
+
+    for (time, expected) in vectors {
+        assert_eq!(
+            code_at(RFC_SEED, step_of(at(time))),
+            expected[2..],
+            "at {time}"
+        );
+    }
+}
+
+#[test]
+fn neighbouring_steps_are_accepted() {
+    let secret = hex::encode(RFC_SEED);
+    let now = at(1_111_111_111);
+
+    assert_eq!(verify(&secret, "062674", now), Some(step_of(now)));
+    let before = code_at(RFC_SEED, step_of(now) - 1);
+    assert_eq!(verify(&secret, &before, now), Some(step_of(now) - 1));
+
+    let too_old = code_at(RFC_SEED, step_of(now) - 2);
+    assert_eq!(verify(&secret, &too_old, now), None);
+    assert_eq!(verify("not hex", "062674", now), None);
+}
+
+fn enable(conn: &mut PgConnection, door_id: i32) {
+    insert_into(door_totp::table)
+        .values((
+            door_totp::door_id.eq(door_id),
+            door_totp::secret.eq(hex::encode(RFC_SEED)),
+            door_totp::created_at.eq(at(0)),
+        ))
+        .execute(conn)
+        .unwrap();
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn codes_open_once() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    enable(conn, door_id);
+    let now = at(1_111_111_111);
+    let step = step_of(now);
+
+    assert_eq!(redeem(conn, door_id, &code_at(RFC_SEED, step), now), Ok(()));
+    assert_eq!(
+        redeem(conn, door_id, &code_at(RFC_SEED, step), now),
+        Err(RedeemError::Replayed)
+    );
+    // Still inside the window, but older than the code already used
+    assert_eq!(
+        redeem(conn, door_id, &code_at(RFC_SEED, step - 1), now),
+        Err(RedeemError::Replayed)
+    );
+
+    let later = now + Duration::seconds(PERIOD_SECONDS);
+    assert_eq!(
+        redeem(conn, door_id, &code_at(RFC_SEED, step + 1), later),
+        Ok(())
+    );
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn guessing_locks_the_keypad_out() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+    enable(conn, door_id);
+    let now = at(1_111_111_111);
+    let code = code_at(RFC_SEED, step_of(now));
+
+    for _ in 0..MAX_FAILED_ATTEMPTS {
+        assert_eq!(
+            redeem(conn, door_id, "000000", now),
+            Err(RedeemError::Invalid)
+        );
+    }
+    assert_eq!(
+        redeem(conn, door_id, &code, now),
+        Err(RedeemError::LockedOut)
+    );
+
+    let later = now + Duration::minutes(10);
+    assert_eq!(
+        redeem(conn, door_id, &code_at(RFC_SEED, step_of(later)), later),
+        Ok(())
+    );
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn doors_without_a_secret_take_no_codes() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let user_id = testing::user(conn, "alice");
+    let door_id = testing::door(conn, user_id);
+
+    assert_eq!(
+        redeem(conn, door_id, "062674", at(1_111_111_111)),
+        Err(RedeemError::Disabled)
+    );
+}
@@ -0,0 +1,236 @@
+use async_session::chrono::{Datelike, Duration, Local, NaiveDate, NaiveDateTime, TimeZone};
+use diesel::{prelude::*, update};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    calendars::{self, DayRule, Exceptions},
+    db::establish_connection,
+    emergency,
+    events::EventBus,
+    lock_mode::{self, LockMode},
+    locks::LockDrivers,
+    models::UnlockSchedule,
+    schema::{door_mode, unlock_exception, unlock_schedule},
+};
+
+const SCHEDULE_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+
+// Doors that stay unlocked during set hours, like a café on Saturdays. The
+// scheduler unlocks a door once per window, until the window ends, and the
+// relock of `lock_mode` locks it again. Whatever someone does with the door
+// during a window stands until the next one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+}
+
+// The window of the schedule `now` is in, all in server local time. Windows
+// belong to the day they start on, so that day's exception closes them or
+// moves them to the exception's hours. Hours never open a day the schedule
+// does not include.
+pub fn active_window(
+    schedule: &UnlockSchedule,
+    exceptions: &Exceptions,
+    now: NaiveDateTime,
+) -> Option<Window> {
+    let today = now.date();
+    [today, today - Duration::days(1)]
+        .into_iter()
+        .filter_map(|day| {
+            if schedule.days & (1 << day.weekday().num_days_from_monday()) == 0 {
+                return None;
+            }
+            let (start, end) = match exceptions.get(&day) {
+                Some(DayRule::Closed) => return None,
+                Some(DayRule::Hours { start, end }) => (*start, *end),
+                None => (schedule.start_time, schedule.end_time),
+            };
+            let end = if end > start {
+                day.and_time(end)
+            } else {
+                (day + Duration::days(1)).and_time(end)
+            };
+            Some(Window {
+                start: day.and_time(start),
+                end,
+            })
+        })
+        .find(|window| window.start <= now && now < window.end)
+}
+
+// Times that do not exist locally, skipped by a DST change, move up an hour
+fn to_utc(local: NaiveDateTime) -> Option<NaiveDateTime> {
+    Local
+        .from_local_datetime(&local)
+        .earliest()
+        .or_else(|| {
+            Local
+                .from_local_datetime(&(local + Duration::hours(1)))
+                .earliest()
+        })
+        .map(|time| time.naive_utc())
+}
+
+async fn run_schedules(conn: &mut PgConnection, locks: &LockDrivers, events: &EventBus) {
+    // Doors stay as the emergency left them until it is cleared
+    if emergency::active_mode(conn).is_some() {
+        return;
+    }
+
+    let now = Local::now().naive_local();
+
+    let schedules = unlock_schedule::table
+        .order(unlock_schedule::id.asc())
+        .select(UnlockSchedule::as_select())
+        .load(conn);
+    let schedules = match schedules {
+        Ok(schedules) => schedules,
+        Err(e) => {
+            tracing::error!("Could not load unlock schedules: {e}");
+            return;
+        }
+    };
+
+    // Windows started yesterday at the earliest
+    let yesterday = now.date() - Duration::days(1);
+    let calendar_ids = schedules
+        .iter()
+        .filter_map(|schedule| schedule.calendar_id)
+        .collect::<Vec<_>>();
+    let calendars = calendars::exceptions_between(conn, &calendar_ids, yesterday, now.date())
+        .unwrap_or_else(|e| {
+            tracing::error!("Could not load calendar exceptions: {e}");
+            HashMap::new()
+        });
+    let exceptions = unlock_exception::table
+        .filter(unlock_exception::date.ge(yesterday))
+        .filter(unlock_exception::date.le(now.date()))
+        .select((unlock_exception::door_id, unlock_exception::date))
+        .load::<(i32, NaiveDate)>(conn)
+        .unwrap_or_else(|e| {
+            tracing::error!("Could not load unlock exceptions: {e}");
+            Vec::new()
+        });
+
+    // Doors the scheduler unlocked may have lost their last schedule since
+    let scheduled_unlocks = door_mode::table
+        .filter(door_mode::scheduled)
+        .filter(door_mode::mode.eq(LockMode::Unlocked.as_str()))
+        .select(door_mode::door_id)
+        .load::<i32>(conn)
+        .unwrap_or_else(|e| {
+            tracing::error!("Could not load scheduled unlocks: {e}");
+            Vec::new()
+        });
+
+    let mut doors = BTreeMap::<i32, Vec<UnlockSchedule>>::new();
+    for door_id in scheduled_unlocks {
+        doors.entry(door_id).or_default();
+    }
+    for schedule in schedules {
+        doors.entry(schedule.door_id).or_default().push(schedule);
+    }
+
+    for (door_id, schedules) in doors {
+        let closed = exceptions
+            .iter()
+            .filter(|(exception_door_id, _)| *exception_door_id == door_id)
+            .map(|(_, date)| *date)
+            .collect::<Vec<_>>();
+        let active = schedules
+            .iter()
+            .filter_map(|schedule| {
+                // The door's own exceptions win over its schedules' calendars
+                let mut rules = schedule
+                    .calendar_id
+                    .and_then(|calendar_id| calendars.get(&calendar_id))
+                    .cloned()
+                    .unwrap_or_default();
+                rules.extend(closed.iter().map(|date| (*date, DayRule::Closed)));
+                active_window(schedule, &rules, now).map(|window| (schedule, window))
+            })
+            .max_by_key(|(_, window)| window.end);
+
+        let mode = match lock_mode::current(conn, door_id) {
+            Ok(mode) => mode,
+            Err(e) => {
+                tracing::error!("Could not load mode of door {door_id}: {e}");
+                continue;
+            }
+        };
+
+        match active {
+            Some((schedule, window)) => {
+                if schedule.applied_window_start == Some(window.start) {
+                    continue;
+                }
+                let Some(until) = to_utc(window.end) else {
+                    continue;
+                };
+
+                // A longer unlock someone asked for stands
+                let held = mode.is_some_and(|mode| {
+                    mode.mode == LockMode::Unlocked.as_str()
+                        && mode.unlocked_until.is_some_and(|held| held >= until)
+                });
+                if !held {
+                    let unlocked = lock_mode::set_scheduled(
+                        conn,
+                        locks,
+                        events,
+                        door_id,
+                        LockMode::Unlocked,
+                        Some(until),
+                    )
+                    .await;
+                    // Tried again on the next check
+                    if let Err(e) = unlocked {
+                        tracing::error!("Could not unlock door {door_id} on schedule: {e}");
+                        continue;
+                    }
+                }
+
+                let applied = update(unlock_schedule::table.find(schedule.id))
+                    .set(unlock_schedule::applied_window_start.eq(window.start))
+                    .execute(conn);
+                if let Err(e) = applied {
+                    tracing::error!("Could not record window of schedule {}: {e}", schedule.id);
+                }
+            }
+            // The window was cut short, by an exception or a removed schedule
+            None => {
+                let scheduled_unlock = mode
+                    .is_some_and(|mode| mode.scheduled && mode.mode == LockMode::Unlocked.as_str());
+                if !scheduled_unlock {
+                    continue;
+                }
+
+                let locked =
+                    lock_mode::set_scheduled(conn, locks, events, door_id, LockMode::Locked, None)
+                        .await;
+                if let Err(e) = locked {
+                    tracing::error!("Could not lock door {door_id} on schedule: {e}");
+                }
+            }
+        }
+    }
+}
+
+// Changes to schedules and exceptions are picked up on the next check
+pub fn spawn_scheduler(locks: &LockDrivers, events: &EventBus) {
+    let locks = locks.clone();
+    let events = events.clone();
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SCHEDULE_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let conn = &mut establish_connection();
+            run_schedules(conn, &locks, &events).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests;
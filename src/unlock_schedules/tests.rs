@@ -0,0 +1,119 @@
+use async_session::chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+
+use super::{active_window, Window};
+use crate::{
+    calendars::{DayRule, Exceptions},
+    models::UnlockSchedule,
+};
+
+const SATURDAY: i16 = 1 << 5;
+const FRIDAY: i16 = 1 << 4;
+
+fn schedule(days: i16, start: (u32, u32), end: (u32, u32)) -> UnlockSchedule {
+    UnlockSchedule {
+        id: 1,
+        door_id: 1,
+        days,
+        start_time: NaiveTime::from_hms_opt(start.0, start.1, 0).unwrap(),
+        end_time: NaiveTime::from_hms_opt(end.0, end.1, 0).unwrap(),
+        label: None,
+        applied_window_start: None,
+        calendar_id: None,
+        created_by: None,
+        created_at: at(2023, 11, 1, 0, 0),
+    }
+}
+
+fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(year, month, day)
+        .unwrap()
+        .and_hms_opt(hour, minute, 0)
+        .unwrap()
+}
+
+#[test]
+fn windows_are_open_during_their_hours() {
+    // The café, Saturdays from 10:00 to 16:00. 2023-11-25 is a Saturday.
+    let cafe = schedule(SATURDAY, (10, 0), (16, 0));
+    let open = Exceptions::new();
+
+    assert_eq!(
+        active_window(&cafe, &open, at(2023, 11, 25, 10, 0)),
+        Some(Window {
+            start: at(2023, 11, 25, 10, 0),
+            end: at(2023, 11, 25, 16, 0),
+        })
+    );
+    assert!(active_window(&cafe, &open, at(2023, 11, 25, 9, 59)).is_none());
+    assert!(active_window(&cafe, &open, at(2023, 11, 25, 16, 0)).is_none());
+    // Sunday
+    assert!(active_window(&cafe, &open, at(2023, 11, 26, 12, 0)).is_none());
+}
+
+#[test]
+fn windows_run_over_midnight() {
+    let bar = schedule(FRIDAY, (20, 0), (2, 0));
+    let open = Exceptions::new();
+    let window = Some(Window {
+        start: at(2023, 11, 24, 20, 0),
+        end: at(2023, 11, 25, 2, 0),
+    });
+
+    assert_eq!(active_window(&bar, &open, at(2023, 11, 24, 23, 0)), window);
+    // Saturday morning still belongs to Friday
+    assert_eq!(active_window(&bar, &open, at(2023, 11, 25, 1, 0)), window);
+    assert!(active_window(&bar, &open, at(2023, 11, 25, 20, 30)).is_none());
+}
+
+#[test]
+fn exceptions_close_the_windows_of_their_day() {
+    let cafe = schedule(SATURDAY, (10, 0), (16, 0));
+    let bar = schedule(FRIDAY, (20, 0), (2, 0));
+    let closed = Exceptions::from([
+        (
+            NaiveDate::from_ymd_opt(2023, 11, 24).unwrap(),
+            DayRule::Closed,
+        ),
+        (
+            NaiveDate::from_ymd_opt(2023, 12, 23).unwrap(),
+            DayRule::Closed,
+        ),
+    ]);
+
+    assert!(active_window(&cafe, &closed, at(2023, 12, 23, 12, 0)).is_none());
+    assert!(active_window(&cafe, &closed, at(2023, 11, 25, 12, 0)).is_some());
+    assert!(active_window(&bar, &closed, at(2023, 11, 25, 1, 0)).is_none());
+}
+
+#[test]
+fn calendar_hours_replace_the_schedule_for_their_day() {
+    let cafe = schedule(SATURDAY, (10, 0), (16, 0));
+    // Christmas Eve is a Sunday, when the café is closed, and the calendar's
+    // hours do not change that
+    let hours = Exceptions::from([
+        (
+            NaiveDate::from_ymd_opt(2023, 12, 23).unwrap(),
+            DayRule::Hours {
+                start: NaiveTime::from_hms_opt(12, 0, 0).unwrap(),
+                end: NaiveTime::from_hms_opt(14, 0, 0).unwrap(),
+            },
+        ),
+        (
+            NaiveDate::from_ymd_opt(2023, 12, 24).unwrap(),
+            DayRule::Hours {
+                start: NaiveTime::from_hms_opt(9, 0, 0).unwrap(),
+                end: NaiveTime::from_hms_opt(12, 0, 0).unwrap(),
+            },
+        ),
+    ]);
+
+    assert!(active_window(&cafe, &hours, at(2023, 12, 23, 11, 0)).is_none());
+    assert_eq!(
+        active_window(&cafe, &hours, at(2023, 12, 23, 13, 0)),
+        Some(Window {
+            start: at(2023, 12, 23, 12, 0),
+            end: at(2023, 12, 23, 14, 0),
+        })
+    );
+    assert!(active_window(&cafe, &hours, at(2023, 12, 24, 10, 0)).is_none());
+}
@@ -0,0 +1,246 @@
+use async_session::chrono::Utc;
+use diesel::{insert_into, prelude::*, update};
+use hmac::{Hmac, Mac};
+use reqwest::{StatusCode, Url};
+use sha2::Sha256;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    access,
+    db::establish_connection,
+    events::{DoorEvent, EventBus},
+    models::{WebhookDelivery, WebhookSubscription},
+    outbound::AllowedHosts,
+    schema::{webhook_delivery, webhook_subscription},
+};
+
+const MAX_ATTEMPTS: i32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// One client for the dispatcher and replays, so every request has a timeout.
+// Receivers on private addresses go into WEBHOOK_ALLOWED_HOSTS (comma separated).
+#[derive(Clone)]
+pub struct Webhooks {
+    client: reqwest::Client,
+    allowed: AllowedHosts,
+}
+
+impl Webhooks {
+    pub fn new() -> Self {
+        Webhooks::with_allowed(AllowedHosts::from_env("WEBHOOK_ALLOWED_HOSTS"))
+    }
+
+    fn with_allowed(allowed: AllowedHosts) -> Self {
+        let client = allowed.client(REQUEST_TIMEOUT);
+        Webhooks { client, allowed }
+    }
+
+    pub fn check_url(&self, url: &str) -> Result<(), String> {
+        let url = Url::parse(url).map_err(|e| e.to_string())?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(format!("`{}` URLs are not supported", url.scheme()));
+        }
+        self.allowed.check(&url)
+    }
+
+    pub fn spawn_dispatcher(&self, events: &EventBus) {
+        let mut receiver = events.subscribe();
+        let webhooks = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => dispatch(&webhooks, &event),
+                    Err(RecvError::Lagged(n)) => {
+                        tracing::warn!("Webhook dispatcher skipped {n} events");
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    // Sends the delivery in the background, retrying with exponential backoff
+    pub fn deliver(&self, subscription: WebhookSubscription, delivery: WebhookDelivery) {
+        let webhooks = self.clone();
+
+        tokio::spawn(async move {
+            for attempt in 1..=MAX_ATTEMPTS {
+                if let Some(backoff) = backoff_before(attempt) {
+                    tokio::time::sleep(backoff).await;
+                }
+
+                let status = webhooks.send(&subscription, &delivery).await;
+
+                let conn = &mut establish_connection();
+                let delivered = match record_attempt(conn, delivery.id, attempt, status) {
+                    Ok(delivered) => delivered,
+                    Err(e) => {
+                        tracing::error!("Could not log webhook delivery {}: {e}", delivery.id);
+                        status.is_some_and(|s| s.is_success())
+                    }
+                };
+
+                if delivered {
+                    return;
+                }
+            }
+
+            tracing::warn!(
+                "Webhook delivery {} to {} failed after {MAX_ATTEMPTS} attempts",
+                delivery.id,
+                subscription.url
+            );
+        });
+    }
+
+    // The receiver's answer, none when it could not be reached
+    async fn send(
+        &self,
+        subscription: &WebhookSubscription,
+        delivery: &WebhookDelivery,
+    ) -> Option<StatusCode> {
+        let timestamp = Utc::now().timestamp().to_string();
+        let signature = sign(&subscription.secret, &timestamp, &delivery.payload);
+
+        let response = self
+            .client
+            .post(&subscription.url)
+            .header("Content-Type", "application/json")
+            .header("X-Webhook-Id", delivery.id.to_string())
+            .header("X-Webhook-Event", &delivery.event_type)
+            .header("X-Webhook-Timestamp", &timestamp)
+            .header("X-Webhook-Signature", format!("sha256={signature}"))
+            .body(delivery.payload.clone())
+            .send()
+            .await;
+
+        response.ok().map(|response| response.status())
+    }
+}
+
+impl Default for Webhooks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn dispatch(webhooks: &Webhooks, event: &DoorEvent) {
+    let conn = &mut establish_connection();
+
+    let subscriptions = match subscriptions_for(conn, event) {
+        Ok(subscriptions) => subscriptions,
+        Err(e) => {
+            tracing::error!("Could not load webhook subscriptions: {e}");
+            return;
+        }
+    };
+
+    let payload = serde_json::to_string(event).unwrap();
+
+    for subscription in subscriptions {
+        match create_delivery(conn, &subscription, event.kind.as_str(), &payload) {
+            Ok(delivery) => webhooks.deliver(subscription, delivery),
+            Err(e) => tracing::error!("Could not log webhook delivery: {e}"),
+        }
+    }
+}
+
+// Subscriptions to the event's door or to every door, as long as their owner
+// still manages the door
+fn subscriptions_for(
+    conn: &mut PgConnection,
+    event: &DoorEvent,
+) -> QueryResult<Vec<WebhookSubscription>> {
+    let subscriptions = webhook_subscription::table
+        .filter(
+            webhook_subscription::door_id
+                .eq(event.door_id)
+                .or(webhook_subscription::door_id.is_null()),
+        )
+        .filter(webhook_subscription::event_types.contains(vec![event.kind.as_str()]))
+        .order(webhook_subscription::id.asc())
+        .select(WebhookSubscription::as_select())
+        .load(conn)?;
+
+    Ok(subscriptions
+        .into_iter()
+        .filter(|subscription| access::can_manage(conn, event.door_id, subscription.owner_id))
+        .collect())
+}
+
+pub fn create_delivery(
+    conn: &mut PgConnection,
+    subscription: &WebhookSubscription,
+    event_type: &str,
+    payload: &str,
+) -> QueryResult<WebhookDelivery> {
+    insert_into(webhook_delivery::table)
+        .values((
+            webhook_delivery::subscription_id.eq(subscription.id),
+            webhook_delivery::event_type.eq(event_type),
+            webhook_delivery::payload.eq(payload),
+            webhook_delivery::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .returning(WebhookDelivery::as_returning())
+        .get_result(conn)
+}
+
+// Replays are logged as new deliveries so the original attempt stays visible.
+// None when the subscription has no such delivery.
+pub fn replay(
+    conn: &mut PgConnection,
+    subscription: &WebhookSubscription,
+    delivery_id: i32,
+) -> QueryResult<Option<WebhookDelivery>> {
+    let original = WebhookDelivery::belonging_to(subscription)
+        .filter(webhook_delivery::id.eq(delivery_id))
+        .select(WebhookDelivery::as_select())
+        .get_result(conn)
+        .optional()?;
+
+    match original {
+        Some(original) => {
+            create_delivery(conn, subscription, &original.event_type, &original.payload).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+// Returns whether the receiver took it
+fn record_attempt(
+    conn: &mut PgConnection,
+    delivery_id: i32,
+    attempt: i32,
+    status: Option<StatusCode>,
+) -> QueryResult<bool> {
+    let delivered = status.is_some_and(|s| s.is_success());
+    update(webhook_delivery::table.find(delivery_id))
+        .set((
+            webhook_delivery::attempts.eq(attempt),
+            webhook_delivery::response_status.eq(status.map(|s| i32::from(s.as_u16()))),
+            webhook_delivery::delivered.eq(delivered),
+            webhook_delivery::last_attempt_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)?;
+    Ok(delivered)
+}
+
+// Nothing to wait for before the first attempt, or after the last one
+fn backoff_before(attempt: i32) -> Option<Duration> {
+    (attempt > 1).then(|| INITIAL_BACKOFF * 2u32.pow((attempt - 2) as u32))
+}
+
+// Receivers recompute this over "<X-Webhook-Timestamp>.<body>" with their secret
+fn sign(secret: &str, timestamp: &str, payload: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(payload.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,265 @@
+use async_session::chrono::Utc;
+use diesel::{insert_into, prelude::*, update};
+use reqwest::StatusCode;
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use super::{
+    backoff_before, create_delivery, record_attempt, replay, sign, subscriptions_for, Webhooks,
+    MAX_ATTEMPTS,
+};
+use crate::{
+    events::{DoorEvent, EventKind},
+    models::{WebhookDelivery, WebhookSubscription},
+    outbound::AllowedHosts,
+    schema::{door_permission, webhook_delivery, webhook_subscription},
+    testing,
+};
+
+fn subscribe(
+    conn: &mut PgConnection,
+    owner_id: i32,
+    door_id: Option<i32>,
+    event_types: &[&str],
+) -> WebhookSubscription {
+    insert_into(webhook_subscription::table)
+        .values((
+            webhook_subscription::door_id.eq(door_id),
+            webhook_subscription::owner_id.eq(owner_id),
+            webhook_subscription::url.eq("https://example.com/hook"),
+            webhook_subscription::secret.eq("secret"),
+            webhook_subscription::event_types.eq(event_types),
+            webhook_subscription::created_at.eq(Utc::now().naive_utc()),
+        ))
+        .returning(WebhookSubscription::as_returning())
+        .get_result(conn)
+        .unwrap()
+}
+
+fn matching(conn: &mut PgConnection, door_id: i32, owner_id: i32) -> Vec<i32> {
+    let event = DoorEvent::new(EventKind::Opened, door_id, None);
+    subscriptions_for(conn, &event)
+        .unwrap()
+        .into_iter()
+        .filter(|subscription| subscription.owner_id == owner_id)
+        .map(|subscription| subscription.id)
+        .collect()
+}
+
+fn delivery(conn: &mut PgConnection, delivery_id: i32) -> WebhookDelivery {
+    webhook_delivery::table
+        .find(delivery_id)
+        .select(WebhookDelivery::as_select())
+        .get_result(conn)
+        .unwrap()
+}
+
+// Answers every request with the given status line, once
+async fn receiver(response: &'static str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = [0u8; 4096];
+        let _ = stream.read(&mut request).await;
+        let _ = stream.write_all(response.as_bytes()).await;
+    });
+    url
+}
+
+#[test]
+fn payloads_are_signed_over_timestamp_and_body() {
+    assert_eq!(
+        sign("secret", "1700000000", r#"{"type":"opened"}"#),
+        "42483772ecccff3dbe6b10488502cf773d37a8748cdaefe4bf8d8bb4721a317c"
+    );
+}
+
+#[test]
+fn signatures_change_with_the_secret_and_the_timestamp() {
+    let payload = r#"{"type":"opened"}"#;
+    let signature = sign("secret", "1700000000", payload);
+
+    assert_ne!(sign("other", "1700000000", payload), signature);
+    assert_ne!(sign("secret", "1700000001", payload), signature);
+}
+
+#[test]
+fn retries_back_off_exponentially() {
+    let waits: Vec<_> = (1..=MAX_ATTEMPTS).map(backoff_before).collect();
+
+    assert_eq!(
+        waits,
+        vec![
+            None,
+            Some(Duration::from_secs(2)),
+            Some(Duration::from_secs(4)),
+            Some(Duration::from_secs(8)),
+            Some(Duration::from_secs(16)),
+        ]
+    );
+}
+
+#[test]
+fn every_event_kind_can_be_subscribed_to() {
+    for kind in EventKind::ALL {
+        assert_eq!(EventKind::parse(kind.as_str()), Some(kind));
+    }
+    assert_eq!(EventKind::parse("door_exploded"), None);
+}
+
+#[test]
+fn receivers_on_private_addresses_need_to_be_allowed() {
+    let webhooks = Webhooks::with_allowed(AllowedHosts::new(&["10.0.0.7"]));
+
+    assert!(webhooks.check_url("https://93.184.216.34/hook").is_ok());
+    assert!(webhooks.check_url("http://10.0.0.7/hook").is_ok());
+    for url in [
+        "http://127.0.0.1:8080/api/v1/doors",
+        "http://169.254.169.254/latest/meta-data",
+        "http://[::1]/hook",
+        "http://192.168.1.1/hook",
+        "file:///etc/passwd",
+        "not a url",
+    ] {
+        assert!(webhooks.check_url(url).is_err(), "{url}");
+    }
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn subscriptions_match_the_door_and_the_event_type() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let alice = testing::user(conn, "alice");
+    let door_id = testing::door(conn, alice);
+    let other_door = testing::door(conn, alice);
+
+    let door = subscribe(conn, alice, Some(door_id), &["opened"]);
+    let global = subscribe(conn, alice, None, &["opened", "denied"]);
+    subscribe(conn, alice, Some(other_door), &["opened"]);
+    subscribe(conn, alice, Some(door_id), &["denied"]);
+
+    assert_eq!(matching(conn, door_id, alice), [door.id, global.id]);
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn subscriptions_stop_when_their_owner_can_no_longer_manage_the_door() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let alice = testing::user(conn, "alice");
+    let bob = testing::user(conn, "bob");
+    let door_id = testing::door(conn, alice);
+    insert_into(door_permission::table)
+        .values((
+            door_permission::door_id.eq(door_id),
+            door_permission::user_profile_id.eq(bob),
+            door_permission::edit_permission.eq(true),
+            door_permission::open_permission.eq(true),
+        ))
+        .execute(conn)
+        .unwrap();
+    let door = subscribe(conn, bob, Some(door_id), &["opened"]);
+    let global = subscribe(conn, bob, None, &["opened"]);
+    assert_eq!(matching(conn, door_id, bob), [door.id, global.id]);
+
+    update(door_permission::table)
+        .filter(door_permission::door_id.eq(door_id))
+        .set(door_permission::edit_permission.eq(false))
+        .execute(conn)
+        .unwrap();
+    assert!(matching(conn, door_id, bob).is_empty());
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn attempts_are_logged_and_replays_are_new_deliveries() {
+    let mut db = testing::database();
+    let conn = &mut db.conn;
+    let alice = testing::user(conn, "alice");
+    let door_id = testing::door(conn, alice);
+    let subscription = subscribe(conn, alice, Some(door_id), &["opened"]);
+    let other = subscribe(conn, alice, None, &["opened"]);
+
+    let original = create_delivery(conn, &subscription, "opened", r#"{"type":"opened"}"#).unwrap();
+    assert_eq!(original.attempts, 0);
+    let delivered = record_attempt(
+        conn,
+        original.id,
+        1,
+        Some(StatusCode::INTERNAL_SERVER_ERROR),
+    )
+    .unwrap();
+    assert!(!delivered);
+    let logged = delivery(conn, original.id);
+    assert_eq!(logged.attempts, 1);
+    assert_eq!(logged.response_status, Some(500));
+    assert!(!logged.delivered);
+
+    let replayed = replay(conn, &subscription, original.id).unwrap().unwrap();
+    assert_ne!(replayed.id, original.id);
+    assert_eq!(replayed.payload, original.payload);
+    assert_eq!(replayed.event_type, "opened");
+    assert_eq!(replayed.attempts, 0);
+    assert_eq!(delivery(conn, original.id), logged);
+
+    assert!(record_attempt(conn, replayed.id, 1, Some(StatusCode::NO_CONTENT)).unwrap());
+    assert!(delivery(conn, replayed.id).delivered);
+
+    // Deliveries of another subscription can not be replayed through this one
+    assert_eq!(replay(conn, &other, original.id).unwrap(), None);
+}
+
+fn unsaved(url: String) -> (WebhookSubscription, WebhookDelivery) {
+    let now = Utc::now().naive_utc();
+    let subscription = WebhookSubscription {
+        id: 1,
+        door_id: None,
+        owner_id: 1,
+        url,
+        secret: "secret".to_string(),
+        event_types: vec!["opened".to_string()],
+        created_at: now,
+    };
+    let delivery = WebhookDelivery {
+        id: 1,
+        subscription_id: 1,
+        event_type: "opened".to_string(),
+        payload: r#"{"type":"opened"}"#.to_string(),
+        attempts: 0,
+        response_status: None,
+        delivered: false,
+        created_at: now,
+        last_attempt_at: None,
+    };
+    (subscription, delivery)
+}
+
+#[tokio::test]
+async fn redirects_are_not_followed() {
+    let url = receiver(
+        "HTTP/1.1 302 Found\r\nLocation: http://169.254.169.254/\r\nContent-Length: 0\r\n\r\n",
+    )
+    .await;
+    let (subscription, delivery) = unsaved(url);
+
+    let webhooks = Webhooks::with_allowed(AllowedHosts::new(&["127.0.0.1"]));
+    assert_eq!(
+        webhooks.send(&subscription, &delivery).await,
+        Some(StatusCode::FOUND)
+    );
+}
+
+#[tokio::test]
+async fn private_receivers_are_not_called() {
+    let url = receiver("HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n").await;
+    let (subscription, delivery) = unsaved(url.replace("127.0.0.1", "localhost"));
+
+    let webhooks = Webhooks::with_allowed(AllowedHosts::default());
+    assert_eq!(webhooks.send(&subscription, &delivery).await, None);
+}
@@ -0,0 +1,364 @@
+// End-to-end runs of the server against door-sim controllers. They need a
+// Postgres database they may wipe, given as TEST_DATABASE_URL, so like the
+// other database tests they are marked `#[ignore]`. Run them with
+// `cargo test --test simulator -- --ignored`.
+
+use diesel::{pg::PgConnection, prelude::*, sql_query};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    process::{Child, Command, Output, Stdio},
+    sync::{Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations/");
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(15);
+const DOOR_CODE: &str = "sim-code";
+
+// Every test starts from an empty database, so they take turns
+static DATABASE: Mutex<()> = Mutex::new(());
+
+struct Server {
+    child: Child,
+    url: String,
+    database_url: String,
+    _database: MutexGuard<'static, ()>,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn token(device_id: i32) -> String {
+    format!("sim-device-{device_id}")
+}
+
+// User 1 owns door 1, which has the given controllers and a door code
+fn reset_database(database_url: &str, devices: &[i32]) {
+    let conn = &mut PgConnection::establish(database_url).unwrap();
+
+    sql_query("DROP SCHEMA public CASCADE")
+        .execute(conn)
+        .unwrap();
+    sql_query("CREATE SCHEMA public").execute(conn).unwrap();
+    conn.run_pending_migrations(MIGRATIONS).unwrap();
+
+    sql_query("INSERT INTO user_profile (id, discord_id, username) VALUES (1, '1', 'sim')")
+        .execute(conn)
+        .unwrap();
+    sql_query("INSERT INTO door (id, about, owner_id) VALUES (1, 'simulated', 1)")
+        .execute(conn)
+        .unwrap();
+    for device_id in devices {
+        sql_query(format!(
+            "INSERT INTO device (id, door_id, name, token_hash, created_at) \
+             VALUES ({device_id}, 1, 'sim {device_id}', '{}', now())",
+            hash_token(&token(*device_id))
+        ))
+        .execute(conn)
+        .unwrap();
+    }
+    sql_query(format!(
+        "INSERT INTO door_code (code, door_id, created_at, creator_id, used) \
+         VALUES ('{DOOR_CODE}', 1, now(), 1, false)"
+    ))
+    .execute(conn)
+    .unwrap();
+
+    // The IDs above were picked by hand. The sequences have to move past them,
+    // or the next insert into the same database, like the unit tests sharing
+    // TEST_DATABASE_URL, collides with them.
+    for table in ["user_profile", "door", "device"] {
+        sql_query(format!(
+            "SELECT setval(pg_get_serial_sequence('{table}', 'id'), \
+             coalesce((SELECT max(id) FROM {table}), 0) + 1, false)"
+        ))
+        .execute(conn)
+        .unwrap();
+    }
+}
+
+fn start_server(devices: &[i32]) -> Server {
+    let database_url = env::var("TEST_DATABASE_URL")
+        .expect("TEST_DATABASE_URL must point at a Postgres database this test may wipe");
+    let database = DATABASE.lock().unwrap_or_else(|e| e.into_inner());
+    reset_database(&database_url, devices);
+
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let dir = env::temp_dir().join(format!("door-sim-{}", address.port()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // A clean environment, so a developer's .env does not leak in
+    let child = Command::new(env!("CARGO_BIN_EXE_server"))
+        .env_clear()
+        .current_dir(&dir)
+        .env("DATABASE_URL", &database_url)
+        .env("BIND_ADDRESS", address.to_string())
+        .env("CLIENT_ID", "sim")
+        .env("CLIENT_SECRET", "sim")
+        .env("REDIRECT_URL", "http://localhost/callback")
+        .env("AUTH_URL", "http://localhost/authorize")
+        .env("TOKEN_URL", "http://localhost/token")
+        .env(
+            "DEVICE_SIGNING_KEY",
+            "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+        )
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let started = Instant::now();
+    while TcpStream::connect(address).is_err() {
+        assert!(
+            started.elapsed() < STARTUP_TIMEOUT,
+            "server did not come up"
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    Server {
+        child,
+        url: format!("http://{address}"),
+        database_url,
+        _database: database,
+    }
+}
+
+fn scenario(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/scenarios")
+        .join(format!("{name}.json"))
+}
+
+impl Server {
+    fn simulate(&self, name: &str, devices: &[i32], options: &[&str]) -> Output {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_door-sim"));
+        command
+            .arg("--server")
+            .arg(&self.url)
+            .arg("--scenario")
+            .arg(scenario(name))
+            .args(options);
+        for device_id in devices {
+            command
+                .arg("--device")
+                .arg(format!("{device_id}:{}", token(*device_id)));
+        }
+        command.output().unwrap()
+    }
+
+    fn run(&self, name: &str, devices: &[i32], options: &[&str]) {
+        let output = self.simulate(name, devices, options);
+        assert!(
+            output.status.success(),
+            "scenario {name} failed:\n{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    fn query<T>(&self, sql: &str) -> Vec<T>
+    where
+        T: QueryableByName<diesel::pg::Pg> + 'static,
+    {
+        let conn = &mut PgConnection::establish(&self.database_url).unwrap();
+        sql_query(sql).load(conn).unwrap()
+    }
+
+    fn execute(&self, sql: &str) {
+        let conn = &mut PgConnection::establish(&self.database_url).unwrap();
+        sql_query(sql).execute(conn).unwrap();
+    }
+
+    fn event_kinds(&self) -> Vec<String> {
+        self.query::<Kind>("SELECT kind FROM door_event ORDER BY id")
+            .into_iter()
+            .map(|row| row.kind)
+            .collect()
+    }
+
+    fn command_statuses(&self) -> Vec<String> {
+        self.query::<Status>("SELECT status FROM device_command ORDER BY id")
+            .into_iter()
+            .map(|row| row.status)
+            .collect()
+    }
+}
+
+#[derive(QueryableByName)]
+struct Kind {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    kind: String,
+}
+
+#[derive(QueryableByName)]
+struct Status {
+    #[diesel(sql_type = diesel::sql_types::Text)]
+    status: String,
+}
+
+#[derive(QueryableByName)]
+struct Count {
+    #[diesel(sql_type = diesel::sql_types::BigInt)]
+    count: i64,
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn door_code_opens_the_door() {
+    let server = start_server(&[1]);
+
+    server.run("open_with_door_code", &[1], &[]);
+
+    assert_eq!(server.command_statuses(), ["acknowledged"]);
+    let kinds = server.event_kinds();
+    for kind in ["code_redeemed", "opened", "sensor_opened", "sensor_closed"] {
+        assert!(kinds.iter().any(|k| k == kind), "no {kind} in {kinds:?}");
+    }
+    assert!(!kinds.iter().any(|k| k == "forced_entry"));
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn door_codes_work_once() {
+    let server = start_server(&[1]);
+
+    server.run("used_door_code", &[1], &[]);
+
+    let kinds = server.event_kinds();
+    assert_eq!(kinds.iter().filter(|k| *k == "opened").count(), 1);
+    assert!(kinds.iter().any(|k| k == "denied"));
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn door_codes_only_open_their_door_until_they_expire() {
+    let server = start_server(&[1]);
+    server.execute("INSERT INTO door (id, about, owner_id) VALUES (2, 'other', 1)");
+    server.execute(
+        "INSERT INTO door_code (code, door_id, created_at, creator_id, used) \
+         VALUES ('other-door-code', 2, now(), 1, false)",
+    );
+    server.execute(
+        "INSERT INTO door_code (code, door_id, created_at, expires_at, creator_id, used) \
+         VALUES ('expired-code', 1, now() - interval '2 hours', now() - interval '1 hour', 1, false)",
+    );
+
+    server.run("foreign_and_expired_door_codes", &[1], &[]);
+
+    let kinds = server.event_kinds();
+    assert_eq!(kinds.iter().filter(|k| *k == "code_redeemed").count(), 1);
+    assert_eq!(kinds.iter().filter(|k| *k == "denied").count(), 2);
+    let used = server.query::<Count>("SELECT count(*) AS count FROM door_code WHERE used");
+    assert_eq!(used[0].count, 1);
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn every_encoding_opens_the_door() {
+    for encoding in ["json", "cbor", "msgpack"] {
+        let server = start_server(&[1]);
+
+        server.run("open_with_door_code", &[1], &["--encoding", encoding]);
+        assert_eq!(server.command_statuses(), ["acknowledged"], "{encoding}");
+    }
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn every_controller_of_the_door_opens() {
+    let server = start_server(&[1, 2]);
+
+    server.run("open_on_every_controller", &[1, 2], &[]);
+
+    assert_eq!(server.command_statuses(), ["acknowledged", "acknowledged"]);
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn slow_controllers_still_open() {
+    let server = start_server(&[1]);
+
+    server.run("open_with_door_code", &[1], &["--latency-ms", "800"]);
+
+    assert_eq!(server.command_statuses(), ["acknowledged"]);
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn failed_opens_stay_unacknowledged() {
+    let server = start_server(&[1]);
+
+    server.run("open_with_door_code", &[1], &["--failure-rate", "1"]);
+
+    assert_eq!(server.command_statuses(), ["delivered"]);
+    assert!(!server.event_kinds().iter().any(|k| k == "sensor_opened"));
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn heartbeats_are_recorded() {
+    let server = start_server(&[1]);
+
+    server.run("heartbeats", &[1], &["--heartbeat-secs", "1"]);
+
+    let heartbeats =
+        server.query::<Count>("SELECT count(*) AS count FROM device_telemetry WHERE device_id = 1");
+    assert!(
+        heartbeats[0].count >= 2,
+        "{} heartbeats",
+        heartbeats[0].count
+    );
+    let hello = server.query::<Count>(
+        "SELECT count(*) AS count FROM device WHERE id = 1 AND firmware_version = 'sim'",
+    );
+    assert_eq!(hello[0].count, 1);
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn doorbell_and_forced_entry_are_logged() {
+    let server = start_server(&[1]);
+
+    server.run(
+        "doorbell_and_forced_entry",
+        &[1],
+        &["--heartbeat-secs", "0"],
+    );
+
+    let kinds = server.event_kinds();
+    assert_eq!(
+        kinds,
+        ["doorbell", "sensor_opened", "forced_entry", "sensor_closed"]
+    );
+    let rings = server.query::<Count>("SELECT count(*) AS count FROM doorbell_ring");
+    assert_eq!(rings[0].count, 1);
+}
+
+#[test]
+#[ignore = "needs TEST_DATABASE_URL"]
+fn unknown_controllers_are_turned_away() {
+    // Device 1 is not registered on the server
+    let server = start_server(&[]);
+
+    let output = server.simulate("connect", &[1], &[]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("did not get `welcome`"));
+}